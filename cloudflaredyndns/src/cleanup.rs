@@ -0,0 +1,210 @@
+use std::io::{BufRead, IsTerminal, Write};
+
+use tracing::{error, info, warn};
+
+use crate::cloudflare::{CloudflareClient, DnsRecord, DuplicateRecords};
+use crate::config::{Config, DomainConfig};
+use crate::error::Error;
+use crate::events::EventBus;
+use crate::ipsource::IpFamily;
+use crate::provider::DnsProvider;
+use crate::state::State;
+
+/// The `cleanup` command: deletes the A/AAAA records the updater would otherwise leave behind.
+/// - Duplicates of an enabled family, keeping the record last published (or else the one modified
+///   most recently). Not with `CF_DUPLICATE_RECORDS=all`, they are wanted then.
+/// - Records of a family disabled for the domain, only when they carry `CF_RECORD_COMMENT` or one
+///   of `CF_RECORD_TAGS`, records added by hand are only reported.
+pub async fn run(dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+	let mut config = Config::load()?;
+	config.dry_run |= dry_run;
+
+	let cloudflare = CloudflareClient::new(config.api_client.clone(), config.auth.clone(), config.fallback_token.as_ref(), &config.api_url, config.retry.clone(), EventBus::default())
+		.dry_run(config.dry_run);
+	let state = State::load(config.state_file.clone()).await;
+
+	let mut deleted = 0;
+	let mut failed = 0;
+	for domain in config.domains.iter().chain(&config.canary) {
+		match cleanup_domain(&config, &cloudflare, &state, domain).await {
+			Ok(count) => deleted += count,
+			Err(e) => {
+				error!("Error cleaning up '{}': {}", domain.name, e);
+				failed += 1;
+			}
+		}
+	}
+
+	if let Err(e) = state.save().await {
+		warn!("Could not save state: {}", e);
+	}
+
+	let verb = if config.dry_run { "Would delete" } else { "Deleted" };
+	info!("{} {} records", verb, deleted);
+	if failed > 0 {
+		return Err(format!("Cleanup failed for {} domains", failed).into());
+	}
+	Ok(())
+}
+
+/// The `purge` command: deletes the A/AAAA records of the configured domains, for decommissioning
+/// the host. With `managed_only` only the ones carrying `CF_RECORD_COMMENT` or one of
+/// `CF_RECORD_TAGS`. The records are listed first and only deleted with `yes`, or once confirmed
+/// on a terminal.
+pub async fn purge(managed_only: bool, yes: bool, dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+	let mut config = Config::load()?;
+	config.dry_run |= dry_run;
+
+	let cloudflare = CloudflareClient::new(config.api_client.clone(), config.auth.clone(), config.fallback_token.as_ref(), &config.api_url, config.retry.clone(), EventBus::default())
+		.dry_run(config.dry_run);
+	let state = State::load(config.state_file.clone()).await;
+
+	let mut records = Vec::new();
+	for domain in config.domains.iter().chain(&config.canary) {
+		let account = cloudflare.account(domain.credentials.as_ref());
+		let zone_id = zone_of(&config, &account, &state, domain).await?;
+		for family in [IpFamily::V4, IpFamily::V6] {
+			for record in account.list_records(&domain.name, family.record_type(), &zone_id).await? {
+				if managed_only && !is_managed(&config, &record) {
+					info!("Keeping {} record '{}' ({}), it isn't marked as managed", record.record_type, record.name, record.content);
+					continue;
+				}
+				records.push((account.clone(), zone_id.clone(), family, record));
+			}
+		}
+	}
+
+	if records.is_empty() {
+		info!("No records to delete");
+		return Ok(());
+	}
+	for (_, _, _, record) in &records {
+		info!("Purging {} record '{}' ({})", record.record_type, record.name, record.content);
+	}
+	if !config.dry_run && !yes && !confirm(records.len())? {
+		return Err("Not deleting anything, confirm with --yes".into());
+	}
+
+	for (account, zone_id, family, record) in &records {
+		account.delete_record(record, zone_id).await?;
+		if !config.dry_run && state.published(&record.name, *family).await.is_some_and(|p| p.id == record.id) {
+			state.forget(&record.name, *family).await;
+		}
+	}
+
+	if let Err(e) = state.save().await {
+		warn!("Could not save state: {}", e);
+	}
+
+	let verb = if config.dry_run { "Would delete" } else { "Deleted" };
+	info!("{} {} records", verb, records.len());
+	Ok(())
+}
+
+/// Asks on the terminal whether `count` records should be deleted, without a terminal there is
+/// nobody to ask.
+fn confirm(count: usize) -> Result<bool, std::io::Error> {
+	if !std::io::stdin().is_terminal() {
+		return Ok(false);
+	}
+	eprint!("Delete {} records? [y/N] ", count);
+	std::io::stderr().flush()?;
+	let mut answer = String::new();
+	std::io::stdin().lock().read_line(&mut answer)?;
+	Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
+pub(crate) async fn zone_of(config: &Config, cloudflare: &CloudflareClient, state: &State, domain: &DomainConfig) -> Result<String, Error> {
+	let base_domain = cloudflare.zone_name(&domain.name)?;
+	match state.zone_id(&base_domain, config.zone_cache_ttl).await {
+		Some(zone_id) => Ok(zone_id),
+		None => cloudflare.get_zone_id(&base_domain, config.zone_status.as_deref()).await,
+	}
+}
+
+async fn cleanup_domain(config: &Config, cloudflare: &CloudflareClient, state: &State, domain: &DomainConfig) -> Result<usize, Error> {
+	let cloudflare = &cloudflare.account(domain.credentials.as_ref());
+	let zone_id = zone_of(config, cloudflare, state, domain).await?;
+
+	let mut deleted = 0;
+	for family in [IpFamily::V4, IpFamily::V6] {
+		let records = cloudflare.list_records(&domain.name, family.record_type(), &zone_id).await?;
+		let enabled = match family {
+			IpFamily::V4 => domain.ipv4_enabled,
+			IpFamily::V6 => domain.ipv6_enabled,
+		};
+
+		let stale: Vec<&DnsRecord> = if enabled && config.duplicate_records == DuplicateRecords::All {
+			continue;
+		} else if enabled {
+			let published = state.published(&domain.name, family).await.map(|p| p.id);
+			let Some(keep) = records.iter()
+				.max_by_key(|r| (published.as_deref() == Some(r.id.as_str()), r.modified_on.clone())) else {
+				continue;
+			};
+			records.iter().filter(|r| r.id != keep.id).collect()
+		} else {
+			let (managed, manual): (Vec<&DnsRecord>, Vec<&DnsRecord>) = records.iter().partition(|r| is_managed(config, r));
+			for record in manual {
+				warn!("Keeping {} record '{}' ({}), {} is disabled but the record isn't marked as managed", record.record_type, record.name, record.content, family);
+			}
+			managed
+		};
+
+		for record in stale {
+			cloudflare.delete_record(record, &zone_id).await?;
+			deleted += 1;
+			if !config.dry_run && state.published(&domain.name, family).await.is_some_and(|p| p.id == record.id) {
+				state.forget(&domain.name, family).await;
+			}
+		}
+	}
+
+	Ok(deleted)
+}
+
+/// Deletes the `family` records of every domain that has it enabled, when the updater published
+/// them or they are marked as managed, e.g. an AAAA record left on an address that is gone. Returns
+/// how many were deleted, a domain that fails is logged and skipped.
+pub async fn withdraw(config: &Config, cloudflare: &CloudflareClient, state: &State, family: IpFamily) -> usize {
+	let mut deleted = 0;
+	for domain in config.domains.iter().chain(&config.canary) {
+		let enabled = match family {
+			IpFamily::V4 => domain.ipv4_enabled,
+			IpFamily::V6 => domain.ipv6_enabled,
+		};
+		if !enabled {
+			continue;
+		}
+
+		let account = cloudflare.account(domain.credentials.as_ref());
+		let result = async {
+			let zone_id = zone_of(config, &account, state, domain).await?;
+			let published = state.published(&domain.name, family).await.map(|p| p.id);
+			let mut count = 0;
+			for record in account.list_records(&domain.name, family.record_type(), &zone_id).await? {
+				if published.as_deref() != Some(record.id.as_str()) && !is_managed(config, &record) {
+					warn!("Keeping {} record '{}' ({}), it isn't marked as managed", record.record_type, record.name, record.content);
+					continue;
+				}
+				account.delete_record(&record, &zone_id).await?;
+				count += 1;
+			}
+			if !config.dry_run {
+				state.forget(&domain.name, family).await;
+			}
+			Ok::<_, Error>(count)
+		};
+		match result.await {
+			Ok(count) => deleted += count,
+			Err(e) => error!("Error deleting the {} records of '{}': {}", family.record_type(), domain.name, e),
+		}
+	}
+	deleted
+}
+
+/// Carries the configured record comment or one of the tags.
+pub(crate) fn is_managed(config: &Config, record: &DnsRecord) -> bool {
+	config.record_comment.as_ref().is_some_and(|comment| record.comment.as_ref() == Some(comment))
+		|| record.tags.iter().any(|tag| config.record_tags.contains(tag))
+}
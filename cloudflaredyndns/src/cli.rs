@@ -0,0 +1,148 @@
+use clap::{Parser, Subcommand};
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+	#[command(subcommand)]
+	pub command: Option<Command>,
+
+	/// Run a single update cycle and exit, for cron jobs and systemd timers (same as CF_RUN_ONCE=true)
+	#[arg(long)]
+	pub once: bool,
+
+	/// Show tokens, passwords and full Cloudflare responses in the logs, for debugging only
+	#[arg(long, global = true)]
+	pub log_insecure: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+	/// Run the updater (default when no command is given)
+	Run,
+
+	/// Delete duplicate A/AAAA records of the configured domains, and the records left behind by a
+	/// disabled IPv4/IPv6 that carry CF_RECORD_COMMENT or CF_RECORD_TAGS
+	Cleanup {
+		/// Only log the records that would be deleted (same as CF_DRY_RUN=true)
+		#[arg(long)]
+		dry_run: bool,
+	},
+
+	/// Delete the A/AAAA records of the configured domains, e.g. when decommissioning the host
+	Purge {
+		/// Only delete the records carrying CF_RECORD_COMMENT or one of CF_RECORD_TAGS
+		#[arg(long)]
+		managed_only: bool,
+
+		/// Delete without asking for confirmation
+		#[arg(long)]
+		yes: bool,
+
+		/// Only log the records that would be deleted (same as CF_DRY_RUN=true)
+		#[arg(long)]
+		dry_run: bool,
+	},
+
+	/// Print the detected public addresses and the A/AAAA records Cloudflare holds for the
+	/// configured domains, and whether they have the address
+	Status {
+		/// Print JSON instead of a table
+		#[arg(long)]
+		json: bool,
+	},
+
+	/// Compare the records of the configured domains with the configuration (address, proxied, TTL)
+	/// and print the differences, exits with 2 when anything differs. Nothing is changed
+	Check {
+		/// Don't compare the addresses, e.g. in CI where the detected address isn't the host's
+		#[arg(long)]
+		ignore_content: bool,
+	},
+
+	/// Validate the configuration, the credentials and the access to the zone of every domain,
+	/// exits with 5 when anything fails. Nothing is changed
+	CheckConfig {
+		/// Only load and validate the settings, without contacting Cloudflare
+		#[arg(long)]
+		offline: bool,
+	},
+
+	/// Print the A/AAAA records Cloudflare holds for the configured domains
+	ListRecords {
+		/// Print JSON instead of a table
+		#[arg(long)]
+		json: bool,
+	},
+
+	/// Write every DNS record of the zones of the configured domains, of any type, as JSON (or CSV)
+	/// for backups
+	Export {
+		/// Only the records carrying CF_RECORD_COMMENT or one of CF_RECORD_TAGS
+		#[arg(long)]
+		managed_only: bool,
+
+		/// Write CSV instead of JSON
+		#[arg(long)]
+		csv: bool,
+
+		/// File to write to instead of stdout
+		#[arg(long, short)]
+		output: Option<std::path::PathBuf>,
+	},
+
+	/// Save a secret (CF_TOKEN by default) in the OS keyring, read from stdin. Used with CF_KEYRING=true
+	#[cfg(feature = "keyring")]
+	StoreToken {
+		/// Setting the secret is stored for
+		#[arg(long, default_value = "CF_TOKEN")]
+		setting: String,
+	},
+
+	/// Stream live events from a running daemon
+	#[cfg(unix)]
+	Watch {
+		/// Print events as JSON lines
+		#[arg(long)]
+		json: bool,
+
+		/// Control socket of the daemon
+		#[arg(long, env = "CF_CONTROL_SOCKET")]
+		socket: Option<std::path::PathBuf>,
+	},
+
+	/// Register the updater as a Windows service started at boot, run from an elevated prompt
+	#[cfg(windows)]
+	InstallService {
+		/// Config file the service reads, a service doesn't see the settings of the current shell
+		#[arg(long, env = "CF_CONFIG")]
+		config: Option<std::path::PathBuf>,
+	},
+
+	/// Stop and remove the Windows service
+	#[cfg(windows)]
+	UninstallService,
+
+	/// Run under the Windows service control manager, started by the service
+	#[cfg(windows)]
+	#[command(hide = true)]
+	RunService {
+		#[arg(long)]
+		config: Option<std::path::PathBuf>,
+	},
+
+	/// Serve an in-memory imitation of the Cloudflare zones/dns_records API for local testing
+	#[cfg(feature = "mock-server")]
+	MockServer {
+		/// Address to listen on
+		#[arg(long, default_value = "127.0.0.1:8787")]
+		listen: std::net::SocketAddr,
+
+		/// Zones served by the mock, can be repeated
+		#[arg(long = "zone", default_value = "example.com")]
+		zones: Vec<String>,
+
+		/// Only accept requests authenticated with this token (any token is accepted by default)
+		#[arg(long)]
+		token: Option<String>,
+	},
+}
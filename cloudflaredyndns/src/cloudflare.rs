@@ -1,240 +1,1338 @@
 use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::time::{Duration, Instant};
 
-use reqwest::Client;
-use serde_json::Value;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}};
 
-const PROTOCOL: &str = "https";
-const CLOUDFLARE_API_HOST: &str = "api.cloudflare.com";
+use async_trait::async_trait;
+use reqwest::{Client, Method, Request, RequestBuilder, Response, StatusCode, header::RETRY_AFTER};
+use serde::{Deserialize, de::DeserializeOwned};
+use tracing::{debug, info, warn};
 
-const PUBLIC_IPV4_RESOLVER_HOST: &str = "https://v4.ident.me";
-const PUBLIC_IPV6_RESOLVER_HOST: &str = "https://v6.ident.me";
+use crate::config::TTL_AUTO;
+use crate::error::Error;
+use crate::events::{Event, EventBus};
+use crate::ipsource::IpFamily;
+use crate::metrics;
+use crate::provider::{DnsProvider, Record, RecordChange, RecordRequest, RecordUpdate};
+use crate::secret::{self, Secret};
 
-const ZONES_PATH: &str = "/client/v4/zones";
+pub const DEFAULT_API_URL: &str = "https://api.cloudflare.com/client/v4";
 
-const LIST_RECORDS_PATH: &str = "/client/v4/zones/$zone_id/dns_records"; //zone_id
+const ZONES_PATH: &str = "/zones";
 
-const CREATE_RECORD_PATH: &str = "/client/v4/zones/$zone_id/dns_records"; //zone_id
+const LIST_RECORDS_PATH: &str = "/zones/$zone_id/dns_records"; //zone_id
 
-const UPDATE_RECORD_PATH: &str = "/client/v4/zones/$zone_id/dns_records/$dns_record_id"; //zone_id, dns_record_id
+const CREATE_RECORD_PATH: &str = "/zones/$zone_id/dns_records"; //zone_id
+
+const UPDATE_RECORD_PATH: &str = "/zones/$zone_id/dns_records/$dns_record_id"; //zone_id, dns_record_id
+
+const DELETE_RECORD_PATH: &str = "/zones/$zone_id/dns_records/$dns_record_id"; //zone_id, dns_record_id
+// https://developers.cloudflare.com/api/resources/dns/subresources/records/methods/batch/
+const BATCH_RECORDS_PATH: &str = "/zones/$zone_id/dns_records/batch"; //zone_id
 
 pub const DNS_RECORD_TYPE_A: &str = "A";
 pub const DNS_RECORD_TYPE_AAAA: &str = "AAAA";
+pub const DNS_RECORD_TYPE_CNAME: &str = "CNAME";
+
+// GET, PATCH
+const POOL_PATH: &str = "/accounts/$account_id/load_balancers/pools/$pool_id"; //account_id, pool_id
+
+// GET, POST, DELETE
+const LIST_ITEMS_PATH: &str = "/accounts/$account_id/rules/lists/$list_id/items"; //account_id, list_id
+// Largest page of list items, allowlists are far shorter
+const LIST_ITEMS_PER_PAGE: u32 = 500;
+
+const USER_PATH: &str = "/user";
+
+const VERIFY_TOKEN_PATH: &str = "/user/tokens/verify";
+
+const TOKEN_PATH: &str = "/user/tokens/$token_id"; //token_id
+
+// Permission group that allows editing DNS records ("Zone.DNS: Edit" in the dashboard)
+const DNS_EDIT_PERMISSION: &str = "DNS Write";
+// The same permission in the zone's permission list
+const ZONE_DNS_EDIT_PERMISSION: &str = "#dns_records:edit";
+
+// Page size of zone and record listings, the most the zones endpoint accepts
+const PER_PAGE: u32 = 50;
+// Changes sent in one batch request, below the limit of every plan
+const BATCH_SIZE: usize = 100;
+
+// Pause after a 429 that doesn't say how long to wait
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+
+/// How failed API requests are repeated: up to `attempts` tries in total, waiting a random time of
+/// up to `base_delay * 2^n` (capped at `max_delay`) before try `n + 1`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+	pub attempts: u32,
+	pub base_delay: Duration,
+	pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		Self {
+			attempts: 4,
+			base_delay: Duration::from_millis(500),
+			max_delay: Duration::from_secs(30),
+		}
+	}
+}
+
+/// What to do when a name has more than one record of the updated type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateRecords {
+	/// Update one of them, the one that already has the address or else the lowest id, and warn
+	#[default]
+	First,
+	/// Update every one of them
+	All,
+}
+
+impl DuplicateRecords {
+	pub fn parse(value: &str) -> Result<Self, String> {
+		match value.trim().to_ascii_lowercase().as_str() {
+			"" | "first" => Ok(DuplicateRecords::First),
+			"all" => Ok(DuplicateRecords::All),
+			other => Err(format!("Unknown mode '{}', expected first or all", other)),
+		}
+	}
+}
+
+/// What to do when a record has to be created for a name that is a CNAME, Cloudflare rejects
+/// address records next to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CnameConflict {
+	/// Leave the CNAME and skip the name with a warning
+	#[default]
+	Skip,
+	/// Delete the CNAME and create the address record
+	Replace,
+}
+
+impl CnameConflict {
+	pub fn parse(value: &str) -> Result<Self, String> {
+		match value.trim().to_ascii_lowercase().as_str() {
+			"" | "skip" => Ok(CnameConflict::Skip),
+			"replace" => Ok(CnameConflict::Replace),
+			other => Err(format!("Unknown mode '{}', expected skip or replace", other)),
+		}
+	}
+}
+
+impl RetryPolicy {
+	fn delay(&self, attempt: u32) -> Duration {
+		let backoff = self.base_delay.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_delay);
+		// Full jitter, spreads out the retries of domains that failed together
+		let random = RandomState::new().hash_one(attempt);
+		backoff.mul_f64((random % 1000) as f64 / 1000.0)
+	}
+}
+
+/// Stops sending API requests for `cooldown` after `threshold` requests in a row failed with a 5xx
+/// response or a connection problem, so that an outage doesn't have every domain wait for its
+/// timeouts. After the cooldown a single request probes whether the API is back. A `threshold`
+/// of 0 disables it.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+	pub threshold: u32,
+	pub cooldown: Duration,
+}
+
+impl Default for CircuitBreaker {
+	fn default() -> Self {
+		Self {
+			threshold: 10,
+			cooldown: Duration::from_secs(60),
+		}
+	}
+}
+
+#[derive(Debug, Default)]
+struct CircuitState {
+	failures: u32,
+	open_until: Option<Instant>,
+	// A request is probing the API after the cooldown, the others are still refused
+	probing: bool,
+}
+
+/// The [`CircuitBreaker`] of a client, shared by every account since an outage hits them all.
+#[derive(Debug, Clone, Default)]
+struct Circuit {
+	breaker: CircuitBreaker,
+	state: Arc<Mutex<CircuitState>>,
+}
+
+/// Permission to send one request, returned by [`Circuit::allow`]. The outcome is counted with
+/// [`CircuitPermit::record`], a probe dropped before its request finished (a cancelled cycle or
+/// shutdown) lets the next request probe instead.
+struct CircuitPermit<'a> {
+	circuit: &'a Circuit,
+	probe: bool,
+}
+
+impl CircuitPermit<'_> {
+	fn record(mut self, error: Option<&Error>) {
+		self.probe = false;
+		self.circuit.record(error);
+	}
+}
+
+impl Drop for CircuitPermit<'_> {
+	fn drop(&mut self) {
+		if self.probe {
+			self.circuit.state.lock().unwrap().probing = false;
+		}
+	}
+}
+
+impl Circuit {
+	/// Fails with [`Error::CircuitOpen`] while requests are paused.
+	fn allow(&self) -> Result<CircuitPermit<'_>, Error> {
+		let permit = |probe| CircuitPermit { circuit: self, probe };
+		if self.breaker.threshold == 0 {
+			return Ok(permit(false));
+		}
+		let mut state = self.state.lock().unwrap();
+		match state.open_until {
+			Some(until) if until > Instant::now() => Err(Error::CircuitOpen),
+			Some(_) if state.probing => Err(Error::CircuitOpen),
+			Some(_) => {
+				info!("Probing whether the Cloudflare API recovered");
+				state.probing = true;
+				Ok(permit(true))
+			},
+			None => Ok(permit(false)),
+		}
+	}
+
+	/// Counts the outcome of a request, `error` is `None` when Cloudflare answered.
+	fn record(&self, error: Option<&Error>) {
+		if self.breaker.threshold == 0 {
+			return;
+		}
+		// A 429 or a rejected request means the API is up
+		let failed = error.is_some_and(|e| e.is_transient() && !e.is_rate_limited());
+		let mut state = self.state.lock().unwrap();
+		if !failed {
+			if state.open_until.is_some() {
+				info!("Cloudflare API recovered, resuming requests");
+			}
+			*state = CircuitState::default();
+			return;
+		}
+
+		state.failures += 1;
+		if state.probing || (state.open_until.is_none() && state.failures >= self.breaker.threshold) {
+			warn!("{} Cloudflare API requests in a row failed, pausing requests for {} seconds", state.failures, self.breaker.cooldown.as_secs());
+			state.open_until = Some(Instant::now() + self.breaker.cooldown);
+			state.probing = false;
+		}
+	}
+}
+
+/// How requests are authenticated.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Credentials {
+	/// Scoped API token (`CF_TOKEN`), sent as `Authorization: Bearer`
+	Token(Secret),
+	/// Legacy Global API Key of the account (`CF_API_KEY`, `CF_AUTH_EMAIL`), sent as
+	/// `X-Auth-Email`/`X-Auth-Key`
+	GlobalKey { email: String, key: Secret },
+}
+
+impl Credentials {
+	fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+		match self {
+			Credentials::Token(token) => request.bearer_auth(token.expose()),
+			Credentials::GlobalKey { email, key } => request.header("X-Auth-Email", email).header("X-Auth-Key", key.expose()),
+		}
+	}
+}
+
+/// Cloudflare API client, bundles the HTTP client with the credentials and the API endpoint
+/// (`CF_API_URL`, which can point at the mock server for local testing).
+#[derive(Clone)]
+pub struct CloudflareClient {
+	client: Client,
+	auth: Credentials,
+	fallback_token: Option<Secret>,
+	// Set once the primary token has been rejected, later requests go straight to the fallback
+	primary_rejected: Arc<AtomicBool>,
+	api_url: String,
+	events: EventBus,
+	retry: RetryPolicy,
+	// Set from Retry-After when rate limited, shared so every domain task backs off together
+	paused_until: Arc<Mutex<Option<Instant>>>,
+	// Requests sent since the last take_api_calls, for the cycle summary
+	api_calls: Arc<AtomicU64>,
+	circuit: Circuit,
+	// Records are looked up but changes are only logged
+	dry_run: bool,
+	// Set on every record created or updated
+	record_tags: Vec<String>,
+	duplicates: DuplicateRecords,
+	// Records found by name that belong to someone else are overwritten too
+	force_takeover: bool,
+	// Names without a record are left alone instead of getting one
+	create_missing: bool,
+	// Records Cloudflare refuses to proxy are sent again unproxied
+	proxy_fallback: bool,
+	cname_conflict: CnameConflict,
+	// Zones in other states are ignored, None accepts any
+	zone_status: Option<String>,
+	// Clients of the other accounts domains are configured with, created on first use
+	accounts: Arc<Mutex<HashMap<Credentials, CloudflareClient>>>,
+}
+
+/// Envelope of every Cloudflare API response.
+#[derive(Debug, Deserialize)]
+pub struct ApiResponse<T> {
+	pub success: bool,
+	#[serde(default)]
+	pub errors: Vec<ApiError>,
+	pub result: Option<T>,
+	/// Paging of list results
+	#[serde(default)]
+	pub result_info: Option<ResultInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResultInfo {
+	#[serde(default)]
+	pub page: u32,
+	#[serde(default)]
+	pub total_pages: u32,
+	/// Cursor paging of the endpoints that don't number their pages (IP List items)
+	#[serde(default)]
+	pub cursors: Option<Cursors>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Cursors {
+	/// Cursor of the next page, missing or empty on the last one
+	#[serde(default)]
+	pub after: Option<String>,
+}
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiError {
+	pub code: u64,
+	pub message: String,
+}
+
+impl std::fmt::Display for ApiError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{} ({})", self.message, self.code)
+	}
+}
+
+impl<T> ApiResponse<T> {
+	/// The result of a successful response, `status` is the HTTP status it came with.
+	pub fn into_result(self, status: u16) -> Result<T, Error> {
+		if !self.success {
+			return Err(Error::Api { status, errors: self.errors });
+		}
+
+		self.result.ok_or_else(|| Error::Response { status, message: "missing 'result'".to_string() })
+	}
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Zone {
+	pub id: String,
+	pub name: String,
+	pub status: String,
+	/// What the credentials may do in the zone (`#dns_records:edit`, ...), not always reported
+	#[serde(default)]
+	pub permissions: Vec<String>,
+}
+
+impl Zone {
+	/// Whether the credentials may edit the zone's DNS records, `None` when Cloudflare didn't say.
+	pub fn can_edit_dns(&self) -> Option<bool> {
+		(!self.permissions.is_empty()).then(|| self.permissions.iter().any(|p| p == ZONE_DNS_EDIT_PERMISSION))
+	}
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct DnsRecord {
 	pub id: String,
 	pub name: String,
+	#[serde(rename = "type")]
 	pub record_type: String,
 	pub content: String,
 	pub proxiable: bool,
 	pub proxied: bool,
 	pub ttl: u64,
+	#[serde(default)]
+	pub comment: Option<String>,
+	#[serde(default)]
+	pub tags: Vec<String>,
+	#[serde(default)]
+	pub modified_on: Option<String>,
 }
 
+impl From<DnsRecord> for Record {
+	fn from(record: DnsRecord) -> Self {
+		Self {
+			id: record.id,
+			name: record.name,
+			record_type: record.record_type,
+			content: record.content,
+			ttl: record.ttl,
+			proxied: record.proxied,
+		}
+	}
+}
 
-fn build_url(protocol: &str, host: &str, path: &str) -> String {
-	format!("{}://{}{}", protocol, host, path)
+/// A Load Balancer pool, the origins are kept as they were read so that the settings this
+/// updater doesn't know about are sent back unchanged.
+#[derive(Debug, Deserialize)]
+struct Pool {
+	origins: Vec<serde_json::Map<String, serde_json::Value>>,
 }
 
-pub fn extract_domain_name(domain: &str) -> Result<String, Box<dyn std::error::Error>> {
-	let parts: Vec<&str> = domain.split('.').collect();
-	if parts.len() < 2 {
-		return Err(format!("Invalid domain: {}", domain).into());
-	}
+/// An entry of a Cloudflare IP List, `ip` is an address or a CIDR range.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListItem {
+	pub id: String,
+	#[serde(default)]
+	pub ip: Option<String>,
+	#[serde(default)]
+	pub comment: Option<String>,
+}
 
-	return Ok(
-		format!("{}.{}", 
-		parts.get(parts.len() - 2).unwrap(), 
-		parts.last().unwrap())
-	);
-	
+#[derive(Debug, Deserialize)]
+struct TokenStatus {
+	id: String,
+	status: String,
+	#[serde(default)]
+	expires_on: Option<String>,
 }
 
-pub async fn get_public_ipv4(client: &Client) -> Result<String, Box<dyn std::error::Error>> {
-	let resp = client
-        .get(PUBLIC_IPV4_RESOLVER_HOST)
-        .send()
-        .await?;
+#[derive(Debug, Deserialize)]
+struct TokenDetails {
+	#[serde(default)]
+	policies: Vec<TokenPolicy>,
+}
 
-	match resp.text().await {
-		Ok(ip) => Ok(ip), 
-		Err(e) => Err(e.into())
-	}
+#[derive(Debug, Deserialize)]
+struct TokenPolicy {
+	effect: String,
+	#[serde(default)]
+	permission_groups: Vec<PermissionGroup>,
 }
 
-pub async fn get_public_ipv6(client: &Client) -> Result<String, Box<dyn std::error::Error>> {
-	let resp = client
-        .get(PUBLIC_IPV6_RESOLVER_HOST)
-        .send()
-        .await?;
+#[derive(Debug, Deserialize)]
+struct PermissionGroup {
+	name: String,
+}
 
-	match resp.text().await {
-		Ok(ip) => Ok(ip), 
-		Err(e) => Err(e.into())
-	}
+#[derive(Debug, Deserialize)]
+struct DeletedRecord {
+	id: String,
 }
 
-pub async fn get_zone_id(client: &Client, token: &str, domain: &str) -> Result<String, Box<dyn std::error::Error>> {
-	 let url = build_url(PROTOCOL, CLOUDFLARE_API_HOST, ZONES_PATH);
-	 let resp_text = client
-        .get(&url)
-        .bearer_auth(token)
-        .query(&[("name", domain), ("status", "active")])
-        .send()
-    	.await?
-		.text()
-		.await?;
+/// Body of a batch request, Cloudflare applies the patches before the posts and either all of them
+/// or none.
+#[derive(Debug, Default, serde::Serialize)]
+struct BatchRequest {
+	patches: Vec<BatchPatch>,
+	posts: Vec<RecordParams>,
+}
 
-	let v: Value = serde_json::from_str(&resp_text)?;
-    let zone_id = v
-        .get("result")
-        .and_then(|r| r.as_array())
-        .and_then(|arr| arr.first())
-        .and_then(|zone| zone.get("id"))
-        .and_then(|id| id.as_str())
-        .ok_or(format!("Zone ID not found for {}", domain))?;
+#[derive(Debug, serde::Serialize)]
+struct BatchPatch {
+	id: String,
+	#[serde(flatten)]
+	params: RecordParams,
+}
 
-    Ok(zone_id.to_string())
+#[derive(Debug, Deserialize)]
+struct BatchResult {
+	#[serde(default)]
+	patches: Vec<DnsRecord>,
+	#[serde(default)]
+	posts: Vec<DnsRecord>,
 }
 
-fn parse_record_data(record: &Value) -> DnsRecord {
-	return DnsRecord {
-        id: record.get("id").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
-        name: record.get("name").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
-		record_type: record.get("type").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
-        content: record.get("content").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
-		proxied: record.get("proxied").and_then(|v| v.as_bool()).unwrap_or(false),
-		proxiable: record.get("proxiable").and_then(|v| v.as_bool()).unwrap_or(false),
-		ttl: record.get("ttl").and_then(|v| v.as_u64()).unwrap_or(0),
-    };
+/// Where the outcome of one request of a batch comes from.
+enum BatchSlot {
+	Unchanged(DnsRecord),
+	Patch(usize),
+	Post(usize),
 }
 
-fn parse_response_errors(response_json: &Value) -> Result<(), Box<dyn std::error::Error>> {
-    let success = response_json.get("success").and_then(|s| s.as_bool()).unwrap_or(false);
-    if !success {
-		let default_error_list = vec![Value::String("Unknown error".to_string())];
-        let errors = response_json
-            .get("errors")
-            .and_then(|e| e.as_array())
-            .unwrap_or(&default_error_list);
 
-        let error_message = errors
-            .iter()
-            .filter_map(|v| v.get("message"))
-			.filter_map(|v| v.as_str())
-            .collect::<Vec<&str>>()
-            .join(", ");
+fn build_url(api_url: &str, path: &str) -> String {
+	format!("{}{}", api_url.trim_end_matches('/'), path)
+}
 
-        return Err(error_message.into());
-    }
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RecordParams {
+	name: String,
+	#[serde(rename = "type")]
+    record_type: String,
+    content: String,
+	proxied: bool,
+	/// Left out to keep the TTL of an existing record, a new one gets the automatic TTL
+	#[serde(skip_serializing_if = "Option::is_none")]
+	ttl: Option<u64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	comment: Option<String>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	tags: Vec<String>,
+}
 
-    Ok(())
+impl RecordParams {
+	/// The params for updating `record` while keeping its proxy status, a proxied record always
+	/// has the automatic TTL.
+	fn keeping_proxied(&self, record: &DnsRecord) -> RecordParams {
+		RecordParams { proxied: record.proxied, ttl: if record.proxied { Some(TTL_AUTO) } else { self.ttl }, ..self.clone() }
+	}
 }
 
-pub async fn record_data(client: &Client, token: &str, record_name: &str, record_type: &str, zone_id: &str) -> Result<Option<DnsRecord>, Box<dyn std::error::Error>> {
-	let path = LIST_RECORDS_PATH.replace("$zone_id", zone_id);
-	let url = build_url(PROTOCOL, CLOUDFLARE_API_HOST, &path);
+impl CloudflareClient {
+	pub fn new(client: Client, auth: Credentials, fallback_token: Option<&Secret>, api_url: &str, retry: RetryPolicy, events: EventBus) -> Self {
+		Self {
+			client,
+			auth,
+			fallback_token: fallback_token.cloned(),
+			primary_rejected: Arc::new(AtomicBool::new(false)),
+			api_url: api_url.to_string(),
+			events,
+			retry,
+			paused_until: Arc::new(Mutex::new(None)),
+			api_calls: Arc::new(AtomicU64::new(0)),
+			circuit: Circuit::default(),
+			dry_run: false,
+			record_tags: Vec::new(),
+			duplicates: DuplicateRecords::default(),
+			force_takeover: false,
+			create_missing: true,
+			proxy_fallback: false,
+			cname_conflict: CnameConflict::default(),
+			zone_status: Some("active".to_string()),
+			accounts: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
 
-    let mut params = HashMap::new();
-    params.insert("name", record_name);
-    params.insert("type", record_type);
+	pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+		self.circuit = Circuit { breaker, state: Arc::default() };
+		self
+	}
+
+	/// Shares the rate limit pause and the circuit breaker state of `other`, a client for the same
+	/// API built from an earlier configuration or used by another part of the daemon.
+	pub fn shared_limits(mut self, other: &CloudflareClient) -> Self {
+		self.paused_until = other.paused_until.clone();
+		self.circuit.state = other.circuit.state.clone();
+		self
+	}
+
+	/// Only log the record changes that would be sent, lookups still go to Cloudflare.
+	pub fn dry_run(mut self, dry_run: bool) -> Self {
+		self.dry_run = dry_run;
+		self
+	}
+
+	/// Tags (`name:value`) to set on the records this client creates or updates.
+	pub fn record_tags(mut self, tags: Vec<String>) -> Self {
+		self.record_tags = tags;
+		self
+	}
+
+	pub fn duplicate_records(mut self, duplicates: DuplicateRecords) -> Self {
+		self.duplicates = duplicates;
+		self
+	}
+
+	/// Overwrite records found by name even when they look like someone else's, see
+	/// [`Self::check_takeover`].
+	pub fn force_takeover(mut self, force_takeover: bool) -> Self {
+		self.force_takeover = force_takeover;
+		self
+	}
+
+	/// Create the records that don't exist yet, otherwise only existing records are updated.
+	pub fn create_missing(mut self, create_missing: bool) -> Self {
+		self.create_missing = create_missing;
+		self
+	}
+
+	/// Publish a record unproxied when Cloudflare refuses to proxy it, see [`Self::send_record`].
+	pub fn proxy_fallback(mut self, proxy_fallback: bool) -> Self {
+		self.proxy_fallback = proxy_fallback;
+		self
+	}
+
+	pub fn cname_conflict(mut self, cname_conflict: CnameConflict) -> Self {
+		self.cname_conflict = cname_conflict;
+		self
+	}
+
+	/// Status a zone must have to be updated through [`DnsProvider::zone_for_domain`].
+	pub fn zone_status(mut self, zone_status: Option<String>) -> Self {
+		self.zone_status = zone_status;
+		self
+	}
 
-    let resp_text = client
-        .get(&url)
-        .bearer_auth(token)
-        .query(&params)
-        .send()
-        .await?
-        .text()
-        .await?;
+	/// The client for a domain with its own credentials (`None` uses these). It shares the settings
+	/// and the API call count, rate limit pauses are per account and there is no fallback token.
+	pub fn account(&self, auth: Option<&Credentials>) -> Self {
+		let Some(auth) = auth.filter(|auth| **auth != self.auth) else {
+			return self.clone();
+		};
 
-    let v: Value = serde_json::from_str(&resp_text)?;
+		self.accounts.lock().unwrap()
+			.entry(auth.clone())
+			.or_insert_with(|| Self {
+				auth: auth.clone(),
+				fallback_token: None,
+				primary_rejected: Arc::new(AtomicBool::new(false)),
+				paused_until: Arc::new(Mutex::new(None)),
+				accounts: Arc::new(Mutex::new(HashMap::new())),
+				..self.clone()
+			})
+			.clone()
+	}
+
+	/// Returns the number of API requests sent since the last call and resets the count.
+	pub fn take_api_calls(&self) -> u64 {
+		self.api_calls.swap(0, Ordering::Relaxed)
+	}
+
+	/// Sends an API request and returns the response status and body. When the primary token is
+	/// rejected with 401/403 and a fallback token is configured, the request is repeated with the fallback.
+	async fn send(&self, request: RequestBuilder) -> Result<(u16, String), Error> {
+		let fallback = self.fallback_token.as_ref().map(Secret::expose);
+		if let Some(fallback) = fallback.filter(|_| self.primary_rejected.load(Ordering::Relaxed)) {
+			let resp = self.dispatch(request.bearer_auth(fallback)).await?;
+			return Ok((resp.status().as_u16(), resp.text().await?));
+		}
 
-    let result_list = v.get("result")
-        .and_then(|r| r.as_array())
-        .ok_or("Could not find 'result' in response")?;
+		let retry = fallback.and_then(|fallback| request.try_clone().map(|r| (r, fallback)));
+		let resp = self.dispatch(self.auth.apply(request)).await?;
 
-    if result_list.is_empty() {
-        return Ok(None);
-    }
+		let status = resp.status();
+		if let (StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN, Some((request, fallback))) = (status, retry) {
+			let resp = self.dispatch(request.bearer_auth(fallback)).await?;
+			if resp.status().is_success() && !self.primary_rejected.swap(true, Ordering::Relaxed) {
+				warn!("Cloudflare rejected the primary credentials ({}), switched to CF_FALLBACK_TOKEN", status);
+				self.events.emit(Event::FallbackTokenActivated { status: status.as_u16() });
+			}
+			return Ok((resp.status().as_u16(), resp.text().await?));
+		}
 
-    let record = &result_list[0];
+		Ok((status.as_u16(), resp.text().await?))
+	}
 
-    let dns_record = parse_record_data(record);
+	/// Sends a single HTTP request. Waits while Cloudflare has asked the client to back off, and
+	/// starts such a pause for every task when a response is 429.
+	async fn dispatch(&self, request: RequestBuilder) -> Result<Response, Error> {
+		loop {
+			let paused_until = *self.paused_until.lock().unwrap();
+			match paused_until {
+				Some(until) if until > Instant::now() => tokio::time::sleep_until(until.into()).await,
+				_ => break,
+			}
+		}
+
+		self.api_calls.fetch_add(1, Ordering::Relaxed);
+		let request = request.build()?;
+		debug!(method = %request.method(), url = %request.url(), "Cloudflare request");
+		let operation = api_operation(&request);
+		let started = Instant::now();
+		let resp = self.client.execute(request).await;
+		metrics::observe_request("cloudflare", operation, resp.as_ref().ok().map(|r| r.status().as_u16()), started.elapsed());
+		let resp = resp?;
+		debug!(status = resp.status().as_u16(), "Cloudflare response");
+
+		if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+			let retry_after = resp.headers()
+				.get(RETRY_AFTER)
+				.and_then(|v| v.to_str().ok())
+				.and_then(|v| v.trim().parse().ok())
+				.map(Duration::from_secs)
+				.unwrap_or(DEFAULT_RETRY_AFTER);
+
+			let until = Instant::now() + retry_after;
+			let mut paused_until = self.paused_until.lock().unwrap();
+			if paused_until.is_none_or(|current| current < until) {
+				if paused_until.is_none_or(|current| current <= Instant::now()) {
+					warn!("Cloudflare rate limit reached, pausing API requests for {} seconds", retry_after.as_secs());
+					self.events.emit(Event::RateLimited { retry_after: retry_after.as_secs() });
+				}
+				*paused_until = Some(until);
+			}
+		}
+
+		Ok(resp)
+	}
+
+	/// Sends an API request and deserializes the `result` of the response, retrying transient
+	/// failures according to the [`RetryPolicy`]. A body that doesn't match the expected model is
+	/// reported instead of being read as empty values.
+	async fn request<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, Error> {
+		self.request_with_info(request).await.map(|(result, _)| result)
+	}
+
+	/// Requests every page of a listing and concatenates them, `request` builds the request
+	/// without the paging parameters.
+	async fn request_all<T: DeserializeOwned>(&self, request: impl Fn() -> RequestBuilder) -> Result<Vec<T>, Error> {
+		let mut results = Vec::new();
+		let mut page = 1;
+		loop {
+			let (items, info): (Vec<T>, _) = self.request_with_info(request().query(&[("page", page), ("per_page", PER_PAGE)])).await?;
+			let empty = items.is_empty();
+			results.extend(items);
+			match info {
+				Some(info) if info.page < info.total_pages && !empty => page = info.page + 1,
+				_ => return Ok(results),
+			}
+		}
+	}
+
+	/// [`CloudflareClient::request`], with the paging of list results.
+	async fn request_with_info<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<(T, Option<ResultInfo>), Error> {
+		// A POST that timed out may still have created the record, only repeat it when it never
+		// reached Cloudflare
+		let built = request.try_clone().and_then(|r| r.build().ok());
+		let idempotent = built.as_ref().is_some_and(|r| r.method() != Method::POST);
+		let operation = built.as_ref().map(api_operation).unwrap_or("other");
+
+		let mut attempt = 1;
+		loop {
+			let Some(retry) = request.try_clone().filter(|_| attempt < self.retry.attempts) else {
+				return self.request_once(request).await;
+			};
+
+			match self.request_once(retry).await {
+				// Rejected with 429 means it wasn't processed, safe to repeat whatever the method
+				Err(e) if e.is_transient() && (idempotent || e.is_rate_limited() || matches!(&e, Error::Http(e) if e.is_connect())) => {
+					let delay = self.retry.delay(attempt - 1);
+					warn!("Cloudflare request failed ({}), retrying in {:.1}s (attempt {}/{})", e, delay.as_secs_f64(), attempt + 1, self.retry.attempts);
+					metrics::observe_retry("cloudflare", operation);
+					tokio::time::sleep(delay).await;
+					attempt += 1;
+				},
+				result => return result,
+			}
+		}
+	}
+
+	async fn request_once<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<(T, Option<ResultInfo>), Error> {
+		let permit = self.circuit.allow()?;
+		let result = self.send_parsed(request).await;
+		permit.record(result.as_ref().err());
+		result
+	}
+
+	async fn send_parsed<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<(T, Option<ResultInfo>), Error> {
+		let (status, resp_text) = self.send(request).await?;
+		// Token details and account data stay out of shared logs
+		if secret::log_insecure() {
+			debug!(status, body = %resp_text, "Cloudflare response body");
+		}
+
+		let mut response: ApiResponse<T> = serde_json::from_str(&resp_text)
+			.map_err(|e| Error::Response { status, message: e.to_string() })?;
+
+		let result_info = response.result_info.take();
+		response.into_result(status).map(|result| (result, result_info))
+	}
+
+	/// Checks that the credentials are accepted. A token must also be active and, when it may read
+	/// its own policies, able to edit DNS records. Fails with [`Error::Token`] when it can't be used.
+	pub async fn verify_credentials(&self) -> Result<(), Error> {
+		if let Credentials::GlobalKey { .. } = self.auth {
+			let url = build_url(&self.api_url, USER_PATH);
+			let _: serde_json::Value = self.request(self.client.get(&url)).await?;
+			return Ok(());
+		}
+
+		let url = build_url(&self.api_url, VERIFY_TOKEN_PATH);
+		let token: TokenStatus = self.request(self.client.get(&url)).await?;
+		if token.status != "active" {
+			return Err(Error::Token(format!("token status is '{}'", token.status)));
+		}
+		if let Some(expires_on) = &token.expires_on {
+			info!("Cloudflare token expires on {}", expires_on);
+		}
+
+		// Needs the "API Tokens Read" permission most tokens don't have, and a 403 here mustn't
+		// switch to the fallback token
+		let path = TOKEN_PATH.replace("$token_id", &token.id);
+		let resp = self.dispatch(self.active_credentials().apply(self.client.get(build_url(&self.api_url, &path)))).await?;
+		let status = resp.status().as_u16();
+		let details = serde_json::from_str::<ApiResponse<TokenDetails>>(&resp.text().await?)
+			.map_err(|e| Error::Response { status, message: e.to_string() })
+			.and_then(|response| response.into_result(status));
+		match details {
+			Ok(details) => {
+				let can_edit = details.policies.iter()
+					.filter(|policy| policy.effect == "allow")
+					.flat_map(|policy| &policy.permission_groups)
+					.any(|group| group.name == DNS_EDIT_PERMISSION);
+				if !can_edit {
+					return Err(Error::Token("token lacks the Zone.DNS edit permission".to_string()));
+				}
+			},
+			Err(e) => debug!("Could not read the token's permissions: {}", e),
+		}
+
+		Ok(())
+	}
+
+	fn active_credentials(&self) -> Credentials {
+		match &self.fallback_token {
+			Some(fallback) if self.primary_rejected.load(Ordering::Relaxed) => Credentials::Token(fallback.clone()),
+			_ => self.auth.clone(),
+		}
+	}
+
+	async fn list_zones(&self, domain: &str, status: Option<&str>) -> Result<Vec<Zone>, Error> {
+		let url = build_url(&self.api_url, ZONES_PATH);
+
+		let mut params = vec![("name", domain)];
+		if let Some(status) = status {
+			params.push(("status", status));
+		}
+
+		self.request_all(|| self.client.get(&url).query(&params)).await
+	}
+
+	/// Looks up the zone ID for `domain`. When `status` is set only zones in that state are
+	/// considered (`None` accepts any state).
+	pub async fn get_zone_id(&self, domain: &str, status: Option<&str>) -> Result<String, Error> {
+		self.get_zone(domain, status).await.map(|zone| zone.id)
+	}
+
+	/// [`CloudflareClient::get_zone_id`], with the whole zone.
+	pub async fn get_zone(&self, domain: &str, status: Option<&str>) -> Result<Zone, Error> {
+		let zones = self.list_zones(domain, status).await?;
+		if let Some(zone) = zones.into_iter().next() {
+			return Ok(zone);
+		}
+
+		let Some(status) = status else {
+			return Err(Error::ZoneNotFound(domain.to_string()));
+		};
+
+		// Query again without the filter so the error can say why the zone didn't match
+		let actual_status = self.list_zones(domain, None).await?
+			.into_iter()
+			.next()
+			.map(|zone| zone.status);
+
+		match actual_status {
+			Some(actual_status) => Err(Error::ZoneStatus {
+				zone: domain.to_string(),
+				status: actual_status,
+				required: status.to_string(),
+			}),
+			None => Err(Error::ZoneNotFound(domain.to_string())),
+		}
+	}
+
+	pub async fn record_data(&self, record_name: &str, record_type: &str, zone_id: &str) -> Result<Option<DnsRecord>, Error> {
+		Ok(self.list_records(record_name, record_type, zone_id).await?.into_iter().next())
+	}
+
+	/// Every record of `record_type` named `record_name`, there can be more than one.
+	pub async fn list_records(&self, record_name: &str, record_type: &str, zone_id: &str) -> Result<Vec<DnsRecord>, Error> {
+		let path = LIST_RECORDS_PATH.replace("$zone_id", zone_id);
+		let url = build_url(&self.api_url, &path);
+
+		let mut params = HashMap::new();
+		params.insert("name", record_name);
+		params.insert("type", record_type);
+
+		self.request_all(|| self.client.get(&url).query(&params)).await
+	}
+
+	/// Every record of the zone whatever its name and type, as Cloudflare returns them (with the
+	/// fields this updater doesn't model, like the priority of MX records).
+	pub async fn zone_records(&self, zone_id: &str) -> Result<Vec<serde_json::Value>, Error> {
+		let path = LIST_RECORDS_PATH.replace("$zone_id", zone_id);
+		let url = build_url(&self.api_url, &path);
+
+		self.request_all(|| self.client.get(&url)).await
+	}
+
+	pub async fn delete_record(&self, record: &DnsRecord, zone_id: &str) -> Result<(), Error> {
+		let path = DELETE_RECORD_PATH.replace("$zone_id", zone_id).replace("$dns_record_id", &record.id);
+		let url = build_url(&self.api_url, &path);
+
+		if self.dry_run {
+			info!("Dry run, not sending DELETE {} for {} record '{}' ({})", url, record.record_type, record.name, record.content);
+			return Ok(());
+		}
+
+		info!("Deleting {} record '{}' ({})", record.record_type, record.name, record.content);
+		let deleted: DeletedRecord = self.request(self.client.delete(&url)).await?;
+		debug!("Deleted record {}", deleted.id);
+		Ok(())
+	}
+
+
+	/// Sets the address of the origin `name` in the Load Balancer pool `pool_id`. The API replaces
+	/// the whole list, the other origins are sent back as they were. Returns false when the origin
+	/// already had the address.
+	pub async fn update_pool_origin(&self, account_id: &str, pool_id: &str, name: &str, address: &str) -> Result<bool, Error> {
+		let path = POOL_PATH.replace("$account_id", account_id).replace("$pool_id", pool_id);
+		let url = build_url(&self.api_url, &path);
+
+		let mut pool: Pool = self.request(self.client.get(&url)).await?;
+		let Some(origin) = pool.origins.iter_mut().find(|origin| origin.get("name").and_then(|n| n.as_str()) == Some(name)) else {
+			return Err(Error::RecordMissing(format!("Load Balancer pool '{}' has no origin named '{}'", pool_id, name)));
+		};
+		if origin.get("address").and_then(|a| a.as_str()) == Some(address) {
+			return Ok(false);
+		}
+		origin.insert("address".to_string(), serde_json::Value::String(address.to_string()));
+
+		if self.dry_run {
+			info!("Dry run, not sending PATCH {} for origin '{}' with '{}'", url, name, address);
+			return Ok(true);
+		}
+
+		info!("Updating origin '{}' of Load Balancer pool '{}' with '{}'", name, pool_id, address);
+		let _: serde_json::Value = self.request(self.client.patch(&url).json(&serde_json::json!({ "origins": pool.origins }))).await?;
+		Ok(true)
+	}
+
+	/// The items of the account's IP List `list_id`, following the cursor through every page.
+	pub async fn list_items(&self, account_id: &str, list_id: &str) -> Result<Vec<ListItem>, Error> {
+		let path = LIST_ITEMS_PATH.replace("$account_id", account_id).replace("$list_id", list_id);
+		let url = build_url(&self.api_url, &path);
+
+		let mut items = Vec::new();
+		let mut cursor: Option<String> = None;
+		loop {
+			let mut request = self.client.get(&url).query(&[("per_page", LIST_ITEMS_PER_PAGE)]);
+			if let Some(cursor) = &cursor {
+				request = request.query(&[("cursor", cursor)]);
+			}
+			let (page, info): (Vec<ListItem>, _) = self.request_with_info(request).await?;
+			let empty = page.is_empty();
+			items.extend(page);
+			match info.and_then(|info| info.cursors).and_then(|cursors| cursors.after).filter(|after| !after.is_empty() && !empty) {
+				Some(after) => cursor = Some(after),
+				None => return Ok(items),
+			}
+		}
+	}
+
+	/// Adds `ips` to the IP List `list_id` with `comment`. Cloudflare applies the change in the background.
+	pub async fn add_list_items(&self, account_id: &str, list_id: &str, ips: &[String], comment: &str) -> Result<(), Error> {
+		let path = LIST_ITEMS_PATH.replace("$account_id", account_id).replace("$list_id", list_id);
+		let url = build_url(&self.api_url, &path);
+
+		if self.dry_run {
+			info!("Dry run, not sending POST {} for {}", url, ips.join(", "));
+			return Ok(());
+		}
+
+		info!("Adding {} to IP List '{}'", ips.join(", "), list_id);
+		let items = ips.iter().map(|ip| serde_json::json!({ "ip": ip, "comment": comment })).collect::<Vec<_>>();
+		let _: serde_json::Value = self.request(self.client.post(&url).json(&items)).await?;
+		Ok(())
+	}
+
+	/// Removes the `items` from the IP List `list_id`.
+	pub async fn delete_list_items(&self, account_id: &str, list_id: &str, items: &[ListItem]) -> Result<(), Error> {
+		let path = LIST_ITEMS_PATH.replace("$account_id", account_id).replace("$list_id", list_id);
+		let url = build_url(&self.api_url, &path);
+		let ips = items.iter().filter_map(|item| item.ip.as_deref()).collect::<Vec<_>>().join(", ");
+
+		if self.dry_run {
+			info!("Dry run, not sending DELETE {} for {}", url, ips);
+			return Ok(());
+		}
+
+		info!("Removing {} from IP List '{}'", ips, list_id);
+		let ids = items.iter().map(|item| serde_json::json!({ "id": item.id })).collect::<Vec<_>>();
+		let _: serde_json::Value = self.request(self.client.delete(&url).json(&serde_json::json!({ "items": ids }))).await?;
+		Ok(())
+	}
+
+	/// Publishes `ip_addr` to the record of `domain`. A `record_id` known from a previous update
+	/// is patched right away, without looking the record up first. Without `proxied` an existing
+	/// record keeps its proxy status and a new one is created unproxied, without `ttl` it keeps its
+	/// TTL.
+	async fn update_record(&self, domain: &str, zone_id: &str, record_id: Option<&str>, ip_addr: &str, proxied: Option<bool>, ttl: Option<u64>, comment: Option<&str>, record_type: &str) -> Result<RecordUpdate, Error> {
+		let mut params = RecordParams {
+			name: domain.to_string(),
+			record_type: record_type.to_string(),
+			content: ip_addr.to_string(),
+			proxied: proxied.unwrap_or(false),
+			ttl,
+			comment: comment.map(|c| c.to_string()),
+			tags: self.record_tags.clone(),
+		};
+
+		// A dry run looks the record up to show what would change, updating all duplicates or
+		// keeping the proxy status has to find them first
+		if let Some(record_id) = record_id.filter(|_| !self.dry_run && self.duplicates == DuplicateRecords::First && proxied.is_some()) {
+			let path = UPDATE_RECORD_PATH.replace("$zone_id", zone_id).replace("$dns_record_id", record_id);
+			let url = build_url(&self.api_url, &path);
+			log_change(None, Some(record_id), &params);
+			match self.send_record(self.client.patch(&url), &params).await {
+				Ok(record) => return Ok(RecordUpdate { change: RecordChange::Updated, record: record.into() }),
+				// Deleted outside of this updater, look it up by name
+				Err(Error::Api { status: 404, .. }) => warn!("Record '{}' no longer exists, looking it up", domain),
+				Err(e) => return Err(e),
+			}
+		}
+
+		let client_request;
+		let change;
+		let existing = match self.duplicates {
+			DuplicateRecords::All => {
+				let existing = self.list_records(domain, record_type, zone_id).await?;
+				if existing.len() > 1 {
+					return self.update_all(zone_id, existing, &params, proxied.is_none()).await;
+				}
+				existing.into_iter().next()
+			},
+			DuplicateRecords::First => self.first_record(domain, record_type, ip_addr, zone_id).await?,
+		};
+		match &existing {
+			Some(record) => {
+				if record.content == ip_addr {
+					return Ok(RecordUpdate { change: RecordChange::Unchanged, record: record.clone().into() });
+
+				} else {
+					self.check_takeover(record, comment)?;
+					if proxied.is_none() {
+						params = params.keeping_proxied(record);
+					}
+					// Update record
+					let path = UPDATE_RECORD_PATH.replace("$zone_id", zone_id).replace("$dns_record_id", &record.id);
+					let url = build_url(&self.api_url, &path);
+					client_request = self.client.patch(&url);
+					change = RecordChange::Updated;
+
+					log_change(Some(record), None, &params);
+				}
+			},
+			None if !self.create_missing => return Err(missing_record(domain, record_type)),
+			None => {
+				self.clear_cname(domain, zone_id).await?;
+				// Create record
+				let path = CREATE_RECORD_PATH.replace("$zone_id", zone_id);
+				let url = build_url(&self.api_url, &path);
+				client_request = self.client.post(&url);
+				change = RecordChange::Created;
+
+				log_change(None, None, &params);
+			}
+		}
+
+		if self.dry_run {
+			let request = client_request.json(&params).build()?;
+			let body = serde_json::to_string(&params).unwrap_or_default();
+			info!("Dry run, not sending {} {} {}", request.method(), request.url(), body);
+			let record = DnsRecord {
+				id: existing.as_ref().map(|r| r.id.clone()).unwrap_or_default(),
+				name: params.name,
+				record_type: params.record_type,
+				content: params.content,
+				proxiable: existing.as_ref().is_none_or(|r| r.proxiable),
+				proxied: params.proxied,
+				ttl: params.ttl.or(existing.as_ref().map(|r| r.ttl)).unwrap_or(TTL_AUTO),
+				comment: params.comment,
+				tags: params.tags,
+				modified_on: None,
+			};
+			return Ok(RecordUpdate { change, record: record.into() });
+		}
+
+		let record = self.send_record(client_request, &params).await?;
+
+		Ok(RecordUpdate { change, record: record.into() })
+	}
+
+	/// Publishes several records of a zone with as few batch requests as possible. Records without
+	/// a known id are looked up first, the ones already holding their address are left out.
+	async fn batch_update(&self, zone_id: &str, requests: &[RecordRequest<'_>]) -> Result<Vec<RecordUpdate>, Error> {
+		let mut batch = BatchRequest::default();
+		let mut slots = Vec::with_capacity(requests.len());
+		for request in requests {
+			let record_type = request.family.record_type();
+			let mut params = RecordParams {
+				name: request.name.to_string(),
+				record_type: record_type.to_string(),
+				content: request.content.to_string(),
+				proxied: request.cloudflare.proxied,
+				ttl: request.ttl,
+				comment: request.comment.map(|c| c.to_string()),
+				tags: self.record_tags.clone(),
+			};
+
+			// Keeping the proxy status needs the record as it is
+			let (id, before) = match request.record_id.filter(|_| !request.cloudflare.keep_proxied) {
+				Some(id) => (Some(id.to_string()), None),
+				None => match self.first_record(request.name, record_type, request.content, zone_id).await? {
+					Some(record) if record.content == request.content => {
+						slots.push(BatchSlot::Unchanged(record));
+						continue;
+					},
+					Some(record) => {
+						self.check_takeover(&record, request.comment)?;
+						if request.cloudflare.keep_proxied {
+							params = params.keeping_proxied(&record);
+						}
+						(Some(record.id.clone()), Some(record))
+					},
+					None if !self.create_missing => return Err(missing_record(request.name, record_type)),
+					None => {
+						self.clear_cname(request.name, zone_id).await?;
+						(None, None)
+					},
+				},
+			};
+			log_change(before.as_ref(), id.as_deref(), &params);
+			match id {
+				Some(id) => {
+					slots.push(BatchSlot::Patch(batch.patches.len()));
+					batch.patches.push(BatchPatch { id, params });
+				},
+				None => {
+					slots.push(BatchSlot::Post(batch.posts.len()));
+					batch.posts.push(params);
+				},
+			}
+		}
+
+		let mut result = BatchResult { patches: Vec::new(), posts: Vec::new() };
+		if !batch.patches.is_empty() || !batch.posts.is_empty() {
+			let path = BATCH_RECORDS_PATH.replace("$zone_id", zone_id);
+			let url = build_url(&self.api_url, &path);
+			debug!("Sending {} patches and {} posts in one batch", batch.patches.len(), batch.posts.len());
+			result = self.request(self.client.post(&url).json(&batch)).await?;
+		}
+
+		slots.into_iter()
+			.map(|slot| match slot {
+				BatchSlot::Unchanged(record) => Some(RecordUpdate { change: RecordChange::Unchanged, record: record.into() }),
+				BatchSlot::Patch(index) => result.patches.get(index).map(|record| RecordUpdate { change: RecordChange::Updated, record: record.clone().into() }),
+				BatchSlot::Post(index) => result.posts.get(index).map(|record| RecordUpdate { change: RecordChange::Created, record: record.clone().into() }),
+			})
+			.collect::<Option<Vec<_>>>()
+			.ok_or_else(|| Error::Response { status: 200, message: "batch result is missing records".to_string() })
+	}
+
+	/// Makes room for an address record on a name that is a CNAME, by deleting the CNAME with
+	/// [`CnameConflict::Replace`] and failing with a conflict otherwise.
+	async fn clear_cname(&self, domain: &str, zone_id: &str) -> Result<(), Error> {
+		let cnames = self.list_records(domain, DNS_RECORD_TYPE_CNAME, zone_id).await?;
+		let Some(cname) = cnames.first() else {
+			return Ok(());
+		};
+
+		match self.cname_conflict {
+			CnameConflict::Skip => Err(Error::Conflict(format!("'{}' is a CNAME to '{}', an address record can't be added next to it (set CF_CNAME_CONFLICT=replace to replace it)", domain, cname.content))),
+			CnameConflict::Replace => {
+				warn!("Replacing CNAME '{}' to '{}' with an address record", domain, cname.content);
+				self.delete_record(cname, zone_id).await
+			},
+		}
+	}
+
+	/// Creates or patches a record with `params`. When Cloudflare refuses to proxy it and
+	/// `CF_PROXY_FALLBACK` is set it is sent again unproxied, otherwise the error says how to allow that.
+	async fn send_record(&self, request: RequestBuilder, params: &RecordParams) -> Result<DnsRecord, Error> {
+		let retry = request.try_clone();
+		match self.request(request.json(params)).await {
+			Err(e) if params.proxied && e.is_not_proxiable() => match retry.filter(|_| self.proxy_fallback) {
+				Some(retry) => {
+					warn!("Cloudflare can't proxy {} record '{}' ({}): {}, publishing it unproxied because CF_PROXY_FALLBACK is set", params.record_type, params.name, params.content, e);
+					let params = RecordParams { proxied: false, ..params.clone() };
+					self.request(retry.json(&params)).await
+				},
+				None => {
+					warn!("Cloudflare can't proxy {} record '{}' ({}), set CF_PROXIED=false for it or CF_PROXY_FALLBACK=true to publish it unproxied", params.record_type, params.name, params.content);
+					Err(e)
+				},
+			},
+			result => result,
+		}
+	}
+
+	/// Refuses to overwrite a record that was found by name (not published by this updater) and
+	/// looks like someone else's: it has a comment other than `comment`, or `comment`/the record
+	/// tags are set and it carries neither.
+	fn check_takeover(&self, record: &DnsRecord, comment: Option<&str>) -> Result<(), Error> {
+		if self.force_takeover {
+			return Ok(());
+		}
+
+		let record_comment = record.comment.as_deref().filter(|c| !c.is_empty());
+		let marked = (comment.is_some() && record_comment == comment) || record.tags.iter().any(|tag| self.record_tags.contains(tag));
+		let foreign_comment = record_comment.is_some_and(|c| Some(c) != comment);
+		let has_markers = comment.is_some() || !self.record_tags.is_empty();
+		if marked || !(foreign_comment || has_markers) {
+			return Ok(());
+		}
+
+		let reason = match record_comment {
+			Some(c) if foreign_comment => format!("has the comment '{}'", c),
+			_ => "carries neither CF_RECORD_COMMENT nor CF_RECORD_TAGS".to_string(),
+		};
+		Err(Error::Conflict(format!("{} record '{}' pointing to '{}' {}, not overwriting it (set CF_FORCE_TAKEOVER=true to take it over)", record.record_type, record.name, record.content, reason)))
+	}
+
+	/// The record `update_record` would update, the one already holding `ip_addr` when there are
+	/// duplicates.
+	async fn first_record(&self, domain: &str, record_type: &str, ip_addr: &str, zone_id: &str) -> Result<Option<DnsRecord>, Error> {
+		let mut existing = self.list_records(domain, record_type, zone_id).await?;
+		if existing.len() > 1 {
+			existing.sort_by(|a, b| (a.content != ip_addr, &a.id).cmp(&(b.content != ip_addr, &b.id)));
+			warn!("'{}' has {} {} records, only {} is updated (set CF_DUPLICATE_RECORDS=all or run cleanup)", domain, existing.len(), record_type, existing[0].id);
+		}
+		Ok(existing.into_iter().next())
+	}
+
+	/// Patches each of `records` that doesn't have the address yet, the update reports the first.
+	async fn update_all(&self, zone_id: &str, records: Vec<DnsRecord>, params: &RecordParams, keep_proxied: bool) -> Result<RecordUpdate, Error> {
+		for record in records.iter().filter(|r| r.content != params.content) {
+			self.check_takeover(record, params.comment.as_deref())?;
+		}
+
+		let mut change = RecordChange::Unchanged;
+		let mut updated = Vec::new();
+		for record in records {
+			if record.content == params.content {
+				updated.push(record);
+				continue;
+			}
+
+			change = RecordChange::Updated;
+			let params = if keep_proxied { params.keeping_proxied(&record) } else { params.clone() };
+			let path = UPDATE_RECORD_PATH.replace("$zone_id", zone_id).replace("$dns_record_id", &record.id);
+			let url = build_url(&self.api_url, &path);
+			log_change(Some(&record), None, &params);
+			if self.dry_run {
+				let body = serde_json::to_string(&params).unwrap_or_default();
+				info!("Dry run, not sending PATCH {} {}", url, body);
+				updated.push(DnsRecord { content: params.content, ..record });
+				continue;
+			}
+
+			updated.push(self.send_record(self.client.patch(&url), &params).await?);
+		}
+
+		Ok(RecordUpdate { change, record: updated.remove(0).into() })
+	}
 
-    Ok(Some(dns_record))
 }
 
+#[async_trait]
+impl DnsProvider for CloudflareClient {
+	async fn zone_for_domain(&self, zone: &str) -> Result<String, Error> {
+		self.get_zone_id(zone, self.zone_status.as_deref()).await
+	}
 
-#[derive(serde::Serialize, serde::Deserialize)]
-pub struct RecordParams{
-	name: String,
-	#[serde(rename = "type")]
-    record_type: String,
-    content: String,
-	proxied: bool
-}
-async fn update_record(client: &Client, token: &str, domain: &str, zone_id: &str, ip_addr: &str, proxied: bool, record_type: &str, record_type_id: &str) -> Result<Option<DnsRecord>, Box<dyn std::error::Error>> {
-	 let client_request;
-	 match record_data(client, token, domain, record_type, zone_id).await? {
-		Some(record) => {
-			if record.content == ip_addr {
-				println!("Record '{}' already has the correct {} address '{}'", domain, record_type_id, ip_addr);
-				return Ok(Some(record));
-
-			} else {
-				// Update record
-				let path = UPDATE_RECORD_PATH.replace("$zone_id", zone_id).replace("$dns_record_id", &record.id);
-				let url = build_url(PROTOCOL, CLOUDFLARE_API_HOST, &path);
-				client_request = client.patch(&url);
-
-				println!("{}", &url.to_string());
-				println!("Updating record '{}' with {} address '{}'", domain, record_type_id, ip_addr);
+	async fn find_record(&self, zone_id: &str, name: &str, family: IpFamily) -> Result<Option<Record>, Error> {
+		Ok(self.record_data(name, family.record_type(), zone_id).await?.map(Record::from))
+	}
+
+	async fn upsert_record(&self, zone_id: &str, request: &RecordRequest<'_>) -> Result<RecordUpdate, Error> {
+		let record_type = request.family.record_type();
+		let proxied = Some(request.cloudflare.proxied).filter(|_| !request.cloudflare.keep_proxied);
+		self.update_record(request.name, zone_id, request.record_id, request.content, proxied, request.ttl, request.comment, record_type).await
+	}
+
+	/// Goes through the batch endpoint, except for dry runs and duplicates (they are updated one by
+	/// one). A rejected batch changes nothing, its records are then tried one by one so a single
+	/// bad record doesn't hold back the others.
+	async fn upsert_records(&self, zone_id: &str, requests: &[RecordRequest<'_>]) -> Vec<Result<RecordUpdate, Error>> {
+		let mut results = Vec::with_capacity(requests.len());
+		if requests.len() < 2 || self.dry_run || self.duplicates == DuplicateRecords::All {
+			for request in requests {
+				results.push(self.upsert_record(zone_id, request).await);
 			}
-		},
-		None => {
-			// Create record
-			let path = CREATE_RECORD_PATH.replace("$zone_id", zone_id);
-			let url = build_url(PROTOCOL, CLOUDFLARE_API_HOST, &path);
-			client_request = client.post(&url);
-
-			println!("Creating record '{}' with {} address '{}'", domain, record_type_id, ip_addr);
-		}
-	 }
-	let params = RecordParams {
-		name: domain.to_string(),
-		record_type: record_type.to_string(),
-		content: ip_addr.to_string(),
-		proxied,
-	};
+			return results;
+		}
 
+		for chunk in requests.chunks(BATCH_SIZE) {
+			match self.batch_update(zone_id, chunk).await {
+				Ok(updates) => results.extend(updates.into_iter().map(Ok)),
+				Err(e) => {
+					warn!("Batch update of {} records failed, updating them one by one: {}", chunk.len(), e);
+					for request in chunk {
+						results.push(self.upsert_record(zone_id, request).await);
+					}
+				}
+			}
+		}
+		results
+	}
 
-	let resp_text = client_request
-		.json(&params)
-		.bearer_auth(token)
-		.send()
-		.await?
-		.text()
-		.await?;
-	
-	let response_json = serde_json::from_str(&resp_text)?;
+	fn cloudflare(&self) -> Option<&CloudflareClient> {
+		Some(self)
+	}
+}
 
-	match parse_response_errors(&response_json) {
-		Ok(_) => {
-			let result_list = response_json.get("result")
-				.ok_or("Could not find 'result' in response")?;
+/// What an API request does, the label of its metrics.
+fn api_operation(request: &Request) -> &'static str {
+	let segments: Vec<&str> = request.url().path_segments().map(|s| s.collect()).unwrap_or_default();
+	let method = request.method();
+	match segments.iter().rposition(|s| *s == "dns_records").map(|i| &segments[i + 1..]) {
+		Some(["batch"]) => "batch_records",
+		Some([]) if method == Method::GET => "list_records",
+		Some([]) => "create_record",
+		Some([_]) if method == Method::DELETE => "delete_record",
+		Some([_]) => "update_record",
+		Some(_) => "other",
+		None if segments.contains(&"pools") && method == Method::GET => "get_pool",
+		None if segments.contains(&"pools") => "update_pool",
+		None if segments.contains(&"lists") && method == Method::GET => "list_items",
+		None if segments.contains(&"lists") => "update_list",
+		None if segments.last() == Some(&"zones") => "list_zones",
+		None if segments.contains(&"tokens") => "verify_token",
+		None if segments.last() == Some(&"user") => "user",
+		None => "other",
+	}
+}
 
-			return Ok(Some(parse_record_data(&result_list[0])));
-		},
-		Err(e) => Err(e)
+/// Logs a record change as what differs between `before` and `params`, each value is a field of
+/// the JSON log lines too. A record patched by its known `record_id` wasn't read, only the new
+/// values are known then.
+fn log_change(before: Option<&DnsRecord>, record_id: Option<&str>, params: &RecordParams) {
+	let Some(before) = before else {
+		let ttl = params.ttl.map(|ttl| ttl.to_string()).unwrap_or_else(|| "unchanged".to_string());
+		match record_id {
+			Some(id) => info!(domain = %params.name, record_type = %params.record_type, record_id = id, new_content = %params.content, new_proxied = params.proxied, new_ttl = %ttl,
+				"Updating {} record '{}' ({}): content -> {}, proxied -> {}, ttl -> {}", params.record_type, params.name, id, params.content, params.proxied, ttl),
+			None => info!(domain = %params.name, record_type = %params.record_type, new_content = %params.content, new_proxied = params.proxied, new_ttl = %params.ttl.unwrap_or(TTL_AUTO),
+				"Creating {} record '{}': content {}, proxied {}, ttl {}", params.record_type, params.name, params.content, params.proxied, params.ttl.unwrap_or(TTL_AUTO)),
+		}
+		return;
+	};
+
+	let ttl = params.ttl.unwrap_or(before.ttl);
+	let mut changes = Vec::new();
+	if before.content != params.content {
+		changes.push(format!("content {} -> {}", before.content, params.content));
+	}
+	if before.proxied != params.proxied {
+		changes.push(format!("proxied {} -> {}", before.proxied, params.proxied));
+	}
+	if before.ttl != ttl {
+		changes.push(format!("ttl {} -> {}", before.ttl, ttl));
 	}
-	
+	info!(domain = %params.name, record_type = %params.record_type, record_id = %before.id,
+		old_content = %before.content, new_content = %params.content, old_proxied = before.proxied, new_proxied = params.proxied, old_ttl = before.ttl, new_ttl = ttl,
+		"Updating {} record '{}' ({}): {}", params.record_type, params.name, before.id, changes.join(", "));
 }
 
-pub async fn update_record_ipv4(client: &Client, token: &str, domain: &str, zone_id: &str, ip_addr: &str, proxied: bool) -> Result<Option<DnsRecord>, Box<dyn std::error::Error>> {
-	update_record(client, token, domain, zone_id, ip_addr, proxied, DNS_RECORD_TYPE_A, "IPV4").await
+fn missing_record(domain: &str, record_type: &str) -> Error {
+	Error::RecordMissing(format!("'{}' has no {} record and CF_CREATE_MISSING is false", domain, record_type))
 }
-pub async fn update_record_ipv6(client: &Client, token: &str, domain: &str, zone_id: &str, ip_addr: &str, proxied: bool) -> Result<Option<DnsRecord>, Box<dyn std::error::Error>> {
-	update_record(client, token, domain, zone_id, ip_addr, proxied, DNS_RECORD_TYPE_AAAA, "IPV6").await
-}
\ No newline at end of file
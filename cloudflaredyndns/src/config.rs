@@ -1,59 +1,1460 @@
-use std::env;
+use std::{collections::HashMap, env, fs, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::cloudflare::{self, CircuitBreaker, CnameConflict, Credentials, DuplicateRecords, RetryPolicy};
+use crate::error::Error;
+use crate::hooks::{self, Hooks};
+use crate::iplist::IpList;
+use crate::loadbalancer::PoolOrigin;
+use crate::http::{self, ClientSettings};
+use crate::ipsource::{self, FritzBoxLogin, IpFamily, IpSource, Ipv6FailurePolicy, Ipv6Policy, NonRoutable, Resolver, ResolverMode};
+use crate::metrics::{self, Pushgateway};
+use crate::notify::{self, Discord, EventKind, Gotify, Notifier, Ntfy, Slack, Subscription, Telegram, Webhook};
+use crate::probe::Probe;
+use crate::secret::Secret;
+#[cfg(feature = "plugins")]
+use crate::plugin::Plugin;
+#[cfg(feature = "mqtt")]
+use crate::mqtt::{self, Mqtt};
+#[cfg(feature = "scripting")]
+use crate::script::Script;
+
+// Cloudflare TTL rules: 1 means "automatic", anything else must be within these bounds.
+pub const TTL_AUTO: u64 = 1;
+pub const TTL_MAX: u64 = 86400;
+const TTL_MIN: u64 = 60;
+const TTL_MIN_ENTERPRISE: u64 = 30;
+
+const DEFAULT_CONFIG_DIR: &str = "/etc/cloudflaredyndns/conf.d";
+
+/// A `[[group]]` table in a config file: domains sharing settings that differ from the global ones.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GroupSpec {
+    name: Option<String>,
+    #[serde(default)]
+    domains: Vec<String>,
+    proxied: Option<bool>,
+    ttl: Option<u64>,
+    /// Seconds between updates of the group's domains, instead of CF_UPDATE_INTERVAL
+    update_interval: Option<u64>,
+    ipv4_enabled: Option<bool>,
+    ipv6_enabled: Option<bool>,
+    failover_ipv4: Option<String>,
+    failover_ipv6: Option<String>,
+    /// Interface identifier of a host behind the router, combined with the detected prefix
+    ipv6_suffix: Option<String>,
+    /// Credentials of another Cloudflare account the group's zones are in
+    token: Option<String>,
+    api_key: Option<String>,
+    auth_email: Option<String>,
+}
+
+/// A `domains` entry written as a table instead of a name, the domain's own group.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DomainSpec {
+    name: String,
+    proxied: Option<bool>,
+    ttl: Option<u64>,
+    update_interval: Option<u64>,
+    ipv4_enabled: Option<bool>,
+    ipv6_enabled: Option<bool>,
+    failover_ipv4: Option<String>,
+    failover_ipv6: Option<String>,
+    ipv6_suffix: Option<String>,
+    token: Option<String>,
+    api_key: Option<String>,
+    auth_email: Option<String>,
+}
+
+impl From<DomainSpec> for GroupSpec {
+    fn from(spec: DomainSpec) -> Self {
+        GroupSpec {
+            name: Some(spec.name.clone()),
+            domains: vec![spec.name],
+            proxied: spec.proxied,
+            ttl: spec.ttl,
+            update_interval: spec.update_interval,
+            ipv4_enabled: spec.ipv4_enabled,
+            ipv6_enabled: spec.ipv6_enabled,
+            failover_ipv4: spec.failover_ipv4,
+            failover_ipv6: spec.failover_ipv6,
+            ipv6_suffix: spec.ipv6_suffix,
+            token: spec.token,
+            api_key: spec.api_key,
+            auth_email: spec.auth_email,
+        }
+    }
+}
+
+/// Where settings are read from. Config file keys are the environment variable names without the
+/// `CF_` prefix, in lowercase (`update_interval = 300` is `CF_UPDATE_INTERVAL=300`).
+#[derive(Default)]
+struct Source {
+    file: HashMap<String, String>,
+    groups: Vec<GroupSpec>,
+    /// Contents of the files named by the `_FILE` variants of `SECRET_SETTINGS`
+    secrets: HashMap<String, String>,
+}
+
+/// Settings that can also be read from a file named by `<setting>_FILE`
+/// (`CF_TOKEN_FILE=/run/secrets/cf_token`), for Docker and Kubernetes secrets, or from the OS
+/// keyring with `CF_KEYRING=true`.
+pub const SECRET_SETTINGS: [&str; 12] = [
+    "CF_TOKEN", "CF_FALLBACK_TOKEN", "CF_API_KEY", "CF_DYNDNS_PASSWORD", "CF_FRITZBOX_PASSWORD", "CF_SMTP_PASSWORD",
+    "CF_HTTP_CONTROL_TOKEN", "CF_TELEGRAM_TOKEN", "CF_GOTIFY_TOKEN", "CF_NTFY_TOKEN", "CF_DISCORD_WEBHOOK_URL", "CF_SLACK_WEBHOOK_URL",
+];
+
+impl Source {
+    /// Adds the settings of a config file. Later files override earlier values, except `domains`
+    /// and `[[group]]` tables which accumulate so every fragment can contribute its own domains.
+    fn merge_file(&mut self, path: &Path) -> Result<(), Error> {
+        let text = interpolate_file(&fs::read_to_string(path)?, is_yaml(path))?;
+        let table: toml::Table = if is_yaml(path) {
+            // Same layout as the TOML file, `group` is a list of mappings
+            serde_yaml::from_str(&text).map_err(|e| Error::Config(e.to_string()))?
+        } else {
+            text.parse().map_err(|e: toml::de::Error| Error::Config(e.to_string()))?
+        };
+
+        for (key, mut value) in table {
+            // `domains = ["a.example.com", { name = "b.example.com", proxied = true }]`
+            if let ("domains", toml::Value::Array(values)) = (key.as_str(), &mut value) {
+                let (specs, names): (Vec<_>, Vec<_>) = values.drain(..).partition(|v| v.is_table());
+                *values = names;
+                for spec in specs {
+                    let spec: DomainSpec = spec.try_into()
+                        .map_err(|e| Error::Config(format!("Invalid entry in 'domains': {}", e)))?;
+                    self.groups.push(spec.into());
+                }
+            }
+
+            if key == "group" {
+                let groups: Vec<GroupSpec> = value.try_into()
+                    .map_err(|e| Error::Config(format!("Invalid [[group]]: {}", e)))?;
+                self.groups.extend(groups);
+                continue;
+            }
+
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Array(values) => values
+                    .into_iter()
+                    .map(|v| match v {
+                        toml::Value::String(s) => Ok(s),
+                        toml::Value::Table(_) | toml::Value::Array(_) => Err(Error::Config(format!("Unsupported value in '{}'", key))),
+                        other => Ok(other.to_string()),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join(","),
+                toml::Value::Table(_) => return Err(Error::Config(format!("Unsupported table '{}'", key))),
+                other => other.to_string(),
+            };
+
+            let key = format!("CF_{}", key.to_ascii_uppercase());
+            match self.file.get_mut(&key) {
+                Some(domains) if key == "CF_DOMAINS" => {
+                    domains.push(',');
+                    domains.push_str(&value);
+                },
+                _ => {
+                    self.file.insert(key, value);
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Merges every `*.toml`, `*.yaml` and `*.yml` fragment in `dir`, in file name order.
+    fn merge_dir(&mut self, dir: &Path) -> Result<(), Error> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+            .map_err(|e| Error::Config(format!("Error reading config directory '{}': {}", dir.display(), e)))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && (is_yaml(path) || path.extension().is_some_and(|ext| ext == "toml")))
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            self.merge_file(&path)
+                .map_err(|e| Error::Config(format!("Error reading config file '{}': {}", path.display(), e)))?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the secret files, a setting can't be given both directly and as a file.
+    fn read_secret_files(&mut self) -> Result<(), Error> {
+        for key in SECRET_SETTINGS {
+            let file_key = format!("{}_FILE", key);
+            let Some(path) = self.var(&file_key).map(|p| p.trim().to_string()).filter(|p| !p.is_empty()) else {
+                continue;
+            };
+            if self.var(key).is_some() {
+                return Err(Error::Config(format!("Set either {} or {}, not both", key, file_key)));
+            }
+            let secret = fs::read_to_string(&path)
+                .map_err(|e| Error::Config(format!("Error reading {} '{}': {}", file_key, path, e)))?;
+            self.secrets.insert(key.to_string(), secret.trim_end_matches(['\r', '\n']).to_string());
+        }
+
+        let keyring = self.var("CF_KEYRING")
+            .unwrap_or_else(|| "false".to_string())
+            .eq_ignore_ascii_case("true");
+        if keyring {
+            #[cfg(feature = "keyring")]
+            {
+                let missing = SECRET_SETTINGS.iter().copied().filter(|key| self.var(key).is_none()).collect::<Vec<&str>>();
+                for key in missing {
+                    if let Some(secret) = crate::keyring::get(key)? {
+                        self.secrets.insert(key.to_string(), secret);
+                    }
+                }
+            }
+
+            #[cfg(not(feature = "keyring"))]
+            return Err(Error::Config("CF_KEYRING requires building with the 'keyring' feature".to_string()));
+        }
+        Ok(())
+    }
+
+    fn var(&self, key: &str) -> Option<String> {
+        env::var(key).ok()
+            .or_else(|| self.file.get(key).cloned())
+            .or_else(|| self.secrets.get(key).cloned())
+    }
+}
+
+/// Settings of a single managed domain, after applying its group's overrides to the global values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DomainConfig {
+    pub name: String,
+    pub proxied: bool,
+    /// Existing records keep their proxy status (`CF_PROXIED=auto`), `proxied` only applies to
+    /// the records created
+    pub keep_proxied: bool,
+    pub ttl: u64,
+    /// Existing records keep their TTL, none is configured (`CF_TTL` or the group's `ttl`) and the
+    /// records aren't proxied
+    pub keep_ttl: bool,
+    /// Seconds between updates of the domain
+    pub update_interval: u64,
+    pub ipv4_enabled: bool,
+    pub ipv6_enabled: bool,
+    /// Published instead of the detected address while the primary origin is down
+    pub failover_ipv4: Option<String>,
+    pub failover_ipv6: Option<String>,
+    /// Published with the detected IPv6 prefix instead of the detected address, for hosts behind
+    /// the router running the updater
+    pub ipv6_suffix: Option<Ipv6Addr>,
+    /// Credentials of the account the zone is in, when not the global ones
+    pub credentials: Option<Credentials>,
+}
+
+impl DomainConfig {
+    pub fn failover(&self, family: IpFamily) -> Option<&str> {
+        match family {
+            IpFamily::V4 => self.failover_ipv4.as_deref(),
+            IpFamily::V6 => self.failover_ipv6.as_deref(),
+        }
+    }
+}
+
+/// Which server `CF_DNS_FIRST` asks whether a name already resolves to the address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsFirst {
+    /// A nameserver of the domain's zone, a recursive resolver may answer from its cache
+    Authoritative,
+    Server(IpAddr),
+}
+
+/// The dyndns2 update server (`CF_DYNDNS_*`), for clients that push their address.
+#[derive(Debug)]
+pub struct DynDnsConfig {
+    pub username: String,
+    pub password: Secret,
+    /// Updated only with pushed addresses, with the global record settings
+    pub domains: Vec<DomainConfig>,
+}
 
 #[derive(Debug)]
 pub struct Config {
-    pub token: String,
-    pub domains: Vec<String>,
+    pub auth: Credentials,
+    pub fallback_token: Option<Secret>,
+    pub domains: Vec<DomainConfig>,
+    /// Updated and verified before `domains`, which are only touched when it passes
+    pub canary: Option<DomainConfig>,
+    /// Whether any domain needs the address of this family
 	pub ipv4_enabled: bool,
     pub ipv6_enabled: bool,
-    pub proxied: bool,
     pub update_interval: u64,
+    /// Domains updated at the same time
+    pub max_concurrency: usize,
+    /// Longest interval in seconds the update interval is stretched to while cycles keep failing
+    pub max_backoff: u64,
+    /// Seconds before a failed cycle is retried, 0 waits for the update interval
+    pub retry_after: u64,
+    /// Minimum time between two content changes of a record, zero disables the hold-down
+    pub hold_down: Duration,
+    /// Cycles in a row a new address has to be detected in before it is published
+    pub debounce_cycles: u32,
+    /// Exit after a single update cycle instead of looping
+    pub run_once: bool,
+    /// Log record changes instead of sending them
+    pub dry_run: bool,
+    /// Comment set on created and updated records, unless the script sets one
+    pub record_comment: Option<String>,
+    pub record_tags: Vec<String>,
+    pub duplicate_records: DuplicateRecords,
+    /// Overwrite records found by name that look like someone else's
+    pub force_takeover: bool,
+    /// Create the records that don't exist, otherwise only existing ones are updated
+    pub create_missing: bool,
+    /// Publish records Cloudflare refuses to proxy unproxied instead of failing
+    pub proxy_fallback: bool,
+    /// Whether a CNAME on a name that needs an address record is replaced
+    pub cname_conflict: CnameConflict,
+    pub zone_status: Option<String>,
+    /// How long a looked up zone ID is used before it is looked up again
+    pub zone_cache_ttl: Duration,
+    pub api_url: String,
+    pub retry: RetryPolicy,
+    pub circuit_breaker: CircuitBreaker,
+    /// Client of the Cloudflare API requests, through `CF_API_PROXY`
+    pub api_client: reqwest::Client,
+    /// Clients of the IP detection requests, through `CF_IP_PROXY` and trusting `CF_IP_RESOLVER_CERT`
+    pub ipv4_client: reqwest::Client,
+    pub ipv6_client: reqwest::Client,
+    /// Client of the other requests (notifiers, Pushgateway), all of them trust `CF_CA_CERTS`
+    pub http_client: reqwest::Client,
+    pub ipv4_source: IpSource,
+    pub ipv6_source: IpSource,
+    /// Interfaces whose address changes trigger an update right away (Linux), in addition to the
+    /// ones of `interface:` sources
+    pub watch_interfaces: Vec<String>,
+    /// Length of the delegated prefix the `ipv6_suffix` of a domain is appended to
+    pub ipv6_prefix_length: u8,
+    /// What to do with a detected address in carrier-grade NAT or other non-routable space
+    pub non_routable: NonRoutable,
+    /// What happens after `ipv6_failure_threshold` failed IPv6 detections in a row
+    pub ipv6_failure_policy: Ipv6FailurePolicy,
+    pub ipv6_failure_threshold: u32,
+    /// Seconds IPv6 is left alone at a time once the policy disables it
+    pub ipv6_pause: u64,
+    pub probe: Option<Probe>,
+    pub probe_delay: Duration,
+    pub probe_timeout: Duration,
+    /// Resolver asked for changed records until it returns the new address
+    pub verify_dns: Option<IpAddr>,
+    pub verify_dns_timeout: Duration,
+    /// Server asked before the API, a record it already resolves to the address isn't read
+    pub dns_first: Option<DnsFirst>,
+    pub failover_after: Duration,
+    /// Load Balancer pool origins set to the detected address next to the records
+    pub lb_origins: Vec<PoolOrigin>,
+    /// IP Lists the detected addresses are added to
+    pub ip_lists: Vec<IpList>,
+    pub pushgateway: Option<Pushgateway>,
+    pub notifiers: Vec<Subscription>,
+    /// Where published addresses, zone IDs and record IDs are kept across restarts
+    pub state_file: Option<PathBuf>,
+    /// File with more domains, watched and reloaded when it changes
+    pub domains_file: Option<PathBuf>,
+    /// JSON lines file every address change is appended to
+    pub history_file: Option<PathBuf>,
+    /// Commands run on address changes and failed updates
+    pub hooks: Hooks,
+    #[cfg(feature = "mqtt")]
+    pub mqtt: Option<Mqtt>,
+    /// Address of the HTTP server with the health check
+    pub http_listen: Option<SocketAddr>,
+    /// Update intervals without a successful cycle before the health check fails
+    pub health_intervals: u32,
+    /// Bearer token of the control endpoints (`/update`, `/status`, `/records`) served next to
+    /// `/healthz`, `None` when they are disabled
+    pub http_control: Option<Secret>,
+    pub dyndns: Option<DynDnsConfig>,
+    #[cfg(unix)]
+    pub control_socket: Option<PathBuf>,
+    #[cfg(feature = "plugins")]
+    pub notifier_plugins: Vec<Arc<Plugin>>,
+    #[cfg(feature = "scripting")]
+    pub script: Option<Arc<Script>>,
 }
 
 impl Config {
-    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
-        let token = env::var("CF_TOKEN")
-            .map_err(|_| "Missing CF_TOKEN")?;
+    /// Loads the file named by `CF_CONFIG` and the fragments in `CF_CONFIG_DIR`
+    /// (`/etc/cloudflaredyndns/conf.d` when it exists), on top of the environment.
+    pub fn load() -> Result<Self, Error> {
+        Self::load_from(env::var("CF_CONFIG").ok().map(|path| PathBuf::from(path.trim())))
+    }
 
-        let domains_raw = env::var("CF_DOMAINS")
-            .map_err(|_| "Missing CF_DOMAINS")?;
-        let mut domains: Vec<String> = domains_raw
-            .split(',')
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.is_empty())
-            .collect();
+    /// [`Config::load`] with `file` instead of the file named by `CF_CONFIG`, e.g. for a service
+    /// that has no environment of its own.
+    pub fn load_from(file: Option<PathBuf>) -> Result<Self, Error> {
+        let mut source = Source::default();
 
-		if domains.is_empty() {
-			return Err("Missing data in CF_DOMAINS".into());
-		}
+        if let Some(path) = file.as_deref().filter(|path| !path.as_os_str().is_empty()) {
+            source.merge_file(path)
+                .map_err(|e| Error::Config(format!("Error reading config file '{}': {}", path.display(), e)))?;
+        }
 
-		domains.sort();
-		domains.dedup();
+        let dir = match env::var("CF_CONFIG_DIR") {
+            Ok(dir) if dir.trim().is_empty() => None,
+            Ok(dir) => Some(PathBuf::from(dir.trim())),
+            Err(_) => Some(PathBuf::from(DEFAULT_CONFIG_DIR)).filter(|dir| dir.is_dir()),
+        };
+        if let Some(dir) = dir {
+            source.merge_dir(&dir)?;
+        }
+
+        Self::from_source(source)
+    }
+
+    pub fn from_env() -> Result<Self, Error> {
+        Self::from_source(Source::default())
+    }
+
+    /// Reads settings from a TOML file, or YAML with a `.yaml`/`.yml` extension, environment variables
+    /// take precedence over its values.
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let mut source = Source::default();
+        source.merge_file(path)
+            .map_err(|e| Error::Config(format!("Error reading config file '{}': {}", path.display(), e)))?;
+        Self::from_source(source)
+    }
+
+    fn from_source(mut source: Source) -> Result<Self, Error> {
+        source.read_secret_files()?;
+        let source = &source;
+
+        let auth = parse_credentials(source.var("CF_TOKEN"), source.var("CF_API_KEY"), source.var("CF_AUTH_EMAIL"), ["CF_TOKEN", "CF_API_KEY", "CF_AUTH_EMAIL"])
+            .map_err(Error::Config)?
+            .ok_or_else(|| Error::Config("Missing CF_TOKEN (or CF_API_KEY and CF_AUTH_EMAIL)".to_string()))?;
+
+        // Tried when CF_TOKEN is rejected, covers token rotation
+        let fallback_token = source.var("CF_FALLBACK_TOKEN")
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .map(Secret::from);
 
-        let ipv4_enabled = env::var("CF_IPV4_ENABLED")
-            .unwrap_or_else(|_| "true".to_string())
+        let ipv4_enabled = source.var("CF_IPV4_ENABLED")
+            .unwrap_or_else(|| "true".to_string())
             .eq_ignore_ascii_case("true");
 
-		 let ipv6_enabled = env::var("CF_IPV6_ENABLED")
-            .unwrap_or_else(|_| "false".to_string())
+		 let ipv6_enabled = source.var("CF_IPV6_ENABLED")
+            .unwrap_or_else(|| "false".to_string())
+            .eq_ignore_ascii_case("true");
+
+        // auto keeps the proxy status of existing records, new ones are created unproxied
+        let proxied_raw = source.var("CF_PROXIED").unwrap_or_else(|| "false".to_string());
+        let proxied = proxied_raw.trim().eq_ignore_ascii_case("true");
+        let keep_proxied = proxied_raw.trim().eq_ignore_ascii_case("auto");
+
+        let update_interval = source.var("CF_UPDATE_INTERVAL")
+            .map(|v| v.trim().parse::<u64>().ok().filter(|interval| *interval > 0)
+                .ok_or_else(|| Error::Config(format!("Invalid CF_UPDATE_INTERVAL '{}': expected a positive number of seconds", v))))
+            .transpose()?
+            .unwrap_or(300);
+
+        let max_concurrency = source.var("CF_MAX_CONCURRENCY")
+            .map(|v| v.trim().parse::<usize>().ok().filter(|n| *n > 0)
+                .ok_or_else(|| Error::Config(format!("Invalid CF_MAX_CONCURRENCY '{}': expected a positive number", v))))
+            .transpose()?
+            .unwrap_or(8);
+
+        let max_backoff = source.var("CF_MAX_BACKOFF")
+            .map(|v| v.trim().parse::<u64>()
+                .map_err(|_| Error::Config(format!("Invalid CF_MAX_BACKOFF '{}': expected a number of seconds", v))))
+            .transpose()?
+            .unwrap_or(3600);
+
+        let retry_after = source.var("CF_RETRY_AFTER")
+            .map(|v| v.trim().parse::<u64>()
+                .map_err(|_| Error::Config(format!("Invalid CF_RETRY_AFTER '{}': expected a number of seconds", v))))
+            .transpose()?
+            .unwrap_or(30);
+
+        let hold_down = Duration::from_secs(source.var("CF_HOLD_DOWN")
+            .map(|v| v.trim().parse::<u64>()
+                .map_err(|_| Error::Config(format!("Invalid CF_HOLD_DOWN '{}': expected a number of seconds", v))))
+            .transpose()?
+            .unwrap_or(0));
+
+        let debounce_cycles = source.var("CF_DEBOUNCE_CYCLES")
+            .map(|v| v.trim().parse::<u32>().ok().filter(|n| *n > 0)
+                .ok_or_else(|| Error::Config(format!("Invalid CF_DEBOUNCE_CYCLES '{}': expected a positive number", v))))
+            .transpose()?
+            .unwrap_or(1);
+
+        let run_once = source.var("CF_RUN_ONCE")
+            .unwrap_or_else(|| "false".to_string())
+            .eq_ignore_ascii_case("true");
+
+        let dry_run = source.var("CF_DRY_RUN")
+            .unwrap_or_else(|| "false".to_string())
+            .eq_ignore_ascii_case("true");
+
+        let record_comment = source.var("CF_RECORD_COMMENT")
+            .map(|comment| comment.trim().to_string())
+            .filter(|comment| !comment.is_empty());
+        let record_tags = source.var("CF_RECORD_TAGS").unwrap_or_default()
+            .split(',')
+            .map(|tag| tag.trim())
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| match tag.split_once(':') {
+                Some((name, _)) if !name.trim().is_empty() => Ok(tag.to_string()),
+                _ => Err(Error::Config(format!("Invalid tag '{}' in CF_RECORD_TAGS: expected name:value", tag))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let force_takeover = source.var("CF_FORCE_TAKEOVER")
+            .unwrap_or_else(|| "false".to_string())
             .eq_ignore_ascii_case("true");
 
-        let proxied = env::var("CF_PROXIED")
-            .unwrap_or_else(|_| "false".to_string())
+        let create_missing = source.var("CF_CREATE_MISSING")
+            .unwrap_or_else(|| "true".to_string())
             .eq_ignore_ascii_case("true");
 
-        let update_interval = env::var("CF_UPDATE_INTERVAL")
-            .unwrap_or_else(|_| "300".to_string())
+        let proxy_fallback = source.var("CF_PROXY_FALLBACK")
+            .unwrap_or_else(|| "false".to_string())
+            .eq_ignore_ascii_case("true");
+
+        let cname_conflict = CnameConflict::parse(&source.var("CF_CNAME_CONFLICT").unwrap_or_default())
+            .map_err(|e| Error::Config(format!("Invalid CF_CNAME_CONFLICT: {}", e)))?;
+
+        let duplicate_records = DuplicateRecords::parse(&source.var("CF_DUPLICATE_RECORDS").unwrap_or_default())
+            .map_err(|e| Error::Config(format!("Invalid CF_DUPLICATE_RECORDS: {}", e)))?;
+
+        // Without a TTL the records keep theirs, e.g. one set in the dashboard
+        let keep_ttl = source.var("CF_TTL").is_none();
+        let ttl_raw = source.var("CF_TTL")
+            .unwrap_or_else(|| TTL_AUTO.to_string());
+        let ttl = ttl_raw
+            .trim()
             .parse::<u64>()
-            .unwrap_or(300);
+            .map_err(|_| Error::Config(format!("Invalid CF_TTL '{}': expected a number of seconds", ttl_raw)))?;
+
+        let enterprise_plan = source.var("CF_ENTERPRISE_PLAN")
+            .unwrap_or_else(|| "false".to_string())
+            .eq_ignore_ascii_case("true");
+
+        let defaults = DomainConfig {
+            name: String::new(),
+            proxied,
+            keep_proxied,
+            ttl: validate_ttl(ttl, proxied, enterprise_plan, "CF_TTL")?,
+            keep_ttl: keep_ttl && !proxied,
+            update_interval,
+            ipv4_enabled,
+            ipv6_enabled,
+            failover_ipv4: parse_failover(source.var("CF_FAILOVER_IPV4"), IpFamily::V4, "CF_FAILOVER_IPV4")?,
+            failover_ipv6: parse_failover(source.var("CF_FAILOVER_IPV6"), IpFamily::V6, "CF_FAILOVER_IPV6")?,
+            ipv6_suffix: None,
+            credentials: None,
+        };
+
+        // One entry per line (or comma separated) like in CF_DOMAINS, # starts a comment
+        let domains_file = source.var("CF_DOMAINS_FILE")
+            .map(|path| path.trim().to_string())
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from);
+        let file_domains = match &domains_file {
+            Some(path) => fs::read_to_string(path)
+                .map_err(|e| Error::Config(format!("Error reading CF_DOMAINS_FILE '{}': {}", path.display(), e)))?
+                .lines()
+                .map(|line| line.split('#').next().unwrap_or_default().to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+            None => String::new(),
+        };
+
+        // Entries with options (`mail.example.com:proxied=false:ttl=300`) become single domain groups
+        let mut domains = Vec::new();
+        let mut inline_groups = Vec::new();
+        let entries = source.var("CF_DOMAINS").unwrap_or_default();
+        for entry in entries.split(',').chain(file_domains.split(',')).map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            match entry.split_once(':') {
+                Some((name, options)) => inline_groups.push(parse_domain_options(name.trim(), options)?),
+                None => domains.push(DomainConfig { name: normalize_domain(entry)?, ..defaults.clone() }),
+            }
+        }
+
+        for (index, group) in inline_groups.iter().chain(&source.groups).enumerate() {
+            let label = group.name.clone().unwrap_or_else(|| format!("#{}", index + 1));
+            let group_proxied = group.proxied.unwrap_or(proxied);
+            let group_ttl = match group.ttl {
+                Some(group_ttl) => validate_ttl(group_ttl, group_proxied, enterprise_plan, &format!("ttl of '{}'", label))?,
+                None if group_proxied => TTL_AUTO,
+                None => validate_ttl(ttl, false, enterprise_plan, "CF_TTL")?,
+            };
+
+            if group.domains.is_empty() {
+                warn!("Group '{}' has no domains", label);
+            }
+            for name in &group.domains {
+                domains.push(DomainConfig {
+                    name: normalize_domain(name)?,
+                    proxied: group_proxied,
+                    keep_proxied: keep_proxied && group.proxied.is_none(),
+                    ttl: group_ttl,
+                    keep_ttl: keep_ttl && group.ttl.is_none() && !group_proxied,
+                    update_interval: match group.update_interval {
+                        Some(0) => return Err(Error::Config(format!("Invalid update_interval of '{}': expected a positive number of seconds", label))),
+                        Some(group_interval) => group_interval,
+                        None => update_interval,
+                    },
+                    ipv4_enabled: group.ipv4_enabled.unwrap_or(ipv4_enabled),
+                    ipv6_enabled: group.ipv6_enabled.unwrap_or(ipv6_enabled),
+                    failover_ipv4: match &group.failover_ipv4 {
+                        Some(ip) => parse_failover(Some(ip.clone()), IpFamily::V4, &format!("failover_ipv4 of '{}'", label))?,
+                        None => defaults.failover_ipv4.clone(),
+                    },
+                    failover_ipv6: match &group.failover_ipv6 {
+                        Some(ip) => parse_failover(Some(ip.clone()), IpFamily::V6, &format!("failover_ipv6 of '{}'", label))?,
+                        None => defaults.failover_ipv6.clone(),
+                    },
+                    ipv6_suffix: group.ipv6_suffix.as_deref()
+                        .map(|suffix| suffix.trim().parse::<Ipv6Addr>()
+                            .map_err(|_| Error::Config(format!("Invalid ipv6_suffix '{}' of '{}': expected an IPv6 address like ::1234", suffix, label))))
+                        .transpose()?,
+                    credentials: parse_credentials(group.token.clone(), group.api_key.clone(), group.auth_email.clone(), ["token", "api_key", "auth_email"])
+                        .map_err(|e| Error::Config(format!("Invalid credentials of group '{}': {}", label, e)))?,
+                });
+            }
+        }
+
+		if domains.is_empty() {
+			return Err(Error::Config("Missing CF_DOMAINS (or CF_DOMAINS_FILE)".to_string()));
+		}
+
+		domains.sort_by(|a, b| a.name.cmp(&b.name));
+		if let Some(pair) = domains.windows(2).find(|pair| pair[0].name == pair[1].name && pair[0] != pair[1]) {
+			return Err(Error::Config(format!("Domain '{}' is configured more than once with different settings", pair[0].name)));
+		}
+		let configured = domains.len();
+		domains.dedup();
+		if domains.len() != configured {
+			warn!("Ignoring {} duplicate domain entries", configured - domains.len());
+		}
+
+        let canary = match source.var("CF_CANARY").filter(|name| !name.trim().is_empty()) {
+            Some(name) => {
+                let name = normalize_domain(name.trim())?;
+                let index = domains.iter().position(|d| d.name == name)
+                    .ok_or_else(|| Error::Config(format!("CF_CANARY '{}' is not one of the configured domains", name)))?;
+                Some(domains.remove(index))
+            },
+            None => None,
+        };
+
+        // "any" disables the status filter when looking up zones
+        let zone_status = source.var("CF_ZONE_STATUS")
+            .unwrap_or_else(|| "active".to_string())
+            .trim()
+            .to_ascii_lowercase();
+        let zone_status = match zone_status.as_str() {
+            "any" | "" => None,
+            "active" | "pending" | "initializing" | "moved" | "deleted" | "deactivated" => Some(zone_status),
+            _ => return Err(Error::Config(format!("Invalid CF_ZONE_STATUS '{}'", zone_status))),
+        };
+
+        let zone_cache_ttl = Duration::from_secs(source.var("CF_ZONE_CACHE_TTL")
+            .map(|v| v.trim().parse::<u64>()
+                .map_err(|_| Error::Config(format!("Invalid CF_ZONE_CACHE_TTL '{}': expected a number of seconds", v))))
+            .transpose()?
+            .unwrap_or(86400));
+
+        let api_url = source.var("CF_API_URL")
+            .unwrap_or_else(|| cloudflare::DEFAULT_API_URL.to_string());
+
+        let default_retry = RetryPolicy::default();
+        let retry = RetryPolicy {
+            // At least the first try
+            attempts: source.var("CF_RETRY_ATTEMPTS")
+                .map(|v| v.trim().parse::<u32>()
+                    .map_err(|_| Error::Config(format!("Invalid CF_RETRY_ATTEMPTS '{}': expected a number of tries", v))))
+                .transpose()?
+                .unwrap_or(default_retry.attempts)
+                .max(1),
+            base_delay: source.var("CF_RETRY_DELAY")
+                .map(|v| v.trim().parse::<u64>()
+                    .map_err(|_| Error::Config(format!("Invalid CF_RETRY_DELAY '{}': expected a number of milliseconds", v))))
+                .transpose()?
+                .map(Duration::from_millis)
+                .unwrap_or(default_retry.base_delay),
+            max_delay: default_retry.max_delay,
+        };
+
+        let default_breaker = CircuitBreaker::default();
+        let circuit_breaker = CircuitBreaker {
+            threshold: source.var("CF_CIRCUIT_BREAKER")
+                .map(|v| v.trim().parse::<u32>()
+                    .map_err(|_| Error::Config(format!("Invalid CF_CIRCUIT_BREAKER '{}': expected a number of failed requests", v))))
+                .transpose()?
+                .unwrap_or(default_breaker.threshold),
+            cooldown: source.var("CF_CIRCUIT_BREAKER_COOLDOWN")
+                .map(|v| v.trim().parse::<u64>().map(Duration::from_secs)
+                    .map_err(|_| Error::Config(format!("Invalid CF_CIRCUIT_BREAKER_COOLDOWN '{}': expected a number of seconds", v))))
+                .transpose()?
+                .unwrap_or(default_breaker.cooldown),
+        };
+
+        let certificates = |setting: &str| -> Result<Vec<reqwest::Certificate>, Error> {
+            let mut certificates = Vec::new();
+            for path in source.var(setting).unwrap_or_default().split(',').map(|path| path.trim()).filter(|path| !path.is_empty()) {
+                certificates.extend(http::load_certificates(Path::new(path))
+                    .map_err(|e| Error::Config(format!("Error reading certificates '{}' of {}: {}", path, setting, e)))?);
+            }
+            Ok(certificates)
+        };
+        let ca_certificates = certificates("CF_CA_CERTS")?;
+        // The certificate of a self-hosted resolver, only trusted for IP detection
+        let resolver_certificates = certificates("CF_IP_RESOLVER_CERT")?;
+
+        let seconds = |setting: &str, default: Duration| -> Result<Duration, Error> {
+            source.var(setting)
+                .map(|v| v.trim().parse::<u64>().ok().filter(|secs| *secs > 0)
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| Error::Config(format!("Invalid {} '{}': expected a positive number of seconds", setting, v))))
+                .transpose()
+                .map(|timeout| timeout.unwrap_or(default))
+        };
+        let timeout = seconds("CF_HTTP_TIMEOUT", http::DEFAULT_TIMEOUT)?;
+        let connect_timeout = seconds("CF_CONNECT_TIMEOUT", http::DEFAULT_CONNECT_TIMEOUT)?;
+
+        // CF_PROXY applies to both kinds of requests, an empty CF_API_PROXY/CF_IP_PROXY turns it off
+        let client_settings = |setting: &str, extra_certificates: &[reqwest::Certificate]| -> Result<ClientSettings, Error> {
+            let proxy = source.var(setting).or_else(|| source.var("CF_PROXY"))
+                .map(|url| url.trim().to_string())
+                .filter(|url| !url.is_empty())
+                .map(|url| http::parse_proxy(&url).map_err(|e| Error::Config(format!("Invalid {}: {}", setting, e))))
+                .transpose()?;
+            let root_certificates = ca_certificates.iter().chain(extra_certificates).cloned().collect();
+            Ok(ClientSettings { proxy, root_certificates, timeout, connect_timeout })
+        };
+        let api_http = client_settings("CF_API_PROXY", &[])?;
+        let ip_http = client_settings("CF_IP_PROXY", &resolver_certificates)?;
+        let build_client = |settings: &ClientSettings, family: Option<IpFamily>| settings.build(family)
+            .map_err(|e| Error::Config(format!("Error creating the HTTP client: {}", e)));
+        let api_client = build_client(&api_http, None)?;
+        let ipv4_client = build_client(&ip_http, Some(IpFamily::V4))?;
+        let ipv6_client = build_client(&ip_http, Some(IpFamily::V6))?;
+        let http_client = build_client(&ClientSettings { proxy: None, root_certificates: ca_certificates.clone(), timeout, connect_timeout }, None)?;
+
+        let resolvers = source.var("CF_IP_RESOLVERS").filter(|v| !v.trim().is_empty())
+            .map(|names| names.split(',')
+                .map(|name| name.trim())
+                .filter(|name| !name.is_empty())
+                .map(|name| Resolver::parse(name).map_err(|e| Error::Config(format!("Invalid CF_IP_RESOLVERS: {}", e))))
+                .collect::<Result<Vec<_>, _>>())
+            .transpose()?;
+
+        // A resolver URL replaces the public resolvers, unless CF_IP_RESOLVERS names fallbacks
+        let family_resolvers = |setting: &str| -> Result<Vec<Resolver>, Error> {
+            match source.var(setting).map(|url| url.trim().to_string()).filter(|url| !url.is_empty()) {
+                Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+                    let mut family_resolvers = vec![Resolver::Url(url)];
+                    family_resolvers.extend(resolvers.iter().flatten().cloned());
+                    Ok(family_resolvers)
+                },
+                Some(url) => Err(Error::Config(format!("Invalid {} '{}': expected an http(s) URL", setting, url))),
+                None => Ok(resolvers.clone().unwrap_or_else(|| ipsource::DEFAULT_RESOLVERS.to_vec())),
+            }
+        };
+        let ipv4_resolvers = family_resolvers("CF_IPV4_RESOLVER_URL")?;
+        let ipv6_resolvers = family_resolvers("CF_IPV6_RESOLVER_URL")?;
+
+        let ipv4_source = IpSource::parse(&source.var("CF_IPV4_SOURCE").unwrap_or_default(), &ipv4_resolvers)
+            .map_err(|e| Error::Config(format!("Invalid CF_IPV4_SOURCE: {}", e)))?;
+        // CF_IPV6_INTERFACE=eth0 is short for CF_IPV6_SOURCE=interface:eth0
+        let ipv6_interface = source.var("CF_IPV6_INTERFACE").filter(|v| !v.trim().is_empty());
+        let ipv6_source = match (source.var("CF_IPV6_SOURCE").filter(|v| !v.trim().is_empty()), ipv6_interface) {
+            (Some(_), Some(_)) => return Err(Error::Config("CF_IPV6_SOURCE and CF_IPV6_INTERFACE can't be used together".to_string())),
+            (None, Some(interface)) => IpSource::Interface(interface.trim().to_string(), Ipv6Policy::default()),
+            (value, None) => IpSource::parse(&value.unwrap_or_default(), &ipv6_resolvers)
+                .map_err(|e| Error::Config(format!("Invalid CF_IPV6_SOURCE: {}", e)))?,
+        };
+        let ipv6_source = match (source.var("CF_IPV6_POLICY").filter(|v| !v.trim().is_empty()), ipv6_source) {
+            (Some(policy), IpSource::Interface(interface, _)) => IpSource::Interface(interface, Ipv6Policy::parse(&policy)
+                .map_err(|e| Error::Config(format!("Invalid CF_IPV6_POLICY '{}': {}", policy, e)))?),
+            (Some(_), ipv6_source) => {
+                warn!("CF_IPV6_POLICY only applies to the interface source, ignoring it");
+                ipv6_source
+            },
+            (None, ipv6_source) => ipv6_source,
+        };
+
+        // A FRITZ!Box user switches the fritzbox sources to the authenticated TR-064 API
+        let fritzbox_login = match (source.var("CF_FRITZBOX_USERNAME"), source.var("CF_FRITZBOX_PASSWORD")) {
+            (Some(username), Some(password)) => Some(FritzBoxLogin { username: username.trim().to_string(), password: Secret::from(password) }),
+            (None, None) => None,
+            _ => return Err(Error::Config("CF_FRITZBOX_USERNAME and CF_FRITZBOX_PASSWORD must be set together".to_string())),
+        };
+        let with_login = |ip_source: IpSource| match ip_source {
+            IpSource::FritzBox(url, _) => IpSource::FritzBox(url, fritzbox_login.clone()),
+            ip_source => ip_source,
+        };
+        let (ipv4_source, ipv6_source) = (with_login(ipv4_source), with_login(ipv6_source));
+
+        let resolver_mode = source.var("CF_IP_RESOLVER_MODE")
+            .map(|mode| ResolverMode::parse(&mode).map_err(|e| Error::Config(format!("Invalid CF_IP_RESOLVER_MODE: {}", e))))
+            .transpose()?
+            .unwrap_or_default();
+        let with_mode = |ip_source: IpSource| match ip_source {
+            IpSource::Http(resolvers, _) => match resolver_mode {
+                ResolverMode::Quorum(Some(required)) if required > resolvers.len() => Err(Error::Config(format!(
+                    "Invalid CF_IP_RESOLVER_MODE: a quorum of {} needs at least as many resolvers, {} configured", required, resolvers.len()))),
+                mode => Ok(IpSource::Http(resolvers, mode)),
+            },
+            ip_source => Ok(ip_source),
+        };
+        let (ipv4_source, ipv6_source) = (with_mode(ipv4_source)?, with_mode(ipv6_source)?);
+
+        let watch_interfaces: Vec<String> = source.var("CF_WATCH_INTERFACES")
+            .map(|interfaces| interfaces.split(',').map(|i| i.trim().to_string()).filter(|i| !i.is_empty()).collect())
+            .unwrap_or_default();
+
+        let non_routable = NonRoutable::parse(&source.var("CF_NON_ROUTABLE").unwrap_or_default())
+            .map_err(|e| Error::Config(format!("Invalid CF_NON_ROUTABLE: {}", e)))?;
+
+        let ipv6_failure_policy = Ipv6FailurePolicy::parse(&source.var("CF_IPV6_FAILURE_POLICY").unwrap_or_default())
+            .map_err(|e| Error::Config(format!("Invalid CF_IPV6_FAILURE_POLICY: {}", e)))?;
+        let ipv6_failure_threshold = source.var("CF_IPV6_FAILURE_THRESHOLD")
+            .map(|v| v.trim().parse::<u32>().ok().filter(|n| *n > 0)
+                .ok_or_else(|| Error::Config(format!("Invalid CF_IPV6_FAILURE_THRESHOLD '{}': expected a positive number of cycles", v))))
+            .transpose()?
+            .unwrap_or(3);
+        let ipv6_pause = source.var("CF_IPV6_PAUSE")
+            .map(|v| v.trim().parse::<u64>().ok().filter(|pause| *pause > 0)
+                .ok_or_else(|| Error::Config(format!("Invalid CF_IPV6_PAUSE '{}': expected a positive number of seconds", v))))
+            .transpose()?
+            .unwrap_or(3600);
+        // The records would never come back
+        if ipv6_failure_policy == Ipv6FailurePolicy::Delete && !create_missing {
+            return Err(Error::Config("CF_IPV6_FAILURE_POLICY=delete requires CF_CREATE_MISSING=true to recreate the AAAA records".to_string()));
+        }
+
+        let ipv6_prefix_length = match source.var("CF_IPV6_PREFIX_LENGTH") {
+            Some(length) => length.trim().parse::<u8>().ok()
+                .filter(|length| (1..=127).contains(length))
+                .ok_or_else(|| Error::Config(format!("Invalid CF_IPV6_PREFIX_LENGTH '{}': expected 1 to 127", length)))?,
+            None => 64,
+        };
+
+        let probe = Probe::parse(
+            &source.var("CF_PROBE").unwrap_or_default(),
+            &source.var("CF_PROBE_PATH").unwrap_or_else(|| "/".to_string()),
+        ).map_err(|e| Error::Config(e.to_string()))?;
+        let probe_delay = Duration::from_secs(source.var("CF_PROBE_DELAY")
+            .map(|v| v.trim().parse::<u64>()
+                .map_err(|_| Error::Config(format!("Invalid CF_PROBE_DELAY '{}': expected a number of seconds", v))))
+            .transpose()?
+            .unwrap_or(5));
+        let probe_timeout = Duration::from_secs(source.var("CF_PROBE_TIMEOUT")
+            .map(|v| v.trim().parse::<u64>()
+                .map_err(|_| Error::Config(format!("Invalid CF_PROBE_TIMEOUT '{}': expected a number of seconds", v))))
+            .transpose()?
+            .unwrap_or(10));
+        let verify_dns = parse_resolver(source.var("CF_VERIFY_DNS"), "CF_VERIFY_DNS")?;
+        let dns_first = match source.var("CF_DNS_FIRST").map(|v| v.trim().to_ascii_lowercase()) {
+            Some(v) if v == "true" => Some(DnsFirst::Authoritative),
+            v => parse_resolver(v, "CF_DNS_FIRST")?.map(DnsFirst::Server),
+        };
+        let verify_dns_timeout = Duration::from_secs(source.var("CF_VERIFY_DNS_TIMEOUT")
+            .map(|v| v.trim().parse::<u64>()
+                .map_err(|_| Error::Config(format!("Invalid CF_VERIFY_DNS_TIMEOUT '{}': expected a number of seconds", v))))
+            .transpose()?
+            .unwrap_or(120));
+        let failover_after = Duration::from_secs(source.var("CF_FAILOVER_AFTER")
+            .map(|v| v.trim().parse::<u64>()
+                .map_err(|_| Error::Config(format!("Invalid CF_FAILOVER_AFTER '{}': expected a number of seconds", v))))
+            .transpose()?
+            .unwrap_or(300));
+
+        // Failover decisions are based on the probe, without one the primary can't be checked
+        if probe.is_none() {
+            if let Some(domain) = domains.iter().chain(&canary).find(|d| d.failover_ipv4.is_some() || d.failover_ipv6.is_some()) {
+                return Err(Error::Config(format!("Domain '{}' has a failover address but CF_PROBE is not set", domain.name)));
+            }
+        }
+        // Reading back the record just written proves nothing about the address it holds
+        if canary.is_some() && probe.is_none() && verify_dns.is_none() {
+            return Err(Error::Config("CF_CANARY needs CF_PROBE or CF_VERIFY_DNS to verify the canary with".to_string()));
+        }
+
+        // Pools and lists belong to an account, unlike the zones they can't be looked up by name
+        let account_id = |setting: &str| source.var("CF_ACCOUNT_ID").map(|id| id.trim().to_string()).filter(|id| !id.is_empty())
+            .ok_or_else(|| Error::Config(format!("CF_ACCOUNT_ID is required with {}", setting)));
+        let list_setting = |setting: &str| source.var(setting)
+            .map(|values| values.split(',').map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let lb_origins = match list_setting("CF_LB_ORIGINS") {
+            origins if origins.is_empty() => Vec::new(),
+            origins => {
+                let account_id = account_id("CF_LB_ORIGINS")?;
+                origins.iter()
+                    .map(|origin| PoolOrigin::parse(origin, &account_id).map_err(|e| Error::Config(format!("Invalid CF_LB_ORIGINS: {}", e))))
+                    .collect::<Result<Vec<_>, _>>()?
+            },
+        };
+        if let Some(origin) = lb_origins.iter().find(|o| match o.family { IpFamily::V4 => !ipv4_enabled, IpFamily::V6 => !ipv6_enabled }) {
+            return Err(Error::Config(format!("Load Balancer origin '{}' needs the {} address but it is not enabled", origin.label(), origin.family)));
+        }
+
+        let ip_lists = match list_setting("CF_IP_LISTS") {
+            lists if lists.is_empty() => Vec::new(),
+            lists => {
+                let account_id = account_id("CF_IP_LISTS")?;
+                lists.into_iter().map(|list_id| IpList { account_id: account_id.clone(), list_id }).collect()
+            },
+        };
+
+        let pushgateway = source.var("CF_PUSHGATEWAY_URL")
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .map(|url| Pushgateway {
+                url,
+                job: source.var("CF_PUSHGATEWAY_JOB")
+                    .map(|job| job.trim().to_string())
+                    .filter(|job| !job.is_empty())
+                    .unwrap_or_else(|| metrics::DEFAULT_PUSHGATEWAY_JOB.to_string()),
+                instance: source.var("CF_PUSHGATEWAY_INSTANCE")
+                    .map(|instance| instance.trim().to_string())
+                    .filter(|instance| !instance.is_empty()),
+            });
+
+        // Each notifier backend has its own <PREFIX>_EVENTS setting
+        let events_for = |prefix: &str| EventKind::parse_list(source.var(&format!("{}_EVENTS", prefix)).as_deref(), &notify::DEFAULT_EVENTS)
+            .map_err(|e| Error::Config(format!("Invalid {}_EVENTS: {}", prefix, e)));
+        let is_http_url = |url: &str| url.starts_with("http://") || url.starts_with("https://");
+
+        let mut notifiers = Vec::new();
+        let webhook_events = events_for("CF_WEBHOOK")?;
+        let webhook_template = source.var("CF_WEBHOOK_TEMPLATE").filter(|t| !t.trim().is_empty());
+        for url in source.var("CF_WEBHOOK_URLS").unwrap_or_default().split(',').map(|u| u.trim()).filter(|u| !u.is_empty()) {
+            if !is_http_url(url) {
+                return Err(Error::Config(format!("Invalid webhook URL '{}': expected an http(s) URL", url)));
+            }
+            notifiers.push(Subscription {
+                notifier: Arc::new(Webhook { url: url.to_string(), template: webhook_template.clone() }),
+                events: webhook_events.clone(),
+            });
+        }
+
+        match (source.var("CF_TELEGRAM_TOKEN").filter(|t| !t.trim().is_empty()), source.var("CF_TELEGRAM_CHAT_ID").filter(|c| !c.trim().is_empty())) {
+            (Some(token), Some(chat_id)) => notifiers.push(Subscription {
+                notifier: Arc::new(Telegram { token: Secret::new(token.trim()), chat_id: chat_id.trim().to_string() }),
+                events: events_for("CF_TELEGRAM")?,
+            }),
+            (None, None) => (),
+            _ => return Err(Error::Config("CF_TELEGRAM_TOKEN and CF_TELEGRAM_CHAT_ID must be set together".to_string())),
+        }
+
+        let chat_webhooks: [(&str, &str, fn(String) -> Arc<dyn Notifier>); 2] = [
+            ("CF_DISCORD_WEBHOOK_URL", "CF_DISCORD", |url| Arc::new(Discord { url })),
+            ("CF_SLACK_WEBHOOK_URL", "CF_SLACK", |url| Arc::new(Slack { url })),
+        ];
+        for (setting, prefix, notifier) in chat_webhooks {
+            let Some(url) = source.var(setting).map(|u| u.trim().to_string()).filter(|u| !u.is_empty()) else {
+                continue;
+            };
+            if !is_http_url(&url) {
+                return Err(Error::Config(format!("Invalid {} '{}': expected an http(s) URL", setting, url)));
+            }
+            notifiers.push(Subscription { notifier: notifier(url), events: events_for(prefix)? });
+        }
+
+        if let Some(url) = source.var("CF_NTFY_URL").map(|u| u.trim().to_string()).filter(|u| !u.is_empty()) {
+            if !is_http_url(&url) {
+                return Err(Error::Config(format!("Invalid CF_NTFY_URL '{}': expected an http(s) URL", url)));
+            }
+            let priorities = notify::parse_priorities(source.var("CF_NTFY_PRIORITIES").as_deref(), Ntfy::parse_priority)
+                .map_err(|e| Error::Config(format!("Invalid CF_NTFY_PRIORITIES: {}", e)))?;
+            let token = source.var("CF_NTFY_TOKEN").map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).map(Secret::from);
+            notifiers.push(Subscription {
+                notifier: Arc::new(Ntfy { url, token, priorities }),
+                events: events_for("CF_NTFY")?,
+            });
+        }
+
+        if let Some(url) = source.var("CF_GOTIFY_URL").map(|u| u.trim().to_string()).filter(|u| !u.is_empty()) {
+            if !is_http_url(&url) {
+                return Err(Error::Config(format!("Invalid CF_GOTIFY_URL '{}': expected an http(s) URL", url)));
+            }
+            let token = source.var("CF_GOTIFY_TOKEN").map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).map(Secret::from)
+                .ok_or_else(|| Error::Config("CF_GOTIFY_TOKEN is required with CF_GOTIFY_URL".to_string()))?;
+            let priorities = notify::parse_priorities(source.var("CF_GOTIFY_PRIORITIES").as_deref(), Gotify::parse_priority)
+                .map_err(|e| Error::Config(format!("Invalid CF_GOTIFY_PRIORITIES: {}", e)))?;
+            notifiers.push(Subscription {
+                notifier: Arc::new(Gotify { url, token, priorities }),
+                events: events_for("CF_GOTIFY")?,
+            });
+        }
+
+        if let Some(host) = source.var("CF_SMTP_HOST").map(|h| h.trim().to_string()).filter(|h| !h.is_empty()) {
+            #[cfg(feature = "email")]
+            notifiers.push(Subscription {
+                notifier: Arc::new(email_notifier(&source, &host)?),
+                // The notifier picks what to mail from these
+                events: vec![EventKind::IpChanged, EventKind::CycleFinished],
+            });
+
+            #[cfg(not(feature = "email"))]
+            return Err(Error::Config(format!("Email notifications through '{}' require building with the 'email' feature", host)));
+        }
+
+        let state_file = source.var("CF_STATE_FILE")
+            .map(|path| path.trim().to_string())
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from);
+
+        let history_file = source.var("CF_HISTORY_FILE")
+            .map(|path| path.trim().to_string())
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from);
+
+        let hook = |setting: &str| source.var(setting).map(|command| command.trim().to_string()).filter(|command| !command.is_empty());
+        let hooks = Hooks {
+            on_ip_change: hook("CF_ON_IP_CHANGE"),
+            on_update_failure: hook("CF_ON_UPDATE_FAILURE"),
+            timeout: source.var("CF_HOOK_TIMEOUT")
+                .map(|v| v.trim().parse::<u64>().ok().filter(|secs| *secs > 0).map(Duration::from_secs)
+                    .ok_or_else(|| Error::Config(format!("Invalid CF_HOOK_TIMEOUT '{}': expected a positive number of seconds", v))))
+                .transpose()?
+                .unwrap_or(hooks::DEFAULT_TIMEOUT),
+        };
+
+        #[cfg(feature = "mqtt")]
+        let mqtt = source.var("CF_MQTT_URL")
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .map(|url| mqtt_settings(&source, &url))
+            .transpose()?;
+
+        #[cfg(not(feature = "mqtt"))]
+        if let Some(url) = source.var("CF_MQTT_URL").filter(|url| !url.trim().is_empty()) {
+            return Err(Error::Config(format!("Publishing to MQTT broker '{}' requires building with the 'mqtt' feature", url.trim())));
+        }
+
+        let http_listen = source.var("CF_HTTP_LISTEN")
+            .map(|listen| listen.trim().to_string())
+            .filter(|listen| !listen.is_empty())
+            .map(|listen| listen.parse::<SocketAddr>()
+                .map_err(|_| Error::Config(format!("Invalid CF_HTTP_LISTEN '{}': expected an address and port like 0.0.0.0:8080", listen))))
+            .transpose()?;
+        let health_intervals = source.var("CF_HEALTH_INTERVALS")
+            .map(|v| v.trim().parse::<u32>().ok().filter(|intervals| *intervals > 0)
+                .ok_or_else(|| Error::Config(format!("Invalid CF_HEALTH_INTERVALS '{}': expected a positive number of intervals", v))))
+            .transpose()?
+            .unwrap_or(3);
+        // The probe port is often reachable by the whole cluster, the control endpoints are not for everyone
+        let http_control = match source.var("CF_HTTP_CONTROL").is_some_and(|v| v.eq_ignore_ascii_case("true")) {
+            true => Some(source.var("CF_HTTP_CONTROL_TOKEN").filter(|token| !token.trim().is_empty()).map(Secret::from)
+                .ok_or_else(|| Error::Config("CF_HTTP_CONTROL requires CF_HTTP_CONTROL_TOKEN".to_string()))?),
+            false => None,
+        };
+
+        let dyndns_domains = source.var("CF_DYNDNS_DOMAINS").unwrap_or_default()
+            .split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(|name| Ok(DomainConfig { name: normalize_domain(name)?, ipv4_enabled: true, ipv6_enabled: true, ..defaults.clone() }))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let dyndns = match (dyndns_domains.is_empty(), source.var("CF_DYNDNS_USERNAME"), source.var("CF_DYNDNS_PASSWORD")) {
+            (true, _, _) => None,
+            (false, Some(username), Some(password)) if !username.is_empty() && !password.is_empty() => {
+                if http_listen.is_none() {
+                    warn!("CF_DYNDNS_DOMAINS is set but CF_HTTP_LISTEN isn't, no updates will be accepted");
+                }
+                if let Some(domain) = dyndns_domains.iter().find(|d| domains.iter().chain(&canary).any(|c| c.name == d.name)) {
+                    return Err(Error::Config(format!("Domain '{}' is in both CF_DOMAINS and CF_DYNDNS_DOMAINS", domain.name)));
+                }
+                Some(DynDnsConfig { username, password: Secret::from(password), domains: dyndns_domains })
+            },
+            (false, _, _) => return Err(Error::Config("CF_DYNDNS_DOMAINS requires CF_DYNDNS_USERNAME and CF_DYNDNS_PASSWORD".to_string())),
+        };
+
+        // An empty value disables the control socket
+        #[cfg(unix)]
+        let control_socket = match source.var("CF_CONTROL_SOCKET") {
+            Some(path) if path.trim().is_empty() => None,
+            Some(path) => Some(PathBuf::from(path.trim())),
+            None => Some(crate::control::default_socket_path()),
+        };
+
+        #[cfg(feature = "plugins")]
+        let notifier_plugins = source.var("CF_NOTIFIER_PLUGINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|path| Plugin::load(Path::new(path)).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::Config(e.to_string()))?;
+
+        #[cfg(feature = "scripting")]
+        let script = match source.var("CF_SCRIPT") {
+            Some(path) if !path.trim().is_empty() => Some(Arc::new(Script::load(Path::new(path.trim())).map_err(|e| Error::Config(e.to_string()))?)),
+            _ => None,
+        };
 
         Ok(Self {
-            token,
+            auth,
+            fallback_token,
+            ipv4_enabled: domains.iter().chain(&canary).any(|d| d.ipv4_enabled),
+            ipv6_enabled: domains.iter().chain(&canary).any(|d| d.ipv6_enabled),
             domains,
-			ipv4_enabled,
-            ipv6_enabled,
-            proxied,
+            canary,
             update_interval,
+            max_concurrency,
+            max_backoff,
+            retry_after,
+            hold_down,
+            debounce_cycles,
+            run_once,
+            dry_run,
+            record_comment,
+            record_tags,
+            duplicate_records,
+            force_takeover,
+            create_missing,
+            proxy_fallback,
+            cname_conflict,
+            zone_status,
+            zone_cache_ttl,
+            api_url,
+            retry,
+            circuit_breaker,
+            api_client,
+            ipv4_client,
+            ipv6_client,
+            http_client,
+            ipv4_source,
+            ipv6_source,
+            watch_interfaces,
+            ipv6_prefix_length,
+            non_routable,
+            ipv6_failure_policy,
+            ipv6_failure_threshold,
+            ipv6_pause,
+            probe,
+            probe_delay,
+            probe_timeout,
+            verify_dns,
+            verify_dns_timeout,
+            dns_first,
+            failover_after,
+            lb_origins,
+            ip_lists,
+            pushgateway,
+            notifiers,
+            state_file,
+            domains_file,
+            history_file,
+            hooks,
+            #[cfg(feature = "mqtt")]
+            mqtt,
+            http_listen,
+            health_intervals,
+            http_control,
+            dyndns,
+            #[cfg(unix)]
+            control_socket,
+            #[cfg(feature = "plugins")]
+            notifier_plugins,
+            #[cfg(feature = "scripting")]
+            script,
         })
     }
 }
+
+/// Reads the broker credentials and topic settings that go with `CF_MQTT_URL`.
+#[cfg(feature = "mqtt")]
+fn mqtt_settings(source: &Source, url: &str) -> Result<Mqtt, Error> {
+    let (host, port, tls) = Mqtt::parse_url(url)
+        .map_err(|e| Error::Config(format!("Invalid CF_MQTT_URL '{}': {}", url, e)))?;
+    let credentials = source.var("CF_MQTT_USERNAME")
+        .filter(|username| !username.trim().is_empty())
+        .map(|username| (username.trim().to_string(), Secret::from(source.var("CF_MQTT_PASSWORD").unwrap_or_default())));
+    let client_id = source.var("CF_MQTT_CLIENT_ID")
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+        .unwrap_or_else(|| mqtt::DEFAULT_TOPIC.to_string());
+    let topic = source.var("CF_MQTT_TOPIC")
+        .map(|topic| topic.trim().trim_end_matches('/').to_string())
+        .filter(|topic| !topic.is_empty())
+        .unwrap_or_else(|| mqtt::DEFAULT_TOPIC.to_string());
+    if topic.contains(['+', '#']) {
+        return Err(Error::Config(format!("Invalid CF_MQTT_TOPIC '{}': wildcards can't be published to", topic)));
+    }
+    // true for the default prefix, or the prefix configured in Home Assistant
+    let discovery = match source.var("CF_MQTT_DISCOVERY").map(|v| v.trim().trim_end_matches('/').to_string()).unwrap_or_default() {
+        v if v.is_empty() || v.eq_ignore_ascii_case("false") => None,
+        v if v.eq_ignore_ascii_case("true") => Some(mqtt::DEFAULT_DISCOVERY_PREFIX.to_string()),
+        v if v.contains(['+', '#']) => return Err(Error::Config(format!("Invalid CF_MQTT_DISCOVERY '{}': wildcards can't be published to", v))),
+        v => Some(v),
+    };
+
+    Ok(Mqtt { host, port, tls, credentials, client_id, topic, discovery })
+}
+
+/// Builds the SMTP notifier from the `CF_SMTP_*` settings.
+#[cfg(feature = "email")]
+fn email_notifier(source: &Source, host: &str) -> Result<notify::Email, Error> {
+    use notify::email;
+
+    let port = source.var("CF_SMTP_PORT")
+        .map(|port| port.trim().parse::<u16>().map_err(|_| Error::Config(format!("Invalid CF_SMTP_PORT '{}'", port))))
+        .transpose()?
+        .unwrap_or(email::DEFAULT_PORT);
+    let tls = notify::SmtpTls::parse(&source.var("CF_SMTP_TLS").unwrap_or_default())
+        .map_err(|e| Error::Config(format!("Invalid CF_SMTP_TLS: {}", e)))?;
+    let credentials = source.var("CF_SMTP_USERNAME")
+        .filter(|username| !username.trim().is_empty())
+        .map(|username| (username.trim().to_string(), Secret::from(source.var("CF_SMTP_PASSWORD").unwrap_or_default())));
+    let from = source.var("CF_SMTP_FROM")
+        .filter(|from| !from.trim().is_empty())
+        .ok_or_else(|| Error::Config("CF_SMTP_FROM is required with CF_SMTP_HOST".to_string()))?;
+    let to: Vec<String> = source.var("CF_SMTP_TO")
+        .unwrap_or_default()
+        .split(',')
+        .map(|to| to.trim().to_string())
+        .filter(|to| !to.is_empty())
+        .collect();
+    if to.is_empty() {
+        return Err(Error::Config("CF_SMTP_TO is required with CF_SMTP_HOST".to_string()));
+    }
+    let failure_threshold = source.var("CF_SMTP_FAILURE_THRESHOLD")
+        .map(|v| v.trim().parse::<u32>().ok().filter(|threshold| *threshold > 0)
+            .ok_or_else(|| Error::Config(format!("Invalid CF_SMTP_FAILURE_THRESHOLD '{}': expected a positive number of cycles", v))))
+        .transpose()?
+        .unwrap_or(email::DEFAULT_FAILURE_THRESHOLD);
+
+    notify::Email::new(host, port, tls, credentials, from.trim(), &to, failure_threshold)
+        .map_err(|e| Error::Config(format!("Invalid SMTP settings: {}", e)))
+}
+
+/// A token, or a Global API Key with the account's email. `None` when neither is set, errors
+/// refer to the settings by `names`.
+fn parse_credentials(token: Option<String>, api_key: Option<String>, auth_email: Option<String>, names: [&str; 3]) -> Result<Option<Credentials>, String> {
+    let token = token.filter(|t| !t.trim().is_empty());
+    let api_key = api_key.map(|k| k.trim().to_string()).filter(|k| !k.is_empty());
+    match (token, api_key) {
+        (Some(token), None) => Ok(Some(Credentials::Token(Secret::from(token)))),
+        (None, Some(key)) => {
+            let email = auth_email
+                .map(|e| e.trim().to_string())
+                .filter(|e| !e.is_empty())
+                .ok_or_else(|| format!("{} requires {}", names[1], names[2]))?;
+            Ok(Some(Credentials::GlobalKey { email, key: Secret::from(key) }))
+        },
+        (Some(_), Some(_)) => Err(format!("Set either {} or {}, not both", names[0], names[1])),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Parses the `:`-separated options of a `CF_DOMAINS` entry: `proxied`, `proxied=false`,
+/// `ttl=300`, `interval=60`, `ipv4=false` and `ipv6=true`. Failover addresses need a config file
+/// table, an IPv6 address can't be written between `:` separators.
+fn parse_domain_options(name: &str, options: &str) -> Result<GroupSpec, Error> {
+    let mut group = GroupSpec {
+        name: Some(name.to_string()),
+        domains: vec![name.to_string()],
+        ..Default::default()
+    };
+
+    for option in options.split(':').map(|o| o.trim()).filter(|o| !o.is_empty()) {
+        let (key, value) = option.split_once('=').unwrap_or((option, "true"));
+        let flag = || match value.to_ascii_lowercase().as_str() {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(Error::Config(format!("Invalid value '{}' for '{}' of domain '{}'", value, key, name))),
+        };
+
+        match key.to_ascii_lowercase().as_str() {
+            "proxied" => group.proxied = Some(flag()?),
+            "ipv4" => group.ipv4_enabled = Some(flag()?),
+            "ipv6" => group.ipv6_enabled = Some(flag()?),
+            "ttl" => group.ttl = Some(value.parse()
+                .map_err(|_| Error::Config(format!("Invalid ttl '{}' of domain '{}': expected a number of seconds", value, name)))?),
+            "interval" | "update_interval" => group.update_interval = Some(value.parse()
+                .map_err(|_| Error::Config(format!("Invalid interval '{}' of domain '{}': expected a number of seconds", value, name)))?),
+            _ => return Err(Error::Config(format!("Unknown option '{}' of domain '{}'", key, name))),
+        }
+    }
+
+    Ok(group)
+}
+
+fn is_yaml(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "yaml" || ext == "yml")
+}
+
+/// Parses the resolver of a DNS check: `true` for 1.1.1.1, an address, or `false`/empty for none.
+fn parse_resolver(value: Option<String>, setting: &str) -> Result<Option<IpAddr>, Error> {
+    match value.map(|v| v.trim().to_ascii_lowercase()).filter(|v| !v.is_empty()) {
+        None => Ok(None),
+        Some(v) if v == "false" => Ok(None),
+        Some(v) if v == "true" => Ok(Some(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)))),
+        Some(v) => v.parse::<IpAddr>().map(Some)
+            .map_err(|_| Error::Config(format!("Invalid {} '{}': expected true or the address of a resolver", setting, v))),
+    }
+}
+
+/// Parses a failover address, an empty value means none. The address must belong to `family`
+/// since it replaces the detected address of that family.
+fn parse_failover(value: Option<String>, family: IpFamily, setting: &str) -> Result<Option<String>, Error> {
+    let Some(value) = value.map(|v| v.trim().to_string()).filter(|v| !v.is_empty()) else {
+        return Ok(None);
+    };
+
+    match (value.parse::<IpAddr>(), family) {
+        (Ok(IpAddr::V4(ip)), IpFamily::V4) => Ok(Some(ip.to_string())),
+        (Ok(IpAddr::V6(ip)), IpFamily::V6) => Ok(Some(ip.to_string())),
+        _ => Err(Error::Config(format!("Invalid {} '{}': expected an {} address", setting, value, family))),
+    }
+}
+
+/// Checks a TTL against the limits Cloudflare enforces, so a bad value is reported once at
+/// startup instead of being rejected by the API on every update.
+pub fn validate_ttl(ttl: u64, proxied: bool, enterprise_plan: bool, setting: &str) -> Result<u64, Error> {
+    if ttl == TTL_AUTO {
+        return Ok(ttl);
+    }
+
+    // Proxied records always use automatic TTL, Cloudflare ignores anything else
+    if proxied {
+        warn!("{} {} has no effect on proxied records, using automatic TTL", setting, ttl);
+        return Ok(TTL_AUTO);
+    }
+
+    let min = if enterprise_plan { TTL_MIN_ENTERPRISE } else { TTL_MIN };
+    if ttl < min {
+        return Err(Error::Config(format!(
+            "Invalid {} {}: must be 1 (automatic) or between {} and {} seconds{}",
+            setting, ttl, min, TTL_MAX,
+            if enterprise_plan { "" } else { " (30 is only allowed on Enterprise plans)" }
+        )));
+    }
+
+    if ttl > TTL_MAX {
+        warn!("{} {} exceeds the maximum of {} seconds, clamping", setting, ttl, TTL_MAX);
+        return Ok(TTL_MAX);
+    }
+
+    Ok(ttl)
+}
+
+/// Lowercases a configured domain and strips the trailing dot of a fully qualified name, rejecting
+/// anything that isn't a valid hostname. The result is what gets used for cache keys and API calls.
+pub fn normalize_domain(domain: &str) -> Result<String, Error> {
+    let normalized = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+
+    if normalized.len() > 253 {
+        return Err(Error::Config(format!("Invalid domain '{}': longer than 253 characters", domain)));
+    }
+
+    // A wildcard record covers the names below the rest, `*` is only allowed as the whole first label
+    let labels: Vec<&str> = normalized.strip_prefix("*.").unwrap_or(&normalized).split('.').collect();
+    if labels.len() < 2 {
+        return Err(Error::Config(format!("Invalid domain '{}': expected at least a name and a TLD", domain)));
+    }
+
+    for label in &labels {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::Config(format!("Invalid domain '{}': labels must be 1 to 63 characters long", domain)));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(Error::Config(format!("Invalid domain '{}': labels can't start or end with '-'", domain)));
+        }
+        if *label == "*" {
+            return Err(Error::Config(format!("Invalid domain '{}': '*' is only allowed as the first label", domain)));
+        }
+        if let Some(c) = label.chars().find(|c| !(c.is_ascii_alphanumeric() || *c == '-' || *c == '_')) {
+            return Err(Error::Config(format!("Invalid domain '{}': invalid character '{}'", domain, c)));
+        }
+    }
+
+    Ok(normalized)
+}
+
+/// [`interpolate`] for a config file, the comments are left as they are so that a commented out
+/// example doesn't need its variables.
+fn interpolate_file(text: &str, yaml: bool) -> Result<String, Error> {
+    let mut result = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        let (content, comment) = line.split_at(comment_start(line, yaml).unwrap_or(line.len()));
+        result.push_str(&interpolate(content)?);
+        result.push_str(comment);
+    }
+    Ok(result)
+}
+
+/// Where the comment of a config file line starts: a `#` outside of quotes, in YAML only after
+/// whitespace (`a#b` is a plain value there).
+fn comment_start(line: &str, yaml: bool) -> Option<usize> {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {},
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' && (!yaml || previous.is_whitespace()) => return Some(i),
+            None => {},
+        }
+        previous = c;
+    }
+    None
+}
+
+/// Expands `${VAR}` and `${VAR:-fallback}` references to environment variables, `$${` escapes a
+/// literal `${`. Referencing an unset variable without a fallback is an error.
+pub fn interpolate(text: &str) -> Result<String, Error> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            result.push_str("${");
+            rest = escaped;
+            continue;
+        }
+
+        let Some(reference) = rest.strip_prefix("${") else {
+            result.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+
+        let end = reference.find('}')
+            .ok_or_else(|| Error::Config(format!("Unterminated variable reference '${{{}'", reference.lines().next().unwrap_or_default())))?;
+        let (name, fallback) = match reference[..end].split_once(":-") {
+            Some((name, fallback)) => (name, Some(fallback)),
+            None => (&reference[..end], None),
+        };
+
+        match (env::var(name.trim()), fallback) {
+            (Ok(value), _) if !value.is_empty() || fallback.is_none() => result.push_str(&value),
+            (_, Some(fallback)) => result.push_str(fallback),
+            (_, None) => return Err(Error::Config(format!("Environment variable '{}' referenced in config is not set", name.trim()))),
+        }
+
+        rest = &reference[end + 1..];
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
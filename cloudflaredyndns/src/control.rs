@@ -0,0 +1,76 @@
+use std::{env, fs, path::{Path, PathBuf}};
+
+use tokio::{
+	io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+	net::{UnixListener, UnixStream},
+	sync::broadcast::{Receiver, error::RecvError},
+};
+use tracing::info;
+
+use crate::events::{EventBus, EventMessage};
+
+/// `$XDG_RUNTIME_DIR/cloudflaredyndns.sock` for user services, `/run/cloudflaredyndns.sock` otherwise.
+pub fn default_socket_path() -> PathBuf {
+	match env::var("XDG_RUNTIME_DIR") {
+		Ok(dir) if !dir.is_empty() => Path::new(&dir).join("cloudflaredyndns.sock"),
+		_ => PathBuf::from("/run/cloudflaredyndns.sock"),
+	}
+}
+
+/// Accepts connections on the control socket and streams every event to them as JSON lines.
+pub async fn serve(path: &Path, events: EventBus) -> Result<(), Box<dyn std::error::Error>> {
+	// A previous instance that didn't shut down cleanly leaves the socket file behind
+	if path.exists() {
+		fs::remove_file(path)?;
+	}
+
+	let listener = UnixListener::bind(path)
+		.map_err(|e| format!("Could not bind control socket '{}': {}", path.display(), e))?;
+	info!("Control socket listening on '{}'", path.display());
+
+	loop {
+		let (stream, _) = listener.accept().await?;
+		tokio::spawn(stream_events(stream, events.subscribe()));
+	}
+}
+
+async fn stream_events(mut stream: UnixStream, mut events: Receiver<EventMessage>) {
+	loop {
+		let message = match events.recv().await {
+			Ok(message) => message,
+			Err(RecvError::Lagged(_)) => continue,
+			Err(RecvError::Closed) => return,
+		};
+
+		let Ok(mut line) = serde_json::to_string(&message) else {
+			continue;
+		};
+		line.push('\n');
+
+		if stream.write_all(line.as_bytes()).await.is_err() {
+			return;
+		}
+	}
+}
+
+/// Connects to a running daemon and prints its events until the connection closes.
+pub async fn watch(path: &Path, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+	let stream = UnixStream::connect(path).await
+		.map_err(|e| format!("Could not connect to control socket '{}': {}", path.display(), e))?;
+	let mut lines = BufReader::new(stream).lines();
+
+	while let Some(line) = lines.next_line().await? {
+		if json {
+			println!("{}", line);
+			continue;
+		}
+
+		match serde_json::from_str::<EventMessage>(&line) {
+			Ok(message) => println!("{}", message),
+			Err(_) => println!("{}", line),
+		}
+	}
+
+	println!("Connection closed by the daemon");
+	Ok(())
+}
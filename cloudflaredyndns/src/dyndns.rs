@@ -0,0 +1,125 @@
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, sync::Arc};
+
+use axum::{
+	Router,
+	extract::{ConnectInfo, Query, State},
+	http::{HeaderMap, HeaderValue, StatusCode, header::{AUTHORIZATION, WWW_AUTHENTICATE}},
+	response::IntoResponse,
+	routing::get,
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+use crate::cloudflare::CloudflareClient;
+use crate::config::Config;
+use crate::events::EventBus;
+use crate::failover::Failover;
+use crate::ipsource::{self, IpFamily};
+use crate::provider::DnsProvider;
+use crate::state::State as PublishedState;
+use crate::summary::RecordOutcome;
+use crate::updater;
+
+/// Accepts dyndns2 (No-IP, DynDNS) style updates from clients such as routers for the
+/// `CF_DYNDNS_DOMAINS` and publishes the pushed addresses, instead of detecting them.
+#[derive(Clone)]
+pub struct DynDns {
+	pub configs: watch::Receiver<Arc<Config>>,
+	/// The update loop's client, requests from both share its rate limit pause and circuit breaker
+	pub cloudflare: CloudflareClient,
+	pub events: EventBus,
+	pub state: PublishedState,
+	pub failover: Failover,
+}
+
+/// `GET /nic/update?hostname=<names>&myip=<addresses>` with basic authentication, as the
+/// clients send it. Without `myip` the address the request came from is published. Pushed
+/// addresses get the checks of detected ones, a private or non-routable address is answered with
+/// `911`.
+pub fn router(dyndns: DynDns) -> Router {
+	Router::new()
+		.route("/nic/update", get(nic_update))
+		.with_state(dyndns)
+}
+
+async fn nic_update(State(dyndns): State<DynDns>, ConnectInfo(remote): ConnectInfo<SocketAddr>, headers: HeaderMap, Query(params): Query<HashMap<String, String>>) -> impl IntoResponse {
+	let config = dyndns.configs.borrow().clone();
+	let Some(settings) = &config.dyndns else {
+		return (StatusCode::NOT_FOUND, HeaderMap::new(), "nohost".to_string());
+	};
+
+	let authorized = basic_auth(&headers).is_some_and(|(username, password)| username == settings.username && password == settings.password.expose());
+	if !authorized {
+		warn!("Rejected dyndns update from {}: bad credentials", remote.ip());
+		let mut headers = HeaderMap::new();
+		headers.insert(WWW_AUTHENTICATE, HeaderValue::from_static("Basic realm=\"cloudflaredyndns\""));
+		return (StatusCode::UNAUTHORIZED, headers, "badauth".to_string());
+	}
+
+	let addresses: Result<Vec<IpAddr>, _> = match params.get("myip").map(|ip| ip.trim()).filter(|ip| !ip.is_empty()) {
+		Some(ips) => ips.split(',').map(|ip| ip.trim().parse::<IpAddr>()).collect(),
+		None => Ok(vec![remote.ip().to_canonical()]),
+	};
+	let Ok(addresses) = addresses else {
+		return (StatusCode::OK, HeaderMap::new(), "911".to_string());
+	};
+	// A router pushing from the LAN without `myip` would otherwise publish its private address
+	let (mut ipv4, mut ipv6) = (None, None);
+	for ip in addresses {
+		let family = if ip.is_ipv4() { IpFamily::V4 } else { IpFamily::V6 };
+		let published = if family == IpFamily::V4 { &mut ipv4 } else { &mut ipv6 };
+		if published.is_some() {
+			continue;
+		}
+		let checked = match ipsource::validate(&ip.to_string(), family) {
+			Ok(ip) => updater::check_routable(&config, &dyndns.events, family, ip),
+			Err(e) => {
+				warn!("Rejected dyndns update from {}: {}", remote.ip(), e);
+				None
+			},
+		};
+		let Some(ip) = checked else {
+			return (StatusCode::OK, HeaderMap::new(), "911".to_string());
+		};
+		*published = Some(ip);
+	}
+	let pushed = ipv4.iter().chain(&ipv6).cloned().collect::<Vec<_>>().join(",");
+
+	let hostnames = params.get("hostname").map(|h| h.as_str()).unwrap_or_default();
+	let mut answers = Vec::new();
+	for hostname in hostnames.split(',').map(|h| h.trim().trim_end_matches('.').to_ascii_lowercase()).filter(|h| !h.is_empty()) {
+		let Some(domain) = settings.domains.iter().find(|d| d.name == hostname) else {
+			answers.push("nohost".to_string());
+			continue;
+		};
+
+		info!("dyndns update of '{}' to {} from {}", domain.name, pushed, remote.ip());
+		let provider: Arc<dyn DnsProvider> = Arc::new(dyndns.cloudflare.account(domain.credentials.as_ref()));
+		let answer = match updater::update_domain(&provider, &config, &dyndns.events, &dyndns.failover, &dyndns.state, domain, ipv4.clone(), ipv6.clone(), false).await {
+			Ok(reports) if reports.iter().any(|r| r.outcome == RecordOutcome::Failed) => "dnserr".to_string(),
+			Ok(reports) if reports.iter().all(|r| matches!(r.outcome, RecordOutcome::Unchanged | RecordOutcome::Skipped)) => format!("nochg {}", pushed),
+			Ok(_) => format!("good {}", pushed),
+			Err(e) => {
+				error!("Error updating domain '{}': {}", domain.name, e);
+				"dnserr".to_string()
+			},
+		};
+		answers.push(answer);
+	}
+
+	if answers.is_empty() {
+		return (StatusCode::OK, HeaderMap::new(), "notfqdn".to_string());
+	}
+	if let Err(e) = dyndns.state.save().await {
+		error!("Error writing state file: {}", e);
+	}
+	(StatusCode::OK, HeaderMap::new(), answers.join("\n"))
+}
+
+fn basic_auth(headers: &HeaderMap) -> Option<(String, String)> {
+	let encoded = headers.get(AUTHORIZATION)?.to_str().ok()?.strip_prefix("Basic ")?;
+	let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+	let (username, password) = decoded.split_once(':')?;
+	Some((username.to_string(), password.to_string()))
+}
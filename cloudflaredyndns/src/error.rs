@@ -0,0 +1,96 @@
+use crate::cloudflare::ApiError;
+use crate::ipsource::IpFamily;
+
+// Cloudflare error codes for missing, invalid or insufficient credentials
+const AUTH_ERROR_CODES: [u64; 5] = [9103, 9106, 9109, 10000, 10001];
+// Cloudflare error codes for an invalid zone identifier
+const ZONE_ERROR_CODES: [u64; 3] = [1001, 7000, 7003];
+// Cloudflare error code for a record that can't be proxied
+const NOT_PROXIABLE_CODES: [u64; 1] = [9041];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	/// Invalid or missing settings
+	#[error("{0}")]
+	Config(String),
+	#[error("{message}")]
+	IpLookup { family: IpFamily, message: String },
+	#[error("Zone ID not found for {0}")]
+	ZoneNotFound(String),
+	#[error("Zone {zone} has status '{status}' but '{required}' is required (set CF_ZONE_STATUS={status} or CF_ZONE_STATUS=any to accept it)")]
+	ZoneStatus { zone: String, status: String, required: String },
+	/// The record looks like someone else's, or another record holds the name. It is left alone
+	#[error("{0}")]
+	Conflict(String),
+	/// The record doesn't exist and may not be created
+	#[error("{0}")]
+	RecordMissing(String),
+	/// The token can't be used for updating records
+	#[error("{0}")]
+	Token(String),
+	/// Cloudflare answered with `success: false`
+	#[error("{}", join_api_errors(.errors))]
+	Api { status: u16, errors: Vec<ApiError> },
+	/// The response body didn't match the expected model (an HTML error page, a changed API)
+	#[error("Unexpected response from Cloudflare (HTTP {status}): {message}")]
+	Response { status: u16, message: String },
+	/// Requests are paused by the circuit breaker after repeated failures
+	#[error("Cloudflare API unavailable after repeated failures, requests are paused")]
+	CircuitOpen,
+	#[error(transparent)]
+	Http(#[from] reqwest::Error),
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+fn join_api_errors(errors: &[ApiError]) -> String {
+	if errors.is_empty() {
+		return "Unknown error".to_string();
+	}
+	errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+impl Error {
+	/// The credentials were rejected, retrying won't help until the token is fixed.
+	pub fn is_auth(&self) -> bool {
+		match self {
+			Error::Api { status, errors } => matches!(status, 401 | 403) || errors.iter().any(|e| AUTH_ERROR_CODES.contains(&e.code)),
+			Error::Token(_) => true,
+			_ => false,
+		}
+	}
+
+	/// The zone ID no longer works for the credentials: the zone was deleted, recreated or moved to
+	/// another account. It is looked up again instead of retrying.
+	pub fn is_zone_gone(&self) -> bool {
+		match self {
+			Error::Api { status, errors } => *status == 404 || errors.iter().any(|e| ZONE_ERROR_CODES.contains(&e.code)),
+			_ => self.is_auth(),
+		}
+	}
+
+	/// Cloudflare refused to proxy the record, its content or type can't go through the proxy.
+	pub fn is_not_proxiable(&self) -> bool {
+		match self {
+			Error::Api { errors, .. } => errors.iter().any(|e| {
+				let message = e.message.to_ascii_lowercase();
+				NOT_PROXIABLE_CODES.contains(&e.code) || message.contains("cannot be proxied") || message.contains("not proxiable")
+			}),
+			_ => false,
+		}
+	}
+
+	/// Cloudflare answered 429, the request was not processed.
+	pub fn is_rate_limited(&self) -> bool {
+		matches!(self, Error::Api { status: 429, .. } | Error::Response { status: 429, .. })
+	}
+
+	/// Timeouts, connection problems and server side errors that may succeed when repeated.
+	pub fn is_transient(&self) -> bool {
+		match self {
+			Error::Http(e) => e.is_timeout() || e.is_connect() || e.is_request() || e.status().is_some_and(|s| s.is_server_error()),
+			Error::Api { status, .. } | Error::Response { status, .. } => *status == 429 || *status >= 500,
+			_ => false,
+		}
+	}
+}
@@ -0,0 +1,110 @@
+use std::{fmt, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::ipsource::IpFamily;
+use crate::summary::CycleSummary;
+
+const EVENT_BUFFER: usize = 256;
+
+/// Something that happened in the updater, published on the [`EventBus`] for the control socket
+/// and notifier plugins. The update loop follows the bus too: `UpdateRequested`, and `IpChanged`
+/// published by anything else than the loop itself, start its next cycle right away.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+	/// An address watcher or the HTTP API asks for a cycle now, instead of at the next interval
+	UpdateRequested { reason: String },
+	CycleStarted,
+	IpDetected { family: IpFamily, ip: String },
+	IpDetectionFailed { family: IpFamily, error: String },
+	/// The detected address differs from the one detected before
+	IpChanged { family: IpFamily, old: String, new: String },
+	RecordCreated { domain: String, record_type: String, content: String },
+	RecordUpdated { domain: String, record_type: String, content: String },
+	/// The reachability probe after a record change failed
+	ProbeFailed { domain: String, error: String },
+	/// The resolver checked after a record change didn't return the new address in time
+	PropagationFailed { domain: String, record_type: String, content: String, error: String },
+	UpdateFailed { domain: String, family: Option<IpFamily>, error: String },
+	/// The primary origin failed its health probes for too long, the failover address was published
+	FailoverActivated { domain: String, family: IpFamily, content: String, error: String },
+	/// The primary origin passes its health probes again after a failover
+	FailoverRecovered { domain: String, family: IpFamily, content: String },
+	/// The canary record wasn't updated or didn't pass verification, the remaining domains were left
+	/// untouched
+	CanaryFailed { domain: String },
+	/// The primary token was rejected with this HTTP status and the fallback token took over
+	FallbackTokenActivated { status: u16 },
+	/// Cloudflare answered 429, API requests are paused for this many seconds
+	RateLimited { retry_after: u64 },
+	CycleFinished { summary: CycleSummary },
+}
+
+impl fmt::Display for Event {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Event::UpdateRequested { reason } => write!(f, "Update requested: {}", reason),
+			Event::CycleStarted => write!(f, "Update cycle started"),
+			Event::IpDetected { family, ip } => write!(f, "Public {}: {}", family, ip),
+			Event::IpDetectionFailed { family, error } => write!(f, "Error getting public {}: {}", family, error),
+			Event::IpChanged { family, old, new } => write!(f, "Public {} changed from {} to {}", family, old, new),
+			Event::RecordCreated { domain, record_type, content } => write!(f, "Created {} record '{}' with '{}'", record_type, domain, content),
+			Event::RecordUpdated { domain, record_type, content } => write!(f, "Updated {} record '{}' to '{}'", record_type, domain, content),
+			Event::ProbeFailed { domain, error } => write!(f, "Probe of '{}' failed: {}", domain, error),
+			Event::PropagationFailed { domain, record_type, content, error } => write!(f, "{} record '{}' with '{}' didn't propagate: {}", record_type, domain, content, error),
+			Event::UpdateFailed { domain, family: Some(family), error } => write!(f, "Error updating domain '{}' with {}: {}", domain, family, error),
+			Event::UpdateFailed { domain, family: None, error } => write!(f, "Error updating domain '{}': {}", domain, error),
+			Event::FailoverActivated { domain, family, content, error } => write!(f, "Primary origin of '{}' is down ({}), failed over to {} address '{}'", domain, error, family, content),
+			Event::FailoverRecovered { domain, family, content } => write!(f, "Primary origin of '{}' recovered, back to {} address '{}'", domain, family, content),
+			Event::CanaryFailed { domain } => write!(f, "Canary '{}' wasn't updated or verified, skipped the remaining domains", domain),
+			Event::FallbackTokenActivated { status } => write!(f, "Primary token rejected with HTTP {}, using the fallback token", status),
+			Event::RateLimited { retry_after } => write!(f, "Cloudflare rate limit reached, pausing API requests for {} seconds", retry_after),
+			Event::CycleFinished { summary } => write!(f, "Update cycle finished, {}", summary),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventMessage {
+	/// Seconds since the Unix epoch
+	pub timestamp: u64,
+	#[serde(flatten)]
+	pub event: Event,
+}
+
+impl fmt::Display for EventMessage {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let secs = self.timestamp % 86400;
+		write!(f, "{:02}:{:02}:{:02} {}", secs / 3600, secs / 60 % 60, secs % 60, self.event)
+	}
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+	sender: broadcast::Sender<EventMessage>,
+}
+
+impl Default for EventBus {
+	fn default() -> Self {
+		let (sender, _) = broadcast::channel(EVENT_BUFFER);
+		Self { sender }
+	}
+}
+
+impl EventBus {
+	pub fn emit(&self, event: Event) {
+		let timestamp = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|d| d.as_secs())
+			.unwrap_or_default();
+
+		// Sending only fails when nobody is listening
+		let _ = self.sender.send(EventMessage { timestamp, event });
+	}
+
+	pub fn subscribe(&self) -> broadcast::Receiver<EventMessage> {
+		self.sender.subscribe()
+	}
+}
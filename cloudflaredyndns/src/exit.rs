@@ -0,0 +1,35 @@
+//! Process exit codes, so wrapper scripts and `RestartPreventExitStatus=` can tell a setup that
+//! needs fixing from a failure that may pass on its own.
+
+use crate::error::Error;
+
+pub const SUCCESS: i32 = 0;
+/// Anything not covered below
+pub const FAILURE: i32 = 1;
+/// Some records failed or an address couldn't be detected while the rest was updated, or `check`
+/// found differences
+pub const PARTIAL: i32 = 2;
+/// Nothing could be updated: no address was detected or Cloudflare couldn't be reached
+pub const UNAVAILABLE: i32 = 3;
+/// Cloudflare rejected the credentials
+pub const AUTH: i32 = 4;
+/// Invalid or missing settings
+pub const CONFIG: i32 = 5;
+
+/// The exit code for a command that failed with `error`.
+pub fn of(error: &(dyn std::error::Error + 'static)) -> i32 {
+	if let Some(error) = error.downcast_ref::<Error>() {
+		return match error {
+			Error::Config(_) => CONFIG,
+			error if error.is_auth() => AUTH,
+			Error::IpLookup { .. } => UNAVAILABLE,
+			error if error.is_transient() => UNAVAILABLE,
+			Error::CircuitOpen => UNAVAILABLE,
+			_ => FAILURE,
+		};
+	}
+	match error.downcast_ref::<reqwest::Error>() {
+		Some(error) if error.is_timeout() || error.is_connect() => UNAVAILABLE,
+		_ => FAILURE,
+	}
+}
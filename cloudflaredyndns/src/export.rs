@@ -0,0 +1,95 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+use serde_json::Value;
+use tracing::debug;
+
+use crate::cleanup;
+use crate::cloudflare::{CloudflareClient, DnsRecord};
+use crate::config::Config;
+use crate::events::EventBus;
+use crate::provider::DnsProvider;
+use crate::state::State;
+
+/// Columns of the CSV export, the fields every record type has
+const CSV_COLUMNS: [&str; 10] = ["zone", "id", "name", "type", "content", "proxied", "ttl", "comment", "tags", "modified_on"];
+
+#[derive(Debug, Serialize)]
+struct ZoneRecords {
+	zone: String,
+	zone_id: String,
+	records: Vec<Value>,
+}
+
+/// The `export` command: writes every record of the zones the configured domains are in, or with
+/// `managed_only` the ones carrying `CF_RECORD_COMMENT` or one of `CF_RECORD_TAGS`, to `output`
+/// or stdout. The JSON keeps the records as Cloudflare returns them, the CSV has the fields common
+/// to all types. Nothing is written unless every zone could be listed.
+pub async fn run(managed_only: bool, csv: bool, output: Option<PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+	let config = Config::load()?;
+	if managed_only && config.record_comment.is_none() && config.record_tags.is_empty() {
+		return Err("--managed-only needs CF_RECORD_COMMENT or CF_RECORD_TAGS to tell the managed records apart".into());
+	}
+
+	let cloudflare = CloudflareClient::new(config.api_client.clone(), config.auth.clone(), config.fallback_token.as_ref(), &config.api_url, config.retry.clone(), EventBus::default());
+	let state = State::load(config.state_file.clone()).await;
+
+	let mut zones: Vec<ZoneRecords> = Vec::new();
+	for domain in config.canary.iter().chain(&config.domains) {
+		let zone = cloudflare.zone_name(&domain.name)?;
+		if zones.iter().any(|z| z.zone == zone) {
+			continue;
+		}
+
+		let account = cloudflare.account(domain.credentials.as_ref());
+		let zone_id = cleanup::zone_of(&config, &account, &state, domain).await?;
+		let records = account.zone_records(&zone_id).await?
+			.into_iter()
+			.filter(|record| !managed_only || serde_json::from_value::<DnsRecord>(record.clone()).is_ok_and(|record| cleanup::is_managed(&config, &record)))
+			.collect::<Vec<_>>();
+		debug!("Exporting {} records of zone {}", records.len(), zone);
+		zones.push(ZoneRecords { zone, zone_id, records });
+	}
+
+	let text = if csv { to_csv(&zones) } else { serde_json::to_string_pretty(&zones)? + "\n" };
+	match output {
+		// Replaced in one step, an interrupted export leaves the previous backup behind
+		Some(path) => {
+			let tmp = path.with_extension("tmp");
+			tokio::fs::write(&tmp, text).await?;
+			tokio::fs::rename(&tmp, &path).await?;
+		},
+		None => print!("{}", text),
+	}
+	Ok(())
+}
+
+fn to_csv(zones: &[ZoneRecords]) -> String {
+	let mut csv = CSV_COLUMNS.join(",") + "\n";
+	for zone in zones {
+		for record in &zone.records {
+			let field = |name: &str| match &record[name] {
+				Value::Null => String::new(),
+				Value::String(value) => value.clone(),
+				Value::Array(values) => values.iter().filter_map(Value::as_str).collect::<Vec<_>>().join(" "),
+				value => value.to_string(),
+			};
+			let row = CSV_COLUMNS.iter()
+				.map(|column| if *column == "zone" { zone.zone.clone() } else { field(*column) })
+				.map(|value| csv_field(&value))
+				.collect::<Vec<_>>();
+			csv += &row.join(",");
+			csv.push('\n');
+		}
+	}
+	csv
+}
+
+/// Quotes a value that contains a separator, a quote or a line break (RFC 4180).
+fn csv_field(value: &str) -> String {
+	if value.contains([',', '"', '\n', '\r']) {
+		format!("\"{}\"", value.replace('"', "\"\""))
+	} else {
+		value.to_string()
+	}
+}
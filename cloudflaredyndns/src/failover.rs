@@ -0,0 +1,70 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::events::{Event, EventBus};
+use crate::ipsource::IpFamily;
+use crate::probe::Probe;
+
+#[derive(Debug, Default)]
+struct Health {
+	failing_since: Option<Instant>,
+	failed_over: bool,
+}
+
+/// Tracks the health of each domain's primary origin across cycles and decides whether the
+/// detected address or the domain's failover address (`CF_FAILOVER_IPV4`/`CF_FAILOVER_IPV6`)
+/// gets published.
+#[derive(Clone)]
+pub struct Failover {
+	after: Duration,
+	health: Arc<Mutex<HashMap<(String, IpFamily), Health>>>,
+}
+
+impl Failover {
+	pub fn new(after: Duration) -> Self {
+		Self {
+			after,
+			health: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
+
+	/// Probes the primary origin directly and returns the address to publish: `primary` while it
+	/// is healthy or has been failing for less than the failover period, `fallback` after that.
+	pub async fn select(&self, probe: &Probe, timeout: Duration, events: &EventBus, domain: &str, family: IpFamily, primary: &str, fallback: &str) -> String {
+		// Always straight to the origin, through Cloudflare the proxy itself would be probed
+		let result = probe.run(domain, primary, false, timeout).await.map_err(|e| e.to_string());
+
+		let mut health = self.health.lock().await;
+		let health = health.entry((domain.to_string(), family)).or_default();
+
+		match result {
+			Ok(_) => {
+				health.failing_since = None;
+				if health.failed_over {
+					health.failed_over = false;
+					info!("Primary origin of '{}' recovered, publishing {} address '{}' again", domain, family, primary);
+					events.emit(Event::FailoverRecovered { domain: domain.to_string(), family, content: primary.to_string() });
+				}
+				primary.to_string()
+			},
+			Err(e) => {
+				let since = *health.failing_since.get_or_insert_with(Instant::now);
+				warn!("Primary origin of '{}' failed its health probe: {}", domain, e);
+
+				if health.failed_over {
+					return fallback.to_string();
+				}
+				if since.elapsed() < self.after {
+					return primary.to_string();
+				}
+
+				health.failed_over = true;
+				warn!("Primary origin of '{}' down for {} seconds, publishing failover {} address '{}'", domain, since.elapsed().as_secs(), family, fallback);
+				events.emit(Event::FailoverActivated { domain: domain.to_string(), family, content: fallback.to_string(), error: e });
+				fallback.to_string()
+			}
+		}
+	}
+}
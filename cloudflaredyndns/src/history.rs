@@ -0,0 +1,143 @@
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+use tracing::{error, warn};
+
+use crate::events::{Event, EventMessage};
+use crate::ipsource::IpFamily;
+
+/// One line of the history file: an address change and what became of the records, written when
+/// the cycle that noticed it finishes.
+#[derive(Debug, Serialize)]
+struct Change {
+	/// Seconds since the Unix epoch
+	timestamp: u64,
+	family: IpFamily,
+	old: String,
+	new: String,
+	/// Domains whose record was created or updated with the new address
+	updated: Vec<String>,
+	failed: Vec<String>,
+	/// updated, partial (some domains failed), failed or unchanged (no record needed an update)
+	outcome: &'static str,
+}
+
+impl Change {
+	fn outcome(&self) -> &'static str {
+		match (self.updated.is_empty(), self.failed.is_empty()) {
+			(false, true) => "updated",
+			(false, false) => "partial",
+			(true, false) => "failed",
+			(true, true) => "unchanged",
+		}
+	}
+}
+
+/// Appends every address change to `path` as a JSON line, for an authoritative local history of
+/// how often the ISP changes the address.
+pub async fn run(path: PathBuf, mut events: Receiver<EventMessage>) {
+	let mut changes: Vec<Change> = Vec::new();
+	loop {
+		let message = match events.recv().await {
+			Ok(message) => message,
+			Err(RecvError::Lagged(skipped)) => {
+				warn!("Address history missed {} events", skipped);
+				continue;
+			},
+			Err(RecvError::Closed) => return,
+		};
+
+		match message.event {
+			Event::CycleStarted => changes.clear(),
+			Event::IpChanged { family, old, new } => changes.push(Change { timestamp: message.timestamp, family, old, new, updated: Vec::new(), failed: Vec::new(), outcome: "" }),
+			Event::RecordCreated { domain, record_type, .. } | Event::RecordUpdated { domain, record_type, .. } => {
+				for change in changes.iter_mut().filter(|c| c.family.record_type() == record_type) {
+					change.updated.push(domain.clone());
+				}
+			},
+			Event::UpdateFailed { domain, family, .. } => {
+				for change in changes.iter_mut().filter(|c| family.is_none_or(|f| f == c.family)) {
+					change.failed.push(domain.clone());
+				}
+			},
+			Event::CycleFinished { .. } if !changes.is_empty() => {
+				if let Err(e) = append(&path, &mut changes).await {
+					error!("Error writing address history '{}': {}", path.display(), e);
+				}
+				changes.clear();
+			},
+			_ => (),
+		}
+	}
+}
+
+async fn append(path: &Path, changes: &mut [Change]) -> Result<(), std::io::Error> {
+	let mut lines = String::new();
+	for change in changes.iter_mut() {
+		change.outcome = change.outcome();
+		lines.push_str(&serde_json::to_string(change).map_err(std::io::Error::other)?);
+		lines.push('\n');
+	}
+
+	let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+	file.write_all(lines.as_bytes()).await?;
+	file.flush().await
+}
+
+/// Keeps address changes and the outcome of every record update in the `address_changes` and
+/// `outcomes` tables of the state database, for querying the history. SQLite blocks while the
+/// state is written to the same database, so this runs on a blocking thread.
+#[cfg(feature = "sqlite")]
+pub async fn run_database(path: PathBuf, events: Receiver<EventMessage>) {
+	if let Err(e) = tokio::task::spawn_blocking(move || write_database(path, events)).await {
+		error!("Database history stopped: {}", e);
+	}
+}
+
+#[cfg(feature = "sqlite")]
+fn write_database(path: PathBuf, mut events: Receiver<EventMessage>) {
+	use rusqlite::params;
+	use crate::state::sqlite;
+
+	let connection = match sqlite::open(&path) {
+		Ok(connection) => connection,
+		Err(e) => {
+			error!("History disabled, error opening '{}': {}", path.display(), e);
+			return;
+		},
+	};
+	let family_of = |record_type: &str| [IpFamily::V4, IpFamily::V6].into_iter().find(|f| f.record_type() == record_type).map(sqlite::family_column);
+
+	loop {
+		let message = match events.blocking_recv() {
+			Ok(message) => message,
+			Err(RecvError::Lagged(skipped)) => {
+				warn!("Database history missed {} events", skipped);
+				continue;
+			},
+			Err(RecvError::Closed) => return,
+		};
+
+		let timestamp = message.timestamp as i64;
+		let result = match &message.event {
+			Event::IpChanged { family, old, new } => connection.execute(
+				"INSERT INTO address_changes (timestamp, family, old, new) VALUES (?1, ?2, ?3, ?4)",
+				params![timestamp, sqlite::family_column(*family), old, new]),
+			Event::RecordCreated { domain, record_type, content } => connection.execute(
+				"INSERT INTO outcomes (timestamp, domain, family, content, outcome) VALUES (?1, ?2, ?3, ?4, 'created')",
+				params![timestamp, domain, family_of(record_type), content]),
+			Event::RecordUpdated { domain, record_type, content } => connection.execute(
+				"INSERT INTO outcomes (timestamp, domain, family, content, outcome) VALUES (?1, ?2, ?3, ?4, 'updated')",
+				params![timestamp, domain, family_of(record_type), content]),
+			Event::UpdateFailed { domain, family, error } => connection.execute(
+				"INSERT INTO outcomes (timestamp, domain, family, outcome, error) VALUES (?1, ?2, ?3, 'failed', ?4)",
+				params![timestamp, domain, family.map(sqlite::family_column), error]),
+			_ => Ok(0),
+		};
+		if let Err(e) = result {
+			error!("Error writing history to '{}': {}", path.display(), e);
+		}
+	}
+}
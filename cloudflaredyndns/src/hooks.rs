@@ -0,0 +1,112 @@
+use std::{process::Stdio, time::Duration};
+
+use tokio::process::Command;
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+use tracing::{error, info, warn};
+
+use crate::events::{Event, EventMessage};
+use crate::ipsource::IpFamily;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Commands run through the shell when the cycle that noticed something finishes,
+/// `CF_ON_IP_CHANGE` and `CF_ON_UPDATE_FAILURE`. What happened is passed in `DYNDNS_*` variables.
+#[derive(Debug, Clone)]
+pub struct Hooks {
+	pub on_ip_change: Option<String>,
+	pub on_update_failure: Option<String>,
+	/// A hook still running after this long is killed
+	pub timeout: Duration,
+}
+
+impl Hooks {
+	pub fn is_empty(&self) -> bool {
+		self.on_ip_change.is_none() && self.on_update_failure.is_none()
+	}
+}
+
+/// Runs `on_ip_change` once per changed address with `DYNDNS_FAMILY`, `DYNDNS_OLD_IP`,
+/// `DYNDNS_NEW_IP` and the domains updated with it in `DYNDNS_DOMAINS`, and `on_update_failure`
+/// once per cycle with failures with `DYNDNS_FAILED_DOMAINS` and `DYNDNS_ERRORS` (one per line).
+pub async fn run(hooks: Hooks, mut events: Receiver<EventMessage>) {
+	let mut changes: Vec<(IpFamily, String, String)> = Vec::new();
+	let mut updated: Vec<(String, String)> = Vec::new();
+	let mut failed: Vec<(String, String)> = Vec::new();
+	loop {
+		let message = match events.recv().await {
+			Ok(message) => message,
+			Err(RecvError::Lagged(skipped)) => {
+				warn!("Hooks missed {} events", skipped);
+				continue;
+			},
+			Err(RecvError::Closed) => return,
+		};
+
+		match message.event {
+			Event::CycleStarted => {
+				changes.clear();
+				updated.clear();
+				failed.clear();
+			},
+			Event::IpChanged { family, old, new } => changes.push((family, old, new)),
+			Event::RecordCreated { domain, record_type, .. } | Event::RecordUpdated { domain, record_type, .. } => updated.push((domain, record_type)),
+			Event::UpdateFailed { domain, error, .. } => failed.push((domain, error)),
+			Event::CycleFinished { .. } => {
+				if let Some(command) = &hooks.on_ip_change {
+					for (family, old, new) in &changes {
+						let domains = updated.iter()
+							.filter(|(_, record_type)| record_type == family.record_type())
+							.map(|(domain, _)| domain.as_str())
+							.collect::<Vec<_>>();
+						execute(command, hooks.timeout, &[
+							("DYNDNS_EVENT", "ip_changed".to_string()),
+							("DYNDNS_FAMILY", family.to_string()),
+							("DYNDNS_OLD_IP", old.clone()),
+							("DYNDNS_NEW_IP", new.clone()),
+							("DYNDNS_DOMAINS", domains.join(",")),
+						]).await;
+					}
+				}
+				if let Some(command) = hooks.on_update_failure.as_ref().filter(|_| !failed.is_empty()) {
+					let mut domains = failed.iter().map(|(domain, _)| domain.as_str()).collect::<Vec<_>>();
+					domains.sort_unstable();
+					domains.dedup();
+					execute(command, hooks.timeout, &[
+						("DYNDNS_EVENT", "update_failed".to_string()),
+						("DYNDNS_FAILED_DOMAINS", domains.join(",")),
+						("DYNDNS_ERRORS", failed.iter().map(|(domain, error)| format!("{}: {}", domain, error)).collect::<Vec<_>>().join("\n")),
+					]).await;
+				}
+			},
+			_ => (),
+		}
+	}
+}
+
+async fn execute(command: &str, timeout: Duration, env: &[(&str, String)]) {
+	let mut process = shell(command);
+	process.envs(env.iter().cloned())
+		.stdin(Stdio::null())
+		.kill_on_drop(true);
+
+	match tokio::time::timeout(timeout, process.output()).await {
+		Ok(Ok(output)) if output.status.success() => info!("Hook '{}' finished", command),
+		Ok(Ok(output)) => warn!("Hook '{}' exited with {}: {}", command, output.status, String::from_utf8_lossy(&output.stderr).trim()),
+		Ok(Err(e)) => error!("Error running hook '{}': {}", command, e),
+		Err(_) => error!("Hook '{}' didn't finish within {} seconds and was killed", command, timeout.as_secs()),
+	}
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+	let mut process = Command::new("sh");
+	process.arg("-c").arg(command);
+	process
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+	let mut process = Command::new("cmd");
+	process.arg("/C").arg(command);
+	process
+}
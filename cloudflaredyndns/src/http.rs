@@ -0,0 +1,105 @@
+use std::{fmt, fs, net::{IpAddr, Ipv4Addr, Ipv6Addr}, path::Path, time::Duration};
+
+use reqwest::{Certificate, Client, NoProxy, Proxy, Url};
+
+use crate::ipsource::IpFamily;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sent with every request, so that API logs and rate limits can tell the updater apart
+pub const USER_AGENT: &str = concat!("cloudflaredyndns/", env!("CARGO_PKG_VERSION"));
+// Idle connections kept per host, enough for the concurrent domain updates of a cycle
+const POOL_MAX_IDLE_PER_HOST: usize = 16;
+// Shorter than the idle timeout of servers and NAT gateways, so a connection they already dropped
+// isn't picked for a request
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+// Keeps NAT mappings of pooled connections alive and finds dead ones before they are reused
+const TCP_KEEPALIVE: Duration = Duration::from_secs(30);
+const HTTP2_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const HTTP2_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a client reaches the outside, `CF_API_*` for the Cloudflare API and `CF_IP_*` for IP
+/// detection.
+#[derive(Clone)]
+pub struct ClientSettings {
+	/// HTTP(S) or SOCKS5 proxy all requests go through, hosts in `NO_PROXY` are reached directly
+	pub proxy: Option<Proxy>,
+	/// Trusted in addition to the built-in roots, e.g. the CA of a TLS-intercepting proxy
+	pub root_certificates: Vec<Certificate>,
+	/// Whole request, from connecting until the body is read
+	pub timeout: Duration,
+	pub connect_timeout: Duration,
+}
+
+impl Default for ClientSettings {
+	fn default() -> Self {
+		Self { proxy: None, root_certificates: Vec::new(), timeout: DEFAULT_TIMEOUT, connect_timeout: DEFAULT_CONNECT_TIMEOUT }
+	}
+}
+
+// The proxy URL may carry credentials
+impl fmt::Debug for ClientSettings {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("ClientSettings")
+			.field("proxy", &self.proxy.as_ref().map(|_| "[redacted]"))
+			.field("root_certificates", &self.root_certificates.len())
+			.field("timeout", &self.timeout)
+			.field("connect_timeout", &self.connect_timeout)
+			.finish()
+	}
+}
+
+impl ClientSettings {
+	/// Builds a client, bound to the unspecified address of `family` when given so services
+	/// reachable over both families answer with the address being detected. Connections are pooled
+	/// and kept alive for the requests of a cycle, HTTP/2 is used where the server offers it (the Cloudflare API
+	/// does), with all requests of a cycle sharing one connection.
+	pub fn build(&self, family: Option<IpFamily>) -> Result<Client, reqwest::Error> {
+		let mut builder = Client::builder()
+			.user_agent(USER_AGENT)
+			.timeout(self.timeout)
+			.connect_timeout(self.connect_timeout)
+			.pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+			.pool_idle_timeout(POOL_IDLE_TIMEOUT)
+			.tcp_keepalive(TCP_KEEPALIVE)
+			.http2_keep_alive_interval(HTTP2_KEEPALIVE_INTERVAL)
+			.http2_keep_alive_timeout(HTTP2_KEEPALIVE_TIMEOUT)
+			.http2_keep_alive_while_idle(true);
+		for certificate in &self.root_certificates {
+			builder = builder.add_root_certificate(certificate.clone());
+		}
+		if let Some(proxy) = &self.proxy {
+			builder = builder.proxy(proxy.clone().no_proxy(NoProxy::from_env()));
+		}
+		builder = match family {
+			Some(IpFamily::V4) => builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+			Some(IpFamily::V6) => builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+			None => builder,
+		};
+		builder.build()
+	}
+}
+
+/// Parses a proxy URL, `http://`, `https://`, `socks5://` or `socks5h://` (resolving names on the
+/// proxy), with optional `user:password@` credentials.
+pub fn parse_proxy(value: &str) -> Result<Proxy, String> {
+	let url = Url::parse(value.trim()).map_err(|e| e.to_string())?;
+	if !matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") {
+		return Err(format!("unsupported scheme '{}', expected http, https, socks5 or socks5h", url.scheme()));
+	}
+	if url.host_str().is_none_or(|host| host.is_empty()) {
+		return Err("missing host".to_string());
+	}
+	Proxy::all(url).map_err(|e| e.to_string())
+}
+
+/// Reads the PEM certificates in a file, a bundle may hold several.
+pub fn load_certificates(path: &Path) -> Result<Vec<Certificate>, String> {
+	let pem = fs::read(path).map_err(|e| e.to_string())?;
+	let certificates = Certificate::from_pem_bundle(&pem).map_err(|e| e.to_string())?;
+	if certificates.is_empty() {
+		return Err("no PEM certificate found".to_string());
+	}
+	Ok(certificates)
+}
@@ -0,0 +1,338 @@
+use serde::Serialize;
+use tracing::warn;
+
+use crate::cloudflare::{CloudflareClient, Credentials, DnsRecord};
+use crate::config::{Config, DomainConfig};
+use crate::error::Error;
+use crate::events::EventBus;
+use crate::ipsource::{self, IpFamily};
+use crate::provider::DnsProvider;
+use crate::state::State;
+
+/// The `status` command: the detected public addresses and, for every configured domain, the
+/// A/AAAA records Cloudflare holds and whether they have the address.
+pub async fn status(json: bool) -> Result<(), Box<dyn std::error::Error>> {
+	let config = Config::load()?;
+	let addresses = detect_addresses(&config).await;
+	let domains = domain_records(&config, Some(&addresses)).await;
+	if json {
+		println!("{}", serde_json::to_string_pretty(&Status { addresses, domains })?);
+		return Ok(());
+	}
+
+	for (family, address) in [(IpFamily::V4, &addresses.ipv4), (IpFamily::V6, &addresses.ipv6)] {
+		println!("Public {}: {}", family, address.as_deref().unwrap_or("-"));
+	}
+	println!();
+	print_records(&domains, true);
+	Ok(())
+}
+
+/// The `list-records` command: the A/AAAA records Cloudflare holds for every configured domain.
+pub async fn list_records(json: bool) -> Result<(), Box<dyn std::error::Error>> {
+	let config = Config::load()?;
+	let domains = domain_records(&config, None).await;
+	if json {
+		println!("{}", serde_json::to_string_pretty(&domains)?);
+		return Ok(());
+	}
+
+	print_records(&domains, false);
+	Ok(())
+}
+
+/// The `check` command: compares the records of every domain with what the updater would publish
+/// (the detected address, unless `ignore_content`, the proxied flag and the TTL) and prints the
+/// differences. Returns whether anything differs, nothing is changed.
+pub async fn check(ignore_content: bool) -> Result<bool, Box<dyn std::error::Error>> {
+	let config = Config::load()?;
+	let addresses = if ignore_content { Addresses::default() } else { detect_addresses(&config).await };
+	let cloudflare = CloudflareClient::new(config.api_client.clone(), config.auth.clone(), config.fallback_token.as_ref(), &config.api_url, config.retry.clone(), EventBus::default());
+	let state = State::load(config.state_file.clone()).await;
+
+	let mut drifted = 0;
+	for domain in config.canary.iter().chain(&config.domains) {
+		let records = match list_domain(&config, &cloudflare, &state, domain).await {
+			Ok(records) => records,
+			Err(e) => {
+				println!("{}: {}", domain.name, e);
+				drifted += 1;
+				continue;
+			},
+		};
+
+		for (family, enabled) in [(IpFamily::V4, domain.ipv4_enabled), (IpFamily::V6, domain.ipv6_enabled)] {
+			if !enabled {
+				continue;
+			}
+			let mut family_records = records.iter().filter(|(f, _)| *f == family).map(|(_, record)| record).peekable();
+			if family_records.peek().is_none() {
+				println!("{} {}: missing", domain.name, family.record_type());
+				drifted += 1;
+				continue;
+			}
+
+			let content = expected(&config, domain, family, &addresses);
+			for record in family_records {
+				// With CF_PROXIED=auto the record keeps its proxy status, proxied records always have
+				// the automatic TTL
+				let proxied = if domain.keep_proxied { record.proxied } else { domain.proxied };
+				let ttl = if proxied { 1 } else { domain.ttl };
+				let mut differences = Vec::new();
+				if let Some(content) = content.as_ref().filter(|content| **content != record.content) {
+					differences.push(format!("content {} -> {}", record.content, content));
+				}
+				if record.proxied != proxied {
+					differences.push(format!("proxied {} -> {}", record.proxied, proxied));
+				}
+				if !domain.keep_ttl && record.ttl != ttl {
+					differences.push(format!("ttl {} -> {}", record.ttl, ttl));
+				}
+				if !differences.is_empty() {
+					println!("{} {} ({}): {}", domain.name, record.record_type, record.id, differences.join(", "));
+					drifted += 1;
+				}
+			}
+		}
+	}
+
+	if drifted == 0 {
+		println!("No drift, {} domains match the configuration", config.domains.len() + config.canary.iter().count());
+	}
+	Ok(drifted > 0)
+}
+
+/// The `check-config` command: loads the configuration and, unless `offline`, verifies the
+/// credentials of every account and that the zone of each domain can be found, its records listed
+/// and, when Cloudflare reports the permissions, edited. Prints one line per domain and returns
+/// whether everything passed, nothing is changed.
+pub async fn check_config(offline: bool) -> Result<bool, Box<dyn std::error::Error>> {
+	let config = Config::load()?;
+	let domains = config.canary.iter().chain(&config.domains).collect::<Vec<_>>();
+	println!("Configuration loaded, {} domains", domains.len());
+	if offline {
+		for domain in &domains {
+			let types = [(IpFamily::V4, domain.ipv4_enabled), (IpFamily::V6, domain.ipv6_enabled)].iter()
+				.filter(|(_, enabled)| *enabled)
+				.map(|(family, _)| family.record_type())
+				.collect::<Vec<_>>();
+			println!("{}: {}", domain.name, if types.is_empty() { "no record types enabled".to_string() } else { types.join(", ") });
+		}
+		return Ok(true);
+	}
+
+	let cloudflare = CloudflareClient::new(config.api_client.clone(), config.auth.clone(), config.fallback_token.as_ref(), &config.api_url, config.retry.clone(), EventBus::default());
+	let mut verified: Vec<(Option<&Credentials>, bool)> = Vec::new();
+	let mut passed = true;
+	for domain in domains {
+		let cloudflare = cloudflare.account(domain.credentials.as_ref());
+		let credentials_ok = match verified.iter().find(|(credentials, _)| *credentials == domain.credentials.as_ref()) {
+			Some((_, ok)) => *ok,
+			None => {
+				let used_by = if domain.credentials.is_some() { format!("the group of '{}'", domain.name) } else { "the global settings".to_string() };
+				let ok = match cloudflare.verify_credentials().await {
+					Ok(()) => {
+						println!("Credentials of {}: ok", used_by);
+						true
+					},
+					Err(e) => {
+						println!("Credentials of {}: {}", used_by, e);
+						false
+					},
+				};
+				verified.push((domain.credentials.as_ref(), ok));
+				ok
+			},
+		};
+		if !credentials_ok {
+			println!("{}: not checked, the credentials failed", domain.name);
+			passed = false;
+			continue;
+		}
+
+		match check_domain(&config, &cloudflare, domain).await {
+			Ok(details) => println!("{}: ok, {}", domain.name, details),
+			Err(e) => {
+				println!("{}: {}", domain.name, e);
+				passed = false;
+			},
+		}
+	}
+	Ok(passed)
+}
+
+/// Looks up the zone of `domain` and lists its records, returns what was found.
+async fn check_domain(config: &Config, cloudflare: &CloudflareClient, domain: &DomainConfig) -> Result<String, Error> {
+	let base_domain = cloudflare.zone_name(&domain.name)?;
+	let zone = cloudflare.get_zone(&base_domain, config.zone_status.as_deref()).await?;
+	let mut details = vec![format!("zone {}", zone.name)];
+	match zone.can_edit_dns() {
+		Some(true) => {},
+		Some(false) => return Err(Error::Token(format!("the token can't edit the DNS records of zone {}", zone.name))),
+		None => details.push("edit permission not reported".to_string()),
+	}
+
+	for (family, enabled) in [(IpFamily::V4, domain.ipv4_enabled), (IpFamily::V6, domain.ipv6_enabled)] {
+		if !enabled {
+			continue;
+		}
+		let records = cloudflare.list_records(&domain.name, family.record_type(), &zone.id).await?;
+		details.push(match records.len() {
+			0 if config.create_missing => format!("{} record to be created", family.record_type()),
+			0 => format!("no {} record (CF_CREATE_MISSING is false)", family.record_type()),
+			1 => format!("1 {} record", family.record_type()),
+			n => format!("{} {} records", n, family.record_type()),
+		});
+	}
+	Ok(details.join(", "))
+}
+
+/// Detects the address of every enabled family, a failed detection leaves it out.
+async fn detect_addresses(config: &Config) -> Addresses {
+	let mut addresses = Addresses::default();
+	for (family, enabled, source, client, address) in [
+		(IpFamily::V4, config.ipv4_enabled, &config.ipv4_source, &config.ipv4_client, &mut addresses.ipv4),
+		(IpFamily::V6, config.ipv6_enabled, &config.ipv6_source, &config.ipv6_client, &mut addresses.ipv6),
+	] {
+		if !enabled {
+			continue;
+		}
+		match source.detect(client, family).await {
+			Ok(ip) => *address = Some(ip),
+			Err(e) => warn!("Error getting public {}: {}", family, e),
+		}
+	}
+	addresses
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Addresses {
+	ipv4: Option<String>,
+	ipv6: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+	addresses: Addresses,
+	domains: Vec<DomainRecords>,
+}
+
+#[derive(Debug, Serialize)]
+struct DomainRecords {
+	domain: String,
+	ipv4: bool,
+	ipv6: bool,
+	records: Vec<Record>,
+	/// Why the records couldn't be listed
+	#[serde(skip_serializing_if = "Option::is_none")]
+	error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Record {
+	id: String,
+	#[serde(rename = "type")]
+	record_type: String,
+	content: String,
+	proxied: bool,
+	ttl: u64,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	comment: Option<String>,
+	/// Whether the record holds the detected address, only known by `status`
+	#[serde(skip_serializing_if = "Option::is_none")]
+	current: Option<bool>,
+}
+
+/// Lists the records of every domain, compared with `addresses` when given.
+async fn domain_records(config: &Config, addresses: Option<&Addresses>) -> Vec<DomainRecords> {
+	let cloudflare = CloudflareClient::new(config.api_client.clone(), config.auth.clone(), config.fallback_token.as_ref(), &config.api_url, config.retry.clone(), EventBus::default());
+	let state = State::load(config.state_file.clone()).await;
+
+	let mut domains = Vec::new();
+	for domain in config.canary.iter().chain(&config.domains) {
+		let (records, error) = match list_domain(config, &cloudflare, &state, domain).await {
+			Ok(records) => (records, None),
+			Err(e) => (Vec::new(), Some(e.to_string())),
+		};
+		let records = records.into_iter()
+			.map(|(family, record)| Record {
+				current: addresses.map(|addresses| expected(config, domain, family, addresses).is_some_and(|ip| ip == record.content)),
+				id: record.id,
+				record_type: record.record_type,
+				content: record.content,
+				proxied: record.proxied,
+				ttl: record.ttl,
+				comment: record.comment,
+			})
+			.collect();
+		domains.push(DomainRecords { domain: domain.name.clone(), ipv4: domain.ipv4_enabled, ipv6: domain.ipv6_enabled, records, error });
+	}
+	domains
+}
+
+async fn list_domain(config: &Config, cloudflare: &CloudflareClient, state: &State, domain: &DomainConfig) -> Result<Vec<(IpFamily, DnsRecord)>, Error> {
+	let cloudflare = &cloudflare.account(domain.credentials.as_ref());
+	let base_domain = cloudflare.zone_name(&domain.name)?;
+	let zone_id = match state.zone_id(&base_domain, config.zone_cache_ttl).await {
+		Some(zone_id) => zone_id,
+		None => cloudflare.get_zone_id(&base_domain, config.zone_status.as_deref()).await?,
+	};
+
+	let mut records = Vec::new();
+	for family in [IpFamily::V4, IpFamily::V6] {
+		for record in cloudflare.list_records(&domain.name, family.record_type(), &zone_id).await? {
+			records.push((family, record));
+		}
+	}
+	Ok(records)
+}
+
+/// The address the updater would publish for `family` of `domain`, before failover.
+fn expected(config: &Config, domain: &DomainConfig, family: IpFamily, addresses: &Addresses) -> Option<String> {
+	match family {
+		IpFamily::V4 => addresses.ipv4.clone(),
+		IpFamily::V6 => match (&addresses.ipv6, domain.ipv6_suffix) {
+			(Some(ip), Some(suffix)) => ipsource::with_suffix(ip, suffix, config.ipv6_prefix_length).ok(),
+			(ip, _) => ip.clone(),
+		},
+	}
+}
+
+/// One row per record, a domain without records or whose records couldn't be listed gets one
+/// row saying so.
+fn print_records(domains: &[DomainRecords], with_status: bool) {
+	let mut rows: Vec<[String; 5]> = Vec::new();
+	for domain in domains {
+		if let Some(error) = &domain.error {
+			rows.push([domain.domain.clone(), "-".to_string(), "-".to_string(), "-".to_string(), format!("error: {}", error)]);
+			continue;
+		}
+		if domain.records.is_empty() {
+			rows.push([domain.domain.clone(), "-".to_string(), "-".to_string(), "-".to_string(), "no records".to_string()]);
+			continue;
+		}
+		for record in &domain.records {
+			let status = match record.current {
+				Some(true) => "current",
+				Some(false) => "outdated",
+				None => "",
+			};
+			rows.push([
+				domain.domain.clone(),
+				record.record_type.clone(),
+				if record.proxied { "proxied".to_string() } else { format!("ttl {}", record.ttl) },
+				record.content.clone(),
+				status.to_string(),
+			]);
+		}
+	}
+
+	let header = ["DOMAIN", "TYPE", "MODE", "CONTENT", if with_status { "STATUS" } else { "" }].map(|h| h.to_string());
+	let widths: Vec<usize> = (0..4)
+		.map(|i| rows.iter().chain([&header]).map(|row| row[i].len()).max().unwrap_or(0))
+		.collect();
+
+	for row in [&header].into_iter().chain(&rows) {
+		println!("{:<w0$}  {:<w1$}  {:<w2$}  {:<w3$}  {}", row[0], row[1], row[2], row[3], row[4], w0 = widths[0], w1 = widths[1], w2 = widths[2], w3 = widths[3]);
+	}
+}
@@ -0,0 +1,100 @@
+use std::{collections::HashMap, net::Ipv6Addr};
+
+use tracing::error;
+
+use crate::cloudflare::{CloudflareClient, ListItem};
+use crate::error::Error;
+use crate::events::{Event, EventBus};
+use crate::ipsource::IpFamily;
+use crate::summary::{RecordOutcome, RecordReport};
+
+/// Comment of the items this updater adds, the only ones it removes again
+pub const ITEM_COMMENT: &str = "cloudflaredyndns";
+
+/// A Cloudflare IP List (used in WAF and Access rules) kept on the detected addresses, one entry
+/// of `CF_IP_LISTS`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpList {
+	pub account_id: String,
+	pub list_id: String,
+}
+
+/// The list entry for `address`: IPv4 addresses as they are, IPv6 ones as their /64 network since
+/// lists don't take longer IPv6 prefixes.
+fn entry(family: IpFamily, address: &str) -> String {
+	match (family, address.parse::<Ipv6Addr>()) {
+		(IpFamily::V6, Ok(address)) => {
+			let network = Ipv6Addr::from(address.to_bits() & (u128::MAX << 64));
+			format!("{}/64", network)
+		},
+		_ => address.to_string(),
+	}
+}
+
+fn family_of(item: &ListItem) -> Option<IpFamily> {
+	item.ip.as_ref().map(|ip| if ip.contains(':') { IpFamily::V6 } else { IpFamily::V4 })
+}
+
+/// Adds the detected addresses to every list and removes the previous ones this updater added,
+/// entries added by hand are left alone. A family that wasn't detected keeps its entries.
+/// `synced` holds the entry each list was last synced to, lists already on it aren't requested again.
+pub async fn sync_lists(cloudflare: &CloudflareClient, events: &EventBus, lists: &[IpList], synced: &mut HashMap<(String, IpFamily), String>, ipv4: Option<&str>, ipv6: Option<&str>) -> Vec<RecordReport> {
+	let mut reports = Vec::new();
+	for list in lists {
+		let wanted = [(IpFamily::V4, ipv4), (IpFamily::V6, ipv6)].into_iter()
+			.filter_map(|(family, address)| address.map(|address| (family, entry(family, address))))
+			.filter(|(family, entry)| synced.get(&(list.list_id.clone(), *family)) != Some(entry))
+			.collect::<Vec<_>>();
+		if wanted.is_empty() {
+			continue;
+		}
+
+		let result = sync_list(cloudflare, list, &wanted).await;
+		for (family, entry) in wanted {
+			let outcome = match &result {
+				Ok(changed) if changed.contains(&family) => {
+					events.emit(Event::RecordUpdated { domain: list.list_id.clone(), record_type: family.record_type().to_string(), content: entry.clone() });
+					RecordOutcome::Updated
+				},
+				Ok(_) => RecordOutcome::Unchanged,
+				Err(e) => {
+					error!("Error updating IP List '{}' with {}: {}", list.list_id, family, e);
+					events.emit(Event::UpdateFailed { domain: list.list_id.clone(), family: Some(family), error: e.to_string() });
+					RecordOutcome::Failed
+				},
+			};
+			if outcome != RecordOutcome::Failed {
+				synced.insert((list.list_id.clone(), family), entry.clone());
+			}
+			reports.push(RecordReport { domain: list.list_id.clone(), family: Some(family), content: entry, outcome });
+		}
+	}
+	reports
+}
+
+/// Brings one list to the `wanted` entries, returns the families whose entry changed.
+async fn sync_list(cloudflare: &CloudflareClient, list: &IpList, wanted: &[(IpFamily, String)]) -> Result<Vec<IpFamily>, Error> {
+	let items = cloudflare.list_items(&list.account_id, &list.list_id).await?;
+
+	let missing = wanted.iter()
+		.filter(|(_, entry)| !items.iter().any(|item| item.ip.as_ref() == Some(entry)))
+		.collect::<Vec<_>>();
+	let stale = items.into_iter()
+		.filter(|item| item.comment.as_deref() == Some(ITEM_COMMENT))
+		.filter(|item| wanted.iter().any(|(family, entry)| family_of(item) == Some(*family) && item.ip.as_ref() != Some(entry)))
+		.collect::<Vec<_>>();
+
+	if !missing.is_empty() {
+		let entries = missing.iter().map(|(_, entry)| entry.clone()).collect::<Vec<_>>();
+		cloudflare.add_list_items(&list.account_id, &list.list_id, &entries, ITEM_COMMENT).await?;
+	}
+	// Removed after the new entries are in, the rules never see a list without the address
+	if !stale.is_empty() {
+		cloudflare.delete_list_items(&list.account_id, &list.list_id, &stale).await?;
+	}
+
+	let mut changed = missing.iter().map(|(family, _)| *family).collect::<Vec<_>>();
+	changed.extend(stale.iter().filter_map(family_of));
+	changed.dedup();
+	Ok(changed)
+}
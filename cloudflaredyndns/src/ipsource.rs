@@ -0,0 +1,376 @@
+use std::{fmt, net::{IpAddr, Ipv4Addr, Ipv6Addr}, path::PathBuf, sync::Arc};
+#[cfg(feature = "plugins")]
+use std::path::Path;
+
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::cloudflare;
+use crate::error::Error;
+use crate::events::EventBus;
+
+pub use dns::{DnsProvider, lookup, nameservers};
+pub use fritzbox::Login as FritzBoxLogin;
+pub use http::{DEFAULT_RESOLVERS, Resolver, ResolverMode};
+pub use interface::Ipv6Policy;
+#[cfg(feature = "plugins")]
+use crate::plugin::Plugin;
+
+mod dns;
+mod fritzbox;
+mod http;
+mod interface;
+mod lease;
+mod natpmp;
+#[cfg(target_os = "linux")]
+mod netlink;
+mod soap;
+mod stun;
+mod upnp;
+#[cfg(feature = "openwrt")]
+mod ubus;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum IpFamily {
+	#[serde(rename = "ipv4")]
+	V4,
+	#[serde(rename = "ipv6")]
+	V6,
+}
+
+impl IpFamily {
+	/// DNS record type holding addresses of this family
+	pub fn record_type(&self) -> &'static str {
+		match self {
+			IpFamily::V4 => cloudflare::DNS_RECORD_TYPE_A,
+			IpFamily::V6 => cloudflare::DNS_RECORD_TYPE_AAAA,
+		}
+	}
+}
+
+impl fmt::Display for IpFamily {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			IpFamily::V4 => write!(f, "IPv4"),
+			IpFamily::V6 => write!(f, "IPv6"),
+		}
+	}
+}
+
+/// Where the public address for an IP family comes from, configured with `CF_IPV4_SOURCE` and
+/// `CF_IPV6_SOURCE`.
+#[derive(Debug, Clone)]
+pub enum IpSource {
+	/// Ask public HTTP resolvers, the next one when a resolver fails or several at once
+	Http(Vec<Resolver>, ResolverMode),
+	/// Ask a DNS service that answers with the querying address, `dns` or `dns:<provider>`
+	Dns(DnsProvider),
+	/// Send a STUN binding request, `stun` or `stun:<host:port>`
+	Stun(String),
+	/// Read the global IPv6 address of a local network interface, `interface:<name>`
+	Interface(String, Ipv6Policy),
+	/// Read a DHCP/PPP lease or status file, `lease:<path>`
+	Lease(PathBuf),
+	/// Ask a FRITZ!Box over TR-064/UPnP, `fritzbox` or `fritzbox:<url>`
+	FritzBox(String, Option<FritzBoxLogin>),
+	/// Ask the gateway over UPnP IGD, `upnp` (SSDP discovery) or `upnp:<description url>`
+	Upnp(Option<String>),
+	/// Ask the gateway over NAT-PMP, `natpmp` (default route) or `natpmp:<address>`
+	NatPmp(Option<Ipv4Addr>),
+	/// Ask netifd for an OpenWrt interface's address, `ubus:<interface>`
+	#[cfg(feature = "openwrt")]
+	Ubus(String),
+	/// Call a WASM plugin, `plugin:<path>`
+	#[cfg(feature = "plugins")]
+	Plugin(Arc<Plugin>),
+}
+
+impl IpSource {
+	/// `resolvers` are the ones used by `http`, the default source.
+	pub fn parse(value: &str, resolvers: &[Resolver]) -> Result<Self, Box<dyn std::error::Error>> {
+		let value = value.trim();
+		if value.is_empty() || value.eq_ignore_ascii_case("http") {
+			return Ok(IpSource::Http(resolvers.to_vec(), ResolverMode::default()));
+		}
+
+		if value.eq_ignore_ascii_case("dns") {
+			return Ok(IpSource::Dns(DnsProvider::Cloudflare));
+		}
+
+		if let Some(provider) = value.strip_prefix("dns:") {
+			return Ok(IpSource::Dns(DnsProvider::parse(provider)?));
+		}
+
+		if value.eq_ignore_ascii_case("stun") {
+			return Ok(IpSource::Stun(stun::DEFAULT_SERVER.to_string()));
+		}
+
+		if let Some(server) = value.strip_prefix("stun:") {
+			// Default STUN port when only a host is given
+			let server = if server.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) && !server.ends_with(']') {
+				server.to_string()
+			} else {
+				format!("{}:3478", server)
+			};
+			return Ok(IpSource::Stun(server));
+		}
+
+		if let Some(interface) = value.strip_prefix("interface:") {
+			return Ok(IpSource::Interface(interface.to_string(), Ipv6Policy::default()));
+		}
+
+		if let Some(path) = value.strip_prefix("lease:") {
+			return Ok(IpSource::Lease(PathBuf::from(path)));
+		}
+
+		if value.eq_ignore_ascii_case("fritzbox") {
+			return Ok(IpSource::FritzBox(fritzbox::DEFAULT_URL.to_string(), None));
+		}
+
+		if let Some(url) = value.strip_prefix("fritzbox:") {
+			return Ok(IpSource::FritzBox(url.to_string(), None));
+		}
+
+		if value.eq_ignore_ascii_case("upnp") {
+			return Ok(IpSource::Upnp(None));
+		}
+
+		if let Some(url) = value.strip_prefix("upnp:") {
+			return Ok(IpSource::Upnp(Some(url.to_string())));
+		}
+
+		if value.eq_ignore_ascii_case("natpmp") {
+			return Ok(IpSource::NatPmp(None));
+		}
+
+		if let Some(gateway) = value.strip_prefix("natpmp:") {
+			let gateway = gateway.parse().map_err(|_| format!("Invalid NAT-PMP gateway '{}': expected an IPv4 address", gateway))?;
+			return Ok(IpSource::NatPmp(Some(gateway)));
+		}
+
+		if let Some(interface) = value.strip_prefix("ubus:") {
+			#[cfg(feature = "openwrt")]
+			return Ok(IpSource::Ubus(interface.to_string()));
+
+			#[cfg(not(feature = "openwrt"))]
+			return Err(format!("IP source 'ubus:{}' requires building with the 'openwrt' feature", interface).into());
+		}
+
+		if let Some(path) = value.strip_prefix("plugin:") {
+			#[cfg(feature = "plugins")]
+			return Ok(IpSource::Plugin(Arc::new(Plugin::load(Path::new(path))?)));
+
+			#[cfg(not(feature = "plugins"))]
+			return Err(format!("IP source plugin '{}' requires building with the 'plugins' feature", path).into());
+		}
+
+		Err(format!("Unknown IP source '{}'", value).into())
+	}
+
+	pub async fn detect(&self, client: &Client, family: IpFamily) -> Result<String, Error> {
+		let result: Result<String, Box<dyn std::error::Error>> = match self {
+			IpSource::Http(resolvers, mode) => http::detect(client, resolvers, *mode, family).await,
+			IpSource::Dns(provider) => dns::detect(*provider, family).await,
+			IpSource::Stun(server) => stun::detect(server, family).await,
+			IpSource::Interface(interface, policy) => interface::detect(interface, *policy, family).await,
+			IpSource::Lease(path) => lease::detect(path, family).await,
+			IpSource::FritzBox(url, login) => fritzbox::detect(client, url, login.as_ref(), family).await,
+			IpSource::Upnp(description) => upnp::detect(client, description.as_deref(), family).await,
+			IpSource::NatPmp(gateway) => natpmp::detect(*gateway, family).await,
+			#[cfg(feature = "openwrt")]
+			IpSource::Ubus(interface) => ubus::detect(interface, family).await,
+			#[cfg(feature = "plugins")]
+			IpSource::Plugin(plugin) => plugin.detect(family).await,
+		};
+
+		result
+			.and_then(|ip| validate(&ip, family))
+			.map_err(|e| Error::IpLookup { family, message: e.to_string() })
+	}
+}
+
+/// Parses a detected address and rejects anything that can't be published: empty or garbage
+/// responses, the wrong family, and private, loopback or link-local addresses.
+pub(crate) fn validate(text: &str, family: IpFamily) -> Result<String, Box<dyn std::error::Error>> {
+	let text = text.trim();
+	if text.is_empty() {
+		return Err("Empty response".into());
+	}
+
+	let ip: IpAddr = text.parse()
+		.map_err(|_| format!("Invalid address '{}'", text.chars().take(64).collect::<String>()))?;
+
+	let reason = match (ip, family) {
+		(IpAddr::V4(ip), IpFamily::V4) => {
+			if ip.is_private() {
+				Some("private")
+			} else if ip.is_loopback() {
+				Some("loopback")
+			} else if ip.is_link_local() {
+				Some("link-local")
+			} else if ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() {
+				Some("non-unicast")
+			} else {
+				None
+			}
+		},
+		(IpAddr::V6(ip), IpFamily::V6) => {
+			if ip.is_unique_local() {
+				Some("unique local")
+			} else if ip.is_loopback() {
+				Some("loopback")
+			} else if ip.is_unicast_link_local() {
+				Some("link-local")
+			} else if ip.is_unspecified() || ip.is_multicast() {
+				Some("non-unicast")
+			} else {
+				None
+			}
+		},
+		_ => return Err(format!("Got {} while looking for an {} address", ip, family).into()),
+	};
+
+	match reason {
+		Some(reason) => Err(format!("Detected {} address {} can't be published", reason, ip).into()),
+		None => Ok(ip.to_string()),
+	}
+}
+
+/// What to do with a detected address that isn't routable on the Internet (carrier-grade NAT,
+/// documentation, benchmarking or reserved space), `CF_NON_ROUTABLE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NonRoutable {
+	/// Keep the records as they are and warn, nobody could reach the address
+	#[default]
+	Skip,
+	/// Publish it anyway with a warning, e.g. for a network that does route it
+	Publish,
+}
+
+impl NonRoutable {
+	pub fn parse(value: &str) -> Result<Self, String> {
+		match value.trim().to_ascii_lowercase().as_str() {
+			"" | "skip" => Ok(NonRoutable::Skip),
+			"publish" => Ok(NonRoutable::Publish),
+			other => Err(format!("Unknown mode '{}', expected skip or publish", other)),
+		}
+	}
+}
+
+/// What happens once IPv6 detection failed `CF_IPV6_FAILURE_THRESHOLD` cycles in a row.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Ipv6FailurePolicy {
+	/// Keep detecting every cycle, the failures are only logged at debug level
+	#[default]
+	Retry,
+	/// Stop detecting IPv6 for a while, the cycles don't fail because of it
+	Disable,
+	/// Like `Disable`, and delete the AAAA records so clients don't try the dead address
+	Delete,
+}
+
+impl Ipv6FailurePolicy {
+	pub fn parse(value: &str) -> Result<Self, String> {
+		match value.trim().to_ascii_lowercase().as_str() {
+			"" | "retry" => Ok(Ipv6FailurePolicy::Retry),
+			"disable" => Ok(Ipv6FailurePolicy::Disable),
+			"delete" => Ok(Ipv6FailurePolicy::Delete),
+			other => Err(format!("Unknown policy '{}', expected retry, disable or delete", other)),
+		}
+	}
+}
+
+/// The kind of non-routable space `address` is in, `None` for a public address. Private, loopback
+/// and link-local addresses are already rejected by the sources.
+pub fn non_routable(address: &str) -> Option<&'static str> {
+	match address.parse::<IpAddr>().ok()? {
+		IpAddr::V4(ip) => {
+			let [a, b, c, _] = ip.octets();
+			match (a, b, c) {
+				(100, 64..=127, _) => Some("carrier-grade NAT (100.64.0.0/10)"),
+				(0, _, _) => Some("\"this network\" (0.0.0.0/8)"),
+				(192, 0, 0) => Some("IETF protocol assignment (192.0.0.0/24)"),
+				(192, 0, 2) | (198, 51, 100) | (203, 0, 113) => Some("documentation"),
+				(198, 18..=19, _) => Some("benchmarking (198.18.0.0/15)"),
+				(240..=255, _, _) => Some("reserved (240.0.0.0/4)"),
+				_ => None,
+			}
+		},
+		IpAddr::V6(ip) => {
+			let segments = ip.segments();
+			match segments {
+				[0x2001, 0x0db8, ..] => Some("documentation (2001:db8::/32)"),
+				[0x0100, 0, 0, 0, ..] => Some("discard-only (100::/64)"),
+				_ if segments[0] & 0xe000 != 0x2000 => Some("outside global unicast (2000::/3)"),
+				_ => None,
+			}
+		},
+	}
+}
+
+/// The address made of the first `prefix_length` bits of `address` (the delegated prefix) and the
+/// rest of `suffix` (a host's interface identifier).
+pub fn with_suffix(address: &str, suffix: Ipv6Addr, prefix_length: u8) -> Result<String, Error> {
+	let address: Ipv6Addr = address.parse()
+		.map_err(|_| Error::IpLookup { family: IpFamily::V6, message: format!("Invalid IPv6 address '{}'", address) })?;
+	let mask = u128::MAX << (128 - u32::from(prefix_length.min(128)));
+	Ok(Ipv6Addr::from((u128::from(address) & mask) | (u128::from(suffix) & !mask)).to_string())
+}
+
+/// Starts background watchers for sources that can report address changes as they happen, each
+/// change is published on `events` as [`crate::events::Event::UpdateRequested`]. On Linux the
+/// interfaces of `interface:` sources and `watched` (`CF_WATCH_INTERFACES`) are followed through
+/// rtnetlink. Returns the watcher tasks, they run until aborted.
+#[cfg_attr(not(any(feature = "openwrt", target_os = "linux")), allow(unused_variables, unused_mut))]
+pub fn spawn_change_watchers(sources: &[&IpSource], watched: &[String], events: EventBus) -> Vec<JoinHandle<()>> {
+	let mut watchers = Vec::new();
+
+	#[cfg(target_os = "linux")]
+	{
+		let mut interfaces: Vec<String> = sources.iter()
+			.filter_map(|source| match source {
+				IpSource::Interface(interface, _) => Some(interface.clone()),
+				_ => None,
+			})
+			.chain(watched.iter().cloned())
+			.collect();
+		interfaces.sort();
+		interfaces.dedup();
+
+		if !interfaces.is_empty() {
+			let events = events.clone();
+			watchers.push(tokio::spawn(async move {
+				if let Err(e) = netlink::watch(interfaces, events).await.map_err(|e| e.to_string()) {
+					tracing::error!("Stopped watching address changes: {}", e);
+				}
+			}));
+		}
+	}
+
+	#[cfg(not(target_os = "linux"))]
+	if !watched.is_empty() {
+		tracing::warn!("CF_WATCH_INTERFACES is only supported on Linux, relying on CF_UPDATE_INTERVAL");
+	}
+
+	#[cfg(feature = "openwrt")]
+	{
+		let mut interfaces: Vec<String> = sources.iter()
+			.filter_map(|source| match source {
+				IpSource::Ubus(interface) => Some(interface.clone()),
+				_ => None,
+			})
+			.collect();
+		interfaces.dedup();
+
+		if !interfaces.is_empty() {
+			watchers.push(tokio::spawn(async move {
+				if let Err(e) = ubus::watch(interfaces, events).await.map_err(|e| e.to_string()) {
+					tracing::error!("Stopped watching OpenWrt interfaces: {}", e);
+				}
+			}));
+		}
+	}
+
+	watchers
+}
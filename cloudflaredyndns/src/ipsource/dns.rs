@@ -0,0 +1,231 @@
+use std::{
+	hash::{BuildHasher, RandomState},
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	time::Duration,
+};
+
+use tokio::net::UdpSocket;
+
+use super::IpFamily;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolver the nameservers of a zone are looked up with
+const NS_RESOLVER: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+
+const TYPE_A: u16 = 1;
+const TYPE_NS: u16 = 2;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const CLASS_CH: u16 = 3;
+
+/// DNS services that answer with the address the query came from, `dns:<provider>`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DnsProvider {
+	/// `whoami.cloudflare` CH TXT on 1.1.1.1
+	Cloudflare,
+	/// `myip.opendns.com` A/AAAA on resolver1.opendns.com
+	OpenDns,
+	/// `o-o.myaddr.l.google.com` TXT on ns1.google.com
+	Google,
+}
+
+impl DnsProvider {
+	pub fn parse(name: &str) -> Result<Self, String> {
+		match name.trim().to_ascii_lowercase().as_str() {
+			"" | "cloudflare" => Ok(DnsProvider::Cloudflare),
+			"opendns" => Ok(DnsProvider::OpenDns),
+			"google" => Ok(DnsProvider::Google),
+			other => Err(format!("Unknown DNS provider '{}', expected cloudflare, opendns or google", other)),
+		}
+	}
+
+	/// Name, type and class to ask for, and the server to ask, over the family being detected.
+	fn question(&self, family: IpFamily) -> (&'static str, u16, u16, IpAddr) {
+		match (self, family) {
+			(DnsProvider::Cloudflare, IpFamily::V4) => ("whoami.cloudflare", TYPE_TXT, CLASS_CH, IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1))),
+			(DnsProvider::Cloudflare, IpFamily::V6) => ("whoami.cloudflare", TYPE_TXT, CLASS_CH, IpAddr::V6(Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111))),
+			(DnsProvider::OpenDns, IpFamily::V4) => ("myip.opendns.com", TYPE_A, CLASS_IN, IpAddr::V4(Ipv4Addr::new(208, 67, 222, 222))),
+			(DnsProvider::OpenDns, IpFamily::V6) => ("myip.opendns.com", TYPE_AAAA, CLASS_IN, IpAddr::V6(Ipv6Addr::new(0x2620, 0x119, 0x35, 0, 0, 0, 0, 0x35))),
+			(DnsProvider::Google, IpFamily::V4) => ("o-o.myaddr.l.google.com", TYPE_TXT, CLASS_IN, IpAddr::V4(Ipv4Addr::new(216, 239, 32, 10))),
+			(DnsProvider::Google, IpFamily::V6) => ("o-o.myaddr.l.google.com", TYPE_TXT, CLASS_IN, IpAddr::V6(Ipv6Addr::new(0x2001, 0x4860, 0x4802, 0x32, 0, 0, 0, 0xa))),
+		}
+	}
+}
+
+/// Detects the public address with a single UDP query, much cheaper than an HTTPS request.
+pub async fn detect(provider: DnsProvider, family: IpFamily) -> Result<String, Box<dyn std::error::Error>> {
+	let (name, qtype, qclass, server) = provider.question(family);
+	let id = RandomState::new().hash_one(name) as u16;
+
+	let bind: SocketAddr = match family {
+		IpFamily::V4 => (Ipv4Addr::UNSPECIFIED, 0).into(),
+		IpFamily::V6 => (Ipv6Addr::UNSPECIFIED, 0).into(),
+	};
+	let socket = UdpSocket::bind(bind).await?;
+	socket.connect((server, 53)).await?;
+	socket.send(&build_query(id, name, qtype, qclass)).await?;
+
+	let mut buf = [0u8; 1232];
+	let len = tokio::time::timeout(QUERY_TIMEOUT, socket.recv(&mut buf)).await
+		.map_err(|_| format!("No answer from {} within {} seconds", server, QUERY_TIMEOUT.as_secs()))??;
+
+	let answer = parse_answer(&buf[..len], id, qtype)
+		.ok_or_else(|| format!("Invalid or empty answer from {}", server))?;
+
+	match (answer.trim_matches('"').parse::<IpAddr>(), family) {
+		(Ok(ip @ IpAddr::V4(_)), IpFamily::V4) | (Ok(ip @ IpAddr::V6(_)), IpFamily::V6) => Ok(ip.to_string()),
+		_ => Err(format!("Unexpected answer '{}' from {}", answer, server).into()),
+	}
+}
+
+/// Asks `server` for the A or AAAA records of `name`, for checking what resolvers see after an
+/// update. An empty list means the name has no records of `family`.
+pub async fn lookup(server: IpAddr, name: &str, family: IpFamily) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+	let qtype = match family {
+		IpFamily::V4 => TYPE_A,
+		IpFamily::V6 => TYPE_AAAA,
+	};
+	query(server, name, qtype).await
+}
+
+/// The IPv4 addresses of the authoritative nameservers of `zone`, looked up through 1.1.1.1. Unlike
+/// a recursive resolver they don't answer from a cache.
+pub async fn nameservers(zone: &str) -> Result<Vec<IpAddr>, Box<dyn std::error::Error>> {
+	let mut addresses = Vec::new();
+	for name in query(NS_RESOLVER, zone, TYPE_NS).await? {
+		addresses.extend(query(NS_RESOLVER, &name, TYPE_A).await?.iter().filter_map(|ip| ip.parse::<IpAddr>().ok()));
+	}
+	if addresses.is_empty() {
+		return Err(format!("No nameservers found for {}", zone).into());
+	}
+	Ok(addresses)
+}
+
+/// Asks `server` for the records of type `qtype` of `name`, see [`parse_answers`].
+async fn query(server: IpAddr, name: &str, qtype: u16) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+	let id = RandomState::new().hash_one(name) as u16;
+
+	let bind: SocketAddr = match server {
+		IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+		IpAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+	};
+	let socket = UdpSocket::bind(bind).await?;
+	socket.connect((server, 53)).await?;
+	socket.send(&build_query(id, name, qtype, CLASS_IN)).await?;
+
+	let mut buf = [0u8; 1232];
+	let len = tokio::time::timeout(QUERY_TIMEOUT, socket.recv(&mut buf)).await
+		.map_err(|_| format!("No answer from {} within {} seconds", server, QUERY_TIMEOUT.as_secs()))??;
+
+	parse_answers(&buf[..len], id, qtype)
+		.ok_or_else(|| format!("Invalid answer from {} or no such name", server).into())
+}
+
+fn build_query(id: u16, name: &str, qtype: u16, qclass: u16) -> Vec<u8> {
+	let mut packet = Vec::with_capacity(64);
+	packet.extend_from_slice(&id.to_be_bytes());
+	// Recursion desired, one question
+	packet.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+	for label in name.split('.') {
+		packet.push(label.len() as u8);
+		packet.extend_from_slice(label.as_bytes());
+	}
+	packet.push(0);
+	packet.extend_from_slice(&qtype.to_be_bytes());
+	packet.extend_from_slice(&qclass.to_be_bytes());
+	packet
+}
+
+/// Returns the first answer of type `qtype`, see [`parse_answers`].
+fn parse_answer(packet: &[u8], id: u16, qtype: u16) -> Option<String> {
+	parse_answers(packet, id, qtype)?.into_iter().next()
+}
+
+/// Returns the answers of type `qtype` as text: the address for A/AAAA, the host name for NS, the
+/// concatenated strings for TXT.
+fn parse_answers(packet: &[u8], id: u16, qtype: u16) -> Option<Vec<String>> {
+	let u16_at = |pos: usize| packet.get(pos..pos + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+
+	// Matching ID, a response, no error
+	if u16_at(0)? != id || packet.get(2)? & 0x80 == 0 || packet.get(3)? & 0x0f != 0 {
+		return None;
+	}
+	let questions = u16_at(4)?;
+	let answers = u16_at(6)?;
+
+	let mut pos = 12;
+	for _ in 0..questions {
+		pos = skip_name(packet, pos)? + 4;
+	}
+
+	let mut texts = Vec::new();
+	for _ in 0..answers {
+		pos = skip_name(packet, pos)?;
+		let rtype = u16_at(pos)?;
+		let rdlength = u16_at(pos + 8)? as usize;
+		let start = pos + 10;
+		let rdata = packet.get(start..start + rdlength)?;
+		pos = start + rdlength;
+
+		if rtype != qtype {
+			continue;
+		}
+		texts.push(match rtype {
+			TYPE_A => Ipv4Addr::from(<[u8; 4]>::try_from(rdata).ok()?).to_string(),
+			TYPE_AAAA => Ipv6Addr::from(<[u8; 16]>::try_from(rdata).ok()?).to_string(),
+			// The name may point back into the rest of the packet
+			TYPE_NS => read_name(packet, start)?,
+			_ => {
+				let mut text = String::new();
+				let mut i = 0;
+				while i < rdata.len() {
+					let len = rdata[i] as usize;
+					text.push_str(&String::from_utf8_lossy(rdata.get(i + 1..i + 1 + len)?));
+					i += 1 + len;
+				}
+				text
+			},
+		});
+	}
+
+	Some(texts)
+}
+
+/// Reads the (possibly compressed) name starting at `pos`, without the trailing dot.
+fn read_name(packet: &[u8], mut pos: usize) -> Option<String> {
+	let mut labels = Vec::new();
+	// Each pointer has to go backwards, a loop of them would never end
+	let mut limit = pos;
+	loop {
+		let len = *packet.get(pos)? as usize;
+		match len {
+			0 => return Some(labels.join(".")),
+			len if len & 0xc0 == 0xc0 => {
+				let target = ((len & 0x3f) << 8) | *packet.get(pos + 1)? as usize;
+				if target >= limit {
+					return None;
+				}
+				limit = target;
+				pos = target;
+			},
+			len => {
+				labels.push(String::from_utf8_lossy(packet.get(pos + 1..pos + 1 + len)?).into_owned());
+				pos += 1 + len;
+			},
+		}
+	}
+}
+
+/// Returns the position after the (possibly compressed) name starting at `pos`.
+fn skip_name(packet: &[u8], mut pos: usize) -> Option<usize> {
+	loop {
+		let len = *packet.get(pos)?;
+		match len {
+			0 => return Some(pos + 1),
+			len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+			len => pos += 1 + len as usize,
+		}
+	}
+}
@@ -0,0 +1,50 @@
+use reqwest::Client;
+
+use crate::secret::Secret;
+
+use super::{IpFamily, soap};
+
+pub const DEFAULT_URL: &str = "http://fritz.box:49000";
+
+// The IGD flavoured service doesn't need credentials, only "Transmit status information over UPnP"
+// enabled in the FRITZ!Box network settings
+const CONTROL_PATH: &str = "/igdupnp/control/WANIPConn1";
+const SERVICE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+// The TR-064 service is always available but needs a FRITZ!Box user, with "Allow access for
+// applications" enabled in the home network settings
+const TR064_CONTROL_PATH: &str = "/upnp/control/wanipconnection1";
+const TR064_SERVICE: &str = "urn:dslforum-org:service:WANIPConnection:1";
+
+/// A FRITZ!Box user for the TR-064 API, `CF_FRITZBOX_USERNAME` and `CF_FRITZBOX_PASSWORD`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Login {
+	pub username: String,
+	pub password: Secret,
+}
+
+/// Asks a FRITZ!Box for its external address, over TR-064 when there is a `login` and over the
+/// unauthenticated UPnP service otherwise.
+pub async fn detect(client: &Client, url: &str, login: Option<&Login>, family: IpFamily) -> Result<String, Box<dyn std::error::Error>> {
+	let (action, field) = match (family, login) {
+		(IpFamily::V4, _) => ("GetExternalIPAddress", "NewExternalIPAddress"),
+		(IpFamily::V6, None) => ("X_AVM_DE_GetExternalIPv6Address", "NewExternalIPv6Address"),
+		(IpFamily::V6, Some(_)) => ("X_AVM-DE_GetExternalIPv6Address", "NewExternalIPv6Address"),
+	};
+	let (control_path, service) = match login {
+		Some(_) => (TR064_CONTROL_PATH, TR064_SERVICE),
+		None => (CONTROL_PATH, SERVICE),
+	};
+
+	let control_url = format!("{}{}", url.trim_end_matches('/'), control_path);
+	let response = soap::call(client, &control_url, service, action, login.map(|l| (l.username.as_str(), l.password.expose()))).await
+		.map_err(|e| format!("FRITZ!Box at {}: {}", url, e))?;
+
+	let address = soap::value(&response, field).unwrap_or_default();
+	// DS-Lite connections have no public IPv4 of their own, the box reports an empty or zero address
+	if address.is_empty() || address == "0.0.0.0" || address == "::" {
+		return Err(format!("FRITZ!Box at {} reports no public {} address{}", url, family,
+			if family == IpFamily::V4 { " (DS-Lite connections don't have one)" } else { "" }).into());
+	}
+
+	Ok(address)
+}
@@ -0,0 +1,186 @@
+use std::{fmt, net::IpAddr, time::Instant};
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use reqwest::Client;
+use tracing::warn;
+
+use super::IpFamily;
+use crate::metrics;
+
+/// Public "what is my IP" services, asked in the order configured by `CF_IP_RESOLVERS`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolver {
+	IdentMe,
+	Icanhazip,
+	Ipify,
+	/// Cloudflare's `/cdn-cgi/trace`, reached on the 1.1.1.1 addresses so the family is fixed
+	CloudflareTrace,
+	/// A self-hosted echo service answering with the address as plain text
+	/// (`CF_IPV4_RESOLVER_URL`/`CF_IPV6_RESOLVER_URL`)
+	Url(String),
+}
+
+pub const DEFAULT_RESOLVERS: [Resolver; 4] = [Resolver::IdentMe, Resolver::Icanhazip, Resolver::Ipify, Resolver::CloudflareTrace];
+
+impl Resolver {
+	pub fn parse(name: &str) -> Result<Self, String> {
+		match name.trim().to_ascii_lowercase().as_str() {
+			"identme" | "ident.me" => Ok(Resolver::IdentMe),
+			"icanhazip" => Ok(Resolver::Icanhazip),
+			"ipify" => Ok(Resolver::Ipify),
+			"cloudflare" => Ok(Resolver::CloudflareTrace),
+			other => Err(format!("Unknown resolver '{}', expected identme, icanhazip, ipify or cloudflare", other)),
+		}
+	}
+
+	fn url(&self, family: IpFamily) -> &str {
+		match (self, family) {
+			(Resolver::IdentMe, IpFamily::V4) => "https://v4.ident.me",
+			(Resolver::IdentMe, IpFamily::V6) => "https://v6.ident.me",
+			(Resolver::Icanhazip, IpFamily::V4) => "https://ipv4.icanhazip.com",
+			(Resolver::Icanhazip, IpFamily::V6) => "https://ipv6.icanhazip.com",
+			(Resolver::Ipify, IpFamily::V4) => "https://api.ipify.org",
+			(Resolver::Ipify, IpFamily::V6) => "https://api6.ipify.org",
+			(Resolver::CloudflareTrace, IpFamily::V4) => "https://1.1.1.1/cdn-cgi/trace",
+			(Resolver::CloudflareTrace, IpFamily::V6) => "https://[2606:4700:4700::1111]/cdn-cgi/trace",
+			(Resolver::Url(url), _) => url,
+		}
+	}
+
+	/// Picks the address out of the response body, the trace is `key=value` lines.
+	fn extract<'a>(&self, body: &'a str) -> &'a str {
+		match self {
+			Resolver::CloudflareTrace => body.lines()
+				.find_map(|line| line.strip_prefix("ip="))
+				.unwrap_or_default(),
+			_ => body,
+		}
+	}
+
+	async fn query(&self, client: &Client, family: IpFamily) -> Result<String, String> {
+		// The client is bound to the family, services reachable over both answer with the right one
+		let started = Instant::now();
+		let resp = client.get(self.url(family)).send().await;
+		metrics::observe_request("resolver", &self.to_string(), resp.as_ref().ok().map(|r| r.status().as_u16()), started.elapsed());
+		let resp = resp.map_err(|e| e.to_string())?;
+		if !resp.status().is_success() {
+			return Err(format!("answered {}", resp.status()));
+		}
+
+		let body = resp.text().await.map_err(|e| e.to_string())?;
+		let text = self.extract(&body).trim();
+
+		// Anything that isn't an address of the requested family is an error page or garbage
+		match (text.parse::<IpAddr>(), family) {
+			(Ok(ip @ IpAddr::V4(_)), IpFamily::V4) | (Ok(ip @ IpAddr::V6(_)), IpFamily::V6) => Ok(ip.to_string()),
+			_ => Err(format!("unexpected response '{}'", text.chars().take(64).collect::<String>())),
+		}
+	}
+}
+
+impl fmt::Display for Resolver {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let name = match self {
+			Resolver::IdentMe => "ident.me",
+			Resolver::Icanhazip => "icanhazip",
+			Resolver::Ipify => "ipify",
+			Resolver::CloudflareTrace => "cloudflare",
+			Resolver::Url(url) => url,
+		};
+		f.write_str(name)
+	}
+}
+
+/// How the resolvers are asked, `CF_IP_RESOLVER_MODE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResolverMode {
+	/// One after the other, until one returns a valid address
+	#[default]
+	Sequential,
+	/// All at once, the first valid address wins
+	Race,
+	/// All at once, an address is only accepted when this many resolvers (a majority without a
+	/// number) return it, so a single misbehaving resolver can't repoint the records
+	Quorum(Option<usize>),
+}
+
+impl ResolverMode {
+	pub fn parse(value: &str) -> Result<Self, String> {
+		match value.trim().to_ascii_lowercase().as_str() {
+			"" | "sequential" => Ok(ResolverMode::Sequential),
+			"race" => Ok(ResolverMode::Race),
+			"quorum" => Ok(ResolverMode::Quorum(None)),
+			other => match other.strip_prefix("quorum:").map(|n| n.parse::<usize>()) {
+				Some(Ok(required)) if required > 0 => Ok(ResolverMode::Quorum(Some(required))),
+				_ => Err(format!("Unknown mode '{}', expected sequential, race, quorum or quorum:<count>", other)),
+			},
+		}
+	}
+}
+
+/// Asks the resolvers as `mode` says and returns the address they found.
+pub async fn detect(client: &Client, resolvers: &[Resolver], mode: ResolverMode, family: IpFamily) -> Result<String, Box<dyn std::error::Error>> {
+	let mut errors = Vec::new();
+	let mut failed = |resolver: &Resolver, e: String| {
+		warn!("Resolver {} failed to return the public {}: {}", resolver, family, e);
+		errors.push(format!("{}: {}", resolver, e));
+	};
+
+	match mode {
+		ResolverMode::Sequential => {
+			for resolver in resolvers {
+				match resolver.query(client, family).await {
+					Ok(ip) => return Ok(ip),
+					Err(e) => failed(resolver, e),
+				}
+			}
+		},
+		ResolverMode::Race => {
+			let mut pending: FuturesUnordered<_> = resolvers.iter()
+				.map(|resolver| async move { (resolver, resolver.query(client, family).await) })
+				.collect();
+			while let Some((resolver, result)) = pending.next().await {
+				match result {
+					Ok(ip) => return Ok(ip),
+					Err(e) => failed(resolver, e),
+				}
+			}
+		},
+		ResolverMode::Quorum(required) => {
+			let required = required.unwrap_or(resolvers.len() / 2 + 1);
+			let answers = futures::future::join_all(resolvers.iter()
+				.map(|resolver| async move { (resolver, resolver.query(client, family).await) }))
+				.await;
+
+			let mut votes: Vec<(String, Vec<&Resolver>)> = Vec::new();
+			for (resolver, result) in answers {
+				match result {
+					Ok(ip) => match votes.iter_mut().find(|(voted, _)| *voted == ip) {
+						Some((_, voters)) => voters.push(resolver),
+						None => votes.push((ip, vec![resolver])),
+					},
+					Err(e) => failed(resolver, e),
+				}
+			}
+			votes.sort_by_key(|(_, voters)| std::cmp::Reverse(voters.len()));
+
+			match votes.first() {
+				Some((ip, voters)) if voters.len() >= required => {
+					for (other, dissenters) in &votes[1..] {
+						for resolver in dissenters {
+							warn!("Resolver {} returned {} '{}', {} resolvers agree on '{}'", resolver, family, other, voters.len(), ip);
+						}
+					}
+					return Ok(ip.clone());
+				},
+				_ => {
+					let answers = votes.iter().map(|(ip, voters)| format!("'{}' from {}", ip, voters.len())).collect::<Vec<_>>();
+					errors.push(format!("{} of {} resolvers have to agree, got {}", required, resolvers.len(), if answers.is_empty() { "no address".to_string() } else { answers.join(", ") }));
+					return Err(format!("No quorum ({})", errors.join(", ")).into());
+				},
+			}
+		},
+	}
+
+	Err(format!("All resolvers failed ({})", errors.join(", ")).into())
+}
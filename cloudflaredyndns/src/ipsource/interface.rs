@@ -0,0 +1,92 @@
+use std::net::Ipv6Addr;
+
+use super::IpFamily;
+
+// Linux's list of IPv6 addresses: address, ifindex, prefix length, scope, flags, interface name
+const IF_INET6_PATH: &str = "/proc/net/if_inet6";
+
+const SCOPE_GLOBAL: u32 = 0x00;
+const IFA_F_TEMPORARY: u32 = 0x01;
+const IFA_F_DADFAILED: u32 = 0x08;
+const IFA_F_DEPRECATED: u32 = 0x20;
+const IFA_F_TENTATIVE: u32 = 0x40;
+const IFA_F_PERMANENT: u32 = 0x80;
+
+/// `CF_IPV6_POLICY`: which of the global addresses of an interface is published. Comma separated
+/// - `stable`: prefer statically configured addresses over SLAAC/DHCPv6 ones (the default)
+/// - `eui64`: prefer addresses with an interface identifier derived from the MAC address
+/// - `temporary`: accept RFC 4941 temporary (privacy) addresses when there is nothing else, they
+///   are skipped otherwise as they change every few hours
+///
+/// Unique local and link-local addresses are never published.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ipv6Policy {
+	pub prefer_eui64: bool,
+	pub allow_temporary: bool,
+}
+
+impl Ipv6Policy {
+	pub fn parse(value: &str) -> Result<Self, String> {
+		let mut policy = Ipv6Policy::default();
+		for option in value.split(',').map(|o| o.trim().to_ascii_lowercase()).filter(|o| !o.is_empty()) {
+			match option.as_str() {
+				"stable" => {},
+				"eui64" | "eui-64" => policy.prefer_eui64 = true,
+				"temporary" => policy.allow_temporary = true,
+				_ => return Err(format!("Unknown option '{}', expected stable, eui64 or temporary", option)),
+			}
+		}
+		Ok(policy)
+	}
+}
+
+/// Reads the global IPv6 address of a local interface, picked by `policy`. Addresses that aren't
+/// usable yet or anymore are always skipped.
+pub async fn detect(interface: &str, policy: Ipv6Policy, family: IpFamily) -> Result<String, Box<dyn std::error::Error>> {
+	if family == IpFamily::V4 {
+		return Err(format!("Interface source '{}' only provides IPv6 addresses", interface).into());
+	}
+
+	let table = tokio::fs::read_to_string(IF_INET6_PATH).await
+		.map_err(|e| format!("Error reading {}: {}", IF_INET6_PATH, e))?;
+
+	let mut candidates: Vec<(Ipv6Addr, u32)> = table.lines()
+		.filter_map(|line| {
+			let fields: Vec<&str> = line.split_whitespace().collect();
+			let [address, _, _, scope, flags, name] = fields[..] else {
+				return None;
+			};
+			if name != interface {
+				return None;
+			}
+
+			let address = Ipv6Addr::from(u128::from_str_radix(address, 16).ok()?);
+			let scope = u32::from_str_radix(scope, 16).ok()?;
+			let flags = u32::from_str_radix(flags, 16).ok()?;
+			Some((address, scope, flags))
+		})
+		.filter(|(address, scope, flags)| {
+			*scope == SCOPE_GLOBAL
+				&& flags & (IFA_F_DADFAILED | IFA_F_DEPRECATED | IFA_F_TENTATIVE) == 0
+				&& (policy.allow_temporary || flags & IFA_F_TEMPORARY == 0)
+				&& !address.is_unique_local()
+				&& !address.is_unicast_link_local()
+		})
+		.map(|(address, _, flags)| (address, flags))
+		.collect();
+
+	candidates.sort_by_key(|(address, flags)| (
+		flags & IFA_F_TEMPORARY != 0,
+		policy.prefer_eui64 && !is_eui64(address),
+		flags & IFA_F_PERMANENT == 0,
+	));
+	candidates.first()
+		.map(|(address, _)| address.to_string())
+		.ok_or_else(|| format!("Interface '{}' has no usable global IPv6 address", interface).into())
+}
+
+/// The interface identifier is the MAC address with ff:fe in the middle (modified EUI-64).
+fn is_eui64(address: &Ipv6Addr) -> bool {
+	let octets = address.octets();
+	octets[11] == 0xff && octets[12] == 0xfe
+}
@@ -0,0 +1,57 @@
+use std::path::Path;
+
+use super::IpFamily;
+
+// Variables holding the WAN address in the key=value dumps written by dhcpcd (`dhcpcd -U`, .info
+// files), udhcpc and pppd ip-up hooks, in order of preference.
+const IPV4_KEYS: &[&str] = &["new_ip_address", "ip_address", "IPADDR", "IPLOCAL", "ip"];
+const IPV6_KEYS: &[&str] = &["new_dhcp6_ia_na1_ia_addr1", "dhcp6_ia_na1_ia_addr1", "IPV6ADDR", "LLLOCAL", "ipv6"];
+
+/// Reads the current WAN address from a DHCP/PPP lease or status file. Supports ISC dhclient
+/// lease files (the most recent lease wins) and shell-style `key=value` dumps.
+pub async fn detect(path: &Path, family: IpFamily) -> Result<String, Box<dyn std::error::Error>> {
+	let text = tokio::fs::read_to_string(path).await
+		.map_err(|e| format!("Could not read lease file '{}': {}", path.display(), e))?;
+
+	let address = if text.contains("lease {") || text.contains("lease6 {") {
+		parse_dhclient(&text, family)
+	} else {
+		parse_key_value(&text, family)
+	};
+
+	address.ok_or_else(|| format!("No {} address found in lease file '{}'", family, path.display()).into())
+}
+
+fn parse_dhclient(text: &str, family: IpFamily) -> Option<String> {
+	// IPv4: `fixed-address 192.0.2.10;`, IPv6: `iaaddr 2001:db8::10 {`
+	let keyword = match family {
+		IpFamily::V4 => "fixed-address",
+		IpFamily::V6 => "iaaddr",
+	};
+
+	text.lines()
+		.map(|line| line.trim())
+		.filter_map(|line| line.strip_prefix(keyword))
+		.filter_map(|rest| rest.split([';', '{']).next())
+		.map(|address| address.trim().to_string())
+		.filter(|address| !address.is_empty())
+		.last()
+}
+
+fn parse_key_value(text: &str, family: IpFamily) -> Option<String> {
+	let keys = match family {
+		IpFamily::V4 => IPV4_KEYS,
+		IpFamily::V6 => IPV6_KEYS,
+	};
+
+	let values: Vec<(&str, &str)> = text.lines()
+		.map(|line| line.trim().trim_start_matches("export "))
+		.filter_map(|line| line.split_once('='))
+		.map(|(key, value)| (key.trim(), value.trim().trim_matches(|c| c == '\'' || c == '"')))
+		.filter(|(_, value)| !value.is_empty())
+		.collect();
+
+	keys.iter()
+		.find_map(|key| values.iter().rev().find(|(k, _)| k == key))
+		.map(|(_, value)| value.to_string())
+}
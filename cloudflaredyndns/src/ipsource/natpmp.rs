@@ -0,0 +1,88 @@
+use std::{net::{Ipv4Addr, SocketAddr}, time::Duration};
+
+use tokio::{net::UdpSocket, time::timeout};
+
+use super::IpFamily;
+
+const PORT: u16 = 5351;
+// RFC 6886: the first retry after 250ms, doubling each time
+const FIRST_TIMEOUT: Duration = Duration::from_millis(250);
+const ATTEMPTS: u32 = 5;
+// Version 0, opcode 0 asks for the external address, answers have opcode 128
+const REQUEST: [u8; 2] = [0, 0];
+const RESPONSE_OPCODE: u8 = 128;
+
+// Linux's routing table: interface, destination, gateway, ...
+#[cfg(target_os = "linux")]
+const ROUTE_PATH: &str = "/proc/net/route";
+
+/// Asks the gateway for its external address over NAT-PMP (RFC 6886). The gateway of the default
+/// route is used unless one is given (`natpmp:<address>`).
+pub async fn detect(gateway: Option<Ipv4Addr>, family: IpFamily) -> Result<String, Box<dyn std::error::Error>> {
+	if family == IpFamily::V6 {
+		return Err("NAT-PMP only provides IPv4 addresses".into());
+	}
+
+	let gateway = match gateway {
+		Some(gateway) => gateway,
+		None => default_gateway().await?,
+	};
+
+	let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await?;
+	socket.connect((gateway, PORT)).await?;
+
+	let mut buffer = [0u8; 16];
+	let mut wait = FIRST_TIMEOUT;
+	for _ in 0..ATTEMPTS {
+		socket.send(&REQUEST).await?;
+		if let Ok(read) = timeout(wait, socket.recv(&mut buffer)).await {
+			let read = read?;
+			if read < 12 || buffer[1] != RESPONSE_OPCODE {
+				return Err(format!("Unexpected NAT-PMP response from {}", gateway).into());
+			}
+			let result = u16::from_be_bytes([buffer[2], buffer[3]]);
+			if result != 0 {
+				return Err(format!("NAT-PMP gateway {} answered with {}", gateway, result_name(result)).into());
+			}
+			return Ok(Ipv4Addr::new(buffer[8], buffer[9], buffer[10], buffer[11]).to_string());
+		}
+		wait *= 2;
+	}
+
+	Err(format!("NAT-PMP gateway {} didn't answer, is NAT-PMP enabled on the router?", gateway).into())
+}
+
+fn result_name(code: u16) -> String {
+	match code {
+		1 => "unsupported version".to_string(),
+		2 => "not authorized (disabled by the administrator)".to_string(),
+		3 => "network failure (no external address yet)".to_string(),
+		4 => "out of resources".to_string(),
+		5 => "unsupported opcode".to_string(),
+		code => format!("result code {}", code),
+	}
+}
+
+#[cfg(target_os = "linux")]
+async fn default_gateway() -> Result<Ipv4Addr, Box<dyn std::error::Error>> {
+	let table = tokio::fs::read_to_string(ROUTE_PATH).await
+		.map_err(|e| format!("Error reading {}: {}", ROUTE_PATH, e))?;
+
+	table.lines().skip(1)
+		.filter_map(|line| {
+			let fields: Vec<&str> = line.split_whitespace().collect();
+			match fields[..] {
+				[_, "00000000", gateway, ..] => u32::from_str_radix(gateway, 16).ok(),
+				_ => None,
+			}
+		})
+		// Printed in host byte order from memory holding the address in network order
+		.map(|gateway| Ipv4Addr::from(gateway.to_ne_bytes()))
+		.find(|gateway| !gateway.is_unspecified())
+		.ok_or_else(|| "No default IPv4 gateway, set it with natpmp:<address>".into())
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn default_gateway() -> Result<Ipv4Addr, Box<dyn std::error::Error>> {
+	Err("The default gateway can only be looked up on Linux, set it with natpmp:<address>".into())
+}
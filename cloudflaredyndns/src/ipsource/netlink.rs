@@ -0,0 +1,105 @@
+use std::{ffi::CString, io, mem, os::fd::{AsRawFd, FromRawFd, OwnedFd}};
+
+use tokio::io::unix::AsyncFd;
+use tracing::{info, warn};
+
+use crate::events::{Event, EventBus};
+
+// rtnetlink multicast groups and message types of address changes, from linux/rtnetlink.h
+const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+// struct nlmsghdr, followed by struct ifaddrmsg with the interface index at offset 4
+const NLMSG_HEADER_LEN: usize = 16;
+const IFADDRMSG_LEN: usize = 8;
+
+/// Subscribes to rtnetlink address changes and requests an update on the bus whenever an address is
+/// added to or removed from one of `interfaces`. The interval poll still runs in case an event is missed.
+pub async fn watch(interfaces: Vec<String>, events: EventBus) -> Result<(), Box<dyn std::error::Error>> {
+	let socket = AsyncFd::new(open()?)?;
+	let mut buffer = vec![0u8; 16384];
+
+	loop {
+		let mut guard = socket.readable().await?;
+		let read = match guard.try_io(|socket| receive(socket.get_ref(), &mut buffer)) {
+			Ok(Ok(read)) => read,
+			// The kernel dropped events because the buffer was full, something changed
+			Ok(Err(e)) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+				warn!("Missed address change events, triggering an update");
+				events.emit(Event::UpdateRequested { reason: "missed address change events".to_string() });
+				continue;
+			},
+			Ok(Err(e)) => return Err(e.into()),
+			Err(_would_block) => continue,
+		};
+
+		// Looked up every time, PPP and VPN interfaces get a new index when they come back
+		let changed = changed_interfaces(&buffer[..read]);
+		if let Some(interface) = interfaces.iter().find(|i| index_of(i).is_some_and(|index| changed.contains(&index))) {
+			info!("Address of interface '{}' changed, triggering an update", interface);
+			events.emit(Event::UpdateRequested { reason: format!("address of interface '{}' changed", interface) });
+		}
+	}
+}
+
+fn open() -> io::Result<OwnedFd> {
+	// SAFETY: plain socket calls, the descriptor is owned right after it is created
+	unsafe {
+		let fd = libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE);
+		if fd < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		let socket = OwnedFd::from_raw_fd(fd);
+
+		let mut address: libc::sockaddr_nl = mem::zeroed();
+		address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+		address.nl_groups = RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR;
+		let bound = libc::bind(
+			socket.as_raw_fd(),
+			&address as *const libc::sockaddr_nl as *const libc::sockaddr,
+			mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+		);
+		if bound < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		Ok(socket)
+	}
+}
+
+fn receive(socket: &OwnedFd, buffer: &mut [u8]) -> io::Result<usize> {
+	// SAFETY: the kernel writes at most buffer.len() bytes into buffer
+	let read = unsafe { libc::recv(socket.as_raw_fd(), buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), 0) };
+	if read < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(read as usize)
+}
+
+fn index_of(interface: &str) -> Option<u32> {
+	let name = CString::new(interface).ok()?;
+	// SAFETY: name is a valid NUL terminated string
+	let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+	(index != 0).then_some(index)
+}
+
+/// Interface indexes of the address messages in one datagram.
+fn changed_interfaces(buffer: &[u8]) -> Vec<u32> {
+	let read_u32 = |at: usize| u32::from_ne_bytes([buffer[at], buffer[at + 1], buffer[at + 2], buffer[at + 3]]);
+
+	let mut indexes = Vec::new();
+	let mut offset = 0;
+	while offset + NLMSG_HEADER_LEN <= buffer.len() {
+		let length = read_u32(offset) as usize;
+		if length < NLMSG_HEADER_LEN || offset + length > buffer.len() {
+			break;
+		}
+		let kind = u16::from_ne_bytes([buffer[offset + 4], buffer[offset + 5]]);
+		if matches!(kind, RTM_NEWADDR | RTM_DELADDR) && length >= NLMSG_HEADER_LEN + IFADDRMSG_LEN {
+			indexes.push(read_u32(offset + NLMSG_HEADER_LEN + 4));
+		}
+		// Messages are aligned to 4 bytes
+		offset += (length + 3) & !3;
+	}
+	indexes
+}
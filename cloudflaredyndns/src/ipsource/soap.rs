@@ -0,0 +1,120 @@
+use std::hash::{BuildHasher, RandomState};
+
+use reqwest::{Client, StatusCode, header::WWW_AUTHENTICATE};
+
+/// Calls a UPnP/TR-064 SOAP action without arguments and returns the response body. With a
+/// `login` (username, password) the HTTP digest challenge of TR-064 is answered.
+pub(super) async fn call(client: &Client, url: &str, service: &str, action: &str, login: Option<(&str, &str)>) -> Result<String, Box<dyn std::error::Error>> {
+	let body = format!(
+		concat!(
+			r#"<?xml version="1.0" encoding="utf-8"?>"#,
+			r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">"#,
+			r#"<s:Body><u:{action} xmlns:u="{service}"/></s:Body></s:Envelope>"#,
+		),
+		action = action,
+		service = service,
+	);
+	let request = || client
+		.post(url)
+		.header("Content-Type", r#"text/xml; charset="utf-8""#)
+		.header("SOAPAction", format!("\"{}#{}\"", service, action))
+		.body(body.clone());
+
+	let mut resp = request().send().await?;
+	if let (StatusCode::UNAUTHORIZED, Some((username, password))) = (resp.status(), login) {
+		let challenge = resp.headers().get(WWW_AUTHENTICATE)
+			.and_then(|v| v.to_str().ok())
+			.ok_or("Unauthorized without a digest challenge")?
+			.to_string();
+		let path = reqwest::Url::parse(url)?.path().to_string();
+		let authorization = digest_authorization(&challenge, username, password, &path)?;
+		resp = request().header("Authorization", authorization).send().await?;
+		if resp.status() == StatusCode::UNAUTHORIZED {
+			return Err(format!("{} failed: wrong username or password", action).into());
+		}
+	}
+
+	let status = resp.status();
+	let text = resp.text().await?;
+	if !status.is_success() {
+		let fault = value(&text, "errorDescription").unwrap_or_else(|| status.to_string());
+		return Err(format!("{} failed: {}", action, fault).into());
+	}
+
+	Ok(text)
+}
+
+/// Answers a `WWW-Authenticate: Digest ...` challenge for a POST to `path` (RFC 7616, MD5 with
+/// qop=auth, which is what the FRITZ!Box asks for).
+fn digest_authorization(challenge: &str, username: &str, password: &str, path: &str) -> Result<String, Box<dyn std::error::Error>> {
+	let parameters = challenge.trim().strip_prefix("Digest ").ok_or("Unsupported authentication scheme")?;
+	let parameters = digest_parameters(parameters);
+	let parameter = |name: &str| parameters.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str());
+
+	let realm = parameter("realm").unwrap_or_default();
+	let nonce = parameter("nonce").ok_or("Digest challenge without a nonce")?;
+	let cnonce = format!("{:016x}", RandomState::new().hash_one(nonce));
+	let nc = "00000001";
+
+	let ha1 = md5_hex(&format!("{}:{}:{}", username, realm, password));
+	let ha2 = md5_hex(&format!("POST:{}", path));
+	let qop = parameter("qop").filter(|qop| qop.split(',').any(|q| q.trim() == "auth"));
+	let response = match qop {
+		Some(_) => md5_hex(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2)),
+		None => md5_hex(&format!("{}:{}:{}", ha1, nonce, ha2)),
+	};
+
+	let mut header = format!(
+		r#"Digest username="{}", realm="{}", nonce="{}", uri="{}", algorithm=MD5, response="{}""#,
+		username, realm, nonce, path, response,
+	);
+	if qop.is_some() {
+		header.push_str(&format!(r#", qop=auth, nc={}, cnonce="{}""#, nc, cnonce));
+	}
+	if let Some(opaque) = parameter("opaque") {
+		header.push_str(&format!(r#", opaque="{}""#, opaque));
+	}
+	Ok(header)
+}
+
+/// `name=value` and `name="value"` pairs, commas inside quotes don't separate them.
+fn digest_parameters(text: &str) -> Vec<(String, String)> {
+	let mut parameters = Vec::new();
+	let mut rest = text;
+	while let Some((name, after)) = rest.split_once('=') {
+		let after = after.trim_start();
+		let (value, next) = match after.strip_prefix('"') {
+			Some(quoted) => {
+				let end = quoted.find('"').unwrap_or(quoted.len());
+				(&quoted[..end], quoted.get(end + 1..).unwrap_or_default())
+			},
+			None => after.split_once(',').unwrap_or((after, "")),
+		};
+		parameters.push((name.trim().trim_start_matches(',').trim().to_string(), value.trim().to_string()));
+		rest = next.trim_start().trim_start_matches(',');
+	}
+	parameters
+}
+
+fn md5_hex(text: &str) -> String {
+	format!("{:x}", md5::compute(text))
+}
+
+/// Text content of the first `<name>` element, ignoring namespace prefixes.
+pub(super) fn value(xml: &str, name: &str) -> Option<String> {
+	let mut rest = xml;
+	while let Some(start) = rest.find('<') {
+		rest = &rest[start + 1..];
+		let end = rest.find('>')?;
+		let tag = &rest[..end];
+		rest = &rest[end + 1..];
+
+		let local_name = tag.rsplit(':').next().unwrap_or(tag);
+		if local_name == name {
+			let close = rest.find("</")?;
+			return Some(rest[..close].trim().to_string());
+		}
+	}
+
+	None
+}
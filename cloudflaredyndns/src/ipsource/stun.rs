@@ -0,0 +1,106 @@
+use std::{
+	hash::{BuildHasher, RandomState},
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	time::Duration,
+};
+
+use tokio::net::{UdpSocket, lookup_host};
+
+use super::IpFamily;
+
+pub const DEFAULT_SERVER: &str = "stun.l.google.com:19302";
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// Detects the public address with a STUN binding request (RFC 5389) to `server` (`host:port`),
+/// which reports the address and port the request arrived from.
+pub async fn detect(server: &str, family: IpFamily) -> Result<String, Box<dyn std::error::Error>> {
+	let target = lookup_host(server).await?
+		.find(|addr| matches!((addr, family), (SocketAddr::V4(_), IpFamily::V4) | (SocketAddr::V6(_), IpFamily::V6)))
+		.ok_or_else(|| format!("STUN server {} has no {} address", server, family))?;
+
+	let bind: SocketAddr = match family {
+		IpFamily::V4 => (Ipv4Addr::UNSPECIFIED, 0).into(),
+		IpFamily::V6 => (Ipv6Addr::UNSPECIFIED, 0).into(),
+	};
+	let socket = UdpSocket::bind(bind).await?;
+	socket.connect(target).await?;
+
+	let random = RandomState::new();
+	let mut transaction_id = [0u8; 12];
+	transaction_id[..8].copy_from_slice(&random.hash_one(server).to_be_bytes());
+	transaction_id[8..].copy_from_slice(&(random.hash_one(family as u8) as u32).to_be_bytes());
+
+	let mut request = Vec::with_capacity(20);
+	request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+	request.extend_from_slice(&0u16.to_be_bytes());
+	request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+	request.extend_from_slice(&transaction_id);
+	socket.send(&request).await?;
+
+	let mut buf = [0u8; 576];
+	let len = tokio::time::timeout(QUERY_TIMEOUT, socket.recv(&mut buf)).await
+		.map_err(|_| format!("No answer from STUN server {} within {} seconds", server, QUERY_TIMEOUT.as_secs()))??;
+
+	let ip = parse_response(&buf[..len], &transaction_id)
+		.ok_or_else(|| format!("Invalid answer from STUN server {}", server))?;
+
+	match (ip, family) {
+		(IpAddr::V4(_), IpFamily::V4) | (IpAddr::V6(_), IpFamily::V6) => Ok(ip.to_string()),
+		_ => Err(format!("STUN server {} returned {} for an {} request", server, ip, family).into()),
+	}
+}
+
+/// Returns the mapped address of a binding success response, preferring XOR-MAPPED-ADDRESS.
+fn parse_response(packet: &[u8], transaction_id: &[u8; 12]) -> Option<IpAddr> {
+	let u16_at = |pos: usize| packet.get(pos..pos + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+
+	if u16_at(0)? != BINDING_SUCCESS || packet.get(4..8)? != MAGIC_COOKIE.to_be_bytes() || packet.get(8..20)? != transaction_id {
+		return None;
+	}
+
+	let end = (20 + u16_at(2)? as usize).min(packet.len());
+	let mut pos = 20;
+	let mut mapped = None;
+	while pos + 4 <= end {
+		let attr = u16_at(pos)?;
+		let len = u16_at(pos + 2)? as usize;
+		let value = packet.get(pos + 4..pos + 4 + len)?;
+		// Attributes are padded to 4 bytes
+		pos += 4 + len.div_ceil(4) * 4;
+
+		match attr {
+			ATTR_XOR_MAPPED_ADDRESS => return parse_address(value, Some(transaction_id)),
+			ATTR_MAPPED_ADDRESS => mapped = parse_address(value, None),
+			_ => (),
+		}
+	}
+
+	mapped
+}
+
+/// Parses a (XOR-)MAPPED-ADDRESS value: reserved byte, family, port, address.
+fn parse_address(value: &[u8], xor_transaction_id: Option<&[u8; 12]>) -> Option<IpAddr> {
+	let mut key = MAGIC_COOKIE.to_be_bytes().to_vec();
+	if let Some(transaction_id) = xor_transaction_id {
+		key.extend_from_slice(transaction_id);
+	}
+	let unxor = |bytes: &[u8]| -> Vec<u8> {
+		match xor_transaction_id {
+			Some(_) => bytes.iter().zip(&key).map(|(b, k)| b ^ k).collect(),
+			None => bytes.to_vec(),
+		}
+	};
+
+	match *value.get(1)? {
+		0x01 => <[u8; 4]>::try_from(unxor(value.get(4..8)?)).ok().map(|b| IpAddr::V4(Ipv4Addr::from(b))),
+		0x02 => <[u8; 16]>::try_from(unxor(value.get(4..20)?)).ok().map(|b| IpAddr::V6(Ipv6Addr::from(b))),
+		_ => None,
+	}
+}
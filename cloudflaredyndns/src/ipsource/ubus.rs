@@ -0,0 +1,75 @@
+use std::process::Stdio;
+
+use serde_json::Value;
+use tokio::{io::{AsyncBufReadExt, BufReader}, process::Command};
+use tracing::info;
+
+use super::IpFamily;
+use crate::events::{Event, EventBus};
+
+/// Asks netifd for the current address of an OpenWrt logical interface (`ubus call
+/// network.interface.<name> status`).
+pub async fn detect(interface: &str, family: IpFamily) -> Result<String, Box<dyn std::error::Error>> {
+	let output = Command::new("ubus")
+		.args(["call", &format!("network.interface.{}", interface), "status"])
+		.output()
+		.await
+		.map_err(|e| format!("Could not run ubus: {}", e))?;
+
+	if !output.status.success() {
+		return Err(format!("ubus status of interface '{}' failed: {}", interface, String::from_utf8_lossy(&output.stderr).trim()).into());
+	}
+
+	let status: Value = serde_json::from_slice(&output.stdout)?;
+	if !status.get("up").and_then(|v| v.as_bool()).unwrap_or(false) {
+		return Err(format!("Interface '{}' is down", interface).into());
+	}
+
+	let first_address = |key: &str| status.get(key)
+		.and_then(|v| v.as_array())
+		.and_then(|addresses| addresses.first())
+		.and_then(|address| address.get("address"))
+		.and_then(|address| address.as_str())
+		.map(|address| address.to_string());
+
+	let address = match family {
+		IpFamily::V4 => first_address("ipv4-address"),
+		// Without a global address on the WAN itself, use the router's address in the delegated prefix
+		IpFamily::V6 => first_address("ipv6-address").or_else(|| status.get("ipv6-prefix-assignment")
+			.and_then(|v| v.as_array())
+			.and_then(|prefixes| prefixes.first())
+			.and_then(|prefix| prefix.pointer("/local-address/address"))
+			.and_then(|address| address.as_str())
+			.map(|address| address.to_string())),
+	};
+
+	address.ok_or_else(|| format!("Interface '{}' has no {} address", interface, family).into())
+}
+
+/// Follows `ubus listen network.interface` and requests an update on the bus whenever one of
+/// `interfaces` goes up, down or gets new addresses.
+pub async fn watch(interfaces: Vec<String>, events: EventBus) -> Result<(), Box<dyn std::error::Error>> {
+	let mut child = Command::new("ubus")
+		.args(["listen", "network.interface"])
+		.stdout(Stdio::piped())
+		.kill_on_drop(true)
+		.spawn()
+		.map_err(|e| format!("Could not run ubus: {}", e))?;
+
+	let stdout = child.stdout.take().ok_or("ubus listen has no output")?;
+	let mut lines = BufReader::new(stdout).lines();
+
+	while let Some(line) = lines.next_line().await? {
+		// {"network.interface":{"action":"ifupdate","interface":"wan"}}
+		let Ok(event) = serde_json::from_str::<Value>(&line) else {
+			continue;
+		};
+		let interface = event.pointer("/network.interface/interface").and_then(|i| i.as_str());
+		if let Some(interface) = interface.filter(|i| interfaces.iter().any(|w| w == i)) {
+			info!("Interface '{}' changed, triggering an update", interface);
+			events.emit(Event::UpdateRequested { reason: format!("interface '{}' changed", interface) });
+		}
+	}
+
+	Err("ubus listen exited".into())
+}
@@ -0,0 +1,75 @@
+use std::{net::{Ipv4Addr, SocketAddr}, time::Duration};
+
+use reqwest::{Client, Url};
+use tokio::{net::UdpSocket, time::timeout};
+
+use super::{IpFamily, soap};
+
+const SSDP_ADDRESS: (Ipv4Addr, u16) = (Ipv4Addr::new(239, 255, 255, 250), 1900);
+const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+// Either service answers GetExternalIPAddress, depending on how the router is connected
+const WAN_SERVICES: [&str; 2] = ["WANIPConnection", "WANPPPConnection"];
+
+/// Asks the gateway for its WAN address over UPnP IGD. The gateway is discovered with SSDP unless
+/// the URL of its device description (`upnp:<url>`) is given.
+pub async fn detect(client: &Client, description: Option<&str>, family: IpFamily) -> Result<String, Box<dyn std::error::Error>> {
+	if family == IpFamily::V6 {
+		return Err("UPnP IGD only provides IPv4 addresses".into());
+	}
+
+	let location = match description {
+		Some(url) => url.to_string(),
+		None => discover().await?,
+	};
+	let location = Url::parse(&location).map_err(|e| format!("Invalid UPnP description URL '{}': {}", location, e))?;
+
+	let description = client.get(location.clone()).send().await?.error_for_status()?.text().await?;
+	let (service, control_path) = description.split("<service>").skip(1)
+		.find_map(|block| {
+			let service = soap::value(block, "serviceType")?;
+			WAN_SERVICES.iter().any(|s| service.contains(s)).then_some((service, soap::value(block, "controlURL")?))
+		})
+		.ok_or_else(|| format!("Gateway at {} has no WAN connection service", location))?;
+	let base = soap::value(&description, "URLBase").and_then(|base| Url::parse(&base).ok()).unwrap_or(location);
+	let control_url = base.join(&control_path)?;
+
+	let response = soap::call(client, control_url.as_str(), &service, "GetExternalIPAddress", None).await
+		.map_err(|e| format!("UPnP gateway at {}: {}", base, e))?;
+	let address = soap::value(&response, "NewExternalIPAddress").unwrap_or_default();
+	if address.is_empty() || address == "0.0.0.0" {
+		return Err(format!("UPnP gateway at {} reports no external address", base).into());
+	}
+
+	Ok(address)
+}
+
+/// Sends an SSDP search for internet gateways and returns the description URL of the first one.
+async fn discover() -> Result<String, Box<dyn std::error::Error>> {
+	let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await?;
+	let request = format!(
+		"M-SEARCH * HTTP/1.1\r\nHOST: {}:{}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n",
+		SSDP_ADDRESS.0, SSDP_ADDRESS.1, DEVICE_TYPE,
+	);
+	socket.send_to(request.as_bytes(), SSDP_ADDRESS).await?;
+
+	let mut buffer = [0u8; 2048];
+	let search = async {
+		loop {
+			let (read, _) = socket.recv_from(&mut buffer).await?;
+			let response = String::from_utf8_lossy(&buffer[..read]);
+			let location = response.lines()
+				.filter_map(|line| line.split_once(':'))
+				.find(|(name, _)| name.trim().eq_ignore_ascii_case("location"))
+				.map(|(_, value)| value.trim().to_string());
+			if let Some(location) = location {
+				return Ok::<_, std::io::Error>(location);
+			}
+		}
+	};
+
+	match timeout(DISCOVERY_TIMEOUT, search).await {
+		Ok(location) => Ok(location?),
+		Err(_) => Err("No UPnP internet gateway answered, is UPnP enabled on the router?".into()),
+	}
+}
@@ -0,0 +1,39 @@
+use std::io::BufRead;
+
+use crate::config::SECRET_SETTINGS;
+use crate::error::Error;
+
+// Entries are stored under this service, with the setting name as the user
+const SERVICE: &str = "cloudflaredyndns";
+
+/// The secret stored for `setting` in the OS keyring (Secret Service, macOS Keychain or Windows
+/// Credential Manager), `None` when there is no entry.
+pub fn get(setting: &str) -> Result<Option<String>, Error> {
+	let entry = ::keyring::Entry::new(SERVICE, setting)
+		.map_err(|e| Error::Config(format!("Error opening the keyring entry of {}: {}", setting, e)))?;
+	match entry.get_password() {
+		Ok(secret) => Ok(Some(secret)),
+		Err(::keyring::Error::NoEntry) => Ok(None),
+		Err(e) => Err(Error::Config(format!("Error reading {} from the keyring: {}", setting, e))),
+	}
+}
+
+/// The `store-token` command: saves the first line of stdin as `setting` in the OS keyring.
+pub fn store_from_stdin(setting: &str) -> Result<(), Box<dyn std::error::Error>> {
+	let setting = setting.trim().to_ascii_uppercase();
+	if !SECRET_SETTINGS.contains(&setting.as_str()) {
+		return Err(format!("{} can't be stored in the keyring, expected one of {}", setting, SECRET_SETTINGS.join(", ")).into());
+	}
+
+	eprintln!("Enter the value of {} and press enter:", setting);
+	let mut secret = String::new();
+	std::io::stdin().lock().read_line(&mut secret)?;
+	let secret = secret.trim_end_matches(['\r', '\n']);
+	if secret.is_empty() {
+		return Err("Nothing entered, the keyring wasn't changed".into());
+	}
+
+	::keyring::Entry::new(SERVICE, &setting)?.set_password(secret)?;
+	eprintln!("Stored {} in the keyring, set CF_KEYRING=true to use it", setting);
+	Ok(())
+}
@@ -0,0 +1,50 @@
+//! Cloudflare dynamic DNS updater. The `cloudflaredyndns` binary is a thin wrapper around
+//! [`updater::run`], which can be embedded the same way: load a [`Config`], create an
+//! [`EventBus`] to follow the cycles and run the loop until it returns.
+
+#[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+compile_error!("Either the rustls or the native-tls feature is required");
+
+pub mod cleanup;
+pub mod config;
+pub mod dyndns;
+pub mod cloudflare;
+#[cfg(unix)]
+pub mod control;
+pub mod error;
+pub mod events;
+pub mod exit;
+pub mod export;
+pub mod failover;
+pub mod history;
+pub mod hooks;
+pub mod http;
+pub mod inspect;
+pub mod iplist;
+pub mod ipsource;
+#[cfg(feature = "keyring")]
+pub mod keyring;
+pub mod loadbalancer;
+pub mod logging;
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod notify;
+pub mod probe;
+pub mod provider;
+#[cfg(feature = "mock-server")]
+pub mod mock;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod secret;
+pub mod server;
+pub mod state;
+pub mod summary;
+pub mod systemd;
+pub mod updater;
+
+pub use config::Config;
+pub use events::{Event, EventBus, EventMessage};
+pub use summary::CycleSummary;
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+use tracing::{error, info};
+
+use crate::cloudflare::CloudflareClient;
+use crate::events::{Event, EventBus};
+use crate::ipsource::IpFamily;
+use crate::summary::{RecordOutcome, RecordReport};
+
+/// An origin of a Cloudflare Load Balancer pool kept on the detected address, one entry of
+/// `CF_LB_ORIGINS`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolOrigin {
+	pub account_id: String,
+	pub pool_id: String,
+	/// Name of the origin inside the pool
+	pub name: String,
+	pub family: IpFamily,
+}
+
+impl PoolOrigin {
+	/// Parses `<pool id>/<origin name>`, with an optional `:ipv4` or `:ipv6` suffix (IPv4 by default).
+	pub fn parse(value: &str, account_id: &str) -> Result<Self, String> {
+		let (origin, family) = match value.rsplit_once(':') {
+			Some((origin, family)) if family.eq_ignore_ascii_case("ipv4") => (origin, IpFamily::V4),
+			Some((origin, family)) if family.eq_ignore_ascii_case("ipv6") => (origin, IpFamily::V6),
+			_ => (value, IpFamily::V4),
+		};
+		let (pool_id, name) = origin.split_once('/')
+			.map(|(pool_id, name)| (pool_id.trim(), name.trim()))
+			.filter(|(pool_id, name)| !pool_id.is_empty() && !name.is_empty())
+			.ok_or_else(|| format!("expected <pool id>/<origin name>, got '{}'", value))?;
+
+		Ok(Self { account_id: account_id.to_string(), pool_id: pool_id.to_string(), name: name.to_string(), family })
+	}
+
+	/// How the origin shows up in reports and events
+	pub fn label(&self) -> String {
+		format!("{}/{}", self.pool_id, self.name)
+	}
+}
+
+/// Sets the origins to the detected address of their family. `published` holds the address each
+/// origin was last set to, origins already on the address aren't requested again.
+pub async fn update_origins(cloudflare: &CloudflareClient, events: &EventBus, origins: &[PoolOrigin], published: &mut HashMap<(String, IpFamily), String>, ipv4: Option<&str>, ipv6: Option<&str>) -> Vec<RecordReport> {
+	let mut reports = Vec::new();
+	for origin in origins {
+		let Some(address) = (match origin.family {
+			IpFamily::V4 => ipv4,
+			IpFamily::V6 => ipv6,
+		}) else {
+			continue;
+		};
+
+		let label = origin.label();
+		let key = (label.clone(), origin.family);
+		if published.get(&key).is_some_and(|published| published == address) {
+			continue;
+		}
+
+		let outcome = match cloudflare.update_pool_origin(&origin.account_id, &origin.pool_id, &origin.name, address).await {
+			Ok(changed) => {
+				published.insert(key, address.to_string());
+				if changed {
+					events.emit(Event::RecordUpdated { domain: label.clone(), record_type: origin.family.record_type().to_string(), content: address.to_string() });
+					RecordOutcome::Updated
+				} else {
+					info!("Origin '{}' already has {} address '{}'", label, origin.family, address);
+					RecordOutcome::Unchanged
+				}
+			},
+			Err(e) => {
+				error!("Error updating Load Balancer origin '{}': {}", label, e);
+				events.emit(Event::UpdateFailed { domain: label.clone(), family: Some(origin.family), error: e.to_string() });
+				RecordOutcome::Failed
+			},
+		};
+		reports.push(RecordReport { domain: label, family: Some(origin.family), content: address.to_string(), outcome });
+	}
+	reports
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+
+use serde_json::{Map, Value};
+use tracing::{Event, Level, Subscriber, field::{Field, Visit}};
+use tracing_subscriber::{
+	EnvFilter,
+	fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields, format::{JsonFields, Writer}, time::{FormatTime, SystemTime}},
+	registry::LookupSpan,
+};
+
+const DEFAULT_LEVEL: Level = Level::INFO;
+
+/// Sets up the log output. `CF_LOG_LEVEL` is a level for this program's own messages (dependencies
+/// only log warnings) or a full `RUST_LOG` style filter, and takes precedence over `RUST_LOG`.
+/// `CF_LOG_FORMAT=json` writes one JSON object per line instead of text.
+/// Read from the environment only, so problems loading the configuration are logged too.
+pub fn init() {
+	let setting = ["CF_LOG_LEVEL", "RUST_LOG"].iter()
+		.find_map(|name| std::env::var(name).ok().map(|value| (name, value.trim().to_string())).filter(|(_, value)| !value.is_empty()));
+
+	let mut invalid = Vec::new();
+	let filter = match setting {
+		Some((_, value)) if value.parse::<Level>().is_ok() => EnvFilter::new(format!("warn,{}={}", env!("CARGO_CRATE_NAME"), value)),
+		Some((name, value)) => EnvFilter::try_new(&value).unwrap_or_else(|e| {
+			invalid.push(format!("Invalid {} '{}': {}, logging at {}", name, value, e, DEFAULT_LEVEL));
+			default_filter()
+		}),
+		None => default_filter(),
+	};
+
+	let json = match std::env::var("CF_LOG_FORMAT").unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+		"" | "text" => false,
+		"json" => true,
+		other => {
+			invalid.push(format!("Invalid CF_LOG_FORMAT '{}', expected text or json", other));
+			false
+		},
+	};
+
+	let builder = tracing_subscriber::fmt().with_env_filter(filter);
+	if json {
+		builder.fmt_fields(JsonFields::new()).event_format(JsonFormat).init();
+	} else {
+		builder.init();
+	}
+
+	for invalid in invalid {
+		tracing::warn!("{}", invalid);
+	}
+}
+
+fn default_filter() -> EnvFilter {
+	EnvFilter::new(format!("warn,{}={}", env!("CARGO_CRATE_NAME"), DEFAULT_LEVEL))
+}
+
+/// One flat JSON object per event: `timestamp`, `level`, `target`, the fields of the spans it
+/// happened in (`domain`, `record_type`) and its own fields, `message` included.
+struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+	S: Subscriber + for<'a> LookupSpan<'a>,
+	N: for<'a> FormatFields<'a> + 'static,
+{
+	fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+		let mut timestamp = String::new();
+		SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+
+		let mut object = Map::new();
+		object.insert("timestamp".to_string(), timestamp.into());
+		object.insert("level".to_string(), event.metadata().level().to_string().into());
+		object.insert("target".to_string(), event.metadata().target().into());
+
+		// Outermost span first, inner spans and the event override fields of the same name
+		if let Some(scope) = ctx.event_scope() {
+			for span in scope.from_root() {
+				let extensions = span.extensions();
+				let fields = extensions.get::<FormattedFields<N>>()
+					.and_then(|fields| serde_json::from_str::<Map<String, Value>>(&fields.fields).ok());
+				object.extend(fields.into_iter().flatten());
+			}
+		}
+
+		event.record(&mut JsonVisitor(&mut object));
+		writeln!(writer, "{}", Value::Object(object))
+	}
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+	fn record_i64(&mut self, field: &Field, value: i64) {
+		self.0.insert(field.name().to_string(), value.into());
+	}
+
+	fn record_u64(&mut self, field: &Field, value: u64) {
+		self.0.insert(field.name().to_string(), value.into());
+	}
+
+	fn record_bool(&mut self, field: &Field, value: bool) {
+		self.0.insert(field.name().to_string(), value.into());
+	}
+
+	fn record_str(&mut self, field: &Field, value: &str) {
+		self.0.insert(field.name().to_string(), value.into());
+	}
+
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+		self.0.insert(field.name().to_string(), format!("{:?}", value).into());
+	}
+}
@@ -1,223 +1,352 @@
-pub mod config;
-pub mod cloudflare;
+mod cli;
+#[cfg(windows)]
+mod service;
 
-use std::{collections::HashMap, sync::Arc};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use config::Config;
-use reqwest::Client;
-use tokio::sync::Mutex;
+use ::notify::{RecursiveMode, Watcher};
+use clap::Parser;
+use cli::{Cli, Command};
+use cloudflaredyndns::{cleanup, exit, export, history, hooks, inspect, ipsource, logging, metrics, notify, secret, server, updater};
+use cloudflaredyndns::cloudflare::CloudflareClient;
+use cloudflaredyndns::config::Config;
+use cloudflaredyndns::dyndns::DynDns;
+use cloudflaredyndns::failover::Failover;
+use cloudflaredyndns::events::EventBus;
+use cloudflaredyndns::state::State;
+#[cfg(unix)]
+use cloudflaredyndns::control;
+#[cfg(feature = "keyring")]
+use cloudflaredyndns::keyring;
+#[cfg(feature = "mock-server")]
+use cloudflaredyndns::mock;
+#[cfg(feature = "plugins")]
+use cloudflaredyndns::plugin;
+use tokio::sync::watch;
+use tracing::{debug, error, info, warn};
+
+// How long one-shot runs wait for notifiers and the Pushgateway before exiting
+const SINK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+// How long a shutdown waits for the running cycle to finish its updates
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+// Quiet time after a change of CF_DOMAINS_FILE before it is read
+const DOMAINS_FILE_SETTLE: std::time::Duration = std::time::Duration::from_secs(1);
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-	let config: Config = match Config::from_env() {
-		Ok(config) => config,
+async fn main() {
+	let cli = Cli::parse();
+	logging::init();
+	if cli.log_insecure {
+		secret::set_log_insecure(true);
+		warn!("--log-insecure is set, the logs will contain secrets");
+	}
+	let once = cli.once;
+	match cli.command.unwrap_or(Command::Run) {
+		Command::Run => (),
+		Command::Cleanup { dry_run } => exit_with(cleanup::run(dry_run).await),
+		Command::Purge { managed_only, yes, dry_run } => exit_with(cleanup::purge(managed_only, yes, dry_run).await),
+		Command::Status { json } => exit_with(inspect::status(json).await),
+		Command::Check { ignore_content } => match inspect::check(ignore_content).await {
+			Ok(drifted) => std::process::exit(if drifted { exit::PARTIAL } else { exit::SUCCESS }),
+			Err(e) => exit_with(Err(e)),
+		},
+		Command::CheckConfig { offline } => match inspect::check_config(offline).await {
+			Ok(passed) => std::process::exit(if passed { exit::SUCCESS } else { exit::CONFIG }),
+			Err(e) => exit_with(Err(e)),
+		},
+		Command::ListRecords { json } => exit_with(inspect::list_records(json).await),
+		Command::Export { managed_only, csv, output } => exit_with(export::run(managed_only, csv, output).await),
+		#[cfg(feature = "keyring")]
+		Command::StoreToken { setting } => exit_with(keyring::store_from_stdin(&setting)),
+		#[cfg(unix)]
+		Command::Watch { json, socket } => {
+			let socket = socket.unwrap_or_else(control::default_socket_path);
+			exit_with(control::watch(&socket, json).await);
+		},
+		#[cfg(feature = "mock-server")]
+		Command::MockServer { listen, zones, token } => {
+			exit_with(mock::serve(listen, zones, token).await);
+		},
+		#[cfg(windows)]
+		Command::InstallService { config } => exit_with(service::install(config)),
+		#[cfg(windows)]
+		Command::UninstallService => exit_with(service::uninstall()),
+		#[cfg(windows)]
+		Command::RunService { config } => exit_with(service::run(config)),
+	}
+
+	let config: Arc<Config> = match Config::load() {
+		Ok(mut config) => {
+			config.run_once |= once;
+			Arc::new(config)
+		},
 		Err(e) => {
-			println!("Error parsing config: {}", e);
-			std::process::exit(1);
+			error!("Error parsing config: {}", e);
+			std::process::exit(exit::CONFIG);
 		}
 	};
 
-	tokio::select! {
-		_ = main_loop(&config) => (),
-		_ = tokio::signal::ctrl_c() => {
-			println!("Received SIGINT, shutting down");
-			std::process::exit(0);
-		},
-		else => println!("Unexpected exit"),
-	}
+	// Set on SIGINT/SIGTERM, the loop stops after the running cycle
+	let (shutdown_tx, shutdown) = watch::channel(false);
+	tokio::spawn(async move {
+		let signal = shutdown_signal().await;
+		info!("Received {}, finishing running updates before shutting down", signal);
+		shutdown_tx.send_replace(true);
+		let signal = shutdown_signal().await;
+		warn!("Received {} again, exiting immediately", signal);
+		std::process::exit(exit::FAILURE);
+	});
 
-	Ok(())
+	std::process::exit(daemon(config, shutdown).await);
 }
 
-async fn main_loop(config: &Config) {
-	let client = reqwest::Client::new();
-	let domain_zone_id_cache: Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(HashMap::new()));
-	
-	loop {
-		println!("Updating IP addresses...");
-		let (ipv4, ipv6) = match update_ips(&client, config.ipv4_enabled, config.ipv6_enabled).await {
-			Ok((i4, i6)) => {
-				(i4, i6)
+/// Runs the update cycles with everything around them until `shutdown` is set, or after one cycle
+/// with `run_once`, and returns the exit code.
+async fn daemon(config: Arc<Config>, shutdown: watch::Receiver<bool>) -> i32 {
+	// Rejected credentials would otherwise only show up as every domain failing
+	let verifier = CloudflareClient::new(config.api_client.clone(), config.auth.clone(), config.fallback_token.as_ref(), &config.api_url, config.retry.clone(), EventBus::default());
+	let mut accounts = vec![(None, "the global settings".to_string())];
+	for domain in config.domains.iter().chain(&config.canary).filter(|d| d.credentials.is_some()) {
+		if !accounts.iter().any(|(auth, _)| *auth == domain.credentials.as_ref()) {
+			accounts.push((domain.credentials.as_ref(), format!("the group of '{}'", domain.name)));
+		}
+	}
+	for (auth, used_by) in accounts {
+		match verifier.account(auth).verify_credentials().await {
+			Ok(()) => debug!("Cloudflare credentials of {} verified", used_by),
+			Err(e) if e.is_auth() => {
+				error!("Cloudflare credentials of {} rejected: {}", used_by, e);
+				return exit::AUTH;
 			},
-			Err(e) => {
-				println!("Error updating IPs: {}", e);
-				(None, None)
+			// Likely no network yet, the cycles will retry
+			Err(e) => warn!("Could not verify the Cloudflare credentials of {}: {}", used_by, e),
+		}
+	}
+
+	let events = EventBus::default();
+	// Tasks publishing on the bus that only end with the process, stopped before the sinks are drained
+	let mut publishers = Vec::new();
+
+	// Nothing would be around long enough to watch a single cycle
+	#[cfg(unix)]
+	if let Some(path) = config.control_socket.clone().filter(|_| !config.run_once) {
+		let events = events.clone();
+		publishers.push(tokio::spawn(async move {
+			if let Err(e) = control::serve(&path, events).await.map_err(|e| e.to_string()) {
+				warn!("Control socket disabled: {}", e);
 			}
-		};
-		
-		
-		if ipv4.is_some() || ipv6.is_some() {
-			println!("Updating domains...");
-
-			let mut futures_list = Vec::new();
-			for domain in &config.domains {
-				let domain_c = domain.clone();
-				let client_c = client.clone();
-				let domain_zone_id_cache_c = domain_zone_id_cache.clone();
-				let token_c = config.token.clone();
-				let proxied_c = config.proxied.clone();
-				let (ipv4_c, ipv6_c) = (ipv4.clone(), ipv6.clone()); 
-				
-				let future = tokio::spawn(
-					async move {
-						println!("Updating domain '{}'", domain_c);
-						match update_domain(&client_c, &token_c, &domain_c, ipv4_c, ipv6_c, proxied_c, &domain_zone_id_cache_c).await {
-							Ok(()) => {
-								println!("Updated domain '{}'", domain_c);
-							},
-							Err(e) => {
-								println!("Error updating domain '{}': {}", domain_c, e);
-							}
-						}
-					}
-				);
-
-				futures_list.push(future);
+		}));
+	}
+
+	let state = State::load(config.state_file.clone()).await;
+	// One client for the update loop and the dyndns endpoint, so that both respect a rate limit
+	// pause or an open circuit breaker
+	let cloudflare = updater::cloudflare_client(&config, &events);
+
+	// A zone the token can't access would otherwise only show up as its domains failing every cycle
+	if let Err(e) = updater::prefetch_zones(&config, &state).await {
+		return exit::of(&e);
+	}
+
+	// Replaced on SIGHUP, picked up by the next cycle
+	let (configs, config_rx) = watch::channel(config.clone());
+	let configs = Arc::new(configs);
+
+	if let Some(listen) = config.http_listen.filter(|_| !config.run_once) {
+		let health = server::Health::new(std::time::Duration::from_secs(config.update_interval * config.health_intervals as u64));
+		tokio::spawn(health.clone().track(events.subscribe()));
+		let scraped = Arc::new(std::sync::Mutex::new(metrics::Metrics::default()));
+		tokio::spawn(metrics::track(scraped.clone(), events.subscribe()));
+		let control = config.http_control.clone().map(|token| server::Control { token, events: events.clone(), state: state.clone() });
+		let dyndns = config.dyndns.as_ref().map(|_| DynDns {
+			configs: config_rx.clone(),
+			cloudflare: cloudflare.clone(),
+			events: events.clone(),
+			state: state.clone(),
+			failover: Failover::new(config.failover_after),
+		});
+		publishers.push(tokio::spawn(async move {
+			if let Err(e) = server::serve(listen, health, scraped, control, dyndns).await.map_err(|e| e.to_string()) {
+				error!("HTTP server stopped: {}", e);
 			}
-			let _ = futures::future::join_all(futures_list).await;
-			println!("Finished updating domains");
+		}));
+	}
 
-		} else {
-			println!("No IP addresses to update");
-		}
+	// Event consumers, waited for before exiting in one-shot mode so they see the whole cycle
+	let mut sinks = Vec::new();
+
+	if let Some(pushgateway) = config.pushgateway.clone() {
+		sinks.push(tokio::spawn(metrics::run_pushgateway(pushgateway, config.http_client.clone(), events.subscribe())));
+	}
+
+	#[cfg(feature = "sqlite")]
+	if let Some(path) = config.state_file.clone().filter(|path| cloudflaredyndns::state::is_database(path)) {
+		sinks.push(tokio::spawn(history::run_database(path, events.subscribe())));
+	}
+
+	if let Some(path) = config.history_file.clone() {
+		sinks.push(tokio::spawn(history::run(path, events.subscribe())));
+	}
+
+	// Hooks act on the records having changed, which a dry run only pretends
+	if !config.hooks.is_empty() && config.dry_run {
+		info!("Dry run, not running CF_ON_IP_CHANGE and CF_ON_UPDATE_FAILURE");
+	} else if !config.hooks.is_empty() {
+		sinks.push(tokio::spawn(hooks::run(config.hooks.clone(), events.subscribe())));
+	}
+
+	#[cfg(feature = "mqtt")]
+	if let Some(mqtt) = config.mqtt.clone() {
+		sinks.push(tokio::spawn(cloudflaredyndns::mqtt::run(mqtt, events.subscribe())));
+	}
+
+	if !config.notifiers.is_empty() {
+		sinks.push(tokio::spawn(notify::run(config.notifiers.clone(), config.http_client.clone(), events.subscribe())));
+	}
+
+	#[cfg(feature = "plugins")]
+	if !config.notifier_plugins.is_empty() {
+		sinks.push(tokio::spawn(plugin::run_notifiers(config.notifier_plugins.clone(), events.subscribe())));
+	}
 
-		println!("Sleeping for {} seconds", config.update_interval);
-		tokio::time::sleep(tokio::time::Duration::from_secs(config.update_interval)).await;
+	if !config.run_once {
+		// Request updates on the bus when an address changes, the update loop follows it
+		publishers.extend(ipsource::spawn_change_watchers(&[&config.ipv4_source, &config.ipv6_source], &config.watch_interfaces, events.clone()));
 	}
-	
+
+	if let Some(path) = config.domains_file.clone().filter(|_| !config.run_once) {
+		tokio::spawn(reload_on_change(path, configs.clone()));
+	}
+
+	#[cfg(unix)]
+	if !config.run_once {
+		tokio::spawn(reload_on_hangup(configs));
+	}
+	#[cfg(not(unix))]
+	drop(configs);
+
+	let main_loop = updater::run(config_rx, cloudflare, events.clone(), state, shutdown.clone());
+	tokio::pin!(main_loop);
+	let mut shutting_down = shutdown.clone();
+	let summary = tokio::select! {
+		summary = &mut main_loop => summary,
+		_ = shutting_down.wait_for(|shutdown| *shutdown) => match tokio::time::timeout(SHUTDOWN_TIMEOUT, &mut main_loop).await {
+			Ok(summary) => summary,
+			Err(_) => {
+				warn!("Timed out waiting for running updates, exiting");
+				return exit::FAILURE;
+			},
+		},
+	};
+
+	metrics::log_request_summary();
+
+	// Closing the bus ends the sinks once they have handled the remaining events, the control
+	// socket, the HTTP server and the address watchers would otherwise keep it open
+	for publisher in publishers {
+		publisher.abort();
+		let _ = publisher.await;
+	}
+	drop(events);
+	let sinks = futures::future::join_all(sinks);
+	if tokio::time::timeout(SINK_TIMEOUT, sinks).await.is_err() {
+		warn!("Timed out waiting for notifications to be delivered");
+	}
+
+	// A requested shutdown is a clean exit, whatever the last cycle did
+	if *shutdown.borrow() {
+		return exit::SUCCESS;
+	}
+	summary.exit_code()
 }
 
-async fn update_ips(client: &Client, ipv4_enabled: bool, ipv6_enabled: bool) -> Result<(Option<String>, Option<String>), Box<dyn std::error::Error>> {
-	let ipv4_client = client.clone();
-	let ipv6_client = client.clone();
-
-	let ipv4_fut = tokio::spawn(
-		async move {
-			if ipv4_enabled {
-				println!("Getting public IPv4...");
-				match cloudflare::get_public_ipv4(&ipv4_client).await {
-					Ok(ipv4) => {
-						println!("Public IPv4: {}", ipv4);
-						Some(ipv4)
-					},
-					Err(e) => {
-						println!("Error getting public IPv4: {}", e);
-						None
-					}
-				}
-			} else {
-				None
-			}
+/// Exits with the code of what a command ran into, see [`exit`].
+fn exit_with(result: Result<(), Box<dyn std::error::Error>>) -> ! {
+	match result {
+		Ok(()) => std::process::exit(exit::SUCCESS),
+		Err(e) => {
+			error!("{}", e);
+			std::process::exit(exit::of(e.as_ref()));
+		},
+	}
+}
+
+/// Waits for SIGINT or SIGTERM and returns its name.
+async fn shutdown_signal() -> &'static str {
+	#[cfg(unix)]
+	if let Ok(mut terminate) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+		return tokio::select! {
+			_ = tokio::signal::ctrl_c() => "SIGINT",
+			_ = terminate.recv() => "SIGTERM",
+		};
+	}
+
+	let _ = tokio::signal::ctrl_c().await;
+	"SIGINT"
+}
+
+/// Loads the configuration again for the next cycle. A configuration that doesn't load is reported
+/// and the current one kept.
+fn reload(configs: &watch::Sender<Arc<Config>>) {
+	match Config::load() {
+		Ok(mut config) => {
+			config.run_once = false;
+			info!("Configuration reloaded, {} domains, applied from the next cycle", config.domains.len() + config.canary.iter().count());
+			configs.send_replace(Arc::new(config));
+		},
+		Err(e) => error!("Error reloading config, keeping the current one: {}", e),
+	}
+}
+
+/// Reloads the configuration whenever `CF_DOMAINS_FILE` changes.
+async fn reload_on_change(path: PathBuf, configs: Arc<watch::Sender<Arc<Config>>>) {
+	let (tx, mut changes) = tokio::sync::mpsc::unbounded_channel();
+	// The notify crate, not the notifiers
+	let watcher = ::notify::recommended_watcher(move |event: ::notify::Result<::notify::Event>| {
+		let _ = tx.send(event);
+	});
+	// The directory, since generators and editors replace the file rather than write to it
+	let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+	let _watcher = match watcher.and_then(|mut watcher| watcher.watch(dir, RecursiveMode::NonRecursive).map(|_| watcher)) {
+		Ok(watcher) => watcher,
+		Err(e) => {
+			warn!("Not watching CF_DOMAINS_FILE '{}' for changes: {}", path.display(), e);
+			return;
 		}
-	);
-
-	let ipv6_fut = tokio::spawn(
-		async move {
-			if ipv6_enabled {
-				println!("Getting public IPv6...");
-				match cloudflare::get_public_ipv6(&ipv6_client).await {
-					Ok(ipv6) => {
-						println!("Public IPv6: {}", ipv6);	
-						Some(ipv6)
-					},
-					Err(e) => {
-						println!("Error getting public IPv6: {}", e);
-						None
-					}
-				}
-			} else {
-				None
-			}
+	};
+
+	while let Some(event) = changes.recv().await {
+		match event {
+			// Reading the file for the reload mustn't trigger another one
+			Ok(event) if !event.kind.is_access() && event.paths.iter().any(|p| p.file_name() == path.file_name()) => (),
+			Ok(_) => continue,
+			Err(e) => {
+				warn!("Error watching CF_DOMAINS_FILE '{}': {}", path.display(), e);
+				continue;
+			},
 		}
-	);
 
-	match tokio::join!(ipv4_fut, ipv6_fut) {
-		(Ok(ipv4), Ok(ipv6)) => Ok((ipv4, ipv6)),
-		(Err(e), _) | (_, Err(e)) => Err(e.into()),
+		// Writing the file takes several events, reload once when they stop
+		tokio::time::sleep(DOMAINS_FILE_SETTLE).await;
+		while changes.try_recv().is_ok() {}
+		info!("CF_DOMAINS_FILE '{}' changed", path.display());
+		reload(&configs);
 	}
-	
 }
 
-async fn update_domain(client: &Client, token: &str, domain: &str, ipv4: Option<String>, ipv6: Option<String>, proxied: bool, domain_zone_id_cache: &Arc<Mutex<HashMap<String, String>>>) -> Result<(), Box<dyn std::error::Error>> {
-	let base_domain = cloudflare::extract_domain_name(domain)?;
-	let cached_zone_id = domain_zone_id_cache.lock().await.get(&base_domain).cloned();
-
-	let zone_id = match cached_zone_id {
-		Some(zone_id) => zone_id.clone(),
-		None => {
-			let zone_id = cloudflare::get_zone_id(client, token, &base_domain).await?;
-			println!("Cached Zone id for {}: {}", base_domain, zone_id);
-			domain_zone_id_cache.lock().await.insert(base_domain.clone(), zone_id.clone());
-			zone_id
+/// Reloads the configuration on every SIGHUP.
+#[cfg(unix)]
+async fn reload_on_hangup(configs: Arc<watch::Sender<Arc<Config>>>) {
+	let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+		Ok(hangup) => hangup,
+		Err(e) => {
+			warn!("Configuration reload on SIGHUP disabled: {}", e);
+			return;
 		}
 	};
 
-	let mut futures_list = Vec::new();
-	if ipv4.is_some() {
-		let ipv4_c = ipv4.unwrap();
-		let domain_c = domain.to_owned();
-		let zone_id_c = zone_id.clone();
-		let client_c = client.clone();
-		let token_c = token.to_owned();
-		let proxied_c = proxied.clone();
-
-		let future = tokio::spawn(
-			async move {
-				println!("Updating domain '{}' with IPv4 address '{}'", domain_c, ipv4_c);
-				match cloudflare::update_record_ipv4(&client_c, &token_c, &domain_c, &zone_id_c, &ipv4_c, proxied_c).await {
-					Ok(result) => {
-						match result {
-							Some(record) => {
-								println!("Record updated for domain '{}': {:#?}", domain_c, record);
-							},
-							None => {
-								println!("Record not found for domain '{}'", domain_c);
-							}
-							
-						}
-					},
-					Err(e) => {
-						println!("Error updating domain '{}' with IPv4 address '{}': {}", domain_c, ipv4_c, e);
-					}
-				}
-			}
-		);
-		futures_list.push(future);
-	}
-	
-	if ipv6.is_some() {
-		let ipv6_c = ipv6.unwrap();
-		let domain_c = domain.to_owned();
-		let zone_id_c = zone_id.clone();
-		let client_c = client.clone();
-		let token_c = token.to_owned();
-		let proxied_c = proxied.clone();
-
-		let future = tokio::spawn(
-			async move {
-				println!("Updating domain '{}' with IPv6 address '{}'", domain_c, ipv6_c);
-				match cloudflare::update_record_ipv6(&client_c, &token_c, &domain_c, &zone_id_c, &ipv6_c, proxied_c).await {
-					Ok(result) => {
-						match result {
-							Some(record) => {
-								println!("Record updated for domain '{}': {:#?}", domain_c, record);
-							},
-							None => {
-								println!("Record not found for domain '{}'", domain_c);
-							}
-							
-						}
-					},
-					Err(e) => {
-						println!("Error updating domain '{}' with IPv6 address '{}': {}", domain_c, ipv6_c, e);
-					}
-				}
-			}
-		);
-		futures_list.push(future);
+	while hangup.recv().await.is_some() {
+		reload(&configs);
 	}
-	
-
-	futures::future::join_all(futures_list).await;
-	Ok(())
-}
\ No newline at end of file
+}
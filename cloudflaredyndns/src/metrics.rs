@@ -0,0 +1,220 @@
+use std::{collections::BTreeMap, fmt::Write, sync::{Arc, Mutex}, time::Duration};
+
+use reqwest::Client;
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+use tracing::{error, info, warn};
+
+use crate::events::{Event, EventMessage};
+use crate::ipsource::IpFamily;
+
+pub const DEFAULT_PUSHGATEWAY_JOB: &str = "cloudflaredyndns";
+
+// Upper bounds in seconds of the request latency histogram buckets
+const LATENCY_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+// Requests to Cloudflare and the resolvers by target and operation, recorded where they are sent
+// deep in the cycle instead of going through the event bus
+static REQUESTS: Mutex<BTreeMap<(&'static str, String), RequestStats>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Default)]
+struct RequestStats {
+	/// Requests per latency bucket, not cumulative
+	buckets: [u64; LATENCY_BUCKETS.len()],
+	count: u64,
+	seconds: f64,
+	max_seconds: f64,
+	/// Responses per HTTP status, `error` when there was no response
+	statuses: BTreeMap<String, u64>,
+	retries: u64,
+}
+
+/// Records a request to `target` (`cloudflare` or `resolver`), `status` is `None` when it failed
+/// without a response.
+pub fn observe_request(target: &'static str, operation: &str, status: Option<u16>, duration: Duration) {
+	let seconds = duration.as_secs_f64();
+	let mut requests = REQUESTS.lock().unwrap();
+	let stats = requests.entry((target, operation.to_string())).or_default();
+	let bucket = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(LATENCY_BUCKETS.len() - 1);
+	stats.buckets[bucket] += 1;
+	stats.count += 1;
+	stats.seconds += seconds;
+	stats.max_seconds = stats.max_seconds.max(seconds);
+	*stats.statuses.entry(status.map(|s| s.to_string()).unwrap_or_else(|| "error".to_string())).or_default() += 1;
+}
+
+/// Records that a failed request to `target` is repeated.
+pub fn observe_retry(target: &'static str, operation: &str) {
+	REQUESTS.lock().unwrap().entry((target, operation.to_string())).or_default().retries += 1;
+}
+
+/// Logs the count, latency, failures and retries of every kind of request, at shutdown.
+pub fn log_request_summary() {
+	let requests = REQUESTS.lock().unwrap();
+	for ((target, operation), stats) in requests.iter().filter(|(_, stats)| stats.count > 0) {
+		let failed: u64 = stats.statuses.iter()
+			.filter(|(status, _)| !status.starts_with('2'))
+			.map(|(_, count)| count)
+			.sum();
+		info!("{} {}: {} requests, {:.0}ms average, {:.0}ms max, {} failed, {} retried",
+			target, operation, stats.count, stats.seconds / stats.count as f64 * 1000.0, stats.max_seconds * 1000.0, failed, stats.retries);
+	}
+}
+
+/// Counters built from the events of the update cycles, rendered in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+	cycles: u64,
+	last_cycle_duration_ms: u64,
+	last_api_calls: u64,
+	last_cycle_timestamp: u64,
+	records_created: u64,
+	records_updated: u64,
+	update_failures: u64,
+	probe_failures: u64,
+	propagation_failures: u64,
+	ip_detection_failures: BTreeMap<&'static str, u64>,
+}
+
+impl Metrics {
+	pub fn observe(&mut self, message: &EventMessage) {
+		match &message.event {
+			Event::CycleFinished { summary } => {
+				self.cycles += 1;
+				self.last_cycle_timestamp = message.timestamp;
+				self.last_cycle_duration_ms = summary.duration_ms;
+				self.last_api_calls = summary.api_calls;
+			},
+			Event::RecordCreated { .. } => self.records_created += 1,
+			Event::RecordUpdated { .. } => self.records_updated += 1,
+			Event::UpdateFailed { .. } => self.update_failures += 1,
+			Event::ProbeFailed { .. } => self.probe_failures += 1,
+			Event::PropagationFailed { .. } => self.propagation_failures += 1,
+			Event::IpDetectionFailed { family, .. } => {
+				let family = match family {
+					IpFamily::V4 => "ipv4",
+					IpFamily::V6 => "ipv6",
+				};
+				*self.ip_detection_failures.entry(family).or_default() += 1;
+			},
+			_ => (),
+		}
+	}
+
+	pub fn render(&self) -> String {
+		let mut out = String::new();
+		let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+			let _ = writeln!(out, "# HELP cloudflaredyndns_{} {}", name, help);
+			let _ = writeln!(out, "# TYPE cloudflaredyndns_{} {}", name, kind);
+			for (labels, value) in samples {
+				let _ = writeln!(out, "cloudflaredyndns_{}{} {}", name, labels, value);
+			}
+		};
+
+		metric("cycles_total", "counter", "Completed update cycles", &[(String::new(), self.cycles.to_string())]);
+		metric("last_cycle_duration_seconds", "gauge", "Duration of the last update cycle", &[(String::new(), (self.last_cycle_duration_ms as f64 / 1000.0).to_string())]);
+		metric("last_cycle_api_calls", "gauge", "Cloudflare API calls made in the last update cycle", &[(String::new(), self.last_api_calls.to_string())]);
+		metric("last_cycle_timestamp_seconds", "gauge", "Unix time the last update cycle finished", &[(String::new(), self.last_cycle_timestamp.to_string())]);
+		metric("records_created_total", "counter", "DNS records created", &[(String::new(), self.records_created.to_string())]);
+		metric("records_updated_total", "counter", "DNS records updated with a new address", &[(String::new(), self.records_updated.to_string())]);
+		metric("update_failures_total", "counter", "Failed domain updates", &[(String::new(), self.update_failures.to_string())]);
+		metric("probe_failures_total", "counter", "Failed reachability probes after record changes", &[(String::new(), self.probe_failures.to_string())]);
+		metric("propagation_failures_total", "counter", "Record changes the verification resolver didn't return in time", &[(String::new(), self.propagation_failures.to_string())]);
+		let detection_failures: Vec<_> = ["ipv4", "ipv6"].iter()
+			.map(|family| (format!("{{family=\"{}\"}}", family), self.ip_detection_failures.get(family).copied().unwrap_or(0).to_string()))
+			.collect();
+		metric("ip_detection_failures_total", "counter", "Failed public address lookups", &detection_failures);
+
+		let requests = REQUESTS.lock().unwrap();
+		let mut latency = Vec::new();
+		let mut responses = Vec::new();
+		let mut retries = Vec::new();
+		for ((target, operation), stats) in requests.iter() {
+			let labels = format!("target=\"{}\",operation=\"{}\"", target, operation.replace('\\', "\\\\").replace('"', "\\\""));
+			let mut cumulative = 0;
+			for (bound, count) in LATENCY_BUCKETS.iter().zip(stats.buckets) {
+				cumulative += count;
+				latency.push((format!("_bucket{{{},le=\"{}\"}}", labels, bound), cumulative.to_string()));
+			}
+			latency.push((format!("_bucket{{{},le=\"+Inf\"}}", labels), stats.count.to_string()));
+			latency.push((format!("_sum{{{}}}", labels), stats.seconds.to_string()));
+			latency.push((format!("_count{{{}}}", labels), stats.count.to_string()));
+			for (status, count) in &stats.statuses {
+				responses.push((format!("{{{},status=\"{}\"}}", labels, status), count.to_string()));
+			}
+			retries.push((format!("{{{}}}", labels), stats.retries.to_string()));
+		}
+		metric("request_duration_seconds", "histogram", "Latency of Cloudflare API and IP resolver requests", &latency);
+		metric("responses_total", "counter", "Responses of Cloudflare API and IP resolver requests by HTTP status, error without a response", &responses);
+		metric("request_retries_total", "counter", "Cloudflare API requests repeated after a transient failure", &retries);
+
+		out
+	}
+}
+
+/// Keeps `metrics` up to date with the events until the bus closes, for the `/metrics` endpoint.
+pub async fn track(metrics: Arc<Mutex<Metrics>>, mut events: Receiver<EventMessage>) {
+	loop {
+		match events.recv().await {
+			Ok(message) => metrics.lock().unwrap().observe(&message),
+			Err(RecvError::Lagged(skipped)) => warn!("Metrics missed {} events", skipped),
+			Err(RecvError::Closed) => return,
+		}
+	}
+}
+
+/// Prometheus Pushgateway the metrics are pushed to after every cycle (`CF_PUSHGATEWAY_URL`),
+/// for cron style runs that can't be scraped.
+#[derive(Debug, Clone)]
+pub struct Pushgateway {
+	pub url: String,
+	pub job: String,
+	pub instance: Option<String>,
+}
+
+impl Pushgateway {
+	fn grouping_url(&self) -> String {
+		let mut url = format!("{}/metrics/job/{}", self.url.trim_end_matches('/'), self.job);
+		if let Some(instance) = &self.instance {
+			url.push_str("/instance/");
+			url.push_str(instance);
+		}
+		url
+	}
+
+	/// Replaces the metrics of this job/instance group with `metrics`.
+	pub async fn push(&self, client: &Client, metrics: &Metrics) -> Result<(), Box<dyn std::error::Error>> {
+		let resp = client
+			.put(self.grouping_url())
+			.header("Content-Type", "text/plain; version=0.0.4")
+			.body(metrics.render())
+			.send()
+			.await?;
+
+		if !resp.status().is_success() {
+			return Err(format!("Pushgateway answered {}: {}", resp.status(), resp.text().await.unwrap_or_default()).into());
+		}
+
+		Ok(())
+	}
+}
+
+pub async fn run_pushgateway(pushgateway: Pushgateway, client: Client, mut events: Receiver<EventMessage>) {
+	let mut metrics = Metrics::default();
+	loop {
+		let message = match events.recv().await {
+			Ok(message) => message,
+			Err(RecvError::Lagged(skipped)) => {
+				warn!("Pushgateway metrics missed {} events", skipped);
+				continue;
+			},
+			Err(RecvError::Closed) => return,
+		};
+
+		metrics.observe(&message);
+		if let Event::CycleFinished { .. } = message.event {
+			if let Err(e) = pushgateway.push(&client, &metrics).await.map_err(|e| e.to_string()) {
+				error!("Error pushing metrics: {}", e);
+			}
+		}
+	}
+}
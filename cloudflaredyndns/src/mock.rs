@@ -0,0 +1,267 @@
+use std::{collections::HashMap, net::SocketAddr, sync::{Arc, Mutex}};
+
+use axum::{
+	Json, Router,
+	extract::{Path, Query, State},
+	http::{HeaderMap, StatusCode, header::AUTHORIZATION},
+	routing::{get, patch, post},
+};
+use serde_json::{Value, json};
+use tracing::info;
+
+const API_PREFIX: &str = "/client/v4";
+
+struct MockZone {
+	id: String,
+	name: String,
+}
+
+struct MockState {
+	token: Option<String>,
+	zones: Vec<MockZone>,
+	records: HashMap<String, Vec<Value>>, // zone_id -> records
+	next_id: u64,
+}
+
+impl MockState {
+	fn new_id(&mut self) -> String {
+		self.next_id += 1;
+		format!("{:032x}", self.next_id)
+	}
+
+	fn zone(&self, zone_id: &str) -> Option<&MockZone> {
+		self.zones.iter().find(|z| z.id == zone_id)
+	}
+}
+
+type SharedState = Arc<Mutex<MockState>>;
+type ApiResponse = (StatusCode, Json<Value>);
+
+fn success(result: Value) -> ApiResponse {
+	(StatusCode::OK, Json(json!({ "success": true, "errors": [], "messages": [], "result": result })))
+}
+
+fn failure(status: StatusCode, code: u64, message: &str) -> ApiResponse {
+	(status, Json(json!({
+		"success": false,
+		"errors": [{ "code": code, "message": message }],
+		"messages": [],
+		"result": null,
+	})))
+}
+
+fn authorize(state: &MockState, headers: &HeaderMap) -> Result<(), ApiResponse> {
+	// Or the Global API Key, the email isn't checked
+	let token = headers.get(AUTHORIZATION)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.strip_prefix("Bearer "))
+		.or_else(|| headers.get("X-Auth-Key").and_then(|v| v.to_str().ok()));
+
+	match (token, &state.token) {
+		(None, _) => Err(failure(StatusCode::BAD_REQUEST, 9106, "Missing Authorization header")),
+		(Some(token), Some(expected)) if token != expected => Err(failure(StatusCode::FORBIDDEN, 9109, "Invalid access token")),
+		_ => Ok(()),
+	}
+}
+
+/// Runs the mock API until the process is stopped. Zones are created up front, records start out
+/// empty and only live in memory.
+pub async fn serve(listen: SocketAddr, zones: Vec<String>, token: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+	let mut state = MockState {
+		token,
+		zones: Vec::new(),
+		records: HashMap::new(),
+		next_id: 0,
+	};
+	for name in zones {
+		let id = state.new_id();
+		info!("Mock zone '{}' has id {}", name, id);
+		state.zones.push(MockZone { id, name: name.to_ascii_lowercase() });
+	}
+
+	let app = Router::new()
+		.route(&format!("{}/user/tokens/verify", API_PREFIX), get(verify_token))
+		.route(&format!("{}/zones", API_PREFIX), get(list_zones))
+		.route(&format!("{}/zones/{{zone_id}}/dns_records", API_PREFIX), get(list_records).post(create_record))
+		.route(&format!("{}/zones/{{zone_id}}/dns_records/batch", API_PREFIX), post(batch_records))
+		.route(&format!("{}/zones/{{zone_id}}/dns_records/{{record_id}}", API_PREFIX), patch(update_record).delete(delete_record))
+		.with_state(Arc::new(Mutex::new(state)));
+
+	let listener = tokio::net::TcpListener::bind(listen).await?;
+	info!("Mock Cloudflare API listening, use CF_API_URL=http://{}{}", listener.local_addr()?, API_PREFIX);
+	axum::serve(listener, app).await?;
+
+	Ok(())
+}
+
+async fn verify_token(State(state): State<SharedState>, headers: HeaderMap) -> ApiResponse {
+	let state = state.lock().unwrap();
+	if let Err(e) = authorize(&state, &headers) {
+		return e;
+	}
+
+	success(json!({ "id": "0".repeat(32), "status": "active" }))
+}
+
+async fn list_zones(State(state): State<SharedState>, headers: HeaderMap, Query(params): Query<HashMap<String, String>>) -> ApiResponse {
+	let state = state.lock().unwrap();
+	if let Err(e) = authorize(&state, &headers) {
+		return e;
+	}
+
+	// Every mock zone is active, so any other status filter matches nothing
+	let status_matches = params.get("status").is_none_or(|s| s == "active");
+	let zones: Vec<Value> = state.zones.iter()
+		.filter(|z| status_matches && params.get("name").is_none_or(|n| *n == z.name))
+		.map(|z| json!({ "id": z.id, "name": z.name, "status": "active" }))
+		.collect();
+
+	success(Value::Array(zones))
+}
+
+async fn list_records(State(state): State<SharedState>, headers: HeaderMap, Path(zone_id): Path<String>, Query(params): Query<HashMap<String, String>>) -> ApiResponse {
+	let state = state.lock().unwrap();
+	if let Err(e) = authorize(&state, &headers) {
+		return e;
+	}
+	if state.zone(&zone_id).is_none() {
+		return failure(StatusCode::NOT_FOUND, 7003, "Could not route to /zones/{zone_id}/dns_records, perhaps your object identifier is invalid?");
+	}
+
+	let field_matches = |record: &Value, field: &str| {
+		params.get(field).is_none_or(|expected| record.get(field).and_then(|v| v.as_str()) == Some(expected.as_str()))
+	};
+	let records: Vec<Value> = state.records.get(&zone_id)
+		.map(|records| records.iter()
+			.filter(|r| field_matches(r, "name") && field_matches(r, "type"))
+			.cloned()
+			.collect())
+		.unwrap_or_default();
+
+	success(Value::Array(records))
+}
+
+async fn create_record(State(state): State<SharedState>, headers: HeaderMap, Path(zone_id): Path<String>, Json(params): Json<Value>) -> ApiResponse {
+	let mut state = state.lock().unwrap();
+	if let Err(e) = authorize(&state, &headers) {
+		return e;
+	}
+	match create(&mut state, &zone_id, &params) {
+		Ok(record) => success(record),
+		Err(e) => e,
+	}
+}
+
+fn create(state: &mut MockState, zone_id: &str, params: &Value) -> Result<Value, ApiResponse> {
+	let Some(zone_name) = state.zone(zone_id).map(|z| z.name.clone()) else {
+		return Err(failure(StatusCode::NOT_FOUND, 7003, "Could not route to /zones/{zone_id}/dns_records, perhaps your object identifier is invalid?"));
+	};
+
+	let name = params.get("name").and_then(|v| v.as_str()).unwrap_or_default().to_ascii_lowercase();
+	if name != zone_name && !name.ends_with(&format!(".{}", zone_name)) {
+		return Err(failure(StatusCode::BAD_REQUEST, 1004, "DNS Validation Error: record name is not part of this zone"));
+	}
+
+	let mut record = json!({
+		"id": state.new_id(),
+		"zone_id": zone_id,
+		"zone_name": zone_name,
+		"name": name,
+		"type": params.get("type").cloned().unwrap_or(Value::Null),
+		"content": params.get("content").cloned().unwrap_or(Value::Null),
+		"proxiable": true,
+		"proxied": params.get("proxied").cloned().unwrap_or(Value::Bool(false)),
+		"ttl": params.get("ttl").cloned().unwrap_or(json!(1)),
+	});
+	if record.get("type").is_none_or(|t| t.is_null()) || record.get("content").is_none_or(|c| c.is_null()) {
+		return Err(failure(StatusCode::BAD_REQUEST, 9000, "DNS record type and content are required"));
+	}
+	if let Some(obj) = record.as_object_mut() {
+		obj.retain(|_, v| !v.is_null());
+	}
+
+	info!("Mock created record {}", record);
+	state.records.entry(zone_id.to_string()).or_default().push(record.clone());
+	Ok(record)
+}
+
+async fn update_record(State(state): State<SharedState>, headers: HeaderMap, Path((zone_id, record_id)): Path<(String, String)>, Json(params): Json<Value>) -> ApiResponse {
+	let mut state = state.lock().unwrap();
+	if let Err(e) = authorize(&state, &headers) {
+		return e;
+	}
+	match update(&mut state, &zone_id, &record_id, &params) {
+		Ok(record) => success(record),
+		Err(e) => e,
+	}
+}
+
+fn update(state: &mut MockState, zone_id: &str, record_id: &str, params: &Value) -> Result<Value, ApiResponse> {
+	let record = state.records.get_mut(zone_id)
+		.and_then(|records| records.iter_mut().find(|r| r.get("id").and_then(|v| v.as_str()) == Some(record_id)));
+	let Some(record) = record else {
+		return Err(failure(StatusCode::NOT_FOUND, 81044, "Record does not exist."));
+	};
+
+	if let (Some(record), Some(params)) = (record.as_object_mut(), params.as_object()) {
+		for field in ["name", "type", "content", "proxied", "ttl"] {
+			if let Some(value) = params.get(field) {
+				record.insert(field.to_string(), value.clone());
+			}
+		}
+	}
+
+	info!("Mock updated record {}", record);
+	Ok(record.clone())
+}
+
+/// Applies the patches, then the posts. Like the real API nothing is changed when one of them fails.
+async fn batch_records(State(state): State<SharedState>, headers: HeaderMap, Path(zone_id): Path<String>, Json(params): Json<Value>) -> ApiResponse {
+	let mut state = state.lock().unwrap();
+	if let Err(e) = authorize(&state, &headers) {
+		return e;
+	}
+
+	let snapshot = state.records.clone();
+	let result = apply_batch(&mut state, &zone_id, &params);
+	match result {
+		Ok(result) => success(result),
+		Err(e) => {
+			state.records = snapshot;
+			e
+		}
+	}
+}
+
+fn apply_batch(state: &mut MockState, zone_id: &str, params: &Value) -> Result<Value, ApiResponse> {
+	let operations = |name: &str| params.get(name).and_then(|v| v.as_array()).cloned().unwrap_or_default();
+
+	let mut patches = Vec::new();
+	for patch in operations("patches") {
+		let record_id = patch.get("id").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+		patches.push(update(state, zone_id, &record_id, &patch)?);
+	}
+	let mut posts = Vec::new();
+	for post in operations("posts") {
+		posts.push(create(state, zone_id, &post)?);
+	}
+	Ok(json!({ "deletes": [], "patches": patches, "puts": [], "posts": posts }))
+}
+
+async fn delete_record(State(state): State<SharedState>, headers: HeaderMap, Path((zone_id, record_id)): Path<(String, String)>) -> ApiResponse {
+	let mut state = state.lock().unwrap();
+	if let Err(e) = authorize(&state, &headers) {
+		return e;
+	}
+
+	let Some(records) = state.records.get_mut(&zone_id) else {
+		return failure(StatusCode::NOT_FOUND, 81044, "Record does not exist.");
+	};
+	let Some(index) = records.iter().position(|r| r.get("id").and_then(|v| v.as_str()) == Some(record_id.as_str())) else {
+		return failure(StatusCode::NOT_FOUND, 81044, "Record does not exist.");
+	};
+
+	let record = records.remove(index);
+	info!("Mock deleted record {}", record);
+	success(json!({ "id": record_id }))
+}
@@ -0,0 +1,256 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+
+use rumqttc::{AsyncClient, Event as MqttEvent, LastWill, MqttOptions, Outgoing, Packet, QoS, Transport};
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+use tracing::{debug, info, warn};
+
+use crate::events::{Event, EventMessage};
+use crate::exit;
+use crate::secret::Secret;
+
+pub const DEFAULT_PORT: u16 = 1883;
+pub const DEFAULT_TLS_PORT: u16 = 8883;
+pub const DEFAULT_TOPIC: &str = "cloudflaredyndns";
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long the last messages get to reach the broker when the updater stops
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_CAPACITY: usize = 64;
+
+/// Broker the addresses and record statuses are published to, `CF_MQTT_URL`.
+#[derive(Debug, Clone)]
+pub struct Mqtt {
+	pub host: String,
+	pub port: u16,
+	pub tls: bool,
+	pub credentials: Option<(String, Secret)>,
+	pub client_id: String,
+	/// Prefix of every topic, without the trailing slash
+	pub topic: String,
+	/// Home Assistant discovery prefix the sensors are announced under, `CF_MQTT_DISCOVERY`
+	pub discovery: Option<String>,
+}
+
+impl Mqtt {
+	/// Parses `mqtt://host[:port]` or `mqtts://host[:port]`.
+	pub fn parse_url(url: &str) -> Result<(String, u16, bool), String> {
+		let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+		let tls = match url.scheme() {
+			"mqtt" | "tcp" => false,
+			"mqtts" | "ssl" => true,
+			scheme => return Err(format!("unsupported scheme '{}', expected mqtt or mqtts", scheme)),
+		};
+		let host = url.host_str().filter(|host| !host.is_empty()).ok_or("missing host")?;
+		let port = url.port().unwrap_or(if tls { DEFAULT_TLS_PORT } else { DEFAULT_PORT });
+		Ok((host.trim_matches(['[', ']']).to_string(), port, tls))
+	}
+
+	/// Topic with `online` or `offline`, the latter set by the broker when the updater disappears
+	pub fn status_topic(&self) -> String {
+		format!("{}/status", self.topic)
+	}
+
+	/// Home Assistant discovery messages: sensors for the addresses and the last successful
+	/// cycle and a problem binary sensor, grouped in one device and unavailable with the updater.
+	fn discovery_configs(&self) -> Vec<(String, String)> {
+		let Some(prefix) = &self.discovery else {
+			return Vec::new();
+		};
+		// Object IDs are limited to letters, digits, underscores and dashes
+		let node = self.client_id.chars()
+			.map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+			.collect::<String>();
+		let device = json!({
+			"identifiers": [node],
+			"name": "Cloudflare DynDNS",
+			"model": "cloudflaredyndns",
+			"sw_version": env!("CARGO_PKG_VERSION"),
+		});
+		let entities = [
+			("sensor", "ipv4", json!({ "name": "Public IPv4", "icon": "mdi:ip-network" })),
+			("sensor", "ipv6", json!({ "name": "Public IPv6", "icon": "mdi:ip-network" })),
+			("sensor", "last_update", json!({ "name": "Last update", "device_class": "timestamp" })),
+			("binary_sensor", "problem", json!({ "name": "Update problem", "device_class": "problem", "payload_on": "ON", "payload_off": "OFF" })),
+		];
+
+		entities.into_iter().map(|(component, object, mut config)| {
+			config["unique_id"] = json!(format!("{}_{}", node, object));
+			config["state_topic"] = json!(format!("{}/{}", self.topic, object));
+			config["availability_topic"] = json!(self.status_topic());
+			config["device"] = device.clone();
+			(format!("{}/{}/{}/{}/config", prefix, component, node, object), config.to_string())
+		}).collect()
+	}
+
+	fn options(&self) -> MqttOptions {
+		let mut options = MqttOptions::new(&self.client_id, &self.host, self.port);
+		options.set_keep_alive(KEEP_ALIVE);
+		options.set_last_will(LastWill::new(self.status_topic(), "offline", QoS::AtLeastOnce, true));
+		if let Some((username, password)) = &self.credentials {
+			options.set_credentials(username, password.expose());
+		}
+		if self.tls {
+			options.set_transport(Transport::tls_with_default_config());
+		}
+		options
+	}
+}
+
+/// Retained status of a domain, the last record change or failure seen for it
+#[derive(Debug, Serialize)]
+struct DomainStatus<'a> {
+	/// created, updated or failed
+	status: &'static str,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	record_type: Option<&'a str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	content: Option<&'a str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	error: Option<&'a str>,
+	/// Seconds since the Unix epoch
+	timestamp: u64,
+}
+
+/// ISO 8601 UTC time of `secs` since the Unix epoch, as Home Assistant expects for timestamps.
+fn iso8601(secs: u64) -> String {
+	// Civil date from days since the epoch, valid for the proleptic Gregorian calendar
+	let days = (secs / 86400) as i64 + 719468;
+	let era = days.div_euclid(146097);
+	let day_of_era = days.rem_euclid(146097);
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let mp = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+	let time = secs % 86400;
+	format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}+00:00", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+/// Messages published so far, sent again after reconnecting in case the broker lost them
+type Retained = Arc<Mutex<HashMap<String, String>>>;
+
+fn publish(client: &AsyncClient, retained: &Retained, topic: String, payload: String) {
+	debug!("Publishing '{}' to MQTT topic '{}'", payload, topic);
+	retained.lock().unwrap().insert(topic.clone(), payload.clone());
+	// Not waiting for a slot keeps the events flowing while the broker is unreachable, what
+	// doesn't fit is sent with the rest on the next connection
+	if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, true, payload) {
+		debug!("MQTT message queued for the next connection: {}", e);
+	}
+}
+
+/// Sends the discovery configs, the status and the retained messages again after connecting.
+async fn replay_messages(client: AsyncClient, messages: Vec<(String, String)>) {
+	for (topic, payload) in messages {
+		if let Err(e) = client.publish(&topic, QoS::AtLeastOnce, true, payload).await {
+			warn!("Error publishing MQTT topic '{}' after reconnecting: {}", topic, e);
+		}
+	}
+}
+
+/// Publishes the detected addresses to `<topic>/ipv4` and `<topic>/ipv6` and the status of every
+/// changed or failed record to `<topic>/domains/<domain>`, all retained, so that subscribers
+/// always see the current WAN address. After every cycle `<topic>/problem` tells whether it
+/// failed and `<topic>/last_update` when the last one succeeded.
+pub async fn run(mqtt: Mqtt, mut events: Receiver<EventMessage>) {
+	let (client, mut eventloop) = AsyncClient::new(mqtt.options(), REQUEST_CAPACITY);
+	let retained: Retained = Arc::default();
+
+	let connection = tokio::spawn({
+		let client = client.clone();
+		let retained = retained.clone();
+		let status_topic = mqtt.status_topic();
+		let discovery = mqtt.discovery_configs();
+		let broker = format!("{}:{}", mqtt.host, mqtt.port);
+		async move {
+			let mut replay: Option<tokio::task::JoinHandle<()>> = None;
+			loop {
+				match eventloop.poll().await {
+					Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+						info!("Connected to MQTT broker {}", broker);
+						let mut messages = discovery.clone();
+						messages.push((status_topic.clone(), "online".to_string()));
+						messages.extend(retained.lock().unwrap().iter().map(|(topic, payload)| (topic.clone(), payload.clone())));
+						// More than the request channel holds, so the replay waits for slots while
+						// this loop keeps polling and sending them
+						if let Some(previous) = replay.replace(tokio::spawn(replay_messages(client.clone(), messages))) {
+							previous.abort();
+						}
+					},
+					Ok(MqttEvent::Outgoing(Outgoing::Disconnect)) => {
+						if let Some(replay) = replay.take() {
+							replay.abort();
+						}
+						return;
+					},
+					Ok(_) => {},
+					Err(e) => {
+						warn!("MQTT broker {} unreachable: {}", broker, e);
+						tokio::time::sleep(RECONNECT_DELAY).await;
+					},
+				}
+			}
+		}
+	});
+
+	loop {
+		let message = match events.recv().await {
+			Ok(message) => message,
+			Err(RecvError::Lagged(skipped)) => {
+				warn!("MQTT publisher missed {} events", skipped);
+				continue;
+			},
+			Err(RecvError::Closed) => break,
+		};
+
+		match &message.event {
+			Event::IpDetected { family, ip } => {
+				let topic = format!("{}/{}", mqtt.topic, family.to_string().to_lowercase());
+				// Only changes go out, the broker keeps the address for new subscribers
+				if retained.lock().unwrap().get(&topic) != Some(ip) {
+					publish(&client, &retained, topic, ip.clone());
+				}
+			},
+			Event::RecordCreated { domain, record_type, content } | Event::RecordUpdated { domain, record_type, content } => {
+				let status = DomainStatus {
+					status: if matches!(message.event, Event::RecordCreated { .. }) { "created" } else { "updated" },
+					record_type: Some(record_type),
+					content: Some(content),
+					error: None,
+					timestamp: message.timestamp,
+				};
+				publish(&client, &retained, format!("{}/domains/{}", mqtt.topic, domain), serde_json::to_string(&status).unwrap_or_default());
+			},
+			Event::UpdateFailed { domain, family, error } => {
+				let status = DomainStatus {
+					status: "failed",
+					record_type: family.map(|family| family.record_type()),
+					content: None,
+					error: Some(error),
+					timestamp: message.timestamp,
+				};
+				publish(&client, &retained, format!("{}/domains/{}", mqtt.topic, domain), serde_json::to_string(&status).unwrap_or_default());
+			},
+			Event::CycleFinished { summary } => {
+				let healthy = summary.exit_code() == exit::SUCCESS;
+				publish(&client, &retained, format!("{}/problem", mqtt.topic), if healthy { "OFF" } else { "ON" }.to_string());
+				if healthy {
+					publish(&client, &retained, format!("{}/last_update", mqtt.topic), iso8601(message.timestamp));
+				}
+			},
+			_ => {},
+		}
+	}
+
+	// A clean disconnect doesn't trigger the last will, so the status is set here
+	let _ = client.try_publish(mqtt.status_topic(), QoS::AtLeastOnce, true, "offline");
+	let _ = client.try_disconnect();
+	if tokio::time::timeout(DISCONNECT_TIMEOUT, connection).await.is_err() {
+		warn!("MQTT broker didn't acknowledge the disconnect in time");
+	}
+}
@@ -0,0 +1,217 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use async_trait::async_trait;
+use reqwest::{Client, RequestBuilder};
+use serde_json::Value;
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+use tracing::{error, warn};
+
+use crate::events::{Event, EventMessage};
+
+pub use chat::{Discord, Slack, Telegram};
+#[cfg(feature = "email")]
+pub use email::{Email, SmtpTls};
+pub use push::{Gotify, Ntfy};
+pub use webhook::Webhook;
+
+mod chat;
+#[cfg(feature = "email")]
+pub mod email;
+mod push;
+mod webhook;
+
+/// Events notifiers can be subscribed to, by their `event` name in the JSON form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+	IpChanged,
+	IpDetectionFailed,
+	RecordCreated,
+	RecordUpdated,
+	UpdateFailed,
+	ProbeFailed,
+	PropagationFailed,
+	FailoverActivated,
+	FailoverRecovered,
+	CanaryFailed,
+	CycleFinished,
+}
+
+pub const DEFAULT_EVENTS: [EventKind; 2] = [EventKind::IpChanged, EventKind::UpdateFailed];
+
+impl EventKind {
+	/// Accepts `ip_changed` as well as `ip-changed`.
+	pub fn parse(name: &str) -> Result<Self, String> {
+		match name.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+			"ip_changed" => Ok(EventKind::IpChanged),
+			"ip_detection_failed" => Ok(EventKind::IpDetectionFailed),
+			"record_created" => Ok(EventKind::RecordCreated),
+			"record_updated" => Ok(EventKind::RecordUpdated),
+			"update_failed" => Ok(EventKind::UpdateFailed),
+			"probe_failed" => Ok(EventKind::ProbeFailed),
+			"propagation_failed" => Ok(EventKind::PropagationFailed),
+			"failover_activated" => Ok(EventKind::FailoverActivated),
+			"failover_recovered" => Ok(EventKind::FailoverRecovered),
+			"canary_failed" => Ok(EventKind::CanaryFailed),
+			"cycle_finished" => Ok(EventKind::CycleFinished),
+			other => Err(format!("Unknown event '{}'", other)),
+		}
+	}
+
+	/// Parses a comma separated list, `default` when it is empty.
+	pub fn parse_list(value: Option<&str>, default: &[EventKind]) -> Result<Vec<Self>, String> {
+		let kinds = value.unwrap_or_default()
+			.split(',')
+			.filter(|name| !name.trim().is_empty())
+			.map(EventKind::parse)
+			.collect::<Result<Vec<_>, _>>()?;
+		Ok(if kinds.is_empty() { default.to_vec() } else { kinds })
+	}
+
+	/// Failures and failovers need attention, everything else is informational.
+	pub fn is_alert(&self) -> bool {
+		matches!(self, EventKind::IpDetectionFailed | EventKind::UpdateFailed | EventKind::ProbeFailed | EventKind::PropagationFailed | EventKind::FailoverActivated | EventKind::CanaryFailed)
+	}
+
+	pub fn of(event: &Event) -> Option<Self> {
+		match event {
+			Event::IpChanged { .. } => Some(EventKind::IpChanged),
+			Event::IpDetectionFailed { .. } => Some(EventKind::IpDetectionFailed),
+			Event::RecordCreated { .. } => Some(EventKind::RecordCreated),
+			Event::RecordUpdated { .. } => Some(EventKind::RecordUpdated),
+			Event::UpdateFailed { .. } => Some(EventKind::UpdateFailed),
+			Event::ProbeFailed { .. } => Some(EventKind::ProbeFailed),
+			Event::PropagationFailed { .. } => Some(EventKind::PropagationFailed),
+			Event::FailoverActivated { .. } => Some(EventKind::FailoverActivated),
+			Event::FailoverRecovered { .. } => Some(EventKind::FailoverRecovered),
+			Event::CanaryFailed { .. } => Some(EventKind::CanaryFailed),
+			Event::CycleFinished { .. } => Some(EventKind::CycleFinished),
+			_ => None,
+		}
+	}
+}
+
+/// Per event priorities of push notifications, `event=priority` pairs (Comma separated).
+pub fn parse_priorities(value: Option<&str>, parse_priority: fn(&str) -> Option<u8>) -> Result<HashMap<EventKind, u8>, String> {
+	value.unwrap_or_default()
+		.split(',')
+		.filter(|pair| !pair.trim().is_empty())
+		.map(|pair| {
+			let (event, priority) = pair.split_once('=').ok_or_else(|| format!("Expected event=priority, got '{}'", pair.trim()))?;
+			let priority = parse_priority(priority.trim()).ok_or_else(|| format!("Invalid priority '{}'", priority.trim()))?;
+			Ok((EventKind::parse(event)?, priority))
+		})
+		.collect()
+}
+
+/// Something that delivers events to people or other systems.
+#[async_trait]
+pub trait Notifier: Send + Sync + fmt::Display {
+	async fn send(&self, client: &Client, message: &EventMessage) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// A notifier and the events it receives.
+#[derive(Clone)]
+pub struct Subscription {
+	pub notifier: Arc<dyn Notifier>,
+	pub events: Vec<EventKind>,
+}
+
+impl fmt::Debug for Subscription {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Subscription")
+			.field("notifier", &self.notifier.to_string())
+			.field("events", &self.events)
+			.finish()
+	}
+}
+
+/// Delivers events to the notifiers subscribed to them, a failing notifier doesn't stop the others.
+pub async fn run(subscriptions: Vec<Subscription>, client: Client, mut events: Receiver<EventMessage>) {
+	loop {
+		let message = match events.recv().await {
+			Ok(message) => message,
+			Err(RecvError::Lagged(skipped)) => {
+				warn!("Notifiers missed {} events", skipped);
+				continue;
+			},
+			Err(RecvError::Closed) => return,
+		};
+
+		let Some(kind) = EventKind::of(&message.event) else {
+			continue;
+		};
+
+		let deliveries = subscriptions.iter()
+			.filter(|subscription| subscription.events.contains(&kind))
+			.map(|subscription| async {
+				if let Err(e) = subscription.notifier.send(&client, &message).await {
+					error!("Error notifying {}: {}", subscription.notifier, e);
+				}
+			});
+		futures::future::join_all(deliveries).await;
+	}
+}
+
+/// POSTs a JSON body, anything but a 2xx answer is an error.
+async fn post_json(client: &Client, url: &str, body: String) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+	deliver(client.post(url).header("Content-Type", "application/json").body(body)).await
+}
+
+async fn deliver(request: RequestBuilder) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+	let resp = request.send()
+		.await
+		// Webhook URLs carry their secret, keep them out of the logs
+		.map_err(|e| e.without_url())?;
+
+	if !resp.status().is_success() {
+		return Err(format!("answered {}: {}", resp.status(), resp.text().await.unwrap_or_default()).into());
+	}
+	Ok(())
+}
+
+/// Replaces `{name}` placeholders with the fields of the event (`{event}`, `{domain}`, `{family}`,
+/// `{old}`, `{new}`, `{error}`, ...) and `{message}` with its description. Values are escaped for
+/// use inside JSON strings, unknown placeholders are left alone.
+pub fn render(template: &str, message: &EventMessage) -> String {
+	let fields = match serde_json::to_value(message) {
+		Ok(Value::Object(fields)) => fields,
+		_ => Default::default(),
+	};
+
+	let mut out = String::with_capacity(template.len());
+	let mut rest = template;
+	while let Some(start) = rest.find('{') {
+		out.push_str(&rest[..start]);
+		rest = &rest[start..];
+
+		let name = rest[1..].find('}').map(|end| &rest[1..end + 1])
+			.filter(|name| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+		let value = match name {
+			Some("message") => Some(message.event.to_string()),
+			Some(name) => fields.get(name).map(|value| match value {
+				Value::String(text) => text.clone(),
+				Value::Null => String::new(),
+				other => other.to_string(),
+			}),
+			None => None,
+		};
+
+		match (name, value) {
+			(Some(name), Some(value)) => {
+				out.push_str(&escape_json(&value));
+				rest = &rest[name.len() + 2..];
+			},
+			_ => {
+				out.push('{');
+				rest = &rest[1..];
+			},
+		}
+	}
+	out.push_str(rest);
+	out
+}
+
+fn escape_json(text: &str) -> String {
+	let quoted = Value::String(text.to_string()).to_string();
+	quoted[1..quoted.len() - 1].to_string()
+}
@@ -0,0 +1,74 @@
+use std::fmt;
+
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::json;
+
+use crate::events::EventMessage;
+use crate::secret::Secret;
+
+use super::{Notifier, post_json};
+
+const TELEGRAM_API_URL: &str = "https://api.telegram.org";
+
+/// Sends the event description through a Telegram bot, `CF_TELEGRAM_TOKEN`/`CF_TELEGRAM_CHAT_ID`.
+#[derive(Debug, Clone)]
+pub struct Telegram {
+	pub token: Secret,
+	pub chat_id: String,
+}
+
+/// Posts the event description to a Discord channel webhook, `CF_DISCORD_WEBHOOK_URL`.
+#[derive(Debug, Clone)]
+pub struct Discord {
+	pub url: String,
+}
+
+/// Posts the event description to a Slack incoming webhook, `CF_SLACK_WEBHOOK_URL`.
+#[derive(Debug, Clone)]
+pub struct Slack {
+	pub url: String,
+}
+
+#[async_trait]
+impl Notifier for Telegram {
+	async fn send(&self, client: &Client, message: &EventMessage) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+		let url = format!("{}/bot{}/sendMessage", TELEGRAM_API_URL, self.token.expose());
+		let body = json!({ "chat_id": self.chat_id, "text": message.event.to_string() });
+		post_json(client, &url, body.to_string()).await
+	}
+}
+
+#[async_trait]
+impl Notifier for Discord {
+	async fn send(&self, client: &Client, message: &EventMessage) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+		let body = json!({ "content": message.event.to_string() });
+		post_json(client, &self.url, body.to_string()).await
+	}
+}
+
+#[async_trait]
+impl Notifier for Slack {
+	async fn send(&self, client: &Client, message: &EventMessage) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+		let body = json!({ "text": message.event.to_string() });
+		post_json(client, &self.url, body.to_string()).await
+	}
+}
+
+impl fmt::Display for Telegram {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Telegram chat {}", self.chat_id)
+	}
+}
+
+impl fmt::Display for Discord {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Discord webhook")
+	}
+}
+
+impl fmt::Display for Slack {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Slack webhook")
+	}
+}
@@ -0,0 +1,158 @@
+use std::{fmt, sync::Mutex};
+
+use async_trait::async_trait;
+use lettre::{
+	AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+	message::{Mailbox, header::ContentType},
+	transport::smtp::authentication::Credentials,
+};
+use reqwest::Client;
+
+use crate::events::{Event, EventMessage};
+use crate::secret::Secret;
+
+use super::Notifier;
+
+pub const DEFAULT_PORT: u16 = 587;
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+
+/// How the SMTP connection is secured, `CF_SMTP_TLS`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmtpTls {
+	StartTls,
+	/// TLS from the start, usually port 465
+	Tls,
+	None,
+}
+
+impl SmtpTls {
+	pub fn parse(value: &str) -> Result<Self, String> {
+		match value.trim().to_ascii_lowercase().as_str() {
+			"" | "starttls" => Ok(SmtpTls::StartTls),
+			"tls" => Ok(SmtpTls::Tls),
+			"none" => Ok(SmtpTls::None),
+			other => Err(format!("Unknown SMTP TLS mode '{}', expected starttls, tls or none", other)),
+		}
+	}
+}
+
+#[derive(Default)]
+struct Tracker {
+	/// Address changes of the running cycle, mailed with its summary
+	changes: Vec<String>,
+	failed_cycles: u32,
+	/// Length of the last run of failed cycles, for the recovery mail
+	streak: u32,
+	/// The failure alert was delivered, the recovery mail is due once a cycle succeeds
+	alerted: bool,
+}
+
+/// Mails a summary after cycles in which the public address changed, and an alert once
+/// `failure_threshold` cycles in a row failed. An alert that couldn't be delivered is sent again
+/// after the next cycle.
+pub struct Email {
+	transport: AsyncSmtpTransport<Tokio1Executor>,
+	host: String,
+	from: Mailbox,
+	to: Vec<Mailbox>,
+	failure_threshold: u32,
+	tracker: Mutex<Tracker>,
+}
+
+impl Email {
+	pub fn new(host: &str, port: u16, tls: SmtpTls, credentials: Option<(String, Secret)>, from: &str, to: &[String], failure_threshold: u32) -> Result<Self, Box<dyn std::error::Error>> {
+		let builder = match tls {
+			SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+			SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+			SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+		};
+		let builder = builder.port(port);
+		let transport = match credentials {
+			Some((username, password)) => builder.credentials(Credentials::new(username, password.expose().to_string())).build(),
+			None => builder.build(),
+		};
+
+		Ok(Self {
+			transport,
+			host: host.to_string(),
+			from: from.parse().map_err(|e| format!("Invalid sender '{}': {}", from, e))?,
+			to: to.iter()
+				.map(|to| to.parse().map_err(|e| format!("Invalid recipient '{}': {}", to, e)))
+				.collect::<Result<Vec<_>, _>>()?,
+			failure_threshold,
+			tracker: Mutex::new(Tracker::default()),
+		})
+	}
+
+	async fn mail(&self, subject: &str, body: String) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+		let mut message = Message::builder()
+			.from(self.from.clone())
+			.subject(format!("cloudflaredyndns: {}", subject))
+			.header(ContentType::TEXT_PLAIN);
+		for to in &self.to {
+			message = message.to(to.clone());
+		}
+
+		self.transport.send(message.body(body)?).await?;
+		Ok(())
+	}
+}
+
+#[async_trait]
+impl Notifier for Email {
+	async fn send(&self, _client: &Client, message: &EventMessage) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+		let summary = match &message.event {
+			Event::IpChanged { .. } => {
+				self.tracker.lock().unwrap().changes.push(message.event.to_string());
+				return Ok(());
+			},
+			Event::CycleFinished { summary } => summary,
+			_ => return Ok(()),
+		};
+
+		let (changes, alert) = {
+			let mut tracker = self.tracker.lock().unwrap();
+			tracker.failed_cycles = if summary.exit_code() == 0 { 0 } else { tracker.failed_cycles + 1 };
+			if tracker.failed_cycles > 0 {
+				tracker.streak = tracker.failed_cycles;
+			}
+			let alert = match (tracker.failed_cycles, tracker.alerted) {
+				(failed, false) if failed >= self.failure_threshold => {
+					Some(("updates failing", format!("The last {} update cycles failed, the latest: {}\n", failed, summary)))
+				},
+				(0, true) => Some(("updates recovered", format!("Updates succeed again after {} failed cycles: {}\n", tracker.streak, summary))),
+				_ => None,
+			};
+			(std::mem::take(&mut tracker.changes), alert)
+		};
+
+		// Sent independently, a failed summary doesn't hold back the alert
+		let mut errors = Vec::new();
+		if !changes.is_empty() {
+			if let Err(e) = self.mail("public address changed", format!("{}\n\nUpdate cycle: {}\n", changes.join("\n"), summary)).await {
+				errors.push(e.to_string());
+			}
+		}
+		if let Some((subject, body)) = alert {
+			match self.mail(subject, body).await {
+				Ok(()) => {
+					let mut tracker = self.tracker.lock().unwrap();
+					tracker.alerted = tracker.failed_cycles > 0;
+				},
+				Err(e) => errors.push(e.to_string()),
+			}
+		}
+
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(errors.join(", ").into())
+		}
+	}
+}
+
+impl fmt::Display for Email {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "email through {}", self.host)
+	}
+}
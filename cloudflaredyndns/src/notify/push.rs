@@ -0,0 +1,97 @@
+use std::{collections::HashMap, fmt};
+
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::json;
+
+use crate::events::EventMessage;
+use crate::secret::Secret;
+
+use super::{EventKind, Notifier, deliver};
+
+const TITLE: &str = "cloudflaredyndns";
+
+/// Publishes to an ntfy topic, `CF_NTFY_URL` (`https://ntfy.sh/<topic>`). Priorities go from 1
+/// (min) to 5 (max), alerts are sent with 4 (high) and everything else with 3 (default).
+#[derive(Debug, Clone)]
+pub struct Ntfy {
+	pub url: String,
+	/// Access token of protected topics
+	pub token: Option<Secret>,
+	pub priorities: HashMap<EventKind, u8>,
+}
+
+impl Ntfy {
+	/// Numbers and ntfy's priority names.
+	pub fn parse_priority(value: &str) -> Option<u8> {
+		match value.to_ascii_lowercase().as_str() {
+			"min" => Some(1),
+			"low" => Some(2),
+			"default" => Some(3),
+			"high" => Some(4),
+			"max" | "urgent" => Some(5),
+			other => other.parse().ok().filter(|p| (1..=5).contains(p)),
+		}
+	}
+}
+
+/// Sends to a Gotify server, `CF_GOTIFY_URL` with the application token `CF_GOTIFY_TOKEN`.
+/// Priorities go from 0 to 10, alerts are sent with 8 and everything else with 4.
+#[derive(Debug, Clone)]
+pub struct Gotify {
+	pub url: String,
+	pub token: Secret,
+	pub priorities: HashMap<EventKind, u8>,
+}
+
+impl Gotify {
+	pub fn parse_priority(value: &str) -> Option<u8> {
+		value.parse().ok().filter(|p| *p <= 10)
+	}
+}
+
+#[async_trait]
+impl Notifier for Ntfy {
+	async fn send(&self, client: &Client, message: &EventMessage) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+		let kind = EventKind::of(&message.event);
+		let priority = kind.and_then(|kind| self.priorities.get(&kind).copied())
+			.unwrap_or(if kind.is_some_and(|kind| kind.is_alert()) { 4 } else { 3 });
+
+		let mut request = client.post(&self.url)
+			.header("Title", TITLE)
+			.header("Priority", priority.to_string())
+			.body(message.event.to_string());
+		if let Some(token) = &self.token {
+			request = request.bearer_auth(token.expose());
+		}
+		deliver(request).await
+	}
+}
+
+#[async_trait]
+impl Notifier for Gotify {
+	async fn send(&self, client: &Client, message: &EventMessage) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+		let kind = EventKind::of(&message.event);
+		let priority = kind.and_then(|kind| self.priorities.get(&kind).copied())
+			.unwrap_or(if kind.is_some_and(|kind| kind.is_alert()) { 8 } else { 4 });
+
+		let url = format!("{}/message", self.url.trim_end_matches('/'));
+		let body = json!({ "title": TITLE, "message": message.event.to_string(), "priority": priority });
+		deliver(client.post(&url)
+			.header("X-Gotify-Key", self.token.expose())
+			.header("Content-Type", "application/json")
+			.body(body.to_string())).await
+	}
+}
+
+impl fmt::Display for Ntfy {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "ntfy topic {}", self.url)
+	}
+}
+
+impl fmt::Display for Gotify {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Gotify server {}", self.url)
+	}
+}
@@ -0,0 +1,36 @@
+use std::fmt;
+
+use async_trait::async_trait;
+use reqwest::Client;
+
+use crate::events::EventMessage;
+
+use super::{Notifier, post_json, render};
+
+/// POSTs events as JSON to a URL, `CF_WEBHOOK_URLS`. Without a template the body is the event
+/// object `watch --json` prints.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+	pub url: String,
+	pub template: Option<String>,
+}
+
+#[async_trait]
+impl Notifier for Webhook {
+	async fn send(&self, client: &Client, message: &EventMessage) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+		let body = match &self.template {
+			Some(template) => render(template, message),
+			None => serde_json::to_string(message)?,
+		};
+
+		post_json(client, &self.url, body).await
+	}
+}
+
+impl fmt::Display for Webhook {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		// The path of chat webhooks is their secret
+		let origin = self.url.splitn(4, '/').take(3).collect::<Vec<_>>().join("/");
+		write!(f, "webhook {}", origin)
+	}
+}
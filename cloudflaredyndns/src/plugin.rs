@@ -0,0 +1,114 @@
+use std::{fmt, path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+use wasmtime::{Config, Engine, Store, component::{Component, Linker}};
+use tracing::{error, warn};
+
+use crate::{events::EventMessage, ipsource::IpFamily};
+
+/// Instructions a call may execute, a plugin stuck in a loop traps instead of keeping its thread
+const CALL_FUEL: u64 = 1_000_000_000;
+
+/// How long a call is waited for, the update loop waits for the address plugins
+const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+mod ip_source_world {
+	wasmtime::component::bindgen!({ world: "ip-source", path: "wit" });
+}
+
+mod notifier_world {
+	wasmtime::component::bindgen!({ world: "notifier", path: "wit" });
+}
+
+/// A compiled WASM component implementing one of the worlds in `wit/plugin.wit`. Every call runs
+/// in a fresh instance, plugins can't keep state between calls and have no host imports. A call
+/// is limited to `CALL_FUEL` instructions and `CALL_TIMEOUT`.
+pub struct Plugin {
+	path: PathBuf,
+	engine: Engine,
+	component: Component,
+}
+
+impl fmt::Debug for Plugin {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Plugin({})", self.path.display())
+	}
+}
+
+impl Plugin {
+	pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+		let mut config = Config::new();
+		config.consume_fuel(true);
+		let engine = Engine::new(&config).map_err(|e| e.to_string())?;
+		let component = Component::from_file(&engine, path)
+			.map_err(|e| format!("Failed to load plugin '{}': {}", path.display(), e))?;
+
+		Ok(Self {
+			path: path.to_path_buf(),
+			engine,
+			component,
+		})
+	}
+
+	fn call_detect(&self, family: IpFamily) -> Result<String, String> {
+		let mut store = Store::new(&self.engine, ());
+		store.set_fuel(CALL_FUEL).map_err(|e| e.to_string())?;
+		let linker = Linker::new(&self.engine);
+		let instance = ip_source_world::IpSource::instantiate(&mut store, &self.component, &linker)
+			.map_err(|e| e.to_string())?;
+
+		let family = match family {
+			IpFamily::V4 => ip_source_world::IpFamily::Ipv4,
+			IpFamily::V6 => ip_source_world::IpFamily::Ipv6,
+		};
+		instance.call_detect(&mut store, family).map_err(|e| e.to_string())?
+	}
+
+	fn call_notify(&self, event: &str) -> Result<(), String> {
+		let mut store = Store::new(&self.engine, ());
+		store.set_fuel(CALL_FUEL).map_err(|e| e.to_string())?;
+		let linker = Linker::new(&self.engine);
+		let instance = notifier_world::Notifier::instantiate(&mut store, &self.component, &linker)
+			.map_err(|e| e.to_string())?;
+
+		instance.call_notify(&mut store, event).map_err(|e| e.to_string())?
+	}
+
+	pub async fn detect(self: &Arc<Self>, family: IpFamily) -> Result<String, Box<dyn std::error::Error>> {
+		let plugin = self.clone();
+		let result = tokio::time::timeout(CALL_TIMEOUT, tokio::task::spawn_blocking(move || plugin.call_detect(family))).await
+			.map_err(|_| format!("Plugin '{}' didn't return within {} seconds", self.path.display(), CALL_TIMEOUT.as_secs()))??;
+		result.map_err(|e| format!("Plugin '{}' failed: {}", self.path.display(), e).into())
+	}
+
+	pub async fn notify(self: &Arc<Self>, event: String) -> Result<(), Box<dyn std::error::Error>> {
+		let plugin = self.clone();
+		let result = tokio::time::timeout(CALL_TIMEOUT, tokio::task::spawn_blocking(move || plugin.call_notify(&event))).await
+			.map_err(|_| format!("Plugin '{}' didn't return within {} seconds", self.path.display(), CALL_TIMEOUT.as_secs()))??;
+		result.map_err(|e| format!("Plugin '{}' failed: {}", self.path.display(), e).into())
+	}
+}
+
+/// Forwards every event to the notifier plugins, a failing plugin doesn't stop the others.
+pub async fn run_notifiers(plugins: Vec<Arc<Plugin>>, mut events: Receiver<EventMessage>) {
+	loop {
+		let message = match events.recv().await {
+			Ok(message) => message,
+			Err(RecvError::Lagged(skipped)) => {
+				warn!("Notifier plugins missed {} events", skipped);
+				continue;
+			},
+			Err(RecvError::Closed) => return,
+		};
+
+		let Ok(event) = serde_json::to_string(&message) else {
+			continue;
+		};
+
+		for plugin in &plugins {
+			if let Err(e) = plugin.notify(event.clone()).await.map_err(|e| e.to_string()) {
+				error!("Error notifying plugin: {}", e);
+			}
+		}
+	}
+}
@@ -0,0 +1,73 @@
+use std::{net::{IpAddr, SocketAddr}, time::Duration};
+
+use reqwest::Client;
+use tokio::net::TcpStream;
+
+// Label probed in place of the `*` of a wildcard record
+const WILDCARD_PROBE_LABEL: &str = "cloudflaredyndns-probe";
+
+// Cloudflare's "origin unreachable" family (521 down, 522 timeout, 523 unreachable, ...)
+const CLOUDFLARE_ORIGIN_ERRORS: std::ops::RangeInclusive<u16> = 520..=530;
+
+/// Reachability check run after a record changes (`CF_PROBE`). Proxied records are probed
+/// through Cloudflare by hostname, unproxied ones by connecting to the new address directly so
+/// the result doesn't depend on DNS caches.
+#[derive(Debug, Clone)]
+pub enum Probe {
+	Http { https: bool, path: String },
+	Tcp(u16),
+}
+
+impl Probe {
+	/// `http`, `https` or `tcp:<port>`, empty or `off` disables probing.
+	pub fn parse(value: &str, path: &str) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+		let value = value.trim().to_ascii_lowercase();
+		let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+
+		match value.as_str() {
+			"" | "off" | "false" => Ok(None),
+			"http" => Ok(Some(Probe::Http { https: false, path })),
+			"https" => Ok(Some(Probe::Http { https: true, path })),
+			_ => match value.strip_prefix("tcp:").map(|port| port.parse::<u16>()) {
+				Some(Ok(port)) => Ok(Some(Probe::Tcp(port))),
+				_ => Err(format!("Invalid CF_PROBE '{}': expected http, https or tcp:<port>", value).into()),
+			},
+		}
+	}
+
+	/// Returns a short description of what was reached. A wildcard domain is probed through one of
+	/// the names it covers.
+	pub async fn run(&self, domain: &str, ip: &str, proxied: bool, timeout: Duration) -> Result<String, Box<dyn std::error::Error>> {
+		let ip: IpAddr = ip.parse()?;
+		let domain = &match domain.strip_prefix("*.") {
+			Some(parent) => format!("{}.{}", WILDCARD_PROBE_LABEL, parent),
+			None => domain.to_string(),
+		};
+
+		match self {
+			Probe::Http { https, path } => {
+				let (scheme, port) = if *https { ("https", 443) } else { ("http", 80) };
+				let mut builder = Client::builder().timeout(timeout);
+				if !proxied {
+					builder = builder.resolve(domain, SocketAddr::new(ip, port));
+				}
+
+				let url = format!("{}://{}{}", scheme, domain, path);
+				let status = builder.build()?.get(&url).send().await?.status();
+				if proxied && CLOUDFLARE_ORIGIN_ERRORS.contains(&status.as_u16()) {
+					return Err(format!("{} answered {}, Cloudflare can't reach the origin", url, status).into());
+				}
+
+				Ok(format!("{} answered {}", url, status))
+			},
+			Probe::Tcp(port) => {
+				let target = if proxied { format!("{}:{}", domain, port) } else { SocketAddr::new(ip, *port).to_string() };
+				tokio::time::timeout(timeout, TcpStream::connect(&target)).await
+					.map_err(|_| format!("Connecting to {} timed out", target))?
+					.map_err(|e| format!("Connecting to {} failed: {}", target, e))?;
+
+				Ok(format!("Connected to {}", target))
+			},
+		}
+	}
+}
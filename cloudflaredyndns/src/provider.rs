@@ -0,0 +1,104 @@
+use async_trait::async_trait;
+
+use crate::cloudflare::CloudflareClient;
+use crate::error::Error;
+use crate::ipsource::IpFamily;
+
+/// Settings of a record only Cloudflare has, other providers ignore them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CloudflareOptions {
+	pub proxied: bool,
+	/// An existing record keeps its proxy status, `proxied` only applies when it is created
+	pub keep_proxied: bool,
+}
+
+/// What to publish in one record.
+pub struct RecordRequest<'a> {
+	pub name: &'a str,
+	pub family: IpFamily,
+	pub content: &'a str,
+	/// Id of the record last published, providers can update it without looking it up
+	pub record_id: Option<&'a str>,
+	/// `None` keeps the TTL of an existing record
+	pub ttl: Option<u64>,
+	pub comment: Option<&'a str>,
+	pub cloudflare: CloudflareOptions,
+}
+
+/// A record as the provider stores it.
+#[derive(Debug, Clone)]
+pub struct Record {
+	pub id: String,
+	pub name: String,
+	pub record_type: String,
+	pub content: String,
+	pub ttl: u64,
+	/// Served through the provider's proxy (Cloudflare), the name then resolves to the proxy's
+	/// addresses instead of `content`
+	pub proxied: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordChange {
+	/// The record already had the requested content
+	Unchanged,
+	Updated,
+	Created,
+}
+
+/// Outcome of [`DnsProvider::upsert_record`], `record` is the record as it is now stored by the
+/// provider.
+#[derive(Debug)]
+pub struct RecordUpdate {
+	pub change: RecordChange,
+	pub record: Record,
+}
+
+/// The registrable domain (the zone) of `domain`, one label below its public suffix according to
+/// the Public Suffix List built into the binary: `host.example.co.uk` is in `example.co.uk`. A
+/// wildcard is in the zone of the name it is under.
+pub fn registrable_domain(domain: &str) -> Result<String, Error> {
+	let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+	let name = domain.strip_prefix("*.").unwrap_or(&domain);
+	match psl::domain_str(name) {
+		Some(zone) => Ok(zone.to_string()),
+		None => Err(Error::Config(format!("Invalid domain: {} (no registrable domain below a public suffix)", domain))),
+	}
+}
+
+/// A DNS hosting service the records are published to. Address detection, scheduling and the
+/// state cache only go through this, other services would be feature gated modules implementing
+/// it next to [`crate::cloudflare`].
+#[async_trait]
+pub trait DnsProvider: Send + Sync {
+	/// The zone `domain` is in, by default its registrable domain. Providers with delegated
+	/// subzones override it.
+	fn zone_name(&self, domain: &str) -> Result<String, Error> {
+		registrable_domain(domain)
+	}
+
+	/// Id of the zone named `zone` (see [`DnsProvider::zone_name`]), for the other calls.
+	async fn zone_for_domain(&self, zone: &str) -> Result<String, Error>;
+
+	/// The record of `family` named `name`, if there is one.
+	async fn find_record(&self, zone_id: &str, name: &str, family: IpFamily) -> Result<Option<Record>, Error>;
+
+	/// Creates the record or updates it to the requested content.
+	async fn upsert_record(&self, zone_id: &str, request: &RecordRequest<'_>) -> Result<RecordUpdate, Error>;
+
+	/// [`DnsProvider::upsert_record`] for several records of one zone, one result per request in
+	/// the same order. Providers with a bulk API override it.
+	async fn upsert_records(&self, zone_id: &str, requests: &[RecordRequest<'_>]) -> Vec<Result<RecordUpdate, Error>> {
+		let mut results = Vec::with_capacity(requests.len());
+		for request in requests {
+			results.push(self.upsert_record(zone_id, request).await);
+		}
+		results
+	}
+
+	/// The Cloudflare account behind the provider, for the Load Balancer origins and IP Lists kept
+	/// on the detected addresses. `None` for other services, those settings are then left alone.
+	fn cloudflare(&self) -> Option<&CloudflareClient> {
+		None
+	}
+}
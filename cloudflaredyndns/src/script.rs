@@ -0,0 +1,95 @@
+use std::{fmt, path::{Path, PathBuf}};
+
+use rhai::{AST, Dynamic, Engine, Scope};
+
+use crate::ipsource::{self, IpFamily};
+
+/// Operations a hook call may run, the hooks are called on the runtime and a script stuck in a
+/// loop would hang it
+const MAX_OPERATIONS: u64 = 10_000_000;
+
+/// A Rhai script loaded from `CF_SCRIPT`. Every hook is optional, the script only defines the
+/// functions it cares about:
+///
+/// - `filter_ip(family, ip)`: return the address to publish, or `()` to skip this family
+/// - `allow_update(domain, record_type, content)`: return `false` to veto a record update
+/// - `record_comment(domain, record_type, content)`: return the comment to set on the record
+pub struct Script {
+	path: PathBuf,
+	engine: Engine,
+	ast: AST,
+}
+
+impl fmt::Debug for Script {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Script({})", self.path.display())
+	}
+}
+
+impl Script {
+	pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+		let mut engine = Engine::new();
+		engine.set_max_operations(MAX_OPERATIONS);
+		let ast = engine.compile_file(path.to_path_buf())
+			.map_err(|e| format!("Failed to load script '{}': {}", path.display(), e))?;
+
+		Ok(Self {
+			path: path.to_path_buf(),
+			engine,
+			ast,
+		})
+	}
+
+	fn has_fn(&self, name: &str, params: usize) -> bool {
+		self.ast.iter_functions().any(|f| f.name == name && f.params.len() == params)
+	}
+
+	fn call(&self, name: &str, args: impl rhai::FuncArgs) -> Result<Dynamic, Box<dyn std::error::Error>> {
+		self.engine.call_fn::<Dynamic>(&mut Scope::new(), &self.ast, name, args)
+			.map_err(|e| format!("Script '{}' failed in {}: {}", self.path.display(), name, e).into())
+	}
+
+	/// Returns the address to publish, `None` if the script dropped it. The returned address gets
+	/// the checks of a detected one.
+	pub fn filter_ip(&self, family: IpFamily, ip: String) -> Result<Option<String>, Box<dyn std::error::Error>> {
+		if !self.has_fn("filter_ip", 2) {
+			return Ok(Some(ip));
+		}
+
+		let result = self.call("filter_ip", (family.to_string(), ip))?;
+		if result.is_unit() {
+			return Ok(None);
+		}
+
+		let ip = result.into_string()
+			.map_err(|t| format!("Script '{}': filter_ip must return a string or (), got {}", self.path.display(), t))?;
+		ipsource::validate(&ip, family)
+			.map(Some)
+			.map_err(|e| format!("Script '{}': filter_ip returned an unusable {}: {}", self.path.display(), family, e).into())
+	}
+
+	pub fn allow_update(&self, domain: &str, record_type: &str, content: &str) -> Result<bool, Box<dyn std::error::Error>> {
+		if !self.has_fn("allow_update", 3) {
+			return Ok(true);
+		}
+
+		self.call("allow_update", (domain.to_string(), record_type.to_string(), content.to_string()))?
+			.as_bool()
+			.map_err(|t| format!("Script '{}': allow_update must return a bool, got {}", self.path.display(), t).into())
+	}
+
+	pub fn record_comment(&self, domain: &str, record_type: &str, content: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+		if !self.has_fn("record_comment", 3) {
+			return Ok(None);
+		}
+
+		let result = self.call("record_comment", (domain.to_string(), record_type.to_string(), content.to_string()))?;
+		if result.is_unit() {
+			return Ok(None);
+		}
+
+		result.into_string()
+			.map(Some)
+			.map_err(|t| format!("Script '{}': record_comment must return a string or (), got {}", self.path.display(), t).into())
+	}
+}
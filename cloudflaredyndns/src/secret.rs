@@ -0,0 +1,55 @@
+use std::{fmt, sync::atomic::{AtomicBool, Ordering}};
+
+// Set by --log-insecure, for debugging authentication problems on a private machine
+static LOG_INSECURE: AtomicBool = AtomicBool::new(false);
+
+/// Shows secrets and full Cloudflare responses in the logs from now on.
+pub fn set_log_insecure(insecure: bool) {
+	LOG_INSECURE.store(insecure, Ordering::Relaxed);
+}
+
+pub fn log_insecure() -> bool {
+	LOG_INSECURE.load(Ordering::Relaxed)
+}
+
+/// A token, key or password. `Debug` and `Display` print `[redacted]` (unless `--log-insecure` is
+/// given), so printing the configuration or an error that contains one doesn't leak it.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Secret(String);
+
+impl Secret {
+	pub fn new(secret: impl Into<String>) -> Self {
+		Secret(secret.into())
+	}
+
+	/// The secret itself, only for sending it.
+	pub fn expose(&self) -> &str {
+		&self.0
+	}
+}
+
+impl From<String> for Secret {
+	fn from(secret: String) -> Self {
+		Secret(secret)
+	}
+}
+
+impl fmt::Debug for Secret {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if log_insecure() {
+			write!(f, "{:?}", self.0)
+		} else {
+			f.write_str("[redacted]")
+		}
+	}
+}
+
+impl fmt::Display for Secret {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if log_insecure() {
+			f.write_str(&self.0)
+		} else {
+			f.write_str("[redacted]")
+		}
+	}
+}
@@ -0,0 +1,193 @@
+use std::{collections::HashMap, net::SocketAddr, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use axum::{Json, Router, extract::State, http::{HeaderMap, StatusCode, header::{AUTHORIZATION, CONTENT_TYPE}}, response::IntoResponse, routing::{get, post}};
+use serde::Serialize;
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+use tracing::info;
+
+use crate::dyndns::{self, DynDns};
+use crate::events::{Event, EventBus, EventMessage};
+use crate::ipsource::IpFamily;
+use crate::metrics::Metrics;
+use crate::secret::Secret;
+use crate::summary::CycleSummary;
+
+/// Whether update cycles keep succeeding, followed on the event bus.
+#[derive(Clone)]
+pub struct Health {
+	started: Instant,
+	/// How long ago the last successful cycle may have finished
+	max_age: Duration,
+	cycles: Arc<Mutex<Cycles>>,
+}
+
+#[derive(Default)]
+struct Cycles {
+	last_success: Option<Instant>,
+	last: Option<CycleSummary>,
+}
+
+/// What the control endpoints act on, the updater's event bus and state.
+#[derive(Clone)]
+pub struct Control {
+	/// Requests have to carry it as `Authorization: Bearer`
+	pub token: Secret,
+	/// Update requests are published here, the update loop starts the next cycle right away
+	pub events: EventBus,
+	pub state: crate::state::State,
+}
+
+#[derive(Clone)]
+struct ControlState {
+	control: Control,
+	health: Health,
+}
+
+#[derive(Serialize)]
+struct HealthReport {
+	healthy: bool,
+	seconds_since_success: Option<u64>,
+	last_cycle: Option<CycleSummary>,
+}
+
+#[derive(Serialize)]
+struct StatusReport {
+	/// Last detected address per family
+	addresses: HashMap<IpFamily, String>,
+	records: Vec<PublishedRecord>,
+	#[serde(flatten)]
+	health: HealthReport,
+}
+
+#[derive(Serialize)]
+struct PublishedRecord {
+	domain: String,
+	#[serde(rename = "type")]
+	record_type: &'static str,
+	id: String,
+	content: String,
+}
+
+impl Health {
+	pub fn new(max_age: Duration) -> Self {
+		Self {
+			started: Instant::now(),
+			max_age,
+			cycles: Arc::new(Mutex::new(Cycles::default())),
+		}
+	}
+
+	/// Records the outcome of every cycle until the bus closes.
+	pub async fn track(self, mut events: Receiver<EventMessage>) {
+		loop {
+			let message = match events.recv().await {
+				Ok(message) => message,
+				Err(RecvError::Lagged(_)) => continue,
+				Err(RecvError::Closed) => return,
+			};
+
+			if let Event::CycleFinished { summary } = message.event {
+				let mut cycles = self.cycles.lock().unwrap();
+				if summary.exit_code() == 0 {
+					cycles.last_success = Some(Instant::now());
+				}
+				cycles.last = Some(summary);
+			}
+		}
+	}
+
+	/// Healthy while the last successful cycle is recent enough, or the first ones are still
+	/// within that time of the start.
+	fn report(&self) -> HealthReport {
+		let cycles = self.cycles.lock().unwrap();
+		let since_success = cycles.last_success.map(|at| at.elapsed());
+		HealthReport {
+			healthy: since_success.unwrap_or_else(|| self.started.elapsed()) <= self.max_age,
+			seconds_since_success: since_success.map(|elapsed| elapsed.as_secs()),
+			last_cycle: cycles.last.clone(),
+		}
+	}
+}
+
+/// Serves `/healthz`, 200 while healthy and 503 otherwise, with the details as JSON, and the
+/// Prometheus `/metrics`. With `control` also `POST /update` to start a cycle, `GET /status` and
+/// `GET /records`, which answer 401 without its token, with `dyndns` the dyndns2 `/nic/update`.
+pub async fn serve(listen: SocketAddr, health: Health, metrics: Arc<Mutex<Metrics>>, control: Option<Control>, dyndns: Option<DynDns>) -> Result<(), Box<dyn std::error::Error>> {
+	let mut app = Router::new()
+		.route("/healthz", get(healthz))
+		.with_state(health.clone())
+		.merge(Router::new().route("/metrics", get(metrics_text)).with_state(metrics));
+	if let Some(control) = control {
+		app = app.merge(Router::new()
+			.route("/update", post(update))
+			.route("/status", get(status))
+			.route("/records", get(records))
+			.with_state(ControlState { control, health }));
+	}
+	if let Some(dyndns) = dyndns {
+		app = app.merge(dyndns::router(dyndns));
+	}
+
+	let listener = tokio::net::TcpListener::bind(listen).await
+		.map_err(|e| format!("Could not listen on {}: {}", listen, e))?;
+	info!("HTTP server listening on {}", listener.local_addr()?);
+	axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
+
+	Ok(())
+}
+
+async fn healthz(State(health): State<Health>) -> (StatusCode, Json<HealthReport>) {
+	let report = health.report();
+	let status = if report.healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+	(status, Json(report))
+}
+
+async fn metrics_text(State(metrics): State<Arc<Mutex<Metrics>>>) -> impl IntoResponse {
+	let body = metrics.lock().unwrap().render();
+	([(CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+/// Fails with 401 unless the request carries the control token.
+fn authorize(control: &Control, headers: &HeaderMap) -> Result<(), StatusCode> {
+	let token = headers.get(AUTHORIZATION)
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.strip_prefix("Bearer "));
+	match token {
+		Some(token) if token.trim() == control.token.expose() => Ok(()),
+		_ => Err(StatusCode::UNAUTHORIZED),
+	}
+}
+
+async fn update(State(state): State<ControlState>, headers: HeaderMap) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+	authorize(&state.control, &headers)?;
+	info!("Update requested over HTTP");
+	state.control.events.emit(Event::UpdateRequested { reason: "requested over HTTP".to_string() });
+	Ok((StatusCode::ACCEPTED, Json(serde_json::json!({ "triggered": true }))))
+}
+
+async fn status(State(state): State<ControlState>, headers: HeaderMap) -> Result<Json<StatusReport>, StatusCode> {
+	authorize(&state.control, &headers)?;
+	Ok(Json(StatusReport {
+		addresses: state.control.state.addresses().await,
+		records: published_records(&state.control.state).await,
+		health: state.health.report(),
+	}))
+}
+
+async fn records(State(state): State<ControlState>, headers: HeaderMap) -> Result<Json<Vec<PublishedRecord>>, StatusCode> {
+	authorize(&state.control, &headers)?;
+	Ok(Json(published_records(&state.control.state).await))
+}
+
+async fn published_records(state: &crate::state::State) -> Vec<PublishedRecord> {
+	let mut records: Vec<PublishedRecord> = state.records().await.into_iter()
+		.flat_map(|(domain, records)| records.into_iter().map(move |(family, record)| PublishedRecord {
+			domain: domain.clone(),
+			record_type: family.record_type(),
+			id: record.id,
+			content: record.content,
+		}))
+		.collect();
+	records.sort_by(|a, b| (&a.domain, a.record_type).cmp(&(&b.domain, b.record_type)));
+	records
+}
@@ -0,0 +1,135 @@
+use std::{ffi::OsString, path::PathBuf, sync::{Arc, OnceLock}, time::Duration};
+
+use cloudflaredyndns::config::Config;
+use cloudflaredyndns::exit;
+use tokio::{runtime::Handle, sync::watch};
+use tracing::{error, info};
+use windows_service::{
+	define_windows_service,
+	service::{ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode, ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType},
+	service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+	service_dispatcher,
+	service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+const SERVICE_NAME: &str = "CloudflareDynDNS";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+/// What the service thread runs on, set before the dispatcher starts it
+static SERVICE: OnceLock<(Handle, Option<PathBuf>)> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Registers the service, started at boot with `run-service` and the absolute path of `config`.
+pub fn install(config: Option<PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+	let mut launch_arguments = vec![OsString::from("run-service")];
+	if let Some(config) = config {
+		launch_arguments.push(OsString::from("--config"));
+		launch_arguments.push(std::path::absolute(config)?.into_os_string());
+	}
+
+	let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)?;
+	let service = manager.create_service(&ServiceInfo {
+		name: OsString::from(SERVICE_NAME),
+		display_name: OsString::from("Cloudflare Dynamic DNS"),
+		service_type: SERVICE_TYPE,
+		start_type: ServiceStartType::AutoStart,
+		error_control: ServiceErrorControl::Normal,
+		executable_path: std::env::current_exe()?,
+		launch_arguments,
+		dependencies: Vec::new(),
+		account_name: None,
+		account_password: None,
+	}, ServiceAccess::CHANGE_CONFIG)?;
+	service.set_description("Keeps Cloudflare DNS records pointed at the public address of this host")?;
+
+	info!("Service '{}' installed, start it with: sc start {}", SERVICE_NAME, SERVICE_NAME);
+	Ok(())
+}
+
+/// Stops the service if it is running and removes it.
+pub fn uninstall() -> Result<(), Box<dyn std::error::Error>> {
+	let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+	let service = manager.open_service(SERVICE_NAME, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)?;
+
+	// Removed once the last handle is closed, a running service would linger until stopped
+	if service.query_status()?.current_state != ServiceState::Stopped {
+		service.stop()?;
+		info!("Stopping service '{}'", SERVICE_NAME);
+	}
+	service.delete()?;
+
+	info!("Service '{}' removed", SERVICE_NAME);
+	Ok(())
+}
+
+/// Hands the process to the service control manager, which runs [`service_main`] on a thread of
+/// its own until the service stops. Only works when started by the SCM.
+pub fn run(config: Option<PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+	let _ = SERVICE.set((Handle::current(), config));
+	tokio::task::block_in_place(|| service_dispatcher::start(SERVICE_NAME, ffi_service_main))?;
+	Ok(())
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+	let Some((runtime, config_file)) = SERVICE.get() else {
+		return;
+	};
+
+	// Stop and shutdown set the same flag as SIGTERM on Unix, the running cycle gets to finish
+	let (shutdown_tx, shutdown) = watch::channel(false);
+	let status = match service_control_handler::register(SERVICE_NAME, move |control| match control {
+		ServiceControl::Stop | ServiceControl::Shutdown => {
+			shutdown_tx.send_replace(true);
+			ServiceControlHandlerResult::NoError
+		},
+		ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+		_ => ServiceControlHandlerResult::NotImplemented,
+	}) {
+		Ok(status) => status,
+		Err(e) => {
+			error!("Error registering the service control handler: {}", e);
+			return;
+		},
+	};
+
+	set_status(&status, ServiceState::Running, 0);
+	let mut stopping = shutdown.clone();
+	runtime.spawn(async move {
+		if stopping.wait_for(|shutdown| *shutdown).await.is_ok() {
+			info!("Service stop requested, finishing running updates before shutting down");
+			set_status(&status, ServiceState::StopPending, 0);
+		}
+	});
+
+	let code = runtime.block_on(async {
+		match Config::load_from(config_file.clone()) {
+			// Cycles keep running until the service is stopped
+			Ok(mut config) => {
+				config.run_once = false;
+				crate::daemon(Arc::new(config), shutdown).await
+			},
+			Err(e) => {
+				error!("Error parsing config: {}", e);
+				exit::CONFIG
+			},
+		}
+	});
+	set_status(&status, ServiceState::Stopped, code);
+}
+
+fn set_status(status: &ServiceStatusHandle, state: ServiceState, code: i32) {
+	let result = status.set_service_status(ServiceStatus {
+		service_type: SERVICE_TYPE,
+		current_state: state,
+		controls_accepted: if state == ServiceState::Running { ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN } else { ServiceControlAccept::empty() },
+		exit_code: ServiceExitCode::Win32(code as u32),
+		checkpoint: 0,
+		// Running updates get up to 30 seconds after a stop
+		wait_hint: if state == ServiceState::StopPending { Duration::from_secs(40) } else { Duration::default() },
+		process_id: None,
+	});
+	if let Err(e) = result {
+		error!("Error reporting the service status: {}", e);
+	}
+}
@@ -0,0 +1,224 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::Arc, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+
+use crate::ipsource::IpFamily;
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// A record as it was last published to Cloudflare.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublishedRecord {
+	pub id: String,
+	pub content: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Stored {
+	/// Zone ID per zone name
+	#[serde(default)]
+	zones: HashMap<String, String>,
+	/// When each zone ID was looked up, in seconds since the epoch
+	#[serde(default)]
+	zones_resolved: HashMap<String, u64>,
+	#[serde(default)]
+	records: HashMap<String, HashMap<IpFamily, PublishedRecord>>,
+	/// Last detected public address per family
+	#[serde(default)]
+	addresses: HashMap<IpFamily, String>,
+	/// When the content of each record last changed, for the hold-down, not kept across restarts
+	#[serde(skip)]
+	changed: HashMap<(String, IpFamily), Instant>,
+	#[serde(skip)]
+	dirty: bool,
+}
+
+/// What has been published to Cloudflare, so records whose address didn't change since the last
+/// successful update aren't read or written again. Kept in `CF_STATE_FILE` when it is set, so a
+/// restart doesn't look up every zone and record again. A `.db`/`.sqlite` file is an SQLite
+/// database with the `sqlite` feature, JSON otherwise.
+#[derive(Clone, Default)]
+pub struct State {
+	path: Option<PathBuf>,
+	stored: Arc<Mutex<Stored>>,
+}
+
+impl Stored {
+	/// The part kept across restarts, to be written from a blocking thread.
+	#[cfg(feature = "sqlite")]
+	fn snapshot(&self) -> Self {
+		Self {
+			zones: self.zones.clone(),
+			zones_resolved: self.zones_resolved.clone(),
+			records: self.records.clone(),
+			addresses: self.addresses.clone(),
+			..Self::default()
+		}
+	}
+}
+
+impl State {
+	/// Loads the state file, starting empty when it doesn't exist yet or can't be read.
+	pub async fn load(path: Option<PathBuf>) -> Self {
+		let stored = match &path {
+			#[cfg(feature = "sqlite")]
+			Some(path) if is_database(path) => {
+				let database = path.clone();
+				tokio::task::spawn_blocking(move || sqlite::load(&database))
+					.await
+					.map_err(|e| e.to_string())
+					.and_then(|loaded| loaded.map_err(|e| e.to_string()))
+					.unwrap_or_else(|e| {
+						error!("Error reading state database '{}': {}", path.display(), e);
+						Stored::default()
+					})
+			},
+			#[cfg(not(feature = "sqlite"))]
+			Some(path) if is_database(path) => {
+				warn!("State file '{}' looks like a database but SQLite support isn't built in, using JSON", path.display());
+				read_json(path).await
+			},
+			Some(path) => read_json(path).await,
+			None => Stored::default(),
+		};
+
+		Self {
+			path,
+			stored: Arc::new(Mutex::new(stored)),
+		}
+	}
+
+	/// Writes the state file if anything changed since it was last written.
+	pub async fn save(&self) -> Result<(), std::io::Error> {
+		let Some(path) = &self.path else {
+			return Ok(());
+		};
+
+		let mut stored = self.stored.lock().await;
+		if !stored.dirty {
+			return Ok(());
+		}
+
+		#[cfg(feature = "sqlite")]
+		if is_database(path) {
+			// SQLite waits up to its busy timeout for the history writer, off the async workers
+			let (database, snapshot) = (path.clone(), stored.snapshot());
+			tokio::task::spawn_blocking(move || sqlite::save(&database, &snapshot))
+				.await
+				.map_err(std::io::Error::other)?
+				.map_err(std::io::Error::other)?;
+			stored.dirty = false;
+			return Ok(());
+		}
+
+		// Replaced in one step, a crash while writing leaves the previous state behind
+		let json = serde_json::to_string_pretty(&*stored).map_err(std::io::Error::other)?;
+		let tmp = path.with_extension("tmp");
+		tokio::fs::write(&tmp, json).await?;
+		tokio::fs::rename(&tmp, path).await?;
+		stored.dirty = false;
+		Ok(())
+	}
+
+	/// Remembers the detected address of `family`, returns the previous one if it was different.
+	pub async fn set_address(&self, family: IpFamily, ip: &str) -> Option<String> {
+		let mut stored = self.stored.lock().await;
+		if stored.addresses.get(&family).is_some_and(|previous| previous == ip) {
+			return None;
+		}
+		stored.dirty = true;
+		stored.addresses.insert(family, ip.to_string())
+	}
+
+	/// The zone ID, unless it was looked up more than `max_age` ago (or by a version that didn't
+	/// record when).
+	pub async fn zone_id(&self, zone: &str, max_age: Duration) -> Option<String> {
+		let stored = self.stored.lock().await;
+		let resolved = stored.zones_resolved.get(zone)?;
+		if now().saturating_sub(*resolved) > max_age.as_secs() {
+			return None;
+		}
+		stored.zones.get(zone).cloned()
+	}
+
+	pub async fn set_zone_id(&self, zone: &str, id: &str) {
+		let mut stored = self.stored.lock().await;
+		stored.zones.insert(zone.to_string(), id.to_string());
+		stored.zones_resolved.insert(zone.to_string(), now());
+		stored.dirty = true;
+	}
+
+	pub async fn forget_zone(&self, zone: &str) {
+		let mut stored = self.stored.lock().await;
+		stored.zones_resolved.remove(zone);
+		let removed = stored.zones.remove(zone).is_some();
+		stored.dirty |= removed;
+	}
+
+	/// The record last published for `family` of `domain`.
+	pub async fn published(&self, domain: &str, family: IpFamily) -> Option<PublishedRecord> {
+		self.stored.lock().await.records.get(domain).and_then(|records| records.get(&family)).cloned()
+	}
+
+	pub async fn set_published(&self, domain: &str, family: IpFamily, id: &str, content: &str) {
+		let mut stored = self.stored.lock().await;
+		let record = PublishedRecord { id: id.to_string(), content: content.to_string() };
+		let previous = stored.records.entry(domain.to_string()).or_default().insert(family, record);
+		if previous.is_some_and(|previous| previous.content != content) {
+			stored.changed.insert((domain.to_string(), family), Instant::now());
+		}
+		stored.dirty = true;
+	}
+
+	/// How much longer the record is held down, when its content changed less than `hold_down` ago.
+	pub async fn held_down(&self, domain: &str, family: IpFamily, hold_down: Duration) -> Option<Duration> {
+		let changed = *self.stored.lock().await.changed.get(&(domain.to_string(), family))?;
+		hold_down.checked_sub(changed.elapsed()).filter(|remaining| !remaining.is_zero())
+	}
+
+	/// The last detected address of every family.
+	pub async fn addresses(&self) -> HashMap<IpFamily, String> {
+		self.stored.lock().await.addresses.clone()
+	}
+
+	/// Every record published, by domain and family.
+	pub async fn records(&self) -> HashMap<String, HashMap<IpFamily, PublishedRecord>> {
+		self.stored.lock().await.records.clone()
+	}
+
+	/// Forgets the record, the next cycle checks it against Cloudflare again.
+	pub async fn forget(&self, domain: &str, family: IpFamily) {
+		let mut stored = self.stored.lock().await;
+		let removed = stored.records.get_mut(domain).is_some_and(|records| records.remove(&family).is_some());
+		stored.dirty |= removed;
+	}
+}
+
+fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or_default()
+}
+
+/// `.db`, `.sqlite` and `.sqlite3` state files are SQLite databases.
+pub fn is_database(path: &Path) -> bool {
+	path.extension().and_then(|e| e.to_str()).is_some_and(|e| matches!(e, "db" | "sqlite" | "sqlite3"))
+}
+
+async fn read_json(path: &Path) -> Stored {
+	match tokio::fs::read_to_string(path).await {
+		Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+			warn!("Ignoring invalid state file '{}': {}", path.display(), e);
+			Stored::default()
+		}),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Stored::default(),
+		Err(e) => {
+			error!("Error reading state file '{}': {}", path.display(), e);
+			Stored::default()
+		},
+	}
+}
@@ -0,0 +1,83 @@
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use rusqlite::{Connection, params};
+
+use super::{PublishedRecord, Stored};
+use crate::ipsource::IpFamily;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS zones (zone TEXT PRIMARY KEY, id TEXT NOT NULL, resolved INTEGER NOT NULL);
+CREATE TABLE IF NOT EXISTS records (domain TEXT NOT NULL, family TEXT NOT NULL, id TEXT NOT NULL, content TEXT NOT NULL, PRIMARY KEY (domain, family));
+CREATE TABLE IF NOT EXISTS addresses (family TEXT PRIMARY KEY, ip TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS address_changes (timestamp INTEGER NOT NULL, family TEXT NOT NULL, old TEXT NOT NULL, new TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS outcomes (timestamp INTEGER NOT NULL, domain TEXT NOT NULL, family TEXT, content TEXT, outcome TEXT NOT NULL, error TEXT);
+";
+
+/// Opens the database and creates the tables it doesn't have yet. WAL mode lets other processes
+/// read it while the updater writes.
+pub fn open(path: &Path) -> rusqlite::Result<Connection> {
+	let connection = Connection::open(path)?;
+	connection.busy_timeout(Duration::from_secs(5))?;
+	connection.pragma_update(None, "journal_mode", "WAL")?;
+	connection.execute_batch(SCHEMA)?;
+	Ok(connection)
+}
+
+/// Families are stored as their record type, `A` or `AAAA`.
+pub fn family_column(family: IpFamily) -> &'static str {
+	family.record_type()
+}
+
+fn parse_family(value: &str) -> Option<IpFamily> {
+	[IpFamily::V4, IpFamily::V6].into_iter().find(|family| family.record_type() == value)
+}
+
+pub(super) fn load(path: &Path) -> rusqlite::Result<Stored> {
+	let connection = open(path)?;
+	let mut stored = Stored::default();
+
+	let mut zones = connection.prepare("SELECT zone, id, resolved FROM zones")?;
+	for row in zones.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?)))? {
+		let (zone, id, resolved) = row?;
+		stored.zones_resolved.insert(zone.clone(), resolved as u64);
+		stored.zones.insert(zone, id);
+	}
+
+	let mut records = connection.prepare("SELECT domain, family, id, content FROM records")?;
+	for row in records.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?)))? {
+		let (domain, family, id, content) = row?;
+		if let Some(family) = parse_family(&family) {
+			stored.records.entry(domain).or_insert_with(HashMap::new).insert(family, PublishedRecord { id, content });
+		}
+	}
+
+	let mut addresses = connection.prepare("SELECT family, ip FROM addresses")?;
+	for row in addresses.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))? {
+		let (family, ip) = row?;
+		if let Some(family) = parse_family(&family) {
+			stored.addresses.insert(family, ip);
+		}
+	}
+
+	Ok(stored)
+}
+
+/// Replaces the stored state in one transaction.
+pub(super) fn save(path: &Path, stored: &Stored) -> rusqlite::Result<()> {
+	let mut connection = open(path)?;
+	let transaction = connection.transaction()?;
+	transaction.execute_batch("DELETE FROM zones; DELETE FROM records; DELETE FROM addresses;")?;
+	for (zone, id) in &stored.zones {
+		let resolved = stored.zones_resolved.get(zone).copied().unwrap_or_default();
+		transaction.execute("INSERT INTO zones (zone, id, resolved) VALUES (?1, ?2, ?3)", params![zone, id, resolved as i64])?;
+	}
+	for (domain, records) in &stored.records {
+		for (family, record) in records {
+			transaction.execute("INSERT INTO records (domain, family, id, content) VALUES (?1, ?2, ?3, ?4)", params![domain, family_column(*family), record.id, record.content])?;
+		}
+	}
+	for (family, ip) in &stored.addresses {
+		transaction.execute("INSERT INTO addresses (family, ip) VALUES (?1, ?2)", params![family_column(*family), ip])?;
+	}
+	transaction.commit()
+}
@@ -0,0 +1,118 @@
+use std::{fmt, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::exit;
+use crate::ipsource::IpFamily;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordOutcome {
+	Created,
+	Updated,
+	/// The record already had the address
+	Unchanged,
+	/// Vetoed by the script
+	Skipped,
+	/// The update, its verification or its probe failed
+	Failed,
+}
+
+impl fmt::Display for RecordOutcome {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let outcome = match self {
+			RecordOutcome::Created => "created",
+			RecordOutcome::Updated => "updated",
+			RecordOutcome::Unchanged => "unchanged",
+			RecordOutcome::Skipped => "skipped",
+			RecordOutcome::Failed => "failed",
+		};
+		f.write_str(outcome)
+	}
+}
+
+/// What happened to one record in a cycle. `family` is `None` when the whole domain failed
+/// before any record was looked at (e.g. the zone lookup).
+#[derive(Debug, Clone)]
+pub struct RecordReport {
+	pub domain: String,
+	pub family: Option<IpFamily>,
+	pub content: String,
+	pub outcome: RecordOutcome,
+}
+
+/// Totals of a cycle, published with [`crate::events::Event::CycleFinished`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CycleSummary {
+	pub domains: usize,
+	/// Enabled address families whose address couldn't be detected (or was dropped by the script)
+	pub missing_addresses: usize,
+	pub created: usize,
+	pub updated: usize,
+	pub unchanged: usize,
+	pub skipped: usize,
+	pub failed: usize,
+	pub api_calls: u64,
+	pub duration_ms: u64,
+}
+
+impl CycleSummary {
+	pub fn new(domains: usize, missing_addresses: usize, reports: &[RecordReport], api_calls: u64, duration: Duration) -> Self {
+		let count = |outcome: RecordOutcome| reports.iter().filter(|r| r.outcome == outcome).count();
+		Self {
+			domains,
+			missing_addresses,
+			created: count(RecordOutcome::Created),
+			updated: count(RecordOutcome::Updated),
+			unchanged: count(RecordOutcome::Unchanged),
+			skipped: count(RecordOutcome::Skipped),
+			failed: count(RecordOutcome::Failed),
+			api_calls,
+			duration_ms: duration.as_millis() as u64,
+		}
+	}
+
+	/// Process exit code of a single cycle run (`--once`): [`exit::SUCCESS`] when everything was
+	/// detected and updated, [`exit::UNAVAILABLE`] when nothing was and [`exit::PARTIAL`] otherwise.
+	pub fn exit_code(&self) -> i32 {
+		if self.failed == 0 && self.missing_addresses == 0 {
+			exit::SUCCESS
+		} else if self.created + self.updated + self.unchanged + self.skipped == 0 {
+			exit::UNAVAILABLE
+		} else {
+			exit::PARTIAL
+		}
+	}
+}
+
+impl fmt::Display for CycleSummary {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} domains in {:.1}s: {} created, {} updated, {} unchanged, {} skipped, {} failed, {} API calls",
+			self.domains, self.duration_ms as f64 / 1000.0, self.created, self.updated, self.unchanged, self.skipped, self.failed, self.api_calls)?;
+		if self.missing_addresses > 0 {
+			write!(f, ", {} addresses not detected", self.missing_addresses)?;
+		}
+		Ok(())
+	}
+}
+
+/// Prints one row per record, shown above the one line summary when attached to a terminal.
+pub fn print_table(reports: &[RecordReport]) {
+	let rows: Vec<[String; 4]> = reports.iter()
+		.map(|r| [
+			r.domain.clone(),
+			r.family.map(|f| f.record_type().to_string()).unwrap_or_else(|| "-".to_string()),
+			r.outcome.to_string(),
+			r.content.clone(),
+		])
+		.collect();
+
+	let header = ["DOMAIN", "TYPE", "RESULT", "CONTENT"].map(|h| h.to_string());
+	let widths: Vec<usize> = (0..4)
+		.map(|i| rows.iter().chain([&header]).map(|row| row[i].len()).max().unwrap_or(0))
+		.collect();
+
+	for row in [&header].into_iter().chain(&rows) {
+		println!("{:<w0$}  {:<w1$}  {:<w2$}  {}", row[0], row[1], row[2], row[3], w0 = widths[0], w1 = widths[1], w2 = widths[2]);
+	}
+}
@@ -0,0 +1,100 @@
+use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+#[cfg(unix)]
+use tracing::{debug, warn};
+
+/// Talks to systemd over `$NOTIFY_SOCKET` (`Type=notify`, `WatchdogSec=`). Every call does nothing
+/// when the service manager didn't ask for notifications.
+pub struct Notifier {
+	#[cfg(unix)]
+	socket: Option<(UnixDatagram, SocketAddr)>,
+	/// Half the watchdog timeout, how often `WATCHDOG=1` is sent
+	watchdog: Option<Duration>,
+	ready: bool,
+}
+
+impl Notifier {
+	pub fn from_env() -> Self {
+		#[cfg(unix)]
+		let socket = std::env::var("NOTIFY_SOCKET").ok()
+			.filter(|path| !path.is_empty())
+			.and_then(|path| match connect(&path) {
+				Ok(socket) => Some(socket),
+				Err(e) => {
+					warn!("Could not use systemd notification socket '{}': {}", path, e);
+					None
+				}
+			});
+
+		// The timeout is meant for the main process only
+		let for_us = std::env::var("WATCHDOG_PID").ok()
+			.and_then(|pid| pid.trim().parse::<u32>().ok())
+			.is_none_or(|pid| pid == std::process::id());
+		let watchdog = std::env::var("WATCHDOG_USEC").ok()
+			.and_then(|usec| usec.trim().parse::<u64>().ok())
+			.filter(|usec| *usec > 0 && for_us)
+			.map(|usec| Duration::from_micros(usec) / 2);
+
+		Self {
+			#[cfg(unix)]
+			socket,
+			watchdog,
+			ready: false,
+		}
+	}
+
+	/// `READY=1`, sent once.
+	pub fn ready(&mut self) {
+		if !self.ready {
+			self.ready = true;
+			self.send("READY=1");
+		}
+	}
+
+	pub fn status(&self, status: &str) {
+		self.send(&format!("STATUS={}", status.replace('\n', " ")));
+	}
+
+	pub fn watchdog(&self) {
+		if self.watchdog.is_some() {
+			self.send("WATCHDOG=1");
+		}
+	}
+
+	/// Completes when the next `WATCHDOG=1` is due, never without a watchdog.
+	pub async fn watchdog_due(&self) {
+		match self.watchdog {
+			Some(interval) => tokio::time::sleep(interval).await,
+			None => std::future::pending().await,
+		}
+	}
+
+	#[cfg_attr(not(unix), allow(unused_variables))]
+	fn send(&self, state: &str) {
+		#[cfg(unix)]
+		if let Some((socket, addr)) = &self.socket {
+			debug!("systemd notification: {}", state);
+			if let Err(e) = socket.send_to_addr(state.as_bytes(), addr) {
+				warn!("Error notifying systemd: {}", e);
+			}
+		}
+	}
+}
+
+/// Paths starting with `@` are in the abstract namespace (Linux only).
+#[cfg(unix)]
+fn connect(path: &str) -> std::io::Result<(UnixDatagram, SocketAddr)> {
+	let addr = match path.strip_prefix('@') {
+		#[cfg(target_os = "linux")]
+		Some(name) => {
+			use std::os::linux::net::SocketAddrExt;
+			SocketAddr::from_abstract_name(name)?
+		},
+		#[cfg(not(target_os = "linux"))]
+		Some(_) => return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "abstract sockets are Linux only")),
+		None => SocketAddr::from_pathname(path)?,
+	};
+	Ok((UnixDatagram::unbound()?, addr))
+}
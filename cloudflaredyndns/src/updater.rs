@@ -0,0 +1,873 @@
+use std::{collections::HashMap, io::IsTerminal, net::IpAddr, sync::Arc, time::{Duration, Instant}};
+
+use futures::StreamExt;
+use tokio::sync::{Semaphore, broadcast::error::{RecvError, TryRecvError}, watch};
+use tracing::{Instrument, debug, error, info, warn};
+
+use crate::cleanup;
+use crate::cloudflare::{CloudflareClient, Credentials};
+use crate::config::{Config, DnsFirst, DomainConfig};
+use crate::error::Error;
+use crate::events::{Event, EventBus};
+use crate::failover::Failover;
+use crate::ipsource::{self, IpFamily, Ipv6FailurePolicy};
+use crate::iplist;
+use crate::loadbalancer;
+use crate::provider::{CloudflareOptions, DnsProvider, RecordChange, RecordRequest, RecordUpdate};
+use crate::state::State;
+use crate::summary::{self, CycleSummary, RecordOutcome, RecordReport};
+use crate::systemd;
+
+/// How often the resolver is asked again while a change propagates
+const PROPAGATION_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Runs update cycles until `config.run_once` is set or `shutdown` is, then returns the summary of
+/// the last cycle. A new configuration from `configs` applies from the next cycle; the HTTP
+/// server, control socket, notifiers and state file keep the settings they were started with.
+/// The loop is a consumer of `events` like the notifiers: an [`Event::UpdateRequested`] (address
+/// watchers, `POST /update`) or an [`Event::IpChanged`] published by something else starts the
+/// next cycle right away. `state` is loaded from `CF_STATE_FILE`. `cloudflare` (see
+/// [`cloudflare_client`]) is rebuilt on a reload but keeps its rate limit pause and circuit
+/// breaker, which other users of the same client such as the dyndns endpoint share. Each cycle
+/// only updates the domains whose own `update_interval` has passed, all of them after a request or
+/// a reload.
+pub async fn run(mut configs: watch::Receiver<Arc<Config>>, mut cloudflare: CloudflareClient, events: EventBus, state: State, mut shutdown: watch::Receiver<bool>) -> CycleSummary {
+	let mut requests = events.subscribe();
+	let mut config = configs.borrow_and_update().clone();
+	if config.dry_run {
+		warn!("Dry run, record changes are only logged");
+	}
+	let mut failover = Failover::new(config.failover_after);
+	let mut systemd = systemd::Notifier::from_env();
+	let interactive = std::io::stdout().is_terminal();
+	// When each domain is updated next, domains that haven't been updated yet are due
+	let mut next_updates: HashMap<String, Instant> = HashMap::new();
+	let mut update_all = true;
+	// Cycles in a row that failed, the interval is stretched until one succeeds
+	let mut failed_cycles: u32 = 0;
+	// New addresses waiting to be confirmed by CF_DEBOUNCE_CYCLES detections, with the count so far
+	let mut candidates: HashMap<IpFamily, (String, u32)> = HashMap::new();
+	// IPv6 detections that failed in a row, and until when IPv6 is left alone once there were
+	// CF_IPV6_FAILURE_THRESHOLD of them
+	let mut ipv6_failures: u32 = 0;
+	let mut ipv6_paused_until: Option<Instant> = None;
+	// Address each Load Balancer origin was last set to
+	let mut origins: HashMap<(String, IpFamily), String> = HashMap::new();
+	// Entry each IP List was last synced to
+	let mut ip_lists: HashMap<(String, IpFamily), String> = HashMap::new();
+	
+	loop {
+		if configs.has_changed().unwrap_or(false) {
+			update_all = true;
+			let previous = std::mem::replace(&mut config, configs.borrow_and_update().clone());
+			cloudflare = cloudflare_client(&config, &events).shared_limits(&cloudflare);
+			origins.clear();
+			ip_lists.clear();
+			if previous.failover_after != config.failover_after {
+				failover = Failover::new(config.failover_after);
+			}
+		}
+
+		events.emit(Event::CycleStarted);
+		let started = Instant::now();
+		debug!("Updating IP addresses...");
+		let detect_ipv6 = ipv6_paused_until.is_none_or(|until| until <= started);
+		let ipv6_quiet = ipv6_failures >= config.ipv6_failure_threshold;
+		let (mut ipv4, mut ipv6) = match update_ips(&config, &events, detect_ipv6, ipv6_quiet).await {
+			Ok((i4, i6)) => {
+				(i4, i6)
+			},
+			Err(e) => {
+				error!("Error updating IPs: {}", e);
+				(None, None)
+			}
+		};
+
+		if config.ipv6_enabled && detect_ipv6 {
+			if ipv6.is_some() {
+				if ipv6_failures >= config.ipv6_failure_threshold {
+					info!("Public IPv6 detected again after {} failed cycles", ipv6_failures);
+				}
+				ipv6_failures = 0;
+				ipv6_paused_until = None;
+			} else {
+				ipv6_failures += 1;
+				match config.ipv6_failure_policy {
+					_ if ipv6_failures < config.ipv6_failure_threshold => {},
+					Ipv6FailurePolicy::Retry if ipv6_failures == config.ipv6_failure_threshold => warn!("IPv6 detection failed {} cycles in a row, still trying every cycle", ipv6_failures),
+					Ipv6FailurePolicy::Retry => {},
+					policy => {
+						let pause = config.ipv6_pause;
+						warn!("IPv6 detection failed {} cycles in a row, pausing IPv6 updates for {} seconds", ipv6_failures, pause);
+						ipv6_paused_until = Some(started + Duration::from_secs(pause));
+						if policy == Ipv6FailurePolicy::Delete && ipv6_failures == config.ipv6_failure_threshold {
+							let deleted = cleanup::withdraw(&config, &cloudflare, &state, IpFamily::V6).await;
+							warn!("Deleted {} AAAA records, they are created again once IPv6 is back", deleted);
+						}
+					},
+				}
+			}
+		}
+		// With retry the failures keep counting against the cycle
+		let ipv6_given_up = config.ipv6_failure_policy != Ipv6FailurePolicy::Retry && ipv6_failures >= config.ipv6_failure_threshold;
+
+		let accepted = state.addresses().await;
+		for (family, ip) in [(IpFamily::V4, &mut ipv4), (IpFamily::V6, &mut ipv6)] {
+			if let Some(detected) = ip.take() {
+				*ip = Some(debounce(&mut candidates, config.debounce_cycles, family, detected, accepted.get(&family)));
+			}
+		}
+		
+		for (family, ip) in [(IpFamily::V4, &ipv4), (IpFamily::V6, &ipv6)] {
+			let Some(ip) = ip else {
+				continue;
+			};
+			if let Some(old) = state.set_address(family, ip).await {
+				info!("Public {} changed from {} to {}", family, old, ip);
+				events.emit(Event::IpChanged { family, old, new: ip.clone() });
+			}
+		}
+
+		let mut reports = Vec::new();
+		let mut domains = 0;
+		
+		let canary_passed = match (&config.canary, ipv4.is_some() || ipv6.is_some()) {
+			(Some(canary), true) => {
+				domains += 1;
+				let provider: Arc<dyn DnsProvider> = Arc::new(cloudflare.account(canary.credentials.as_ref()));
+				let canary_reports = match update_domain(&provider, &config, &events, &failover, &state, canary, ipv4.clone(), ipv6.clone(), true).await {
+					Ok(canary_reports) => canary_reports,
+					Err(e) => {
+						error!("Error updating domain '{}': {}", canary.name, e);
+						events.emit(Event::UpdateFailed { domain: canary.name.clone(), family: None, error: e.to_string() });
+						vec![RecordReport { domain: canary.name.clone(), family: None, content: String::new(), outcome: RecordOutcome::Failed }]
+					}
+				};
+				// A canary that was only skipped (conflict, script veto, missing record) proves nothing
+				let passed = canary_reports.iter().all(|r| r.outcome != RecordOutcome::Failed)
+					&& canary_reports.iter().any(|r| matches!(r.outcome, RecordOutcome::Created | RecordOutcome::Updated | RecordOutcome::Unchanged));
+				reports.extend(canary_reports);
+				if !passed {
+					error!("Canary '{}' wasn't updated or verified, not updating the remaining domains", canary.name);
+					events.emit(Event::CanaryFailed { domain: canary.name.clone() });
+				}
+				passed
+			},
+			_ => true,
+		};
+
+		if !canary_passed {
+			// Alerted above, keep the current records until the next cycle
+		} else if ipv4.is_some() || ipv6.is_some() {
+			debug!("Updating domains...");
+
+			// Hundreds of domains at once get throttled by Cloudflare or drop connections
+			let permits = Arc::new(Semaphore::new(config.max_concurrency));
+			let due: Vec<&DomainConfig> = config.domains.iter()
+				.filter(|d| update_all || next_updates.get(&d.name).is_none_or(|next| *next <= started))
+				.collect();
+			update_all = false;
+			let mut futures_list = Vec::new();
+			for domain in due {
+				next_updates.insert(domain.name.clone(), started + Duration::from_secs(domain.update_interval));
+				let domain_c = domain.clone();
+				let provider: Arc<dyn DnsProvider> = Arc::new(cloudflare.account(domain.credentials.as_ref()));
+				let config_c = config.clone();
+				let events_c = events.clone();
+				let failover_c = failover.clone();
+				let state_c = state.clone();
+				let (ipv4_c, ipv6_c) = (ipv4.clone(), ipv6.clone()); 
+				let permits_c = permits.clone();
+				
+				let future = tokio::spawn(
+					async move {
+						let _permit = permits_c.acquire_owned().await;
+						match plan_domain(&provider, &config_c, &events_c, &failover_c, &state_c, &domain_c, ipv4_c, ipv6_c, false).await {
+							Ok(plan) => (domain_c, plan),
+							Err(e) => {
+								error!("Error updating domain '{}': {}", domain_c.name, e);
+								if e.is_auth() {
+									error!("Cloudflare rejected the token, check that it can read the zone of '{}' and edit its DNS records", domain_c.name);
+								}
+								events_c.emit(Event::UpdateFailed { domain: domain_c.name.clone(), family: None, error: e.to_string() });
+								let failed = RecordReport { domain: domain_c.name.clone(), family: None, content: String::new(), outcome: RecordOutcome::Failed };
+								(domain_c, DomainPlan { reports: vec![failed], pending: Vec::new(), zone: None })
+							}
+						}
+					}
+				);
+
+				futures_list.push(future);
+			}
+			domains += futures_list.len();
+
+			// The records of a zone are sent together, the provider can batch them
+			let mut zones: HashMap<(Option<Credentials>, String, String), Vec<(DomainConfig, PendingRecord)>> = HashMap::new();
+			for plan in futures::future::join_all(futures_list).await {
+				match plan {
+					Ok((domain, DomainPlan { reports: domain_reports, pending, zone })) => {
+						reports.extend(domain_reports);
+						if let Some((base_domain, zone_id)) = zone {
+							zones.entry((domain.credentials.clone(), base_domain, zone_id)).or_default()
+								.extend(pending.into_iter().map(|record| (domain.clone(), record)));
+						}
+					},
+					Err(e) => error!("Domain update task failed: {}", e),
+				}
+			}
+
+			let mut futures_list = Vec::new();
+			for ((credentials, base_domain, zone_id), records) in zones {
+				let provider: Arc<dyn DnsProvider> = Arc::new(cloudflare.account(credentials.as_ref()));
+				let config_c = config.clone();
+				let events_c = events.clone();
+				let state_c = state.clone();
+				let permits_c = permits.clone();
+
+				futures_list.push(tokio::spawn(
+					async move {
+						let _permit = permits_c.acquire_owned().await;
+						publish(&provider, &config_c, &events_c, &state_c, &base_domain, &zone_id, records, false).await
+					}
+				));
+			}
+			for zone_reports in futures::future::join_all(futures_list).await {
+				match zone_reports {
+					Ok(zone_reports) => reports.extend(zone_reports),
+					Err(e) => error!("Zone update task failed: {}", e),
+				}
+			}
+
+			// Load Balancer pools and IP Lists only exist at Cloudflare
+			let provider: Arc<dyn DnsProvider> = Arc::new(cloudflare.clone());
+			if let Some(account) = provider.cloudflare() {
+				reports.extend(loadbalancer::update_origins(account, &events, &config.lb_origins, &mut origins, ipv4.as_deref(), ipv6.as_deref()).await);
+				reports.extend(iplist::sync_lists(account, &events, &config.ip_lists, &mut ip_lists, ipv4.as_deref(), ipv6.as_deref()).await);
+			}
+
+		} else {
+			warn!("No IP addresses to update");
+		}
+
+		if let Err(e) = state.save().await {
+			error!("Error writing state file: {}", e);
+		}
+
+		reports.sort_by(|a, b| a.domain.cmp(&b.domain));
+		let missing_addresses = [(config.ipv4_enabled, &ipv4), (config.ipv6_enabled && !ipv6_given_up, &ipv6)].iter()
+			.filter(|(enabled, ip)| *enabled && ip.is_none())
+			.count();
+		let summary = CycleSummary::new(domains, missing_addresses, &reports, cloudflare.take_api_calls(), started.elapsed());
+		if interactive && !reports.is_empty() {
+			summary::print_table(&reports);
+		}
+		info!("Cycle finished, {}", summary);
+		events.emit(Event::CycleFinished { summary: summary.clone() });
+
+		let addresses = [(IpFamily::V4, &ipv4), (IpFamily::V6, &ipv6)].iter()
+			.filter_map(|(family, ip)| ip.as_ref().map(|ip| format!("{} {}", family, ip)))
+			.collect::<Vec<_>>();
+		systemd.status(&format!("{}, {}", if addresses.is_empty() { "No addresses".to_string() } else { addresses.join(", ") }, summary));
+		systemd.watchdog();
+		if summary.exit_code() == 0 {
+			systemd.ready();
+			failed_cycles = 0;
+		} else {
+			failed_cycles += 1;
+		}
+
+		if config.run_once {
+			return summary;
+		}
+
+		// Addresses are detected at least every CF_UPDATE_INTERVAL, earlier when a domain is due.
+		// A first failure is likely a hiccup and retried soon, during an outage the interval doubles
+		// with each failed cycle, up to CF_MAX_BACKOFF
+		let now = Instant::now();
+		let next_cycle = if failed_cycles == 1 && config.retry_after > 0 && config.retry_after < config.update_interval {
+			warn!("Cycle failed, retrying in {} seconds", config.retry_after);
+			// Published records are cached, the retry only contacts Cloudflare for what failed
+			update_all = true;
+			now + Duration::from_secs(config.retry_after)
+		} else if failed_cycles > 1 {
+			let backoff = backoff_interval(config.update_interval, config.max_backoff, failed_cycles);
+			warn!("{} cycles in a row failed, retrying in {} seconds", failed_cycles, backoff);
+			now + Duration::from_secs(backoff)
+		} else {
+			config.domains.iter()
+				.filter_map(|d| next_updates.get(&d.name).copied())
+				.filter(|next| *next > now)
+				.chain([now + Duration::from_secs(config.update_interval)])
+				.min()
+				.unwrap_or(now)
+		};
+		// The events of the cycle that just ran are this loop's own, only an update requested
+		// meanwhile starts the next cycle early
+		let mut requested = None;
+		loop {
+			match requests.try_recv() {
+				Ok(message) => if let Event::UpdateRequested { reason } = message.event {
+					requested = Some(reason);
+				},
+				Err(TryRecvError::Lagged(_)) => continue,
+				Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+			}
+		}
+		let next_cycle = if requested.is_some() { now } else { next_cycle };
+		if let Some(reason) = requested {
+			info!("Update requested during the cycle ({}), updating now", reason);
+			update_all = true;
+		}
+
+		debug!("Sleeping for {} seconds", next_cycle.duration_since(now).as_secs());
+		let sleep = tokio::time::sleep_until(next_cycle.into());
+		tokio::pin!(sleep);
+		loop {
+			tokio::select! {
+				_ = &mut sleep => break,
+				message = requests.recv() => match message.map(|message| message.event) {
+					Ok(Event::UpdateRequested { reason }) => {
+						info!("Update requested ({}), updating now", reason);
+						update_all = true;
+						break;
+					},
+					// Published by an embedding application or a plugin, the loop's own are only
+					// published during a cycle
+					Ok(Event::IpChanged { family, new, .. }) => {
+						info!("Public {} changed to {}, updating now", family, new);
+						update_all = true;
+						break;
+					},
+					Ok(_) => {},
+					// Whatever was missed may have been a request
+					Err(RecvError::Lagged(_)) => {
+						update_all = true;
+						break;
+					},
+					// Not while the loop holds the bus itself
+					Err(RecvError::Closed) => {},
+				},
+				// Only pinged between cycles, a cycle stuck for longer than WatchdogSec gets the service restarted
+				_ = systemd.watchdog_due() => systemd.watchdog(),
+				_ = shutdown.wait_for(|shutdown| *shutdown) => return summary,
+			}
+		}
+	}
+	
+}
+
+/// The address to publish for `family`: `detected` once it was detected in `cycles` cycles in a
+/// row, the `accepted` one until then. An address flapping between two values (e.g. dual-WAN
+/// failover) never gets confirmed and doesn't rewrite the records each time.
+fn debounce(candidates: &mut HashMap<IpFamily, (String, u32)>, cycles: u32, family: IpFamily, detected: String, accepted: Option<&String>) -> String {
+	let Some(accepted) = accepted.filter(|accepted| cycles > 1 && **accepted != detected) else {
+		candidates.remove(&family);
+		return detected;
+	};
+
+	let candidate = candidates.entry(family).or_insert_with(|| (detected.clone(), 0));
+	if candidate.0 != detected {
+		*candidate = (detected.clone(), 0);
+	}
+	candidate.1 += 1;
+	if candidate.1 >= cycles {
+		candidates.remove(&family);
+		return detected;
+	}
+
+	info!("Public {} {} detected {} of {} times, keeping {} until it is confirmed", family, detected, candidate.1, cycles, accepted);
+	accepted.clone()
+}
+
+/// `interval` doubled for each failed cycle after the first, never above `max` (or `interval`).
+fn backoff_interval(interval: u64, max: u64, failed_cycles: u32) -> u64 {
+	let factor = 1u64 << failed_cycles.saturating_sub(1).min(16);
+	interval.saturating_mul(factor).min(max.max(interval))
+}
+
+/// Looks up the zones of all configured domains at once and caches them in `state`, instead of one
+/// domain at a time in the first cycle. Zones that don't exist, have the wrong status or can't be
+/// read with the domain's credentials are logged and the first such error returned; network
+/// errors are only warned about, the cycles look those zones up again.
+pub async fn prefetch_zones(config: &Config, state: &State) -> Result<(), Error> {
+	let cloudflare = cloudflare_client(config, &EventBus::default());
+	let mut zones: Vec<(Option<&Credentials>, String)> = Vec::new();
+	for domain in config.domains.iter().chain(&config.canary) {
+		let zone = cloudflare.account(domain.credentials.as_ref()).zone_name(&domain.name)?;
+		if !zones.iter().any(|(_, z)| *z == zone) && state.zone_id(&zone, config.zone_cache_ttl).await.is_none() {
+			zones.push((domain.credentials.as_ref(), zone));
+		}
+	}
+	if zones.is_empty() {
+		return Ok(());
+	}
+
+	debug!("Looking up {} zones", zones.len());
+	let lookups = zones.into_iter().map(|(credentials, zone)| {
+		let provider = cloudflare.account(credentials);
+		async move {
+			let result = provider.zone_for_domain(&zone).await;
+			(zone, result)
+		}
+	});
+	let mut first_error = None;
+	let mut results = futures::stream::iter(lookups).buffer_unordered(config.max_concurrency);
+	while let Some((zone, result)) = results.next().await {
+		match result {
+			Ok(zone_id) => {
+				debug!("Cached Zone id for {}: {}", zone, zone_id);
+				state.set_zone_id(&zone, &zone_id).await;
+			},
+			Err(e) if e.is_transient() || matches!(&e, Error::Http(_) | Error::CircuitOpen) => warn!("Could not look up zone {}: {}", zone, e),
+			Err(e) => {
+				error!("Zone {} can't be updated: {}", zone, e);
+				first_error.get_or_insert(e);
+			},
+		}
+	}
+	first_error.map_or(Ok(()), Err)
+}
+
+/// The Cloudflare client with the connection and record settings of `config`.
+pub fn cloudflare_client(config: &Config, events: &EventBus) -> CloudflareClient {
+	CloudflareClient::new(config.api_client.clone(), config.auth.clone(), config.fallback_token.as_ref(), &config.api_url, config.retry.clone(), events.clone())
+		.dry_run(config.dry_run)
+		.record_tags(config.record_tags.clone())
+		.duplicate_records(config.duplicate_records)
+		.force_takeover(config.force_takeover)
+		.create_missing(config.create_missing)
+		.proxy_fallback(config.proxy_fallback)
+		.cname_conflict(config.cname_conflict)
+		.zone_status(config.zone_status.clone())
+		.circuit_breaker(config.circuit_breaker.clone())
+}
+
+/// Detects the addresses of the enabled families, IPv6 only with `detect_ipv6`. With `ipv6_quiet`
+/// a failed IPv6 detection is only logged at debug level.
+async fn update_ips(config: &Config, events: &EventBus, detect_ipv6: bool, ipv6_quiet: bool) -> Result<(Option<String>, Option<String>), Box<dyn std::error::Error>> {
+	let (ipv4_enabled, ipv6_enabled) = (config.ipv4_enabled, config.ipv6_enabled && detect_ipv6);
+	let (ipv4_source, ipv6_source) = (config.ipv4_source.clone(), config.ipv6_source.clone());
+	let (ipv4_client, ipv6_client) = (config.ipv4_client.clone(), config.ipv6_client.clone());
+	let (ipv4_events, ipv6_events) = (events.clone(), events.clone());
+
+	let ipv4_fut = tokio::spawn(
+		async move {
+			if ipv4_enabled {
+				debug!("Getting public IPv4...");
+				match ipv4_source.detect(&ipv4_client, IpFamily::V4).await {
+					Ok(ipv4) => {
+						info!("Public IPv4: {}", ipv4);
+						ipv4_events.emit(Event::IpDetected { family: IpFamily::V4, ip: ipv4.clone() });
+						Some(ipv4)
+					},
+					Err(e) => {
+						error!("Error getting public IPv4: {}", e);
+						ipv4_events.emit(Event::IpDetectionFailed { family: IpFamily::V4, error: e.to_string() });
+						None
+					}
+				}
+			} else {
+				None
+			}
+		}
+	);
+
+	let ipv6_fut = tokio::spawn(
+		async move {
+			if ipv6_enabled {
+				debug!("Getting public IPv6...");
+				match ipv6_source.detect(&ipv6_client, IpFamily::V6).await {
+					Ok(ipv6) => {
+						info!("Public IPv6: {}", ipv6);
+						ipv6_events.emit(Event::IpDetected { family: IpFamily::V6, ip: ipv6.clone() });	
+						Some(ipv6)
+					},
+					Err(e) if ipv6_quiet => {
+						debug!("Error getting public IPv6: {}", e);
+						ipv6_events.emit(Event::IpDetectionFailed { family: IpFamily::V6, error: e.to_string() });
+						None
+					},
+					Err(e) => {
+						error!("Error getting public IPv6: {}", e);
+						ipv6_events.emit(Event::IpDetectionFailed { family: IpFamily::V6, error: e.to_string() });
+						None
+					}
+				}
+			} else {
+				None
+			}
+		}
+	);
+
+	match tokio::join!(ipv4_fut, ipv6_fut) {
+		(Ok(ipv4), Ok(ipv6)) => Ok((
+			// The script may replace the address, what it returns is checked like a detected one
+			ipv4.and_then(|ip| script_filter_ip(config, IpFamily::V4, ip)).and_then(|ip| check_routable(config, events, IpFamily::V4, ip)),
+			ipv6.and_then(|ip| script_filter_ip(config, IpFamily::V6, ip)).and_then(|ip| check_routable(config, events, IpFamily::V6, ip)),
+		)),
+		(Err(e), _) | (_, Err(e)) => Err(e.into()),
+	}
+	
+}
+
+/// A record that doesn't hold the detected address yet, or the canary's record to be verified.
+struct PendingRecord {
+	family: IpFamily,
+	ip: String,
+	/// Id of the record last published
+	record_id: Option<String>,
+	comment: Option<String>,
+}
+
+/// What [`plan_domain`] decided for a domain: reports of the records left alone, and the records
+/// to send to the provider with the zone they are in (`base_domain`, `zone_id`).
+struct DomainPlan {
+	reports: Vec<RecordReport>,
+	pending: Vec<PendingRecord>,
+	zone: Option<(String, String)>,
+}
+
+/// Updates the records of `domain` and reports what happened to each of them. Records already
+/// holding the address according to `state` aren't touched. With `verify` (the canary) the record
+/// is read back and probed even when it didn't change.
+pub(crate) async fn update_domain(provider: &Arc<dyn DnsProvider>, config: &Arc<Config>, events: &EventBus, failover: &Failover, state: &State, domain: &DomainConfig, ipv4: Option<String>, ipv6: Option<String>, verify: bool) -> Result<Vec<RecordReport>, Error> {
+	let DomainPlan { mut reports, pending, zone } = plan_domain(provider, config, events, failover, state, domain, ipv4, ipv6, verify).await?;
+	if let Some((base_domain, zone_id)) = zone {
+		let records = pending.into_iter().map(|record| (domain.clone(), record)).collect();
+		reports.extend(publish(provider, config, events, state, &base_domain, &zone_id, records, verify).await);
+	}
+	Ok(reports)
+}
+
+/// Picks the address of each record of `domain` and finds the records that need an update, the
+/// zone is only looked up when there are any.
+#[tracing::instrument(skip_all, fields(domain = %domain.name))]
+async fn plan_domain(provider: &Arc<dyn DnsProvider>, config: &Arc<Config>, events: &EventBus, failover: &Failover, state: &State, domain: &DomainConfig, ipv4: Option<String>, ipv6: Option<String>, verify: bool) -> Result<DomainPlan, Error> {
+	let addresses = [
+		(IpFamily::V4, ipv4.filter(|_| domain.ipv4_enabled)),
+		(IpFamily::V6, ipv6.filter(|_| domain.ipv6_enabled)),
+	];
+
+	let mut reports = Vec::new();
+	let mut pending = Vec::new();
+	for (family, ip) in addresses {
+		let Some(mut ip) = ip else {
+			continue;
+		};
+		if let (IpFamily::V6, Some(suffix)) = (family, domain.ipv6_suffix) {
+			ip = ipsource::with_suffix(&ip, suffix, config.ipv6_prefix_length)?;
+		}
+		if let (Some(probe), Some(fallback)) = (&config.probe, domain.failover(family)) {
+			ip = failover.select(probe, config.probe_timeout, events, &domain.name, family, &ip, fallback).await;
+		}
+		if !script_allows_update(config, &domain.name, family.record_type(), &ip) {
+			reports.push(RecordReport { domain: domain.name.clone(), family: Some(family), content: ip, outcome: RecordOutcome::Skipped });
+			continue;
+		}
+		let published = state.published(&domain.name, family).await;
+		let record_id = match published {
+			Some(published) if published.content == ip => {
+				if !verify {
+					reports.push(RecordReport { domain: domain.name.clone(), family: Some(family), content: ip, outcome: RecordOutcome::Unchanged });
+					continue;
+				}
+				// The canary is looked up to be verified, not patched
+				None
+			},
+			Some(published) => {
+				if let Some(remaining) = state.held_down(&domain.name, family, config.hold_down).await {
+					info!("Record '{}' {} changed less than {} seconds ago, keeping '{}' for another {} seconds", domain.name, family.record_type(), config.hold_down.as_secs(), published.content, remaining.as_secs());
+					reports.push(RecordReport { domain: domain.name.clone(), family: Some(family), content: ip, outcome: RecordOutcome::Skipped });
+					continue;
+				}
+				Some(published.id)
+			},
+			None => None,
+		};
+		// Resolving the name costs no API request, a record that already holds the address isn't read.
+		// Proxied records resolve to Cloudflare's addresses
+		if let (Some(dns_first), false) = (config.dns_first, verify || domain.proxied || domain.keep_proxied) {
+			match dns_first_server(provider, dns_first, &domain.name).await {
+				Ok(server) => match resolves_to(server, &domain.name, family, &ip).await {
+					Ok(()) => {
+						debug!("'{}' resolves to '{}' on {}, not reading the record", domain.name, ip, server);
+						reports.push(RecordReport { domain: domain.name.clone(), family: Some(family), content: ip, outcome: RecordOutcome::Unchanged });
+						continue;
+					},
+					Err(seen) => debug!("'{}' resolves to {} on {}, checking the record", domain.name, seen, server),
+				},
+				Err(e) => debug!("Could not find a nameserver for '{}', checking the record: {}", domain.name, e),
+			}
+		}
+		let comment = script_record_comment(config, &domain.name, family.record_type(), &ip)
+			.or_else(|| config.record_comment.clone());
+		pending.push(PendingRecord { family, ip, record_id, comment });
+	}
+
+	// Nothing changed since the last update, no need to ask the provider
+	if pending.is_empty() {
+		return Ok(DomainPlan { reports, pending, zone: None });
+	}
+
+	let base_domain = provider.zone_name(&domain.name)?;
+	let zone_id = match state.zone_id(&base_domain, config.zone_cache_ttl).await {
+		Some(zone_id) => zone_id,
+		None => {
+			let zone_id = provider.zone_for_domain(&base_domain).await?;
+			debug!("Cached Zone id for {}: {}", base_domain, zone_id);
+			state.set_zone_id(&base_domain, &zone_id).await;
+			zone_id
+		}
+	};
+
+	Ok(DomainPlan { reports, pending, zone: Some((base_domain, zone_id)) })
+}
+
+/// Sends the pending `records` of one zone to the provider together, then checks each of them.
+async fn publish(provider: &Arc<dyn DnsProvider>, config: &Arc<Config>, events: &EventBus, state: &State, base_domain: &str, zone_id: &str, records: Vec<(DomainConfig, PendingRecord)>, verify: bool) -> Vec<RecordReport> {
+	let results = {
+		let requests: Vec<RecordRequest> = records.iter()
+			.map(|(domain, record)| RecordRequest {
+				name: &domain.name,
+				family: record.family,
+				content: &record.ip,
+				record_id: record.record_id.as_deref(),
+				ttl: Some(domain.ttl).filter(|_| !domain.keep_ttl),
+				comment: record.comment.as_deref(),
+				cloudflare: CloudflareOptions { proxied: domain.proxied, keep_proxied: domain.keep_proxied },
+			})
+			.collect();
+		provider.upsert_records(zone_id, &requests).await
+	};
+
+	let mut futures_list = Vec::new();
+	for ((domain, record), result) in records.into_iter().zip(results) {
+		let span = tracing::info_span!("record", domain = %domain.name, record_type = record.family.record_type());
+		let future = tokio::spawn(
+			check_record(provider.clone(), config.clone(), events.clone(), state.clone(), base_domain.to_string(), zone_id.to_string(), domain, record, result, verify)
+				.instrument(span)
+		);
+		futures_list.push(future);
+	}
+
+	let mut reports = Vec::new();
+	for report in futures::future::join_all(futures_list).await {
+		match report {
+			Ok(report) => {
+				// Failed records are checked against Cloudflare again next cycle
+				if let (RecordOutcome::Failed, Some(family)) = (&report.outcome, report.family) {
+					state.forget(&report.domain, family).await;
+				}
+				reports.push(report)
+			},
+			Err(e) => error!("Record update task failed: {}", e),
+		}
+	}
+	reports
+}
+
+/// Remembers, announces, verifies and probes the outcome of one record update.
+async fn check_record(provider: Arc<dyn DnsProvider>, config: Arc<Config>, events: EventBus, state: State, base_domain: String, zone_id: String, domain: DomainConfig, pending: PendingRecord, result: Result<RecordUpdate, Error>, verify: bool) -> RecordReport {
+	let PendingRecord { family, ip, .. } = pending;
+	let report = |outcome| RecordReport { domain: domain.name.clone(), family: Some(family), content: ip.clone(), outcome };
+
+	let (outcome, record) = match result {
+		Ok(RecordUpdate { change, record }) => {
+			// A dry run didn't change anything, nothing to remember, announce or check
+			if config.dry_run && change != RecordChange::Unchanged {
+				return report(if change == RecordChange::Created { RecordOutcome::Created } else { RecordOutcome::Updated });
+			}
+			state.set_published(&domain.name, family, &record.id, &record.content).await;
+			if change == RecordChange::Unchanged && !verify {
+				return report(RecordOutcome::Unchanged);
+			}
+			let outcome = match change {
+				RecordChange::Created => RecordOutcome::Created,
+				RecordChange::Updated => RecordOutcome::Updated,
+				RecordChange::Unchanged => RecordOutcome::Unchanged,
+			};
+			match outcome {
+				RecordOutcome::Created => events.emit(Event::RecordCreated { domain: domain.name.clone(), record_type: record.record_type.clone(), content: record.content.clone() }),
+				RecordOutcome::Updated => events.emit(Event::RecordUpdated { domain: domain.name.clone(), record_type: record.record_type.clone(), content: record.content.clone() }),
+				_ => (),
+			}
+			(outcome, record)
+		},
+		Err(Error::Conflict(e) | Error::RecordMissing(e)) => {
+			warn!("Skipping {} address of '{}': {}", family, domain.name, e);
+			return report(RecordOutcome::Skipped);
+		},
+		Err(e) => {
+			// The zone was deleted, recreated or moved to another account, look its ID up next cycle
+			if e.is_zone_gone() {
+				state.forget_zone(&base_domain).await;
+				state.forget(&domain.name, family).await;
+			}
+			error!("Error updating domain '{}' with {} address '{}': {}", domain.name, family, ip, e);
+			events.emit(Event::UpdateFailed { domain: domain.name.clone(), family: Some(family), error: e.to_string() });
+			return report(RecordOutcome::Failed);
+		}
+	};
+
+	if verify {
+		// Read the record back, so the check doesn't rely on the write response alone
+		let stored = provider.find_record(&zone_id, &domain.name, family).await
+			.map_err(|e| e.to_string())
+			.and_then(|r| r.ok_or_else(|| "record not found".to_string()))
+			.and_then(|r| if r.content == ip { Ok(()) } else { Err(format!("record holds '{}' instead of '{}'", r.content, ip)) });
+		if let Err(e) = stored {
+			error!("Verification of '{}' failed: {}", domain.name, e);
+			events.emit(Event::UpdateFailed { domain: domain.name.clone(), family: Some(family), error: e });
+			return report(RecordOutcome::Failed);
+		}
+	}
+
+	// A proxied record resolves to Cloudflare's addresses, not the published one
+	if let (Some(server), false, false) = (config.verify_dns, record.proxied, outcome == RecordOutcome::Unchanged) {
+		match verify_propagation(server, &domain.name, family, &record.content, config.verify_dns_timeout).await {
+			Ok(elapsed) => info!("'{}' resolves to '{}' on {} after {} seconds", domain.name, record.content, server, elapsed.as_secs()),
+			Err(e) => {
+				error!("'{}' didn't propagate: {}", domain.name, e);
+				events.emit(Event::PropagationFailed { domain: domain.name.clone(), record_type: record.record_type.clone(), content: record.content.clone(), error: e });
+				return report(RecordOutcome::Failed);
+			}
+		}
+	}
+
+	let Some(probe) = &config.probe else {
+		return report(outcome);
+	};
+
+	// Give the new address a moment to settle before checking it
+	tokio::time::sleep(config.probe_delay).await;
+	match probe.run(&domain.name, &record.content, record.proxied, config.probe_timeout).await.map_err(|e| e.to_string()) {
+		Ok(result) => {
+			info!("Probe of '{}' succeeded: {}", domain.name, result);
+			report(outcome)
+		},
+		Err(e) => {
+			error!("Probe of '{}' failed: {}", domain.name, e);
+			events.emit(Event::ProbeFailed { domain: domain.name.clone(), error: e });
+			report(RecordOutcome::Failed)
+		}
+	}
+}
+
+/// Asks `server` for the records of `domain` until it returns `content` and nothing else. A stale
+/// cache or a duplicate record with another address keeps failing until `timeout`.
+async fn verify_propagation(server: IpAddr, domain: &str, family: IpFamily, content: &str, timeout: Duration) -> Result<Duration, String> {
+	let started = Instant::now();
+	loop {
+		let Err(seen) = resolves_to(server, domain, family, content).await else {
+			return Ok(started.elapsed());
+		};
+		if started.elapsed() >= timeout {
+			return Err(format!("{} still answers {} after {} seconds", server, seen, timeout.as_secs()));
+		}
+		debug!("'{}' resolves to {} on {}, checking again", domain, seen, server);
+		tokio::time::sleep(PROPAGATION_INTERVAL).await;
+	}
+}
+
+/// The server `CF_DNS_FIRST` asks about `domain`, by default a nameserver of its zone.
+async fn dns_first_server(provider: &Arc<dyn DnsProvider>, dns_first: DnsFirst, domain: &str) -> Result<IpAddr, String> {
+	match dns_first {
+		DnsFirst::Server(server) => Ok(server),
+		DnsFirst::Authoritative => {
+			let zone = provider.zone_name(domain).map_err(|e| e.to_string())?;
+			let nameservers = ipsource::nameservers(&zone).await.map_err(|e| e.to_string())?;
+			Ok(nameservers[0])
+		},
+	}
+}
+
+/// Whether `server` resolves `domain` to `content` and nothing else, what it answered otherwise.
+async fn resolves_to(server: IpAddr, domain: &str, family: IpFamily, content: &str) -> Result<(), String> {
+	let expected = content.parse::<IpAddr>().map_err(|e| e.to_string())?;
+	match ipsource::lookup(server, domain, family).await {
+		Ok(answers) if answers.is_empty() => Err("no records".to_string()),
+		Ok(answers) if answers.iter().all(|answer| answer.parse::<IpAddr>().is_ok_and(|ip| ip == expected)) => Ok(()),
+		Ok(answers) => Err(answers.join(", ")),
+		Err(e) => Err(e.to_string()),
+	}
+}
+
+/// Drops an address nobody outside could reach, unless `CF_NON_ROUTABLE=publish`. Behind
+/// carrier-grade NAT the public address belongs to the ISP and the router only sees 100.64.0.0/10.
+pub(crate) fn check_routable(config: &Config, events: &EventBus, family: IpFamily, ip: String) -> Option<String> {
+	let Some(space) = ipsource::non_routable(&ip) else {
+		return Some(ip);
+	};
+
+	match config.non_routable {
+		ipsource::NonRoutable::Skip => {
+			let message = format!("Public {} {} is in {} space and can't be reached from the Internet, not publishing it (CF_NON_ROUTABLE=publish to publish it anyway)", family, ip, space);
+			error!("{}", message);
+			if space.starts_with("carrier-grade NAT") {
+				error!("This host is behind carrier-grade NAT, ask the ISP for a public {} or use IPv6", family);
+			}
+			events.emit(Event::IpDetectionFailed { family, error: message });
+			None
+		},
+		ipsource::NonRoutable::Publish => {
+			warn!("Public {} {} is in {} space, publishing it anyway", family, ip, space);
+			Some(ip)
+		},
+	}
+}
+
+fn script_filter_ip(config: &Config, family: IpFamily, ip: String) -> Option<String> {
+	#[cfg(feature = "scripting")]
+	if let Some(script) = &config.script {
+		return match script.filter_ip(family, ip) {
+			Ok(Some(ip)) => Some(ip),
+			Ok(None) => {
+				info!("Script dropped the public {}", family);
+				None
+			},
+			Err(e) => {
+				error!("Error running script: {}", e);
+				None
+			}
+		};
+	}
+
+	#[cfg(not(feature = "scripting"))]
+	let _ = (config, family);
+	Some(ip)
+}
+
+fn script_allows_update(config: &Config, domain: &str, record_type: &str, content: &str) -> bool {
+	#[cfg(feature = "scripting")]
+	if let Some(script) = &config.script {
+		return match script.allow_update(domain, record_type, content) {
+			Ok(true) => true,
+			Ok(false) => {
+				info!("Script vetoed the {} update of '{}' to '{}'", record_type, domain, content);
+				false
+			},
+			Err(e) => {
+				error!("Error running script: {}", e);
+				false
+			}
+		};
+	}
+
+	#[cfg(not(feature = "scripting"))]
+	let _ = (config, domain, record_type, content);
+	true
+}
+
+fn script_record_comment(config: &Config, domain: &str, record_type: &str, content: &str) -> Option<String> {
+	#[cfg(feature = "scripting")]
+	if let Some(script) = &config.script {
+		return script.record_comment(domain, record_type, content).unwrap_or_else(|e| {
+			error!("Error running script: {}", e);
+			None
+		});
+	}
+
+	#[cfg(not(feature = "scripting"))]
+	let _ = (config, domain, record_type, content);
+	None
+}
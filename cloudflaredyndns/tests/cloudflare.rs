@@ -0,0 +1,240 @@
+//! The Cloudflare client against a mocked API: what it sends for each kind of record update and
+//! how it handles paging and failed requests.
+
+use std::time::Duration;
+
+use cloudflaredyndns::EventBus;
+use cloudflaredyndns::cloudflare::{CloudflareClient, Credentials, RetryPolicy};
+use cloudflaredyndns::error::Error;
+use cloudflaredyndns::ipsource::IpFamily;
+use cloudflaredyndns::provider::{CloudflareOptions, DnsProvider, RecordChange, RecordRequest};
+use cloudflaredyndns::secret::Secret;
+use serde_json::{Value, json};
+use wiremock::matchers::{body_partial_json, header, method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const ZONE_ID: &str = "023e105f4ecef8ad9ca31a8372d0c353";
+const RECORDS_PATH: &str = "/zones/023e105f4ecef8ad9ca31a8372d0c353/dns_records";
+
+fn client(server: &MockServer) -> CloudflareClient {
+	let retry = RetryPolicy { attempts: 3, base_delay: Duration::from_millis(1), max_delay: Duration::from_millis(10) };
+	CloudflareClient::new(reqwest::Client::new(), Credentials::Token(Secret::new("token")), None, &server.uri(), retry, EventBus::default())
+}
+
+fn record(id: &str, name: &str, content: &str) -> Value {
+	json!({ "id": id, "name": name, "type": "A", "content": content, "proxiable": true, "proxied": false, "ttl": 1 })
+}
+
+fn success(result: Value) -> ResponseTemplate {
+	ResponseTemplate::new(200).set_body_json(json!({ "success": true, "errors": [], "messages": [], "result": result }))
+}
+
+fn page(result: Value, page: u32, total_pages: u32) -> ResponseTemplate {
+	ResponseTemplate::new(200).set_body_json(json!({
+		"success": true, "errors": [], "messages": [], "result": result,
+		"result_info": { "page": page, "total_pages": total_pages },
+	}))
+}
+
+fn failure(status: u16, code: u64, message: &str) -> ResponseTemplate {
+	ResponseTemplate::new(status).set_body_json(json!({ "success": false, "errors": [{ "code": code, "message": message }], "messages": [], "result": null }))
+}
+
+fn request<'a>(name: &'a str, content: &'a str, record_id: Option<&'a str>) -> RecordRequest<'a> {
+	RecordRequest { name, family: IpFamily::V4, content, record_id, ttl: None, comment: None, cloudflare: CloudflareOptions::default() }
+}
+
+async fn mock_records(server: &MockServer, record_type: &str, records: Value) {
+	Mock::given(method("GET"))
+		.and(path(RECORDS_PATH))
+		.and(query_param("type", record_type))
+		.respond_with(success(records))
+		.mount(server)
+		.await;
+}
+
+#[tokio::test]
+async fn creates_missing_record() {
+	let server = MockServer::start().await;
+	mock_records(&server, "A", json!([])).await;
+	mock_records(&server, "CNAME", json!([])).await;
+	Mock::given(method("POST"))
+		.and(path(RECORDS_PATH))
+		.and(header("Authorization", "Bearer token"))
+		.and(body_partial_json(json!({ "name": "home.example.com", "type": "A", "content": "203.0.113.7", "proxied": false })))
+		.respond_with(success(record("new", "home.example.com", "203.0.113.7")))
+		.expect(1)
+		.mount(&server)
+		.await;
+
+	let update = client(&server).upsert_record(ZONE_ID, &request("home.example.com", "203.0.113.7", None)).await.unwrap();
+	assert_eq!(update.change, RecordChange::Created);
+	assert_eq!(update.record.id, "new");
+}
+
+#[tokio::test]
+async fn updates_changed_record() {
+	let server = MockServer::start().await;
+	mock_records(&server, "A", json!([record("rec", "home.example.com", "198.51.100.1")])).await;
+	Mock::given(method("PATCH"))
+		.and(path(format!("{}/rec", RECORDS_PATH)))
+		.and(body_partial_json(json!({ "content": "203.0.113.7" })))
+		.respond_with(success(record("rec", "home.example.com", "203.0.113.7")))
+		.expect(1)
+		.mount(&server)
+		.await;
+
+	let update = client(&server).upsert_record(ZONE_ID, &request("home.example.com", "203.0.113.7", None)).await.unwrap();
+	assert_eq!(update.change, RecordChange::Updated);
+	assert_eq!(update.record.content, "203.0.113.7");
+}
+
+#[tokio::test]
+async fn leaves_unchanged_record_alone() {
+	let server = MockServer::start().await;
+	mock_records(&server, "A", json!([record("rec", "home.example.com", "203.0.113.7")])).await;
+	Mock::given(method("PATCH")).respond_with(success(json!({}))).expect(0).mount(&server).await;
+	Mock::given(method("POST")).respond_with(success(json!({}))).expect(0).mount(&server).await;
+
+	let update = client(&server).upsert_record(ZONE_ID, &request("home.example.com", "203.0.113.7", None)).await.unwrap();
+	assert_eq!(update.change, RecordChange::Unchanged);
+}
+
+#[tokio::test]
+async fn patches_known_record_without_lookup() {
+	let server = MockServer::start().await;
+	Mock::given(method("GET")).respond_with(success(json!([]))).expect(0).mount(&server).await;
+	Mock::given(method("PATCH"))
+		.and(path(format!("{}/rec", RECORDS_PATH)))
+		.respond_with(success(record("rec", "home.example.com", "203.0.113.7")))
+		.expect(1)
+		.mount(&server)
+		.await;
+
+	let update = client(&server).upsert_record(ZONE_ID, &request("home.example.com", "203.0.113.7", Some("rec"))).await.unwrap();
+	assert_eq!(update.change, RecordChange::Updated);
+}
+
+#[tokio::test]
+async fn recreates_known_record_deleted_elsewhere() {
+	let server = MockServer::start().await;
+	Mock::given(method("PATCH"))
+		.and(path(format!("{}/gone", RECORDS_PATH)))
+		.respond_with(failure(404, 81044, "Record does not exist."))
+		.expect(1)
+		.mount(&server)
+		.await;
+	mock_records(&server, "A", json!([])).await;
+	mock_records(&server, "CNAME", json!([])).await;
+	Mock::given(method("POST"))
+		.and(path(RECORDS_PATH))
+		.respond_with(success(record("new", "home.example.com", "203.0.113.7")))
+		.expect(1)
+		.mount(&server)
+		.await;
+
+	let update = client(&server).upsert_record(ZONE_ID, &request("home.example.com", "203.0.113.7", Some("gone"))).await.unwrap();
+	assert_eq!(update.change, RecordChange::Created);
+}
+
+#[tokio::test]
+async fn lists_every_page() {
+	let server = MockServer::start().await;
+	Mock::given(method("GET"))
+		.and(path(RECORDS_PATH))
+		.and(query_param("page", "1"))
+		.respond_with(page(json!([record("a", "home.example.com", "203.0.113.1")]), 1, 2))
+		.expect(1)
+		.mount(&server)
+		.await;
+	Mock::given(method("GET"))
+		.and(path(RECORDS_PATH))
+		.and(query_param("page", "2"))
+		.respond_with(page(json!([record("b", "home.example.com", "203.0.113.2")]), 2, 2))
+		.expect(1)
+		.mount(&server)
+		.await;
+
+	let records = client(&server).list_records("home.example.com", "A", ZONE_ID).await.unwrap();
+	let ids = records.iter().map(|r| r.id.as_str()).collect::<Vec<_>>();
+	assert_eq!(ids, ["a", "b"]);
+}
+
+#[tokio::test]
+async fn reports_rejected_request() {
+	let server = MockServer::start().await;
+	mock_records(&server, "A", json!([])).await;
+	mock_records(&server, "CNAME", json!([])).await;
+	Mock::given(method("POST"))
+		.and(path(RECORDS_PATH))
+		.respond_with(failure(400, 9005, "Content for A record is invalid."))
+		.expect(1)
+		.mount(&server)
+		.await;
+
+	let error = client(&server).upsert_record(ZONE_ID, &request("home.example.com", "203.0.113.7", None)).await.unwrap_err();
+	assert!(matches!(&error, Error::Api { status: 400, errors } if errors[0].code == 9005), "{:?}", error);
+	assert!(!error.is_transient());
+}
+
+#[tokio::test]
+async fn reports_rejected_credentials() {
+	let server = MockServer::start().await;
+	Mock::given(method("GET")).respond_with(failure(403, 10000, "Authentication error")).expect(1).mount(&server).await;
+
+	let error = client(&server).find_record(ZONE_ID, "home.example.com", IpFamily::V4).await.unwrap_err();
+	assert!(error.is_auth(), "{:?}", error);
+}
+
+#[tokio::test]
+async fn retries_server_errors() {
+	let server = MockServer::start().await;
+	Mock::given(method("GET"))
+		.respond_with(failure(502, 0, "Bad gateway"))
+		.up_to_n_times(2)
+		.expect(2)
+		.mount(&server)
+		.await;
+	mock_records(&server, "A", json!([record("rec", "home.example.com", "203.0.113.7")])).await;
+
+	let record = client(&server).find_record(ZONE_ID, "home.example.com", IpFamily::V4).await.unwrap();
+	assert_eq!(record.map(|r| r.id).as_deref(), Some("rec"));
+}
+
+#[tokio::test]
+async fn reports_unexpected_response() {
+	let server = MockServer::start().await;
+	Mock::given(method("GET"))
+		.respond_with(ResponseTemplate::new(200).set_body_string("<html>Maintenance</html>"))
+		.mount(&server)
+		.await;
+
+	let error = client(&server).find_record(ZONE_ID, "home.example.com", IpFamily::V4).await.unwrap_err();
+	assert!(matches!(error, Error::Response { status: 200, .. }), "{:?}", error);
+}
+
+#[tokio::test]
+async fn publishes_unproxied_when_refused() {
+	let server = MockServer::start().await;
+	mock_records(&server, "A", json!([record("rec", "home.example.com", "198.51.100.1")])).await;
+	Mock::given(method("PATCH"))
+		.and(body_partial_json(json!({ "proxied": true })))
+		.respond_with(failure(400, 9041, "This DNS record cannot be proxied."))
+		.expect(2)
+		.mount(&server)
+		.await;
+	Mock::given(method("PATCH"))
+		.and(body_partial_json(json!({ "proxied": false })))
+		.respond_with(success(record("rec", "home.example.com", "10.0.0.7")))
+		.expect(1)
+		.mount(&server)
+		.await;
+
+	let request = RecordRequest { cloudflare: CloudflareOptions { proxied: true, keep_proxied: false }, ..request("home.example.com", "10.0.0.7", None) };
+	let update = client(&server).proxy_fallback(true).upsert_record(ZONE_ID, &request).await.unwrap();
+	assert_eq!(update.change, RecordChange::Updated);
+	assert!(!update.record.proxied);
+
+	let error = client(&server).upsert_record(ZONE_ID, &request).await.unwrap_err();
+	assert!(error.is_not_proxiable(), "{:?}", error);
+}